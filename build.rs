@@ -0,0 +1,19 @@
+use std::process::Command;
+
+fn main() {
+    // Embed the short git hash so bug reports can identify the exact build.
+    // Falls back to "unknown" for crates.io tarballs and other non-git builds.
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=KB_LAYOUT_DAEMON_GIT_HASH={}", hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
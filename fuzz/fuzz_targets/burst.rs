@@ -0,0 +1,32 @@
+#![no_main]
+
+use kb_layout_daemon::events::BurstDetector;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // First two bytes are the key count and window (in 10ms units); then
+    // pairs of a time step (10ms units) and an event count
+    let Some(([keys, window], rest)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let keys = *keys as u32 % 16;
+    let window_ms = *window as u64 * 10;
+    let mut burst = BurstDetector::new(keys, window_ms);
+    let mut now_ms = 0u64;
+    // Model: every event with its time
+    let mut history: Vec<u64> = Vec::new();
+
+    for step in rest.chunks_exact(2) {
+        now_ms += step[0] as u64 * 10;
+        let count = step[1] as u32 % 8;
+        history.extend(std::iter::repeat_n(now_ms, count as usize));
+
+        let bursting = burst.check(count, now_ms);
+        if keys <= 1 {
+            assert_eq!(bursting, count > 0);
+        } else {
+            let recent = history.iter().filter(|&&time| time >= now_ms.saturating_sub(window_ms)).count();
+            assert_eq!(bursting, recent >= keys as usize);
+        }
+    }
+});
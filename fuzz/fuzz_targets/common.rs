@@ -0,0 +1,15 @@
+use evdev::{EventType, InputEvent};
+
+// Decode raw fuzzer bytes into events: 8 bytes each (type, code, value),
+// so any event the kernel could hand us is reachable.
+pub fn decode_events(data: &[u8]) -> Vec<InputEvent> {
+    data.chunks_exact(8)
+        .map(|c| {
+            InputEvent::new(
+                EventType(u16::from_le_bytes([c[0], c[1]])),
+                u16::from_le_bytes([c[2], c[3]]),
+                i32::from_le_bytes([c[4], c[5], c[6], c[7]]),
+            )
+        })
+        .collect()
+}
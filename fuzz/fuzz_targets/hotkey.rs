@@ -0,0 +1,36 @@
+#![no_main]
+
+use evdev::{InputEvent, InputEventKind, Key};
+use kb_layout_daemon::events::{Hotkey, Modifiers, PressedKeys};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let events = common::decode_events(data);
+
+    let mut hotkey = Hotkey::new(Modifiers::CTRL, Key::KEY_SPACE);
+    let mut pressed = PressedKeys::new();
+    for batch in events.chunks(4) {
+        let mut kept = batch.to_vec();
+        let presses = hotkey.take(&mut kept, pressed.modifiers());
+        pressed.process(batch);
+
+        // Only the hotkey's own events are taken, order is kept
+        let same = |a: &InputEvent, b: &InputEvent| {
+            (a.event_type(), a.code(), a.value()) == (b.event_type(), b.code(), b.value())
+        };
+        let mut rest = kept.iter();
+        let mut taken = Vec::new();
+        for ev in batch {
+            if rest.as_slice().first().is_some_and(|next| same(next, ev)) {
+                rest.next();
+            } else {
+                taken.push(ev);
+            }
+        }
+        assert!(rest.next().is_none());
+        assert!(taken.iter().all(|ev| ev.kind() == InputEventKind::Key(Key::KEY_SPACE)));
+        assert_eq!(presses, taken.iter().filter(|ev| ev.value() == 1).count());
+    }
+});
@@ -0,0 +1,35 @@
+#![no_main]
+
+use evdev::{EventType, InputEventKind, Key};
+use kb_layout_daemon::events::LockState;
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let events = common::decode_events(data);
+
+    let mut locks = LockState::default();
+    for batch in events.chunks(3) {
+        let before = locks;
+        let changed = locks.process(batch);
+        assert_eq!(changed, locks != before);
+    }
+
+    // Model: each lock is on iff its key was pressed an odd number of times
+    let presses = |key: Key| {
+        events
+            .iter()
+            .filter(|ev| ev.kind() == InputEventKind::Key(key) && ev.value() == 1)
+            .count()
+    };
+    assert_eq!(locks.caps, presses(Key::KEY_CAPSLOCK) % 2 == 1);
+    assert_eq!(locks.num, presses(Key::KEY_NUMLOCK) % 2 == 1);
+    assert_eq!(locks.scroll, presses(Key::KEY_SCROLLLOCK) % 2 == 1);
+
+    // LED frames carry exactly the three lock LEDs and end the frame
+    let leds = locks.led_events();
+    assert_eq!(leds.len(), 4);
+    assert!(leds[..3].iter().all(|ev| ev.event_type() == EventType::LED));
+    assert_eq!(leds[3].event_type(), EventType::SYNCHRONIZATION);
+});
@@ -0,0 +1,67 @@
+#![no_main]
+
+use kb_layout_daemon::monitor_state::{Action, Input, MonitorState};
+use libfuzzer_sys::fuzz_target;
+
+// Decode 2 bytes per step: input kind and argument
+fn decode_input(kind: u8, arg: u8) -> Input {
+    match kind % 6 {
+        0 => Input::Opened { grabbed: arg & 1 == 1 },
+        1 => Input::Mode { grab: arg & 1 == 1 },
+        2 => Input::SwitchSubmitted { ticket: arg as u64 },
+        3 => Input::SwitchCompleted { ticket: arg as u64 },
+        4 => Input::Lost,
+        _ => Input::Drained,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut state = MonitorState::Disconnected;
+    let mut keys_held = false;
+
+    for step in data.chunks_exact(2) {
+        let input = decode_input(step[0], step[1]);
+        // Key presses only reach the virtual keyboard while grabbed
+        if state.is_grabbed() && step[1] & 2 == 2 {
+            keys_held = true;
+        }
+
+        let (next, action) = state.next(input, keys_held);
+
+        match action {
+            // Releases only for keys that are down on the virtual keyboard
+            Action::ReleaseHeld => {
+                assert!(state.is_grabbed() && keys_held);
+                assert_eq!(next, MonitorState::Draining);
+                keys_held = false;
+            }
+            // A reopen always starts from a closed device and flips the
+            // grab (a draining device is still grabbed)
+            Action::Reopen { grab } => {
+                assert_eq!(next, MonitorState::Disconnected);
+                assert_ne!(grab, state.is_grabbed() || state == MonitorState::Draining);
+            }
+            // Only grabbed monitors hold frames back
+            Action::AwaitSwitch { ticket } => {
+                assert!(state.is_grabbed());
+                assert_eq!(next, MonitorState::Switching { ticket });
+            }
+            Action::None => {}
+        }
+
+        if input == Input::Lost {
+            assert_eq!(next, MonitorState::Disconnected);
+        }
+        // Grabbed states are only entered through a grabbed open or from
+        // another grabbed state
+        if next.is_grabbed() && !state.is_grabbed() {
+            assert_eq!(input, Input::Opened { grabbed: true });
+        }
+        // Draining is left only once the releases are out
+        if state == MonitorState::Draining && next != MonitorState::Draining {
+            assert!(input == Input::Drained || input == Input::Lost);
+        }
+
+        state = next;
+    }
+});
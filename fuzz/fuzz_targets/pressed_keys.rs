@@ -0,0 +1,78 @@
+#![no_main]
+
+use evdev::{EventType, InputEventKind};
+use kb_layout_daemon::events::{retain_passthrough, Activity, PressedKeys};
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let events = common::decode_events(data);
+
+    // Feed the stream in uneven batches, as fetch_events would
+    let mut tracker = PressedKeys::new();
+    let mut presses = 0;
+    for batch in events.chunks(3) {
+        presses += tracker.process(batch);
+    }
+
+    // Model: a key is held iff its last press/release event was a press
+    let mut last_value: HashMap<u16, i32> = HashMap::new();
+    for ev in &events {
+        if let InputEventKind::Key(key) = ev.kind() {
+            if ev.value() == 0 || ev.value() == 1 {
+                last_value.insert(key.code(), ev.value());
+            }
+        }
+    }
+    let held: Vec<u16> = last_value
+        .iter()
+        .filter(|(_, &v)| v == 1)
+        .map(|(&code, _)| code)
+        .collect();
+
+    assert_eq!(tracker.len(), held.len());
+    for code in &held {
+        assert!(tracker.contains(*code));
+    }
+    let expected_presses = events
+        .iter()
+        .filter(|ev| matches!(ev.kind(), InputEventKind::Key(_)) && ev.value() == 1)
+        .count();
+    assert_eq!(presses, expected_presses);
+    // The default activity setting counts exactly the presses
+    assert_eq!(Activity::default().count(&events), expected_presses);
+
+    // Releases cover exactly the held keys
+    let releases = tracker.release_events();
+    assert_eq!(releases.len(), held.len());
+    for ev in &releases {
+        assert_eq!(ev.event_type(), EventType::KEY);
+        assert_eq!(ev.value(), 0);
+        assert!(tracker.contains(ev.code()));
+    }
+
+    tracker.clear();
+    assert!(tracker.is_empty());
+
+    // A keyboard locked from the start only ever holds its passthrough keys
+    let passthrough: Vec<u16> = events.iter().take(1).map(|ev| ev.code()).collect();
+    let mut locked = PressedKeys::new();
+    for batch in events.chunks(3) {
+        let mut batch = batch.to_vec();
+        retain_passthrough(&mut batch, &locked, &passthrough);
+        for ev in &batch {
+            if ev.event_type() == EventType::KEY {
+                assert!(passthrough.contains(&ev.code()) || ev.value() == 0);
+            } else {
+                assert_eq!(ev.event_type(), EventType::SYNCHRONIZATION);
+            }
+        }
+        locked.process(&batch);
+    }
+    assert!(locked.len() <= passthrough.len());
+    for ev in locked.release_events() {
+        assert!(passthrough.contains(&ev.code()));
+    }
+});
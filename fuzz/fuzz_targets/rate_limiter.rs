@@ -0,0 +1,38 @@
+#![no_main]
+
+use evdev::InputEventKind;
+use kb_layout_daemon::events::RateLimiter;
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    // First two bytes are the limit; then batches of a time step byte (in
+    // 10ms units) and up to 8 events
+    let Some((limit, rest)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let limit = u16::from_le_bytes(*limit) as u32 % 64;
+    let mut limiter = RateLimiter::new(limit);
+    let mut now_ms = 0u64;
+    let mut total = 0u32;
+
+    for batch in rest.chunks(1 + 8 * 8) {
+        now_ms += batch[0] as u64 * 10;
+        let events = common::decode_events(&batch[1..]);
+        total += events
+            .iter()
+            .filter(|ev| matches!(ev.kind(), InputEventKind::Key(_)) && (ev.value() == 0 || ev.value() == 1))
+            .count() as u32;
+
+        let flooding = limiter.check(&events, now_ms);
+        // Off means off
+        if limit == 0 {
+            assert!(!flooding);
+        }
+        // A device that never sent more than the limit can't flood
+        if total <= limit {
+            assert!(!flooding);
+        }
+    }
+});
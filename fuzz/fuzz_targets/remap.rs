@@ -0,0 +1,63 @@
+#![no_main]
+
+use evdev::{EventType, InputEvent, InputEventKind};
+use kb_layout_daemon::events::{PressedKeys, Remap, RemapRule};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    // First byte is the rule count; then 3 bytes per rule (layer or 0,
+    // from, to), drawn from a few key codes so rules overlap; then events
+    let Some((&count, rest)) = data.split_first() else {
+        return;
+    };
+    let count = (count % 8) as usize;
+    if rest.len() < count * 3 {
+        return;
+    }
+    let (rule_bytes, event_bytes) = rest.split_at(count * 3);
+    let rules: Vec<RemapRule> = rule_bytes
+        .chunks_exact(3)
+        .map(|r| RemapRule {
+            layer: (r[0] % 8 != 0).then_some(r[0] as u16 % 8),
+            from: r[1] as u16 % 8 + 1,
+            to: r[2] as u16 % 8 + 1,
+        })
+        .collect();
+    // Layer keys that no rule sends
+    let layers: Vec<u16> = rules
+        .iter()
+        .filter_map(|rule| rule.layer)
+        .filter(|&layer| !rules.iter().any(|rule| rule.to == layer))
+        .collect();
+    let mut remap = Remap::new(rules);
+
+    let mut physical = PressedKeys::new();
+    let mut sent = PressedKeys::new();
+    for batch in event_bytes.chunks(8 * 8) {
+        let mut events = common::decode_events(batch);
+        physical.process(&events);
+        let released: Vec<u16> = events
+            .iter()
+            .filter(|ev| ev.event_type() == EventType::KEY && ev.value() == 0)
+            .map(|ev| ev.code())
+            .collect();
+        remap.apply(&mut events);
+        // Layer keys are only sent as themselves while held, or as a tap
+        // when released
+        assert!(!events.iter().any(|ev| matches!(ev.kind(), InputEventKind::Key(key)
+            if ev.value() == 1
+                && layers.contains(&key.code())
+                && !physical.contains(key.code())
+                && !released.contains(&key.code()))));
+        sent.process(&events);
+    }
+
+    // Releasing every physical key releases everything that was sent
+    let mut releases = physical.release_events();
+    releases.push(InputEvent::new(EventType::SYNCHRONIZATION, 0, 0));
+    remap.apply(&mut releases);
+    sent.process(&releases);
+    assert!(sent.is_empty());
+});
@@ -0,0 +1,30 @@
+#![no_main]
+
+use kb_layout_daemon::events::{has_key_release, is_repeat_frame, is_syn_report, split_frames};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let events = common::decode_events(data);
+    let frames: Vec<_> = split_frames(&events).collect();
+
+    // No frame is empty or contains a SYN_REPORT
+    for frame in &frames {
+        assert!(!frame.is_empty());
+        assert!(!frame.iter().any(is_syn_report));
+        // Frames the output queue may drop never release a key
+        assert!(!(is_repeat_frame(frame) && has_key_release(frame)));
+    }
+
+    // Frames preserve every non-SYN_REPORT event, in order
+    let flattened: Vec<_> = frames.iter().flat_map(|f| f.iter()).collect();
+    let expected: Vec<_> = events.iter().filter(|ev| !is_syn_report(ev)).collect();
+    assert_eq!(flattened.len(), expected.len());
+    for (a, b) in flattened.iter().zip(&expected) {
+        assert_eq!(
+            (a.event_type(), a.code(), a.value()),
+            (b.event_type(), b.code(), b.value())
+        );
+    }
+});
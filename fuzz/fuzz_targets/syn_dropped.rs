@@ -0,0 +1,65 @@
+#![no_main]
+
+use kb_layout_daemon::events::{drop_corrupted, is_syn_dropped, is_syn_report, PressedKeys};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    // First byte picks where the input is split into batches; the leading
+    // bytes also serve as the held keys the "kernel" reports on resync
+    let Some((&split, rest)) = data.split_first() else {
+        return;
+    };
+    let events = common::decode_events(rest);
+    let split = (split as usize).min(events.len());
+
+    let mut dropping = false;
+    let mut seen = false;
+    let mut kept = Vec::new();
+    for batch in [&events[..split], &events[split..]] {
+        let mut batch = batch.to_vec();
+        seen |= drop_corrupted(&mut batch, &mut dropping);
+        // Nothing from a dropped block survives
+        assert!(!batch.iter().any(is_syn_dropped));
+        kept.extend(batch);
+    }
+    assert_eq!(seen, events.iter().any(is_syn_dropped));
+
+    // Kept events are the input minus each SYN_DROPPED..=SYN_REPORT span
+    let mut expected = Vec::new();
+    let mut in_drop = false;
+    for ev in &events {
+        if is_syn_dropped(ev) {
+            in_drop = true;
+        } else if in_drop {
+            in_drop = !is_syn_report(ev);
+        } else {
+            expected.push(ev);
+        }
+    }
+    assert_eq!(kept.len(), expected.len());
+    assert_eq!(dropping, in_drop);
+
+    // Resync makes the tracker match the held keys exactly
+    let mut tracker = PressedKeys::new();
+    tracker.process(&kept);
+    let held: Vec<u16> = rest
+        .chunks_exact(2)
+        .take(8)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    let fixes = tracker.resync(&held);
+    for code in &held {
+        assert!(tracker.contains(*code));
+    }
+    let mut unique = held.clone();
+    unique.sort_unstable();
+    unique.dedup();
+    assert_eq!(tracker.len(), unique.len());
+    // Replaying the fixes onto a fresh copy of the old state gives the same result
+    let mut replay = PressedKeys::new();
+    replay.process(&kept);
+    replay.process(&fixes);
+    assert_eq!(replay.len(), tracker.len());
+});
@@ -0,0 +1,105 @@
+// Per-keyboard D-Bus actions. A keyboard entry can call an arbitrary D-Bus
+// method on every key press instead of (or in addition to) switching the
+// layout, e.g. a macro pad that switches OBS scenes. Calls are made from
+// the async runtime so a slow service never stalls a monitor thread.
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use zbus::zvariant::{StructureBuilder, Value};
+use zbus::Connection;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DbusAction {
+    pub service: String,
+    pub path: String,
+    pub interface: String,
+    pub method: String,
+    // Strings may use {keyboard}, {key}, {code} and {layout_index}
+    #[serde(default)]
+    pub args: Vec<toml::Value>,
+    // Also switch to the keyboard's layout (default: the action replaces it)
+    #[serde(default)]
+    pub switch_layout: bool,
+}
+
+/// One key press to run an action for.
+pub(crate) struct ActionCall {
+    pub(crate) action: DbusAction,
+    pub(crate) keyboard: String,
+    pub(crate) code: u16,
+    pub(crate) layout_index: u32,
+}
+
+impl ActionCall {
+    fn expand(&self, template: &str) -> String {
+        template
+            .replace("{keyboard}", &self.keyboard)
+            .replace("{key}", &format!("{:?}", evdev::Key::new(self.code)))
+            .replace("{code}", &self.code.to_string())
+            .replace("{layout_index}", &self.layout_index.to_string())
+    }
+
+    fn arg(&self, value: &toml::Value) -> Option<Value<'static>> {
+        Some(match value {
+            toml::Value::String(s) => Value::from(self.expand(s)),
+            toml::Value::Integer(i) => match i32::try_from(*i) {
+                Ok(i) => Value::from(i),
+                Err(_) => Value::from(*i),
+            },
+            toml::Value::Float(f) => Value::from(*f),
+            toml::Value::Boolean(b) => Value::from(*b),
+            _ => return None,
+        })
+    }
+
+    async fn call(&self, conn: &Connection) -> zbus::Result<()> {
+        let action = &self.action;
+        let proxy = zbus::Proxy::new(
+            conn,
+            action.service.as_str(),
+            action.path.as_str(),
+            action.interface.as_str(),
+        )
+        .await?;
+
+        if action.args.is_empty() {
+            proxy.call_method(action.method.as_str(), &()).await?;
+            return Ok(());
+        }
+
+        let mut body = StructureBuilder::new();
+        for value in &action.args {
+            match self.arg(value) {
+                Some(arg) => body.push_value(arg),
+                None => {
+                    return Err(zbus::Error::Failure(format!(
+                        "unsupported argument type in action: {}",
+                        value
+                    )))
+                }
+            }
+        }
+        // Method arguments are sent as the fields of this structure
+        proxy
+            .call_method(action.method.as_str(), &body.build())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Run queued actions. Runs until all senders are gone.
+pub(crate) async fn run(conn: Connection, mut action_rx: mpsc::UnboundedReceiver<ActionCall>) {
+    while let Some(call) = action_rx.recv().await {
+        debug!(
+            "Running action {}.{} for '{}'",
+            call.action.interface, call.action.method, call.keyboard
+        );
+        if let Err(e) = call.call(&conn).await {
+            warn!(
+                "Action {}.{} for '{}' failed: {}",
+                call.action.interface, call.action.method, call.keyboard, e
+            );
+        }
+    }
+}
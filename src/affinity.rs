@@ -0,0 +1,53 @@
+// Thread tuning: CPU pinning for the forwarding threads and the async loop,
+// and the size of the tokio runtime. Useful to keep forwarding on fast cores
+// for latency, or everything on efficiency cores on low-power machines.
+
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ThreadConfig {
+    // CPUs the per-keyboard forwarding threads may run on (empty = any)
+    #[serde(default)]
+    pub forward_cpus: Vec<usize>,
+    // CPUs the async runtime (D-Bus, hot-plug, peer sync) may run on
+    #[serde(default)]
+    pub runtime_cpus: Vec<usize>,
+    // Worker threads for the async runtime; unset keeps a single-threaded loop
+    pub runtime_threads: Option<usize>,
+}
+
+/// Restrict the calling thread to `cpus`. An empty list leaves it alone.
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current_thread(cpus: &[usize]) {
+    if cpus.is_empty() {
+        return;
+    }
+
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            warn!("Ignoring CPU {} - above CPU_SETSIZE", cpu);
+            continue;
+        }
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+
+    let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        warn!(
+            "Failed to pin thread to CPUs {:?}: {}",
+            cpus,
+            std::io::Error::last_os_error()
+        );
+    } else {
+        debug!("Pinned thread to CPUs {:?}", cpus);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_current_thread(cpus: &[usize]) {
+    if !cpus.is_empty() {
+        warn!("CPU pinning is only supported on Linux - ignoring {:?}", cpus);
+    }
+}
@@ -0,0 +1,102 @@
+// Command backend: runs user-supplied shell commands from the [command]
+// config section, for compositors without a native backend. Templates get
+// {index}, {layout} (the layout_name configured for that index) and
+// {keyboard} (the keyboard that was used last, as in ContextChanged)
+// substituted, shell-quoted.
+
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use zbus::blocking::Connection;
+
+use crate::config::Config;
+use super::LayoutSwitcher;
+
+struct Templates {
+    switch: String,
+    current: Option<String>,
+    // layout_name of the first keyboard entry using each index
+    layout_names: Vec<(u32, String)>,
+}
+
+static TEMPLATES: OnceLock<Templates> = OnceLock::new();
+// Last layout switched to, reported when there is no `current` command
+static LAST_SWITCH: AtomicU32 = AtomicU32::new(0);
+
+fn failure(message: String) -> zbus::Error {
+    zbus::Error::Failure(message)
+}
+
+/// Take the command templates from the config; a switch command is needed.
+pub(super) fn configure(config: &Config) -> Result<(), String> {
+    if config.command.switch.as_deref().is_none_or(str::is_empty) {
+        return Err("the command backend needs command.switch in the config".to_string());
+    }
+    let mut layout_names: Vec<(u32, String)> = Vec::new();
+    for kb in &config.keyboards {
+        if !layout_names.iter().any(|(index, _)| *index == kb.layout_index) {
+            layout_names.push((kb.layout_index, kb.layout_name.clone()));
+        }
+    }
+    let _ = TEMPLATES.set(Templates {
+        switch: config.command.switch.clone().unwrap_or_default(),
+        current: config.command.current.clone(),
+        layout_names,
+    });
+    Ok(())
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// Run a template through sh, returning its standard output
+fn run(template: &str, layout_index: u32) -> Result<String, zbus::Error> {
+    let templates = TEMPLATES.get().ok_or(zbus::Error::Unsupported)?;
+    let layout = templates
+        .layout_names
+        .iter()
+        .find(|(index, _)| *index == layout_index)
+        .map(|(_, name)| name.as_str())
+        .unwrap_or_default();
+    let command = template
+        .replace("{index}", &layout_index.to_string())
+        .replace("{layout}", &quote(layout))
+        .replace("{keyboard}", &quote(&crate::daemon::last_active_keyboard()));
+
+    let output = process::Command::new("sh")
+        .args(["-c", &command])
+        .output()
+        .map_err(|e| failure(format!("failed to run '{}': {}", command, e)))?;
+    if !output.status.success() {
+        return Err(failure(format!(
+            "'{}' failed ({}): {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+pub(super) struct Command;
+
+impl LayoutSwitcher for Command {
+    fn switch(&self, _conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+        let switch = TEMPLATES.get().ok_or(zbus::Error::Unsupported)?.switch.as_str();
+        run(switch, layout_index)?;
+        LAST_SWITCH.store(layout_index, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn current(&self, _conn: &Connection) -> Result<u32, zbus::Error> {
+        let last = LAST_SWITCH.load(Ordering::SeqCst);
+        let Some(current) = TEMPLATES.get().and_then(|t| t.current.as_deref()) else {
+            return Ok(last);
+        };
+        let output = run(current, last)?;
+        output
+            .parse()
+            .map_err(|_| failure(format!("command.current printed '{}', not a layout index", output)))
+    }
+}
@@ -0,0 +1,73 @@
+// Linux console backend: loads a kernel keymap with `loadkeys` when a
+// keyboard is used on a virtual terminal. The kernel has a single keymap
+// for all VTs, so switching affects every console (and grab mode's virtual
+// keyboards, which type through it). Layout indices are positions in the
+// [console] keymaps list.
+
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use zbus::blocking::Connection;
+
+use crate::config::Config;
+use super::LayoutSwitcher;
+
+static KEYMAPS: OnceLock<Vec<String>> = OnceLock::new();
+// Last keymap loaded; the kernel can't tell which one it was
+static LAST_SWITCH: AtomicU32 = AtomicU32::new(0);
+
+fn failure(message: String) -> zbus::Error {
+    zbus::Error::Failure(format!("console: {}", message))
+}
+
+/// Take the keymaps from the config; every keyboard's index needs one.
+pub(super) fn configure(config: &Config) -> Result<(), String> {
+    let keymaps = &config.console.keymaps;
+    if keymaps.is_empty() {
+        return Err("the console backend needs console.keymaps in the config".to_string());
+    }
+    if let Some(kb) = config
+        .keyboards
+        .iter()
+        .find(|kb| kb.layout_index as usize >= keymaps.len())
+    {
+        return Err(format!(
+            "keyboard '{}' uses layout_index {} but console.keymaps has only {} keymap(s)",
+            kb.name,
+            kb.layout_index,
+            keymaps.len()
+        ));
+    }
+    let _ = KEYMAPS.set(keymaps.clone());
+    Ok(())
+}
+
+pub(super) struct Console;
+
+impl LayoutSwitcher for Console {
+    fn switch(&self, _conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+        let keymap = KEYMAPS
+            .get()
+            .ok_or(zbus::Error::Unsupported)?
+            .get(layout_index as usize)
+            .ok_or_else(|| failure(format!("no keymap for layout index {}", layout_index)))?;
+        let output = process::Command::new("loadkeys")
+            .args(["-q", keymap])
+            .output()
+            .map_err(|e| failure(format!("failed to run loadkeys: {}", e)))?;
+        if !output.status.success() {
+            return Err(failure(format!(
+                "loadkeys {} failed ({}): {}",
+                keymap,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        LAST_SWITCH.store(layout_index, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn current(&self, _conn: &Connection) -> Result<u32, zbus::Error> {
+        Ok(LAST_SWITCH.load(Ordering::SeqCst))
+    }
+}
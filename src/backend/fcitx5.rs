@@ -0,0 +1,80 @@
+// fcitx5 backend: drives the input method framework instead of the
+// desktop's layouts. Layout indices are positions in fcitx5's current
+// input method group, in the order shown by fcitx5-configtool; keyboard
+// layouts appear there as "keyboard-<layout>[-<variant>]".
+
+use zbus::blocking::{Connection, Proxy};
+
+use super::LayoutSwitcher;
+
+fn proxy(conn: &Connection) -> Result<Proxy<'_>, zbus::Error> {
+    Proxy::new(
+        conn,
+        "org.fcitx.Fcitx5",
+        "/controller",
+        "org.fcitx.Fcitx.Controller1",
+    )
+}
+
+// Input methods of the current group, in order
+fn group_methods(proxy: &Proxy<'_>) -> Result<Vec<String>, zbus::Error> {
+    let group: String = proxy.call("CurrentInputMethodGroup", &())?;
+    let (_layout, items): (String, Vec<(String, String)>) =
+        proxy.call("InputMethodGroupInfo", &(group.as_str(),))?;
+    Ok(items.into_iter().map(|(name, _layout)| name).collect())
+}
+
+pub(super) struct Fcitx5;
+
+impl LayoutSwitcher for Fcitx5 {
+    fn switch(&self, conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+        let proxy = proxy(conn)?;
+        let methods = group_methods(&proxy)?;
+        let Some(method) = methods.get(layout_index as usize) else {
+            return Err(zbus::Error::Failure(format!(
+                "no input method at index {} ({} in the current group)",
+                layout_index,
+                methods.len()
+            )));
+        };
+        proxy.call::<_, _, ()>("SetCurrentIM", &(method.as_str(),))
+    }
+
+    fn current(&self, conn: &Connection) -> Result<u32, zbus::Error> {
+        let proxy = proxy(conn)?;
+        let current: String = proxy.call("CurrentInputMethod", &())?;
+        group_methods(&proxy)?
+            .iter()
+            .position(|method| *method == current)
+            .map(|index| index as u32)
+            .ok_or_else(|| {
+                zbus::Error::Failure(format!("input method {} is not in the current group", current))
+            })
+    }
+
+    fn layouts(&self, conn: &Connection) -> Result<Vec<(String, String, String)>, zbus::Error> {
+        let proxy = proxy(conn)?;
+        // (unique name, name, native name, icon, label, language, configurable)
+        type Available = (String, String, String, String, String, String, bool);
+        let available: Vec<Available> = proxy.call("AvailableInputMethods", &())?;
+
+        Ok(group_methods(&proxy)?
+            .into_iter()
+            .map(|method| {
+                let long = available
+                    .iter()
+                    .find(|im| im.0 == method)
+                    .map(|im| im.1.clone())
+                    .unwrap_or_default();
+                match method.strip_prefix("keyboard-") {
+                    // "keyboard-de-neo" is layout "de", variant "neo"
+                    Some(xkb) => {
+                        let (layout, variant) = xkb.split_once('-').unwrap_or((xkb, ""));
+                        (layout.to_string(), variant.to_string(), long)
+                    }
+                    None => (method, String::new(), long),
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,149 @@
+// GNOME backend. Layout indices are positions in the
+// org.gnome.desktop.input-sources `sources` list, as in GNOME Settings.
+// Switching goes through GNOME Shell's input source manager when
+// org.gnome.Shell.Eval is allowed (unsafe mode, development sessions);
+// stock GNOME refuses Eval, so the input-sources settings (`current` and
+// the `mru-sources` order the Shell follows) are written instead.
+
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, warn};
+use zbus::blocking::{Connection, Proxy};
+
+use super::LayoutSwitcher;
+
+const SCHEMA: &str = "org.gnome.desktop.input-sources";
+const MANAGER: &str = "imports.ui.status.keyboard.getInputSourceManager()";
+
+// Set once the Shell has refused Eval, so it isn't asked on every switch
+static EVAL_REFUSED: AtomicBool = AtomicBool::new(false);
+
+// Run JavaScript in GNOME Shell; None when Eval isn't allowed
+fn shell_eval(conn: &Connection, script: &str) -> Option<String> {
+    if EVAL_REFUSED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let result: Result<(bool, String), zbus::Error> =
+        Proxy::new(conn, "org.gnome.Shell", "/org/gnome/Shell", "org.gnome.Shell")
+            .and_then(|proxy| proxy.call("Eval", &(script,)));
+    match result {
+        Ok((true, output)) => Some(output),
+        Ok((false, _)) | Err(_) => {
+            debug!("org.gnome.Shell.Eval unavailable, using input-sources settings");
+            EVAL_REFUSED.store(true, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+fn gsettings(args: &[&str]) -> Result<String, zbus::Error> {
+    let output = Command::new("gsettings")
+        .args(args)
+        .output()
+        .map_err(|e| zbus::Error::Failure(format!("failed to run gsettings: {}", e)))?;
+    if !output.status.success() {
+        return Err(zbus::Error::Failure(format!(
+            "gsettings {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Parse an a(ss) value as printed by gsettings, e.g.
+// "[('xkb', 'us'), ('xkb', 'de+neo')]"
+fn parse_sources(value: &str) -> Vec<(String, String)> {
+    let strings: Vec<&str> = value.split('\'').skip(1).step_by(2).collect();
+    strings
+        .chunks_exact(2)
+        .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+        .collect()
+}
+
+fn format_sources(sources: &[(String, String)]) -> String {
+    if sources.is_empty() {
+        return "@a(ss) []".to_string();
+    }
+    let items: Vec<String> = sources
+        .iter()
+        .map(|(kind, id)| format!("('{}', '{}')", kind, id))
+        .collect();
+    format!("[{}]", items.join(", "))
+}
+
+fn sources() -> Result<Vec<(String, String)>, zbus::Error> {
+    Ok(parse_sources(&gsettings(&["get", SCHEMA, "sources"])?))
+}
+
+pub(super) struct Gnome;
+
+impl LayoutSwitcher for Gnome {
+    fn switch(&self, conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+        let script = format!("{}.inputSources[{}].activate(false)", MANAGER, layout_index);
+        if shell_eval(conn, &script).is_some() {
+            return Ok(());
+        }
+
+        let sources = sources()?;
+        let Some(target) = sources.get(layout_index as usize).cloned() else {
+            return Err(zbus::Error::Failure(format!(
+                "no input source at index {} ({} configured)",
+                layout_index,
+                sources.len()
+            )));
+        };
+
+        // Most recently used first, keeping the order of the others
+        let mut mru = parse_sources(&gsettings(&["get", SCHEMA, "mru-sources"])?);
+        if mru.is_empty() {
+            mru = sources;
+        }
+        mru.retain(|source| *source != target);
+        mru.insert(0, target);
+
+        // `current` is deprecated but still read by older Shells
+        if let Err(e) = gsettings(&["set", SCHEMA, "current", &layout_index.to_string()]) {
+            warn!("Failed to set input-sources current: {}", e);
+        }
+        gsettings(&["set", SCHEMA, "mru-sources", &format_sources(&mru)])?;
+        Ok(())
+    }
+
+    fn current(&self, conn: &Connection) -> Result<u32, zbus::Error> {
+        let script = format!("{}.currentSource.index", MANAGER);
+        if let Some(index) = shell_eval(conn, &script).and_then(|out| out.trim().parse().ok()) {
+            return Ok(index);
+        }
+
+        let sources = sources()?;
+        let mru = parse_sources(&gsettings(&["get", SCHEMA, "mru-sources"])?);
+        if let Some(index) = mru.first().and_then(|first| sources.iter().position(|s| s == first)) {
+            return Ok(index as u32);
+        }
+
+        // Printed as "uint32 1"
+        let current = gsettings(&["get", SCHEMA, "current"])?;
+        current
+            .rsplit(' ')
+            .next()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| zbus::Error::Failure(format!("unexpected current value: {}", current)))
+    }
+
+    fn layouts(&self, _conn: &Connection) -> Result<Vec<(String, String, String)>, zbus::Error> {
+        Ok(sources()?
+            .into_iter()
+            .map(|(kind, id)| {
+                if kind == "xkb" {
+                    // "de+neo" is layout "de", variant "neo"
+                    let (layout, variant) = id.split_once('+').unwrap_or((&id, ""));
+                    (layout.to_string(), variant.to_string(), String::new())
+                } else {
+                    // Input method engines, e.g. ('ibus', 'anthy')
+                    (id.clone(), String::new(), format!("{} ({})", id, kind))
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,149 @@
+// IBus backend: switches the global engine on IBus's own bus (not the
+// session bus), found through $IBUS_ADDRESS or `ibus address`. Keyboards
+// name an `engine` instead of a layout index; the engines named in the
+// config, in order of first use, make up the layout list.
+
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+use zbus::blocking::{connection, Connection, Proxy};
+use zbus::zvariant::{OwnedValue, Value};
+
+use crate::config::KeyboardConfig;
+use super::LayoutSwitcher;
+
+// Engine names in layout index order
+static ENGINES: OnceLock<Vec<String>> = OnceLock::new();
+// Connection to the IBus bus, dropped after a failed call so the next one
+// reconnects to a restarted ibus-daemon
+static BUS: Mutex<Option<Connection>> = Mutex::new(None);
+
+fn failure(message: String) -> zbus::Error {
+    zbus::Error::Failure(message)
+}
+
+/// Give each keyboard with an `engine` the index of that engine, and
+/// remember the engines as the layout list. Returns keyboards without one.
+pub(super) fn assign_engines(keyboards: &mut [KeyboardConfig]) -> Vec<String> {
+    let mut engines: Vec<String> = Vec::new();
+    let mut missing = Vec::new();
+    for kb in keyboards.iter_mut() {
+        let Some(engine) = &kb.engine else {
+            // Keyboards driving a D-Bus action instead don't need one
+            if kb.dbus_action.as_ref().is_none_or(|a| a.switch_layout) {
+                missing.push(kb.name.clone());
+            }
+            continue;
+        };
+        kb.layout_index = match engines.iter().position(|e| e == engine) {
+            Some(index) => index,
+            None => {
+                engines.push(engine.clone());
+                engines.len() - 1
+            }
+        } as u32;
+    }
+    let _ = ENGINES.set(engines);
+    missing
+}
+
+fn engines() -> &'static [String] {
+    ENGINES.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+fn address() -> Result<String, zbus::Error> {
+    if let Some(address) = std::env::var("IBUS_ADDRESS").ok().filter(|a| !a.is_empty()) {
+        return Ok(address);
+    }
+    let output = Command::new("ibus")
+        .arg("address")
+        .output()
+        .map_err(|e| failure(format!("failed to run ibus: {}", e)))?;
+    let address = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    // Prints "(null)" when no ibus-daemon is running
+    if !output.status.success() || !address.contains('=') {
+        return Err(failure("ibus-daemon is not running".to_string()));
+    }
+    Ok(address)
+}
+
+/// Whether an ibus-daemon can be found, for the session check.
+pub(crate) fn is_running() -> bool {
+    address().is_ok()
+}
+
+// Run a call against the IBus bus, connecting on first use
+fn with_bus<T>(call: impl Fn(&Proxy<'_>) -> Result<T, zbus::Error>) -> Result<T, zbus::Error> {
+    let mut bus = BUS.lock().unwrap();
+    let conn = match bus.as_ref() {
+        Some(conn) => conn.clone(),
+        None => connection::Builder::address(address()?.as_str())?.build()?,
+    };
+    let result = Proxy::new(
+        &conn,
+        "org.freedesktop.IBus",
+        "/org/freedesktop/IBus",
+        "org.freedesktop.IBus",
+    )
+    .and_then(|proxy| call(&proxy));
+    *bus = result.is_ok().then_some(conn);
+    result
+}
+
+// (name, long name) of a serialized IBusEngineDesc, a struct of the type
+// name, attachments, name, long name, description, ...
+fn engine_desc(value: &Value<'_>) -> Option<(String, String)> {
+    match value {
+        Value::Value(inner) => engine_desc(inner),
+        Value::Structure(desc) => {
+            let field = |i: usize| match desc.fields().get(i) {
+                Some(Value::Str(s)) => Some(s.to_string()),
+                _ => None,
+            };
+            Some((field(2)?, field(3).unwrap_or_default()))
+        }
+        _ => None,
+    }
+}
+
+pub(super) struct Ibus;
+
+impl LayoutSwitcher for Ibus {
+    fn switch(&self, _conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+        let Some(engine) = engines().get(layout_index as usize) else {
+            return Err(failure(format!(
+                "no engine at index {} ({} configured)",
+                layout_index,
+                engines().len()
+            )));
+        };
+        with_bus(|proxy| proxy.call::<_, _, ()>("SetGlobalEngine", &(engine.as_str(),)))
+    }
+
+    fn current(&self, _conn: &Connection) -> Result<u32, zbus::Error> {
+        let desc: OwnedValue = with_bus(|proxy| proxy.get_property("GlobalEngine"))?;
+        let (name, _) = engine_desc(&desc).ok_or_else(|| failure("unexpected GlobalEngine value".to_string()))?;
+        engines()
+            .iter()
+            .position(|engine| *engine == name)
+            .map(|index| index as u32)
+            .ok_or_else(|| failure(format!("engine {} is not used by any keyboard", name)))
+    }
+
+    fn layouts(&self, _conn: &Connection) -> Result<Vec<(String, String, String)>, zbus::Error> {
+        let descs: Vec<OwnedValue> = with_bus(|proxy| proxy.call("GetEnginesByNames", &(engines(),)))?;
+        let long_names: Vec<(String, String)> = descs.iter().filter_map(|desc| engine_desc(desc)).collect();
+
+        // Engines IBus doesn't know keep an empty long name
+        Ok(engines()
+            .iter()
+            .map(|engine| {
+                let long = long_names
+                    .iter()
+                    .find(|(name, _)| name == engine)
+                    .map(|(_, long)| long.clone())
+                    .unwrap_or_default();
+                (engine.clone(), String::new(), long)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,76 @@
+// KDE Plasma backend: org.kde.KeyboardLayouts on the session bus.
+
+use futures::StreamExt;
+use zbus::blocking::{Connection, Proxy};
+
+use super::LayoutSwitcher;
+
+fn proxy(conn: &Connection) -> Result<Proxy<'_>, zbus::Error> {
+    Proxy::new(
+        conn,
+        "org.kde.keyboard",
+        "/Layouts",
+        "org.kde.KeyboardLayouts",
+    )
+}
+
+pub(super) struct Kde;
+
+impl LayoutSwitcher for Kde {
+    fn switch(&self, conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+        let result: bool = proxy(conn)?.call("setLayout", &(layout_index,))?;
+
+        if result {
+            Ok(())
+        } else {
+            Err(zbus::Error::Failure("setLayout returned false".to_string()))
+        }
+    }
+
+    fn current(&self, conn: &Connection) -> Result<u32, zbus::Error> {
+        proxy(conn)?.call("getLayout", &())
+    }
+
+    fn layouts(&self, conn: &Connection) -> Result<Vec<(String, String, String)>, zbus::Error> {
+        proxy(conn)?.call("getLayoutsList", &())
+    }
+}
+
+// Report every layoutChanged signal, including switches made by the user
+// through KDE itself, and re-resolve layouts on layoutListChanged (layouts
+// added, removed or reordered in the settings). Returns when the
+// subscription ends.
+pub(super) async fn watch(conn: &zbus::Connection) -> zbus::Result<()> {
+    let proxy: zbus::Proxy<'_> = zbus::ProxyBuilder::new(conn)
+        .destination("org.kde.keyboard")?
+        .path("/Layouts")?
+        .interface("org.kde.KeyboardLayouts")?
+        .cache_properties(zbus::proxy::CacheProperties::No)
+        .build()
+        .await?;
+    let mut changes = proxy.receive_signal("layoutChanged").await?;
+    let mut list_changes = proxy.receive_signal("layoutListChanged").await?;
+    // Catch up on changes made while nobody was listening
+    let current: u32 = proxy.call("getLayout", &()).await?;
+    crate::daemon::report_layout(current);
+    crate::daemon::set_layout_reports_live(true);
+
+    loop {
+        tokio::select! {
+            signal = changes.next() => {
+                let Some(signal) = signal else { break };
+                if let Ok(index) = signal.body().deserialize::<u32>() {
+                    crate::daemon::report_layout(index);
+                }
+            }
+            signal = list_changes.next() => {
+                if signal.is_none() {
+                    break;
+                }
+                let conn = Connection::from(conn.clone());
+                let _ = tokio::task::spawn_blocking(move || crate::layouts::refresh(&conn)).await;
+            }
+        }
+    }
+    Ok(())
+}
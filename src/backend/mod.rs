@@ -0,0 +1,265 @@
+// Layout switching backends. Each desktop exposes layout switching its own
+// way behind the `LayoutSwitcher` trait; the rest of the daemon only sees
+// layout indices and goes through the functions here. The backend is
+// chosen once at startup.
+
+use serde::Deserialize;
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing::debug;
+use zbus::blocking::Connection;
+
+use crate::config::{Config, KeyboardConfig};
+
+#[cfg(feature = "command")]
+mod command;
+#[cfg(feature = "console")]
+mod console;
+#[cfg(feature = "fcitx5")]
+mod fcitx5;
+#[cfg(feature = "gnome")]
+mod gnome;
+#[cfg(feature = "ibus")]
+mod ibus;
+#[cfg(feature = "kde")]
+mod kde;
+#[cfg(feature = "sway")]
+mod sway;
+#[cfg(feature = "x11")]
+mod x11;
+
+/// Layout switching backend, selected with `backend` in the config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Detect the desktop at startup and pick its backend
+    #[default]
+    Auto,
+    /// Monitor keyboards (context signals, filters, actions) without switching
+    None,
+    /// KDE Plasma, via `org.kde.keyboard`
+    Kde,
+    /// GNOME Shell input sources
+    Gnome,
+    /// Sway, via its IPC socket
+    Sway,
+    /// fcitx5 input methods of the current group
+    Fcitx5,
+    /// IBus global engine, named per keyboard with `engine`
+    Ibus,
+    /// Shell commands from the `[command]` section
+    Command,
+    /// XKB group of the X server's core keyboard
+    X11,
+    /// Linux console keymap, loaded with `loadkeys`
+    Console,
+    /// A `LayoutSwitcher` given to `Daemon::with_switcher`
+    #[serde(skip)]
+    Custom,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Auto => "auto",
+            Backend::None => "none",
+            Backend::Kde => "kde",
+            Backend::Gnome => "gnome",
+            Backend::Sway => "sway",
+            Backend::Fcitx5 => "fcitx5",
+            Backend::Ibus => "ibus",
+            Backend::Command => "command",
+            Backend::X11 => "x11",
+            Backend::Console => "console",
+            Backend::Custom => "custom",
+        }
+    }
+
+    /// Whether this build includes the backend.
+    pub fn is_available(self) -> bool {
+        match self {
+            Backend::Auto | Backend::None | Backend::Custom => true,
+            Backend::Kde => cfg!(feature = "kde"),
+            Backend::Gnome => cfg!(feature = "gnome"),
+            Backend::Sway => cfg!(feature = "sway"),
+            Backend::Fcitx5 => cfg!(feature = "fcitx5"),
+            Backend::Ibus => cfg!(feature = "ibus"),
+            Backend::Command => cfg!(feature = "command"),
+            Backend::X11 => cfg!(feature = "x11"),
+            Backend::Console => cfg!(feature = "console"),
+        }
+    }
+}
+
+// Delay before resubscribing to a backend's layout changes
+#[cfg(feature = "kde")]
+const WATCH_RETRY: std::time::Duration = std::time::Duration::from_secs(5);
+
+// The selected backend and what switches for it
+static ACTIVE: OnceLock<(Backend, Box<dyn LayoutSwitcher>)> = OnceLock::new();
+
+/// Resolve keyboards' `engine` names to layout indices for backends that
+/// switch by name, returning the names of keyboards that lack one.
+#[cfg_attr(not(feature = "ibus"), allow(unused_variables))]
+pub(crate) fn assign_names(backend: Backend, keyboards: &mut [KeyboardConfig]) -> Vec<String> {
+    match backend {
+        #[cfg(feature = "ibus")]
+        Backend::Ibus => ibus::assign_engines(keyboards),
+        _ => Vec::new(),
+    }
+}
+
+/// Hand the selected backend its config section, failing when it can't work
+/// with what is configured.
+#[cfg_attr(not(any(feature = "command", feature = "console")), allow(unused_variables))]
+pub(crate) fn configure(config: &Config) -> Result<(), String> {
+    match config.backend {
+        #[cfg(feature = "command")]
+        Backend::Command => command::configure(config),
+        #[cfg(feature = "console")]
+        Backend::Console => console::configure(config),
+        _ => Ok(()),
+    }
+}
+
+/// Connect to the bus named by `backend_bus`: "session", "system", or a
+/// D-Bus address such as "unix:path=/run/greeter/bus".
+pub(crate) fn connect(bus: &str) -> Result<Connection, zbus::Error> {
+    match bus {
+        "session" => Connection::session(),
+        "system" => Connection::system(),
+        address => zbus::blocking::connection::Builder::address(address)?.build(),
+    }
+}
+
+/// Whether the IBus daemon can be reached.
+#[cfg(feature = "ibus")]
+pub(crate) fn ibus_running() -> bool {
+    ibus::is_running()
+}
+
+/// Layout switching as the daemon uses it: by layout index, through the
+/// session bus connection (which backends talking elsewhere ignore).
+/// Implemented by every backend; embedders can bring their own with
+/// `Daemon::with_switcher`, e.g. a mock to drive the daemon in tests.
+pub trait LayoutSwitcher: Send + Sync {
+    fn switch(&self, conn: &Connection, layout_index: u32) -> Result<(), zbus::Error>;
+
+    fn current(&self, conn: &Connection) -> Result<u32, zbus::Error>;
+
+    /// Configured layouts as (short name, variant, long name), in index
+    /// order; the long name is empty when the backend doesn't have one.
+    fn layouts(&self, _conn: &Connection) -> Result<Vec<(String, String, String)>, zbus::Error> {
+        Err(zbus::Error::Unsupported)
+    }
+
+    /// Wait until `current` reports the layout, up to the deadline, asking
+    /// every `confirm_poll_us`. Returns whether it did.
+    fn confirm(&self, conn: &Connection, layout_index: u32, deadline: Instant) -> bool {
+        while crate::clock::now() < deadline {
+            if self.current(conn).is_ok_and(|current| current == layout_index) {
+                return true;
+            }
+            std::thread::sleep(crate::daemon::confirm_poll_interval());
+        }
+        false
+    }
+}
+
+// Backend::None: keyboards are monitored, layouts are never switched
+struct NoSwitcher;
+
+impl LayoutSwitcher for NoSwitcher {
+    fn switch(&self, _conn: &Connection, _layout_index: u32) -> Result<(), zbus::Error> {
+        Err(zbus::Error::Unsupported)
+    }
+
+    fn current(&self, _conn: &Connection) -> Result<u32, zbus::Error> {
+        Err(zbus::Error::Unsupported)
+    }
+}
+
+impl Backend {
+    // The backend's switcher; backends missing from this build don't switch
+    fn switcher(self) -> Box<dyn LayoutSwitcher> {
+        match self {
+            #[cfg(feature = "kde")]
+            Backend::Kde => Box::new(kde::Kde),
+            #[cfg(feature = "gnome")]
+            Backend::Gnome => Box::new(gnome::Gnome),
+            #[cfg(feature = "sway")]
+            Backend::Sway => Box::new(sway::Sway),
+            #[cfg(feature = "fcitx5")]
+            Backend::Fcitx5 => Box::new(fcitx5::Fcitx5),
+            #[cfg(feature = "ibus")]
+            Backend::Ibus => Box::new(ibus::Ibus),
+            #[cfg(feature = "command")]
+            Backend::Command => Box::new(command::Command),
+            #[cfg(feature = "x11")]
+            Backend::X11 => Box::new(x11::X11),
+            #[cfg(feature = "console")]
+            Backend::Console => Box::new(console::Console),
+            _ => Box::new(NoSwitcher),
+        }
+    }
+}
+
+/// Select the backend for the rest of the run; only the first call counts.
+/// A custom switcher replaces the backend's own.
+pub(crate) fn select(backend: Backend, custom: Option<Box<dyn LayoutSwitcher>>) {
+    let switcher = custom.unwrap_or_else(|| backend.switcher());
+    let _ = ACTIVE.set((backend, switcher));
+}
+
+pub(crate) fn active() -> Backend {
+    ACTIVE.get().map_or(Backend::None, |(backend, _)| *backend)
+}
+
+fn switcher() -> &'static dyn LayoutSwitcher {
+    match ACTIVE.get() {
+        Some((_, switcher)) => switcher.as_ref(),
+        None => &NoSwitcher,
+    }
+}
+
+pub(crate) fn switch(conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+    switcher().switch(conn, layout_index)
+}
+
+pub(crate) fn current(conn: &Connection) -> Result<u32, zbus::Error> {
+    switcher().current(conn)
+}
+
+pub(crate) fn layouts(conn: &Connection) -> Result<Vec<(String, String, String)>, zbus::Error> {
+    switcher().layouts(conn)
+}
+
+pub(crate) fn confirm(conn: &Connection, layout_index: u32, deadline: Instant) -> bool {
+    switcher().confirm(conn, layout_index, deadline)
+}
+
+/// Follow layout changes pushed by the backend, wherever they come from
+/// (the tray, shortcuts, other tools), keeping `CURRENT_LAYOUT` in sync;
+/// the reports also confirm switches (see `daemon::report_layout`). A lost
+/// subscription is retried, with switches polled in the meantime. Backends
+/// without change notifications return at once and are always polled.
+#[cfg_attr(not(feature = "kde"), allow(unused_variables))]
+pub(crate) async fn watch(conn: zbus::Connection) {
+    #[cfg(feature = "kde")]
+    if active() == Backend::Kde {
+        let mut warned = false;
+        loop {
+            match kde::watch(&conn).await {
+                Ok(()) => debug!("layoutChanged subscription ended, resubscribing"),
+                Err(e) if !warned => {
+                    tracing::warn!("Cannot follow layout changes, polling until it works: {}", e);
+                    warned = true;
+                }
+                Err(e) => debug!("Cannot follow layout changes: {}", e),
+            }
+            crate::daemon::set_layout_reports_live(false);
+            tokio::time::sleep(WATCH_RETRY).await;
+        }
+    }
+    debug!("{} backend doesn't report layout changes", active().name());
+}
@@ -0,0 +1,97 @@
+// Sway backend: the i3-compatible IPC socket named by $SWAYSOCK. Sway keeps
+// a layout per input device, so switches go to every keyboard (including
+// the daemon's virtual keyboards) with `input type:keyboard`.
+
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use zbus::blocking::Connection;
+
+use super::LayoutSwitcher;
+
+const MAGIC: &[u8] = b"i3-ipc";
+const RUN_COMMAND: u32 = 0;
+const GET_INPUTS: u32 = 100;
+
+fn failure(message: String) -> zbus::Error {
+    zbus::Error::Failure(message)
+}
+
+// One request/reply round trip on a fresh connection
+fn ipc(message_type: u32, payload: &str) -> Result<Value, zbus::Error> {
+    let socket = std::env::var_os("SWAYSOCK")
+        .ok_or_else(|| failure("SWAYSOCK is not set".to_string()))?;
+    let io = |e: std::io::Error| failure(format!("sway IPC: {}", e));
+    let mut stream = UnixStream::connect(socket).map_err(io)?;
+
+    let mut request = MAGIC.to_vec();
+    request.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    request.extend_from_slice(&message_type.to_ne_bytes());
+    request.extend_from_slice(payload.as_bytes());
+    stream.write_all(&request).map_err(io)?;
+
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).map_err(io)?;
+    if &header[..6] != MAGIC {
+        return Err(failure("sway IPC: bad reply header".to_string()));
+    }
+    let len = u32::from_ne_bytes(header[6..10].try_into().unwrap()) as usize;
+    let mut reply = vec![0u8; len];
+    stream.read_exact(&mut reply).map_err(io)?;
+    serde_json::from_slice(&reply).map_err(|e| failure(format!("sway IPC: {}", e)))
+}
+
+// Keyboards as reported by get_inputs, the daemon's virtual keyboard first
+// since that's the one typed through in grab mode
+fn keyboards() -> Result<Vec<Value>, zbus::Error> {
+    let inputs = ipc(GET_INPUTS, "")?;
+    let mut keyboards: Vec<Value> = inputs
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|input| input["type"] == "keyboard" && input["xkb_layout_names"].is_array())
+        .cloned()
+        .collect();
+    keyboards.sort_by_key(|input| input["vendor"] != crate::daemon::VIRTUAL_VENDOR);
+    Ok(keyboards)
+}
+
+pub(super) struct Sway;
+
+impl LayoutSwitcher for Sway {
+    fn switch(&self, _conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+        let command = format!("input type:keyboard xkb_switch_layout {}", layout_index);
+        let reply = ipc(RUN_COMMAND, &command)?;
+        let results = reply.as_array().cloned().unwrap_or_default();
+        match results.iter().find(|r| r["success"] != true) {
+            Some(r) => Err(failure(format!(
+                "sway refused '{}': {}",
+                command,
+                r["error"].as_str().unwrap_or("unknown error")
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn current(&self, _conn: &Connection) -> Result<u32, zbus::Error> {
+        keyboards()?
+            .iter()
+            .find_map(|kb| kb["xkb_active_layout_index"].as_u64())
+            .map(|index| index as u32)
+            .ok_or_else(|| failure("sway reports no keyboard layout".to_string()))
+    }
+
+    fn layouts(&self, _conn: &Connection) -> Result<Vec<(String, String, String)>, zbus::Error> {
+        let keyboards = keyboards()?;
+        let names = keyboards
+            .first()
+            .and_then(|kb| kb["xkb_layout_names"].as_array())
+            .ok_or_else(|| failure("sway reports no keyboard layouts".to_string()))?;
+        // Sway only reports the long names
+        Ok(names
+            .iter()
+            .filter_map(Value::as_str)
+            .map(|name| (name.to_string(), String::new(), name.to_string()))
+            .collect())
+    }
+}
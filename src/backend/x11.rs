@@ -0,0 +1,102 @@
+// X11 backend for window managers without a desktop layout service: locks
+// the XKB group of the core keyboard, as `setxkbmap -layout us,de` plus a
+// group toggle would. Layout indices are positions in the layout list the
+// server was configured with (`setxkbmap -query`), read from the
+// _XKB_RULES_NAMES root window property.
+
+use std::sync::Mutex;
+use x11rb::connection::Connection as _;
+use x11rb::protocol::xkb::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _};
+use x11rb::rust_connection::RustConnection;
+use zbus::blocking::Connection;
+
+use super::LayoutSwitcher;
+
+// X connection, dropped after a failed request so the next one reconnects
+static DISPLAY: Mutex<Option<(RustConnection, usize)>> = Mutex::new(None);
+
+fn failure(message: String) -> zbus::Error {
+    zbus::Error::Failure(format!("X11: {}", message))
+}
+
+// Run requests on the X connection, connecting and enabling XKB on first use
+fn with_display<T>(
+    call: impl Fn(&RustConnection, usize) -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, zbus::Error> {
+    let mut display = DISPLAY.lock().unwrap();
+    if display.is_none() {
+        let (conn, screen) = x11rb::connect(None).map_err(|e| failure(e.to_string()))?;
+        let xkb_version = conn
+            .xkb_use_extension(1, 0)
+            .map_err(|e| failure(e.to_string()))?
+            .reply()
+            .map_err(|e| failure(e.to_string()))?;
+        if !xkb_version.supported {
+            return Err(failure("the server has no XKB extension".to_string()));
+        }
+        *display = Some((conn, screen));
+    }
+    let (conn, screen) = display.as_ref().unwrap();
+    let result = call(conn, *screen).map_err(|e| failure(e.to_string()));
+    if result.is_err() {
+        *display = None;
+    }
+    result
+}
+
+// Layouts and variants from _XKB_RULES_NAMES: rules, model, layout,
+// variant and options, each NUL-terminated
+fn rules_names(conn: &RustConnection, screen: usize) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let atom = conn.intern_atom(true, b"_XKB_RULES_NAMES")?.reply()?.atom;
+    let root = conn.setup().roots[screen].root;
+    let property = conn
+        .get_property(false, root, atom, AtomEnum::STRING, 0, 1024)?
+        .reply()?;
+    let fields: Vec<String> = property
+        .value
+        .split(|&b| b == 0)
+        .map(|field| String::from_utf8_lossy(field).into_owned())
+        .collect();
+    let field = |i: usize| fields.get(i).cloned().unwrap_or_default();
+    Ok((field(2), field(3)))
+}
+
+pub(super) struct X11;
+
+impl LayoutSwitcher for X11 {
+    fn switch(&self, _conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+        // XKB has at most four groups
+        if layout_index > 3 {
+            return Err(failure(format!("XKB has 4 groups, no layout at index {}", layout_index)));
+        }
+        let group = xkb::Group::from(layout_index as u8);
+        with_display(|conn, _| {
+            let core = xkb::ID::USE_CORE_KBD.into();
+            conn.xkb_latch_lock_state(core, 0u16.into(), 0u16.into(), true, group, 0u16.into(), false, 0)?
+                .check()?;
+            Ok(())
+        })
+    }
+
+    fn current(&self, _conn: &Connection) -> Result<u32, zbus::Error> {
+        with_display(|conn, _| {
+            let state = conn.xkb_get_state(xkb::ID::USE_CORE_KBD.into())?.reply()?;
+            Ok(u8::from(state.locked_group) as u32)
+        })
+    }
+
+    fn layouts(&self, _conn: &Connection) -> Result<Vec<(String, String, String)>, zbus::Error> {
+        let (layouts, variants) = with_display(rules_names)?;
+        let mut variants = variants.split(',');
+        // Only short codes are known; display names come from the registry
+        Ok(layouts
+            .split(',')
+            .filter(|layout| !layout.is_empty())
+            .map(|layout| {
+                let variant = variants.next().unwrap_or_default();
+                (layout.to_string(), variant.to_string(), String::new())
+            })
+            .collect())
+    }
+}
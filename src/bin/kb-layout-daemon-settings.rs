@@ -0,0 +1,505 @@
+// Graphical settings for kb-layout-daemon. Lists detected keyboards, edits the
+// [[keyboards]] entries of the config file and controls the running daemon over
+// its D-Bus interface. Built only with `--features gui`.
+
+use eframe::egui;
+use evdev::{Device, EventType};
+use kb_layout_daemon::config::{self, config_path, Config, Format};
+use kb_layout_daemon::{inspect, remap};
+use std::path::PathBuf;
+use toml_edit::DocumentMut;
+use zbus::blocking::{Connection, Proxy};
+
+const DEST: &str = "org.kblayout.Daemon";
+const OBJECT_PATH: &str = "/org/kblayout/Daemon";
+const INTERFACE: &str = "org.kblayout.Daemon";
+
+struct KeyboardEntry {
+    // Position of the entry in the file as loaded; None for a new entry
+    source: Option<usize>,
+    name: String,
+    layout_index: u32,
+    layout_name: String,
+    // "grab" or "passive"; None follows the global mode
+    mode: Option<String>,
+    // One remap rule per line
+    remap: String,
+}
+
+impl KeyboardEntry {
+    fn new(name: String) -> Self {
+        KeyboardEntry {
+            source: None,
+            name,
+            layout_index: 0,
+            layout_name: String::new(),
+            mode: None,
+            remap: String::new(),
+        }
+    }
+
+    fn remap_rules(&self) -> Vec<String> {
+        self.remap
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    // Write the edited fields into a copy of the entry's table
+    fn update(&self, table: &mut toml::Table) {
+        table.insert("name".to_string(), toml::Value::String(self.name.clone()));
+        table.insert("layout_index".to_string(), toml::Value::Integer(self.layout_index as i64));
+        // A version 1 label that was edited is a name now
+        if table.get("layout_name").and_then(|v| v.as_str()) != Some(self.layout_name.as_str()) {
+            table.remove("legacy_layout_name");
+        }
+        table.insert("layout_name".to_string(), toml::Value::String(self.layout_name.clone()));
+        match &self.mode {
+            Some(mode) => table.insert("mode".to_string(), toml::Value::String(mode.clone())),
+            None => table.remove("mode"),
+        };
+        match self.remap_rules() {
+            rules if rules.is_empty() => table.remove("remap"),
+            rules => table.insert(
+                "remap".to_string(),
+                toml::Value::Array(rules.into_iter().map(toml::Value::String).collect()),
+            ),
+        };
+    }
+
+    // Same as `update`, for a TOML document whose comments are kept
+    fn update_document(&self, table: &mut toml_edit::Table) {
+        set_value(table, "name", self.name.as_str());
+        set_value(table, "layout_index", self.layout_index as i64);
+        if table.get("layout_name").and_then(|item| item.as_str()) != Some(self.layout_name.as_str()) {
+            table.remove("legacy_layout_name");
+        }
+        set_value(table, "layout_name", self.layout_name.as_str());
+        match &self.mode {
+            Some(mode) => set_value(table, "mode", mode.as_str()),
+            None => {
+                table.remove("mode");
+            }
+        }
+        match self.remap_rules() {
+            rules if rules.is_empty() => {
+                table.remove("remap");
+            }
+            rules => set_value(table, "remap", rules.into_iter().collect::<toml_edit::Array>()),
+        }
+    }
+}
+
+// Set a key, keeping the comment after its old value
+fn set_value(table: &mut toml_edit::Table, key: &str, value: impl Into<toml_edit::Value>) {
+    let mut value = value.into();
+    if let Some(old) = table.get(key).and_then(|item| item.as_value()) {
+        *value.decor_mut() = old.decor().clone();
+    }
+    table[key] = toml_edit::Item::Value(value);
+}
+
+struct DaemonInfo {
+    mode: String,
+    version: String,
+    uptime: u64,
+}
+
+struct SettingsApp {
+    config_path: PathBuf,
+    // Full config, so sections this tool doesn't edit are written back untouched
+    config: toml::Table,
+    // The TOML file as written, so saving keeps its comments and layout;
+    // None for YAML and JSON files
+    document: Option<DocumentMut>,
+    mode: String,
+    keyboards: Vec<KeyboardEntry>,
+    devices: Vec<(PathBuf, Device)>,
+    dbus: Option<Connection>,
+    daemon: Option<DaemonInfo>,
+    status: String,
+}
+
+// Keyboard-capable input devices, as the daemon would see them
+fn scan_devices() -> Vec<(PathBuf, Device)> {
+    let mut devices = Vec::new();
+    let entries = match std::fs::read_dir("/dev/input") {
+        Ok(e) => e,
+        Err(_) => return devices,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.to_string_lossy().contains("event") {
+            continue;
+        }
+        if let Ok(device) = Device::open(&path) {
+            if device.supported_events().contains(EventType::KEY) {
+                devices.push((path, device));
+            }
+        }
+    }
+
+    devices.sort_by(|a, b| a.0.cmp(&b.0));
+    devices
+}
+
+fn daemon_proxy(conn: &Connection) -> zbus::Result<Proxy<'_>> {
+    Proxy::new(conn, DEST, OBJECT_PATH, INTERFACE)
+}
+
+fn query_daemon(conn: &Connection) -> zbus::Result<DaemonInfo> {
+    let proxy = daemon_proxy(conn)?;
+    Ok(DaemonInfo {
+        mode: proxy.call("GetMode", &())?,
+        version: proxy.get_property("Version")?,
+        uptime: proxy.get_property("Uptime")?,
+    })
+}
+
+impl SettingsApp {
+    fn new() -> Self {
+        let config_path = config_path();
+        let mut status = String::new();
+
+        let content = std::fs::read_to_string(&config_path).unwrap_or_default();
+        let mut config: toml::Table = Format::of(&config_path).parse(&content).unwrap_or_else(|e| {
+            status = format!("Failed to parse {:?}: {}", config_path, e);
+            toml::Table::new()
+        });
+        // Older schemas are upgraded here, so saving writes the current one
+        let migrated = match config::migrate(&mut config) {
+            Ok(changes) => !changes.is_empty(),
+            Err(e) => {
+                status = format!("{:?}: {}", config_path, e);
+                false
+            }
+        };
+        let document = match Format::of(&config_path) {
+            // An upgraded file is written anew, in the current schema
+            Format::Toml if migrated => toml::to_string(&config).ok().and_then(|c| c.parse().ok()),
+            Format::Toml => content.parse().ok(),
+            Format::Yaml | Format::Json => None,
+        };
+        if migrated {
+            status = format!("{:?} uses an older schema; saving upgrades it and drops its comments", config_path);
+        }
+
+        let mode = config
+            .get("mode")
+            .and_then(|m| m.as_str())
+            .unwrap_or("grab")
+            .to_string();
+
+        let keyboards = config
+            .get("keyboards")
+            .and_then(|k| k.as_array())
+            .map(|entries| {
+                entries
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, e)| Some((i, e.as_table()?)))
+                    .map(|(i, t)| KeyboardEntry {
+                        source: Some(i),
+                        name: t
+                            .get("name")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or_default()
+                            .to_string(),
+                        layout_index: t
+                            .get("layout_index")
+                            .and_then(|v| v.as_integer())
+                            .unwrap_or(0) as u32,
+                        layout_name: t
+                            .get("layout_name")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or_default()
+                            .to_string(),
+                        mode: t.get("mode").and_then(|v| v.as_str()).map(str::to_string),
+                        remap: t
+                            .get("remap")
+                            .and_then(|v| v.as_array())
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|v| v.as_str())
+                            .collect::<Vec<_>>()
+                            .join("\n"),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut app = SettingsApp {
+            config_path,
+            config,
+            document,
+            mode,
+            keyboards,
+            devices: scan_devices(),
+            dbus: Connection::session().ok(),
+            daemon: None,
+            status,
+        };
+        app.refresh_daemon();
+        app
+    }
+
+    fn refresh_daemon(&mut self) {
+        self.daemon = self.dbus.as_ref().and_then(|c| query_daemon(c).ok());
+    }
+
+    fn set_daemon_mode(&mut self, mode: &str) {
+        let result = match &self.dbus {
+            Some(conn) => {
+                daemon_proxy(conn).and_then(|p| p.call::<_, _, bool>("SetMode", &(mode,)))
+            }
+            None => return,
+        };
+        if let Err(e) = result {
+            self.status = format!("Failed to set mode: {}", e);
+        }
+        self.refresh_daemon();
+    }
+
+    // The config table with the edits applied
+    fn edited_config(&self) -> toml::Table {
+        let mut config = self.config.clone();
+        config.insert("mode".to_string(), toml::Value::String(self.mode.clone()));
+
+        let loaded = config.get("keyboards").and_then(|k| k.as_array()).cloned().unwrap_or_default();
+        let keyboards = self
+            .keyboards
+            .iter()
+            .map(|kb| {
+                // Keep any per-keyboard keys this tool doesn't know about
+                let mut table = kb
+                    .source
+                    .and_then(|i| loaded.get(i))
+                    .and_then(|e| e.as_table())
+                    .cloned()
+                    .unwrap_or_default();
+                kb.update(&mut table);
+                toml::Value::Table(table)
+            })
+            .collect();
+        config.insert("keyboards".to_string(), toml::Value::Array(keyboards));
+        config
+    }
+
+    // The TOML document with the edits applied, comments and all
+    fn edited_document(&self, document: &DocumentMut) -> DocumentMut {
+        let mut document = document.clone();
+        set_value(document.as_table_mut(), "mode", self.mode.as_str());
+
+        // Entries as loaded, whether written as [[keyboards]] or inline
+        let loaded: Vec<toml_edit::Table> = match document.get("keyboards") {
+            Some(toml_edit::Item::ArrayOfTables(entries)) => entries.iter().cloned().collect(),
+            Some(toml_edit::Item::Value(toml_edit::Value::Array(entries))) => entries
+                .iter()
+                .map(|e| e.as_inline_table().cloned().unwrap_or_default().into_table())
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut keyboards = toml_edit::ArrayOfTables::new();
+        for kb in &self.keyboards {
+            let mut table = kb.source.and_then(|i| loaded.get(i)).cloned().unwrap_or_default();
+            kb.update_document(&mut table);
+            keyboards.push(table);
+        }
+        // Re-inserted, as inline spacing around the key doesn't suit [[keyboards]]
+        document.remove("keyboards");
+        document.insert("keyboards", toml_edit::Item::ArrayOfTables(keyboards));
+        document
+    }
+
+    fn save(&mut self) {
+        let config = self.edited_config();
+        let content = match &self.document {
+            Some(document) => Ok(self.edited_document(document).to_string()),
+            None => Format::of(&self.config_path).write(&config),
+        };
+        let result = content.and_then(|content| {
+            if let Some(dir) = self.config_path.parent() {
+                std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+            }
+            std::fs::write(&self.config_path, content).map_err(|e| e.to_string())
+        });
+
+        self.status = match result {
+            Ok(()) => {
+                // Saved entries are the new baseline for the next save
+                if let Some(document) = &self.document {
+                    self.document = Some(self.edited_document(document));
+                }
+                for (i, kb) in self.keyboards.iter_mut().enumerate() {
+                    kb.source = Some(i);
+                }
+                self.config = config;
+                format!(
+                    "Saved {:?}. The daemon applies keyboard changes right away; the startup mode takes effect \
+                     when it next starts.",
+                    self.config_path
+                )
+            }
+            Err(e) => format!("Failed to save: {}", e),
+        };
+    }
+
+    fn daemon_section(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Daemon");
+        let mut new_mode = None;
+        match &self.daemon {
+            Some(info) => {
+                ui.label(format!(
+                    "Running version {}, up {} s",
+                    info.version, info.uptime
+                ));
+                ui.horizontal(|ui| {
+                    ui.label("Current mode:");
+                    for mode in ["grab", "passive"] {
+                        if ui.selectable_label(info.mode == mode, mode).clicked()
+                            && info.mode != mode
+                        {
+                            new_mode = Some(mode);
+                        }
+                    }
+                });
+            }
+            None => {
+                ui.label("Daemon is not running");
+            }
+        }
+        if let Some(mode) = new_mode {
+            self.set_daemon_mode(mode);
+        }
+        if ui.button("Refresh").clicked() {
+            self.refresh_daemon();
+        }
+    }
+
+    fn devices_section(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Detected keyboards");
+        if self.devices.is_empty() {
+            ui.label("No keyboards found (is your user in the `input` group?)");
+        }
+        // Match against the entries as edited, with the daemon's own rules
+        let matcher: Result<Config, String> =
+            toml::Value::Table(self.edited_config()).try_into().map_err(|e| e.to_string());
+        if let Err(e) = &matcher {
+            ui.label(format!("Can't match keyboards, the configuration is invalid: {}", e));
+        }
+        let mut add = None;
+        egui::Grid::new("devices").striped(true).show(ui, |ui| {
+            for (path, device) in &self.devices {
+                let name = device.name().unwrap_or("Unknown");
+                ui.label(path.to_string_lossy());
+                ui.label(name);
+                let entries = match &matcher {
+                    Ok(config) => inspect::matching_entries(device, path, config)
+                        .into_iter()
+                        .filter(|kb| !config.default.as_ref().is_some_and(|d| std::ptr::eq(*kb, d)))
+                        .map(|kb| kb.name.clone())
+                        .collect::<Vec<_>>(),
+                    Err(_) => Vec::new(),
+                };
+                if !entries.is_empty() {
+                    ui.label(format!("configured: {}", entries.join(", ")));
+                } else if ui.button("Add").clicked() {
+                    add = Some(name.to_string());
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(name) = add {
+            self.keyboards.push(KeyboardEntry::new(name));
+        }
+        if ui.button("Rescan").clicked() {
+            self.devices = scan_devices();
+        }
+    }
+
+    fn keyboards_section(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Configuration");
+        ui.horizontal(|ui| {
+            ui.label("Startup mode:");
+            egui::ComboBox::from_id_salt("mode")
+                .selected_text(self.mode.as_str())
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.mode, "grab".to_string(), "grab");
+                    ui.selectable_value(&mut self.mode, "passive".to_string(), "passive");
+                });
+        });
+
+        let mut remove = None;
+        egui::Grid::new("keyboards").striped(true).show(ui, |ui| {
+            ui.strong("Name match");
+            ui.strong("Layout index");
+            ui.strong("Layout name");
+            ui.strong("Mode");
+            ui.strong("Remap (one rule per line)");
+            ui.end_row();
+            for (i, kb) in self.keyboards.iter_mut().enumerate() {
+                ui.text_edit_singleline(&mut kb.name);
+                ui.add(egui::DragValue::new(&mut kb.layout_index).range(0..=31));
+                ui.text_edit_singleline(&mut kb.layout_name);
+                egui::ComboBox::from_id_salt(("keyboard_mode", i))
+                    .selected_text(kb.mode.as_deref().unwrap_or("global"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut kb.mode, None, "global");
+                        ui.selectable_value(&mut kb.mode, Some("grab".to_string()), "grab");
+                        ui.selectable_value(&mut kb.mode, Some("passive".to_string()), "passive");
+                    });
+                ui.vertical(|ui| {
+                    ui.text_edit_multiline(&mut kb.remap);
+                    if let Err(e) = remap::parse(&kb.remap_rules()) {
+                        ui.colored_label(egui::Color32::RED, e);
+                    }
+                });
+                if ui.button("Remove").clicked() {
+                    remove = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(i) = remove {
+            self.keyboards.remove(i);
+        }
+
+        ui.horizontal(|ui| {
+            if ui.button("Add entry").clicked() {
+                self.keyboards.push(KeyboardEntry::new(String::new()));
+            }
+            if ui.button("Save").clicked() {
+                self.save();
+            }
+        });
+    }
+}
+
+impl eframe::App for SettingsApp {
+    fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+        egui::CentralPanel::default().show(ui, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                self.daemon_section(ui);
+                ui.separator();
+                self.devices_section(ui);
+                ui.separator();
+                self.keyboards_section(ui);
+                if !self.status.is_empty() {
+                    ui.separator();
+                    ui.label(&self.status);
+                }
+            });
+        });
+    }
+}
+
+fn main() -> eframe::Result {
+    eframe::run_native(
+        "kb-layout-daemon settings",
+        eframe::NativeOptions::default(),
+        Box::new(|_cc| Ok(Box::new(SettingsApp::new()))),
+    )
+}
@@ -0,0 +1,149 @@
+// Input capture detection. While a client of the InputCapture portal
+// (Deskflow, Input Leap, ...) captures input to send it to another machine,
+// grabbed keyboards would only reach it through our virtual keyboards, and
+// both sides would fight over the devices. The portal talks to its clients
+// privately, so the session bus is watched as a monitor: a capture counts
+// as active from the portal's Activated signal until Deactivated, Disabled,
+// the session closing or the client leaving the bus.
+
+use futures::StreamExt;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::{debug, info, warn};
+use zbus::message::Type;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+use zbus::{fdo::DBusProxy, Connection, Message, MessageStream};
+
+use crate::daemon::set_grab_suspended;
+
+const INPUT_CAPTURE: &str = "org.freedesktop.portal.InputCapture";
+const SESSION: &str = "org.freedesktop.portal.Session";
+
+// Everything that starts or ends a capture
+const RULES: &[&str] = &[
+    "type='signal',interface='org.freedesktop.portal.InputCapture'",
+    "type='signal',interface='org.freedesktop.portal.Session',member='Closed'",
+    "type='method_call',interface='org.freedesktop.portal.Session',member='Close'",
+    "type='signal',interface='org.freedesktop.DBus',member='NameOwnerChanged'",
+];
+
+#[derive(Debug, Deserialize)]
+pub struct CaptureConfig {
+    // Release grabbed keyboards while an input capture session is active
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            enabled: default_enabled(),
+        }
+    }
+}
+
+// Active captures: session handle -> unique name of the capturing client
+#[derive(Default)]
+struct Captures(HashMap<String, String>);
+
+impl Captures {
+    // Apply one monitored message; returns whether anything changed
+    fn update(&mut self, msg: &Message) -> bool {
+        let header = msg.header();
+        let (Some(interface), Some(member)) = (header.interface(), header.member()) else {
+            return false;
+        };
+        match (msg.message_type(), interface.as_str(), member.as_str()) {
+            (Type::Signal, INPUT_CAPTURE, "Activated") => {
+                let Ok((session, _)) = msg
+                    .body()
+                    .deserialize::<(OwnedObjectPath, HashMap<String, OwnedValue>)>()
+                else {
+                    return false;
+                };
+                let client = header.destination().map(|d| d.to_string()).unwrap_or_default();
+                self.0.insert(session.to_string(), client).is_none()
+            }
+            (Type::Signal, INPUT_CAPTURE, "Deactivated" | "Disabled") => {
+                let Ok((session, _)) = msg
+                    .body()
+                    .deserialize::<(OwnedObjectPath, HashMap<String, OwnedValue>)>()
+                else {
+                    return false;
+                };
+                self.0.remove(session.as_str()).is_some()
+            }
+            (Type::Signal, SESSION, "Closed") | (Type::MethodCall, SESSION, "Close") => header
+                .path()
+                .is_some_and(|path| self.0.remove(path.as_str()).is_some()),
+            (Type::Signal, "org.freedesktop.DBus", "NameOwnerChanged") => {
+                let Ok((name, _, new_owner)) = msg.body().deserialize::<(String, String, String)>() else {
+                    return false;
+                };
+                let before = self.0.len();
+                if new_owner.is_empty() {
+                    self.0.retain(|_, client| *client != name);
+                }
+                self.0.len() != before
+            }
+            _ => false,
+        }
+    }
+}
+
+// Name of the capturing client's process, for the suspension reason
+async fn client_name(conn: &Connection, client: &str) -> String {
+    let pid = match (DBusProxy::new(conn).await, client.try_into()) {
+        (Ok(dbus), Ok(name)) => dbus.get_connection_unix_process_id(name).await.ok(),
+        _ => None,
+    };
+    pid.and_then(|pid| std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok())
+        .map(|comm| comm.trim().to_string())
+        .unwrap_or_else(|| client.to_string())
+}
+
+async fn watch(conn: &Connection) -> zbus::Result<()> {
+    // A monitor can't send anything, so it gets a connection of its own
+    let monitor = zbus::connection::Builder::session()?.build().await?;
+    monitor
+        .call_method(
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            Some("org.freedesktop.DBus.Monitoring"),
+            "BecomeMonitor",
+            &(RULES, 0u32),
+        )
+        .await?;
+    info!("Watching for input capture sessions");
+
+    let mut captures = Captures::default();
+    let mut messages = MessageStream::from(&monitor);
+    while let Some(msg) = messages.next().await {
+        let Ok(msg) = msg else {
+            continue;
+        };
+        if !captures.update(&msg) {
+            continue;
+        }
+        let reason = match captures.0.values().next() {
+            Some(client) => Some(format!("input capture by {}", client_name(conn, client).await)),
+            None => None,
+        };
+        set_grab_suspended(conn, reason).await;
+    }
+    Ok(())
+}
+
+/// Suspend grabbing while an input capture session is active. Runs until
+/// the bus connection ends.
+pub(crate) async fn run(conn: Connection) {
+    match watch(&conn).await {
+        Ok(()) => debug!("Input capture detection stopped"),
+        Err(e) => warn!("Input capture detection unavailable: {}", e),
+    }
+    set_grab_suspended(&conn, None).await;
+}
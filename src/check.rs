@@ -0,0 +1,543 @@
+// `check-config`: validate the config without starting the daemon. Each
+// file is parsed on its own first, so mistakes are reported with the line
+// they are on, including keys the daemon would silently ignore. The merged
+// config is then checked the way the daemon would use it: values, remap
+// rules, entries that can never match or that tie on a connected keyboard,
+// and the layouts against the desktop's layout list (without switching).
+
+use evdev::{Device, Key};
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use toml::Spanned;
+
+use crate::backend::{self, Backend};
+use crate::config::{self, Config, Format, KeyboardConfig};
+use crate::daemon::{matching_keyboard_configs, parse_source_tag, preferred_entry};
+use crate::{layouts, preflight, remap, session};
+
+/// Something `check_config` found. Errors make the config unusable or
+/// make it behave differently than written; warnings are worth a look.
+pub struct Finding {
+    pub error: bool,
+    // "path:line", where the finding is tied to a place in a file
+    pub at: Option<String>,
+    pub what: String,
+    pub fix: Option<String>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", if self.error { "error" } else { "warning" })?;
+        if let Some(at) = &self.at {
+            write!(f, "{}: ", at)?;
+        }
+        write!(f, "{}", self.what)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n  fix: {}", fix)?;
+        }
+        Ok(())
+    }
+}
+
+fn error(at: Option<String>, what: String) -> Finding {
+    Finding {
+        error: true,
+        at,
+        what,
+        fix: None,
+    }
+}
+
+fn warning(at: Option<String>, what: String) -> Finding {
+    Finding {
+        error: false,
+        at,
+        what,
+        fix: None,
+    }
+}
+
+// The [[keyboards]] entries of one file, for type errors with their
+// position. [default] isn't parsed here, a file may set only part of it
+#[derive(Deserialize)]
+struct FileEntries {
+    #[serde(default)]
+    keyboards: Vec<KeyboardConfig>,
+}
+
+// The same as tables, with where they are
+#[derive(Deserialize)]
+struct FileTables {
+    #[serde(default)]
+    keyboards: Vec<Spanned<toml::Table>>,
+    default: Option<Spanned<toml::Table>>,
+}
+
+// Records the field names a derived Deserialize impl asks for, then
+// gives up; no data is involved
+struct FieldNames(&'static [&'static str]);
+
+impl<'de> Deserializer<'de> for &mut FieldNames {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0 = fields;
+        Err(de::Error::custom("fields recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+fn field_names<T: for<'de> Deserialize<'de>>() -> &'static [&'static str] {
+    let mut names = FieldNames(&[]);
+    let _ = T::deserialize(&mut names);
+    names.0
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+// Line of `key = ...` within `range` of the text
+fn key_line(text: &str, range: std::ops::Range<usize>, key: &str) -> usize {
+    let start = range.start.min(text.len());
+    let mut offset = start;
+    for line in text[start..range.end.min(text.len())].split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let bare = trimmed.trim_start_matches('"').trim_start_matches('\'');
+        if bare.strip_prefix(key).is_some_and(|rest| {
+            let rest = rest.trim_start_matches(['"', '\'']).trim_start();
+            rest.starts_with('=') || rest.starts_with('.')
+        }) {
+            return line_of(text, offset);
+        }
+        offset += line.len();
+    }
+    line_of(text, start)
+}
+
+// Keys of `table` the daemon doesn't know, as findings at `at(key)`
+fn unknown_keys(table: &toml::Table, known: &[&str], what: &str, at: impl Fn(&str) -> String) -> Vec<Finding> {
+    table
+        .keys()
+        .filter(|key| !known.contains(&key.as_str()))
+        .map(|key| error(Some(at(key)), format!("unknown key '{}' in {}, the daemon ignores it", key, what)))
+        .collect()
+}
+
+fn top_level_keys() -> Vec<&'static str> {
+    let mut known = field_names::<Config>().to_vec();
+    known.push("inherit_keyboards");
+    known
+}
+
+// Where a file's entries are, as "path:line"
+#[derive(Default)]
+struct Located {
+    keyboards: Vec<(KeyboardConfig, String)>,
+    default: Option<String>,
+}
+
+// Upgrade a file's table to the current schema as the daemon would,
+// reporting the migration; None for configs from a newer release
+fn migrated(file: &str, mut table: toml::Table, findings: &mut Vec<Finding>) -> Option<toml::Table> {
+    match config::migrate(&mut table) {
+        Ok(changes) if changes.is_empty() => {}
+        Ok(changes) => {
+            let mut finding = warning(Some(file.to_string()), format!("older config schema: {}", changes.join("; ")));
+            finding.fix = Some(format!("update the file and set version = {}", config::CONFIG_VERSION));
+            findings.push(finding);
+        }
+        Err(e) => {
+            findings.push(error(Some(file.to_string()), e));
+            return None;
+        }
+    }
+    Some(table)
+}
+
+// One entry of a file, upgraded like its file, for its keys and values
+fn migrated_entry(entry: &toml::Table, version: Option<&toml::Value>) -> toml::Table {
+    let mut table = toml::Table::new();
+    table.insert("keyboards".to_string(), toml::Value::Array(vec![toml::Value::Table(entry.clone())]));
+    if let Some(version) = version {
+        table.insert("version".to_string(), version.clone());
+    }
+    let _ = config::migrate(&mut table);
+    match table.remove("keyboards") {
+        Some(toml::Value::Array(mut entries)) => match entries.pop() {
+            Some(toml::Value::Table(entry)) => entry,
+            _ => toml::Table::new(),
+        },
+        _ => toml::Table::new(),
+    }
+}
+
+// Parse one file on its own; None if it doesn't parse
+fn check_file(path: &Path, findings: &mut Vec<Finding>) -> Option<Located> {
+    let file = path.display().to_string();
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            findings.push(error(Some(file), format!("cannot read: {}", e)));
+            return None;
+        }
+    };
+    let format = Format::of(path);
+    if format != Format::Toml {
+        return check_other_format(&file, &text, format, findings);
+    }
+    let raw: toml::Table = match toml::from_str(&text) {
+        Ok(table) => table,
+        Err(e) => {
+            findings.push(error(Some(file), e.to_string()));
+            return None;
+        }
+    };
+    // Type errors in entries, with their position
+    if let Err(e) = toml::from_str::<FileEntries>(&text) {
+        findings.push(error(Some(file), e.to_string()));
+        return None;
+    }
+    let version = raw.get("version").cloned();
+    let table = migrated(&file, raw, findings)?;
+
+    let text = text.as_str();
+    let key_at = |range: std::ops::Range<usize>| {
+        let file = &file;
+        move |key: &str| format!("{}:{}", file, key_line(text, range.clone(), key))
+    };
+    findings.extend(unknown_keys(&table, &top_level_keys(), "the top level", key_at(0..text.len())));
+    let mut located = Located::default();
+    let Ok(tables) = toml::from_str::<FileTables>(text) else {
+        return Some(located);
+    };
+    let known = field_names::<KeyboardConfig>();
+    for entry in &tables.keyboards {
+        let at = format!("{}:{}", file, line_of(text, entry.span().start));
+        let upgraded = migrated_entry(entry.get_ref(), version.as_ref());
+        findings.extend(unknown_keys(&upgraded, known, "[[keyboards]]", key_at(entry.span())));
+        match toml::Value::Table(upgraded).try_into::<KeyboardConfig>() {
+            Ok(kb) => located.keyboards.push((kb, at)),
+            Err(e) => findings.push(error(Some(at), e.to_string())),
+        }
+    }
+    if let Some(entry) = &tables.default {
+        let upgraded = migrated_entry(entry.get_ref(), version.as_ref());
+        findings.extend(unknown_keys(&upgraded, known, "[default]", key_at(entry.span())));
+        located.default = Some(format!("{}:{}", file, line_of(text, entry.span().start)));
+    }
+    Some(located)
+}
+
+// YAML and JSON files: parse errors carry a position, but entries and
+// unknown keys are only tied to the file
+fn check_other_format(file: &str, text: &str, format: Format, findings: &mut Vec<Finding>) -> Option<Located> {
+    let raw: toml::Table = match format.parse(text) {
+        Ok(table) => table,
+        Err(e) => {
+            findings.push(error(Some(file.to_string()), e));
+            return None;
+        }
+    };
+    if let Err(e) = format.parse::<FileEntries>(text) {
+        findings.push(error(Some(file.to_string()), e));
+        return None;
+    }
+    let table = migrated(file, raw, findings)?;
+
+    let at = |_: &str| file.to_string();
+    findings.extend(unknown_keys(&table, &top_level_keys(), "the top level", at));
+    let known = field_names::<KeyboardConfig>();
+    let entries = table.get("keyboards").and_then(|v| v.as_array()).into_iter().flatten();
+    for entry in entries.filter_map(|entry| entry.as_table()) {
+        findings.extend(unknown_keys(entry, known, "keyboards", at));
+    }
+    if let Some(entry) = table.get("default").and_then(|v| v.as_table()) {
+        findings.extend(unknown_keys(entry, known, "default", at));
+    }
+
+    let has_default = table.contains_key("default");
+    match toml::Value::Table(table).try_into::<FileEntries>() {
+        Ok(entries) => Some(Located {
+            keyboards: entries.keyboards.into_iter().map(|kb| (kb, file.to_string())).collect(),
+            default: has_default.then(|| file.to_string()),
+        }),
+        Err(e) => {
+            findings.push(error(Some(file.to_string()), e.to_string()));
+            None
+        }
+    }
+}
+
+// Settings with a fixed set of values, which the daemon reads leniently
+fn check_values(config: &Config, findings: &mut Vec<Finding>) {
+    if !["grab", "passive"].contains(&config.mode.to_lowercase().as_str()) {
+        findings.push(error(None, format!("mode '{}' is neither \"grab\" nor \"passive\"", config.mode)));
+    }
+    if !["long", "short"].contains(&config.layout_display.as_str()) {
+        findings.push(error(
+            None,
+            format!("layout_display '{}' is neither \"long\" nor \"short\"", config.layout_display),
+        ));
+    }
+    if let Some(tag) = &config.source_tag {
+        if parse_source_tag(tag).is_none() {
+            findings.push(error(None, format!("source_tag '{}' is not a usable EV_MSC code", tag)));
+        }
+    }
+    if let Some(primary) = &config.primary_keyboard {
+        if !config.keyboards.iter().any(|kb| kb.name == *primary) {
+            findings.push(error(None, format!("primary_keyboard '{}' names no [[keyboards]] entry", primary)));
+        }
+    }
+}
+
+fn check_entries(config: &Config, locate: &dyn Fn(&KeyboardConfig) -> Option<String>, findings: &mut Vec<Finding>) {
+    for kb in config.keyboards.iter().chain(&config.default) {
+        if let Err(e) = remap::parse(&kb.remap) {
+            findings.push(error(locate(kb), format!("keyboard '{}': remap {}", kb.name, e)));
+        }
+        for key in kb.passthrough_keys.iter().filter(|key| key.parse::<Key>().is_err()) {
+            findings.push(error(locate(kb), format!("keyboard '{}': unknown passthrough key '{}'", kb.name, key)));
+        }
+        for key in kb.require_keys.iter().filter(|key| key.parse::<Key>().is_err()) {
+            findings.push(error(locate(kb), format!("keyboard '{}': unknown require_keys key '{}'", kb.name, key)));
+        }
+        for key in kb.ignore_keys.iter().filter(|key| key.parse::<Key>().is_err()) {
+            findings.push(error(locate(kb), format!("keyboard '{}': unknown ignore_keys key '{}'", kb.name, key)));
+        }
+        match &kb.cycle_key {
+            Some(spec) => {
+                if let Err(e) = remap::hotkey(spec) {
+                    findings.push(error(locate(kb), format!("keyboard '{}': cycle_key {}", kb.name, e)));
+                }
+                if kb.layouts.is_empty() {
+                    let what = format!("keyboard '{}' has a cycle_key but no layouts to cycle through", kb.name);
+                    findings.push(warning(locate(kb), what));
+                }
+            }
+            None if !kb.layouts.is_empty() => {
+                let what = format!("keyboard '{}' has layouts but no cycle_key to cycle through them", kb.name);
+                findings.push(warning(locate(kb), what));
+            }
+            None => {}
+        }
+    }
+
+    // [default] only gets keyboards no entry matches, and a catch-all
+    // without further match rules matches every one
+    let unrestricted = |kb: &&KeyboardConfig| {
+        kb.is_catch_all()
+            && kb.exclude.is_empty()
+            && kb.id.is_none()
+            && kb.by_id.is_none()
+            && kb.phys.is_none()
+            && kb.uniq.is_none()
+            && kb.require_keys.is_empty()
+            && !kb.reject_rel
+    };
+    if let (Some(default), Some(all)) = (&config.default, config.keyboards.iter().find(unrestricted)) {
+        let mut finding = warning(
+            locate(default),
+            "[default] never applies: the catch-all entry \"*\" takes every keyboard no other entry matches".to_string(),
+        );
+        finding.fix = Some(format!(
+            "remove [default] or the catch-all entry{}",
+            locate(all).map(|at| format!(" at {}", at)).unwrap_or_default()
+        ));
+        findings.push(finding);
+    }
+
+    // A later entry for the same keyboard without a higher priority never
+    // gets a device
+    for (i, kb) in config.keyboards.iter().enumerate() {
+        let shadowing = config.keyboards[..i].iter().find(|earlier| {
+            earlier.same_keyboard(kb)
+                && earlier.exclude == kb.exclude
+                && earlier.priority >= kb.priority
+        });
+        if let Some(earlier) = shadowing {
+            let mut finding = error(
+                locate(kb),
+                format!(
+                    "keyboard '{}' duplicates the entry{} and never matches a device",
+                    kb.name,
+                    locate(earlier).map(|at| format!(" at {}", at)).unwrap_or_default()
+                ),
+            );
+            finding.fix = Some("remove one of the entries, or give this one a higher priority".to_string());
+            findings.push(finding);
+        }
+    }
+}
+
+// Entries that tie on a connected keyboard, where file order decides
+fn check_connected(config: &Config, locate: &dyn Fn(&KeyboardConfig) -> Option<String>, findings: &mut Vec<Finding>) {
+    let nodes = preflight::event_nodes();
+    let mut opened = 0;
+    for path in &nodes {
+        let Ok(device) = Device::open(path) else {
+            continue;
+        };
+        opened += 1;
+        let entries = matching_keyboard_configs(&device, path, config);
+        let Some(chosen) = preferred_entry(&entries) else {
+            continue;
+        };
+        let tied: Vec<&&KeyboardConfig> = entries.iter().filter(|kb| kb.priority == chosen.priority).collect();
+        if tied.len() < 2 {
+            continue;
+        }
+        let listed: Vec<String> = tied
+            .iter()
+            .map(|kb| match locate(kb) {
+                Some(at) => format!("'{}' ({})", kb.name, at),
+                None => format!("'{}'", kb.name),
+            })
+            .collect();
+        let mut finding = error(
+            locate(chosen),
+            format!(
+                "'{}' at {} matches {} entries of the same priority: {}; the first in file order wins",
+                device.name().unwrap_or("Unknown"),
+                path.display(),
+                tied.len(),
+                listed.join(", ")
+            ),
+        );
+        finding.fix = Some("narrow the entries down (exclude, id, phys, ...) or set `priority`".to_string());
+        findings.push(finding);
+    }
+    if opened == 0 && !nodes.is_empty() {
+        findings.push(warning(
+            None,
+            "cannot open any input device, so connected keyboards weren't checked for ambiguous entries".to_string(),
+        ));
+    }
+}
+
+// The layouts against the backend the daemon would use
+fn check_layouts(config: &mut Config, findings: &mut Vec<Finding>) {
+    if config.backend == Backend::Auto {
+        let conn = backend::connect(&config.backend_bus).ok();
+        let (detected, _) = session::detect_backend(conn.as_ref());
+        if detected == Backend::Command && config.command.switch.is_none() {
+            config.command.switch = Some(session::HYPRLAND_SWITCH.to_string());
+        }
+        config.backend = detected;
+    }
+    if config.backend == Backend::None {
+        findings.push(warning(None, "no layout backend detected, layouts weren't checked".to_string()));
+        return;
+    }
+    if !config.backend.is_available() {
+        findings.push(error(None, format!("this build has no {} backend", config.backend.name())));
+        return;
+    }
+    if let Err(e) = backend::configure(config) {
+        findings.push(error(None, e));
+        return;
+    }
+    for name in backend::assign_names(config.backend, &mut config.keyboards) {
+        let what = format!("keyboard '{}' has no engine for the {} backend", name, config.backend.name());
+        findings.push(warning(None, what));
+    }
+    backend::select(config.backend, None);
+    let conn = match backend::connect(&config.backend_bus) {
+        Ok(conn) => conn,
+        Err(e) => {
+            findings.push(warning(None, format!("layouts weren't checked: {}", e)));
+            return;
+        }
+    };
+    if config.backend != Backend::Ibus {
+        layouts::resolve_indices(&conn, &mut config.keyboards);
+        for kb in &config.keyboards {
+            for layout in kb.layouts.iter().filter(|layout| layouts::index_of(layout).is_none()) {
+                let what = format!("keyboard '{}': no layout named '{}' to cycle through", kb.name, layout);
+                findings.push(error(None, what));
+            }
+        }
+    }
+    for problem in preflight::check_layouts(&conn, config, None) {
+        let mut finding = error(None, problem.what);
+        finding.fix = Some(problem.fix);
+        findings.push(finding);
+    }
+}
+
+/// Validate the system and user config files and the drop-ins as the
+/// daemon would load them; see `Finding`.
+pub fn check_config() -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let files: Vec<PathBuf> = [config::system_config_path(), config::config_path()]
+        .into_iter()
+        .filter(|path| path.exists())
+        .chain(config::drop_in_paths())
+        .collect();
+    let mut located = Located::default();
+    let mut parsed = true;
+    for path in &files {
+        match check_file(path, &mut findings) {
+            Some(file) => {
+                located.keyboards.extend(file.keyboards);
+                located.default = file.default.or(located.default);
+            }
+            None => parsed = false,
+        }
+    }
+    if !parsed {
+        return findings;
+    }
+
+    let mut config = match config::read_overridden() {
+        Ok(env) => {
+            for rejected in env.rejected {
+                findings.push(error(None, format!("invalid environment override {}", rejected)));
+            }
+            env.config
+        }
+        Err(e) => {
+            findings.push(error(None, e));
+            return findings;
+        }
+    };
+    // Where each merged entry came from: the first file entry equal to it
+    // that no earlier one took
+    let mut unused = located.keyboards;
+    let places: Vec<Option<String>> = config
+        .keyboards
+        .iter()
+        .map(|kb| {
+            let i = unused.iter().position(|(entry, _)| entry == kb)?;
+            Some(unused.remove(i).1)
+        })
+        .collect();
+    let locate = |kb: &KeyboardConfig| {
+        if config.default.as_ref().is_some_and(|default| std::ptr::eq(default, kb)) {
+            return located.default.clone();
+        }
+        let i = config.keyboards.iter().position(|entry| std::ptr::eq(entry, kb))?;
+        places[i].clone()
+    };
+    check_values(&config, &mut findings);
+    check_entries(&config, &locate, &mut findings);
+    check_connected(&config, &locate, &mut findings);
+    check_layouts(&mut config, &mut findings);
+    findings
+}
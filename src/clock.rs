@@ -0,0 +1,117 @@
+// Time source for policy decisions: layout holds, the rate limiter, typing
+// bursts, the switch debounce and delay, the switch sound cooldown, and the
+// timeouts of switch confirmation and the context debounce read the time
+// through `now()`, so an embedder or a test can drive them with simulated
+// time. Timed waits wake up every TICK under a configured clock to notice it
+// moving (see `wait_slice`). The reconnect grace and retry backoff still
+// take real time, as they wait on the system rather than decide.
+
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Where the daemon reads the current time from.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock, used unless `Daemon::with_clock` says
+/// otherwise.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, for deterministic tests.
+///
+/// ```
+/// use kb_layout_daemon::clock::{Clock, SimulatedClock};
+/// use std::time::Duration;
+///
+/// let clock = SimulatedClock::new();
+/// let start = clock.now();
+/// clock.advance(Duration::from_millis(1500));
+/// assert_eq!(clock.now() - start, Duration::from_millis(1500));
+/// ```
+pub struct SimulatedClock {
+    start: Instant,
+    offset: Mutex<Duration>,
+}
+
+impl SimulatedClock {
+    pub fn new() -> Self {
+        SimulatedClock {
+            start: Instant::now(),
+            offset: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Move the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap() += by;
+    }
+}
+
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        SimulatedClock::new()
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+}
+
+impl<C: Clock> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        C::now(self)
+    }
+}
+
+static CLOCK: OnceLock<Box<dyn Clock>> = OnceLock::new();
+
+/// Use `clock` for the rest of the process; only the first call counts.
+pub(crate) fn set(clock: Box<dyn Clock>) {
+    let _ = CLOCK.set(clock);
+}
+
+/// The current time from the configured clock.
+pub(crate) fn now() -> Instant {
+    match CLOCK.get() {
+        Some(clock) => clock.now(),
+        None => Instant::now(),
+    }
+}
+
+// Longest real wait under a configured clock before looking at it again
+const TICK: Duration = Duration::from_millis(10);
+
+/// How long to block (on a condition variable, a channel, ...) before
+/// checking `deadline` against `now()` again: the time left, or at most
+/// TICK when the clock may be simulated. Zero once the deadline is reached.
+pub(crate) fn wait_slice(deadline: Instant) -> Duration {
+    let remaining = deadline.saturating_duration_since(now());
+    match CLOCK.get() {
+        Some(_) => remaining.min(TICK),
+        None => remaining,
+    }
+}
+
+// One simulated clock for all tests, as the configured clock is global;
+// the guard keeps tests that move it from running at the same time
+#[cfg(test)]
+pub(crate) fn simulated() -> (Arc<SimulatedClock>, std::sync::MutexGuard<'static, ()>) {
+    static SIMULATED: OnceLock<Arc<SimulatedClock>> = OnceLock::new();
+    static IN_USE: Mutex<()> = Mutex::new(());
+    let guard = IN_USE.lock().unwrap_or_else(|e| e.into_inner());
+    let clock = SIMULATED.get_or_init(|| {
+        let clock = Arc::new(SimulatedClock::new());
+        set(Box::new(Arc::clone(&clock)));
+        clock
+    });
+    (Arc::clone(clock), guard)
+}
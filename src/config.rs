@@ -0,0 +1,1153 @@
+// Configuration file format and loading
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tracing::{error, info, warn};
+
+use crate::action::DbusAction;
+use crate::affinity::ThreadConfig;
+use crate::backend::Backend;
+use crate::capture::CaptureConfig;
+use crate::events::Activity;
+use crate::logging::LogConfig;
+use crate::osk::OskConfig;
+use crate::sound::SoundConfig;
+#[cfg(feature = "peer")]
+use crate::peer::PeerConfig;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    // Schema the config was written for; older ones are migrated on load
+    #[serde(default = "default_version")]
+    pub version: u32,
+    pub keyboards: Vec<KeyboardConfig>,
+    // Entry for keyboards no [[keyboards]] entry matches, e.g. a borrowed
+    // one; unset leaves them alone
+    pub default: Option<KeyboardConfig>,
+    // Devices whose name contains any of these are never matched by any
+    // entry, e.g. "Consumer Control" or "System Control" nodes
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    #[serde(default = "default_mode")]
+    pub mode: String,
+    // Keep the mode set over D-Bus and cycled layouts across restarts
+    #[serde(default = "default_persist_state")]
+    pub persist_state: bool,
+    // Refuse to start on a config that doesn't load, instead of running
+    // with the built-in defaults; read even from a file that doesn't parse
+    // (see load_config_strict)
+    #[serde(default)]
+    pub strict: bool,
+    // Layout switching backend: "auto" (detect), "kde", "gnome", "command",
+    // ... or "none"
+    #[serde(default)]
+    pub backend: Backend,
+    // D-Bus bus the backend talks to: "session", "system" or a bus
+    // address; the control interface stays on the session bus
+    #[serde(default = "default_backend_bus")]
+    pub backend_bus: String,
+    // Key presses another keyboard needs during a hold to take the layout anyway
+    #[serde(default = "default_sticky_threshold")]
+    pub sticky_threshold: u32,
+    // Switches within this long of the previous one are skipped; 0 is off
+    #[serde(default)]
+    pub switch_debounce_ms: u64,
+    // How long a switch waits for the backend to report the new layout,
+    // and how often backends without change reports are polled meanwhile
+    #[serde(default = "default_confirm_timeout_ms")]
+    pub confirm_timeout_ms: u64,
+    #[serde(default = "default_confirm_poll_us")]
+    pub confirm_poll_us: u64,
+    // EV_MSC code ("MSC_SERIAL", "MSC_RAW", ... or a number) used to tag
+    // forwarded events with the index of the source keyboard entry
+    pub source_tag: Option<String>,
+    // Name of each keyboard's virtual device, with {original} replaced by
+    // the physical keyboard's name, so desktops can keep per-device settings
+    #[serde(default = "default_virtual_name")]
+    pub virtual_name: String,
+    // How grab mode forwards events: "uinput" virtual keyboards, or where
+    // uinput isn't available "portal" (the RemoteDesktop portal) or
+    // "wayland" (zwp_virtual_keyboard_v1)
+    #[serde(default)]
+    pub injection: Injection,
+    // Watch for keyboards being plugged in (needs udev/devd); off runs on
+    // the startup scan only, e.g. in containers
+    #[serde(default = "default_hotplug")]
+    pub hotplug: bool,
+    // Rescan /dev/input for new keyboards this often; 0 disables
+    #[serde(default)]
+    pub rescan_secs: u64,
+    // How long a disconnected keyboard's virtual device and key state are
+    // kept for it to come back (e.g. a Bluetooth blip); 0 disables
+    #[serde(default = "default_reconnect_grace_ms")]
+    pub reconnect_grace_ms: u64,
+    // Wait before opening a hot-plugged device, for it to settle
+    #[serde(default = "default_hotplug_settle_ms")]
+    pub hotplug_settle_ms: u64,
+    // Retry delay for a keyboard that can't be opened or grabbed, doubling
+    // on every failure up to the max
+    #[serde(default = "default_reopen_retry_ms")]
+    pub reopen_retry_ms: u64,
+    #[serde(default = "default_reopen_retry_max_ms")]
+    pub reopen_retry_max_ms: u64,
+    // Layout names shown to the user: "long" (localized) or "short" codes
+    #[serde(default = "default_layout_display")]
+    pub layout_display: String,
+    // Keyboard entry (by name) whose layout is set at startup when it is
+    // connected, ahead of any assert_layout_on_start entry
+    pub primary_keyboard: Option<String>,
+    // Check every configured layout against the backend at startup
+    #[serde(default = "default_validate_layouts")]
+    pub validate_layouts: bool,
+    // Turn off KDE's per-window switching and grp: options while running
+    #[serde(default)]
+    pub fix_kde_conflicts: bool,
+    #[serde(default)]
+    pub context: ContextConfig,
+    #[serde(default)]
+    pub all_disconnected: AllDisconnectedConfig,
+    #[serde(default)]
+    pub command: CommandConfig,
+    #[serde(default)]
+    pub console: ConsoleConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub threads: ThreadConfig,
+    #[serde(default)]
+    pub osk: OskConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub sound: SoundConfig,
+    #[cfg(feature = "peer")]
+    pub peer: Option<PeerConfig>,
+}
+
+/// A keyboard's own mode, overriding the global one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyboardMode {
+    Grab,
+    Passive,
+}
+
+/// Where grab mode injects the events it forwards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Injection {
+    /// A uinput virtual keyboard per physical keyboard
+    #[default]
+    Uinput,
+    /// The XDG RemoteDesktop portal, after the desktop's permission prompt
+    Portal,
+    /// A Wayland virtual keyboard (zwp_virtual_keyboard_v1) per keyboard
+    Wayland,
+}
+
+impl Injection {
+    pub fn name(self) -> &'static str {
+        match self {
+            Injection::Uinput => "uinput",
+            Injection::Portal => "portal",
+            Injection::Wayland => "wayland",
+        }
+    }
+
+    /// Whether this build includes the injection path.
+    pub fn is_available(self) -> bool {
+        match self {
+            Injection::Uinput => true,
+            Injection::Portal => cfg!(feature = "portal"),
+            Injection::Wayland => cfg!(feature = "wayland"),
+        }
+    }
+}
+
+fn default_version() -> u32 {
+    CONFIG_VERSION
+}
+
+fn default_mode() -> String {
+    "grab".to_string()
+}
+
+fn default_persist_state() -> bool {
+    true
+}
+
+fn default_virtual_name() -> String {
+    "{original} (kb-layout-daemon)".to_string()
+}
+
+fn default_backend_bus() -> String {
+    "session".to_string()
+}
+
+fn default_sticky_threshold() -> u32 {
+    3
+}
+
+pub(crate) fn default_confirm_timeout_ms() -> u64 {
+    50
+}
+
+pub(crate) fn default_confirm_poll_us() -> u64 {
+    100
+}
+
+fn default_hotplug() -> bool {
+    true
+}
+
+fn default_reconnect_grace_ms() -> u64 {
+    2000
+}
+
+fn default_hotplug_settle_ms() -> u64 {
+    100
+}
+
+fn default_reopen_retry_ms() -> u64 {
+    500
+}
+
+fn default_reopen_retry_max_ms() -> u64 {
+    10_000
+}
+
+fn default_burst_window_ms() -> u64 {
+    1000
+}
+
+fn default_layout_display() -> String {
+    "long".to_string()
+}
+
+fn default_validate_layouts() -> bool {
+    true
+}
+
+// Context change: fired when the active keyboard changes, e.g. to switch
+// monitor input or audio output depending on which desk position is in use
+#[derive(Debug, Deserialize)]
+pub struct ContextConfig {
+    #[serde(default = "default_context_debounce_ms")]
+    pub debounce_ms: u64,
+    #[serde(default)]
+    pub hooks: Vec<String>,
+}
+
+fn default_context_debounce_ms() -> u64 {
+    500
+}
+
+// What happens once the last monitored keyboard is gone (dock unplugged).
+// Nothing set keeps the layout where it was.
+#[derive(Debug, Default, Deserialize)]
+pub struct AllDisconnectedConfig {
+    // Switch back to this layout
+    pub layout_index: Option<u32>,
+    // Shell command, with the last keyboard's name in KB_LAST_KEYBOARD
+    pub hook: Option<String>,
+}
+
+// Shell commands for backend = "command". {index}, {layout} and {keyboard}
+// are substituted, shell-quoted
+#[derive(Debug, Default, Deserialize)]
+pub struct CommandConfig {
+    // Switches to a layout, e.g. "setxkbmap {layout}"
+    pub switch: Option<String>,
+    // Prints the current layout index; unset reports the last switch
+    pub current: Option<String>,
+}
+
+// Keymaps for backend = "console", loaded with loadkeys
+#[derive(Debug, Default, Deserialize)]
+pub struct ConsoleConfig {
+    // Keymap per layout index, e.g. ["us", "de-latin1"]
+    #[serde(default)]
+    pub keymaps: Vec<String>,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            debounce_ms: default_context_debounce_ms(),
+            hooks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct KeyboardConfig {
+    // Empty matches every device name, e.g. with `id` set; "*" makes the
+    // entry a catch-all for keyboards no other entry matches
+    #[serde(default)]
+    pub name: String,
+    // Only match the device with this stable ID (see `inspect`), to tell
+    // apart identical keyboards
+    pub id: Option<String>,
+    // Devices whose name also contains any of these aren't matched, e.g.
+    // ["Mouse", "Consumer Control"] for the sibling nodes of a combo receiver
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    // Only match the device this /dev/input/by-id link (e.g.
+    // "usb-Lofree_Flow-event-kbd") points to when it is found
+    pub by_id: Option<String>,
+    // Only match devices whose evdev phys starts with this (e.g.
+    // "usb-0000:00:14.0-2", covering all its interfaces), or whose uniq
+    // (serial, or the MAC of a Bluetooth keyboard) is this; case-insensitive
+    pub phys: Option<String>,
+    pub uniq: Option<String>,
+    // Only match devices that have all these keys (e.g. ["KEY_A", "KEY_Z"])
+    // and, with reject_rel, no relative axes, skipping the mouse and
+    // consumer control nodes of a multi-interface device
+    #[serde(default)]
+    pub require_keys: Vec<String>,
+    #[serde(default)]
+    pub reject_rel: bool,
+    // Among entries matching the same device, the highest priority wins;
+    // equal priorities go by file order
+    #[serde(default)]
+    pub priority: i32,
+    // "grab" or "passive" for this keyboard whatever the global mode (and
+    // SetMode) says; unset follows it
+    pub mode: Option<KeyboardMode>,
+    // Ignored with the ibus backend, which takes `engine` instead
+    #[serde(default)]
+    pub layout_index: u32,
+    pub layout_name: String,
+    // Set when upgrading a version 1 file, where layout_name was only a
+    // label: it then picks the layout only if it names the layout_index one
+    #[serde(default)]
+    pub legacy_layout_name: bool,
+    // IBus engine (e.g. "anthy" or "xkb:de::ger") for the ibus backend
+    pub engine: Option<String>,
+    // After this keyboard's last key press, other keyboards can't take the
+    // layout for this long (unless they exceed the sticky threshold)
+    #[serde(default)]
+    pub hold_ms: u64,
+    // Key event values that count as activity: "press", "repeat", "release"
+    #[serde(default)]
+    pub activity_on: Activity,
+    // Modifier presses (Shift, Ctrl, Alt, AltGr, Meta) and these keys
+    // (e.g. "KEY_F13") are forwarded but don't count as activity
+    #[serde(default)]
+    pub ignore_modifiers: bool,
+    #[serde(default)]
+    pub ignore_keys: Vec<String>,
+    // Only take the layout after this many activity events within
+    // burst_window_ms, not on isolated presses; 0 switches on every press
+    #[serde(default, alias = "min_presses")]
+    pub burst_keys: u32,
+    #[serde(default = "default_burst_window_ms")]
+    pub burst_window_ms: u64,
+    // Only take the layout this long after a press, and only if no other
+    // keyboard was typed on meanwhile, so brushing it doesn't switch
+    #[serde(default)]
+    pub switch_delay_ms: u64,
+    // Give the virtual keyboard REL axes; unset follows the source device
+    pub advertise_pointer: Option<bool>,
+    // D-Bus call made on every key press, instead of switching the layout
+    // unless the action sets switch_layout
+    pub dbus_action: Option<DbusAction>,
+    // Name of a second virtual device receiving a copy of this keyboard's
+    // events in both modes, for recording or analysis tools
+    pub mirror: Option<String>,
+    // Keys (e.g. "KEY_POWER") still forwarded while the keyboard is locked
+    // with SetKeyboardLocked
+    #[serde(default)]
+    pub passthrough_keys: Vec<String>,
+    // Key presses and releases per second above which the keyboard is
+    // considered malfunctioning and its events are dropped; 0 = no limit
+    #[serde(default)]
+    pub max_events_per_sec: u32,
+    // Set this keyboard's layout at startup if it is connected, instead of
+    // waiting for its first key press
+    #[serde(default)]
+    pub assert_layout_on_start: bool,
+    // Also lock the keyboard (as SetKeyboardLocked does) when it floods,
+    // until it is unlocked over D-Bus
+    #[serde(default)]
+    pub lock_on_flood: bool,
+    // Key remapping in grab mode, e.g. "capslock -> esc" or
+    // "ralt+hjkl -> arrows" (see remap.rs)
+    #[serde(default)]
+    pub remap: Vec<String>,
+    // Layouts (names or indices) that cycle_key steps through for this
+    // keyboard, e.g. ["English (US)", "German"]
+    #[serde(default)]
+    pub layouts: Vec<String>,
+    // Hotkey such as "ctrl+space" moving this keyboard to the next of its
+    // layouts
+    pub cycle_key: Option<String>,
+}
+
+impl KeyboardConfig {
+    /// Whether this is a catch-all entry (`name = "*"`), which takes on the
+    /// keyboards that no other `[[keyboards]]` entry matches.
+    ///
+    /// ```
+    /// use kb_layout_daemon::config::KeyboardConfig;
+    ///
+    /// let all = KeyboardConfig { name: "*".into(), ..Default::default() };
+    /// assert!(all.is_catch_all());
+    /// assert!(!KeyboardConfig { name: "Lofree".into(), ..Default::default() }.is_catch_all());
+    /// ```
+    pub fn is_catch_all(&self) -> bool {
+        self.name == "*"
+    }
+
+    /// Whether both entries pick the same keyboard: the same name and
+    /// device rules (`id`, `by_id`, `phys`, `uniq`).
+    pub fn same_keyboard(&self, other: &KeyboardConfig) -> bool {
+        self.name == other.name
+            && self.id == other.id
+            && self.by_id == other.by_id
+            && self.phys == other.phys
+            && self.uniq == other.uniq
+    }
+
+    /// Whether a monitor running with this entry has to be set up again to
+    /// use `other`: the match rules or the virtual devices differ. Anything
+    /// else is applied by the running monitor.
+    pub fn needs_restart(&self, other: &KeyboardConfig) -> bool {
+        !self.same_keyboard(other)
+            || self.exclude != other.exclude
+            || self.require_keys != other.require_keys
+            || self.reject_rel != other.reject_rel
+            || self.advertise_pointer != other.advertise_pointer
+            || self.mirror != other.mirror
+    }
+}
+
+// The config used without a config file: two example keyboards, with
+// every setting at its default
+const BUILT_IN: &str = r#"
+[[keyboards]]
+name = "Lofree"
+layout_index = 1
+layout_name = "English (US)"
+
+[[keyboards]]
+name = "CHERRY"
+layout_index = 0
+layout_name = "German"
+"#;
+
+/// The built-in config as a table, for environment overrides to apply to
+/// when there is no config file to read
+pub fn default_table() -> toml::Table {
+    BUILT_IN.parse().expect("the built-in config parses")
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        toml::Value::Table(default_table())
+            .try_into()
+            .expect("the built-in config is valid")
+    }
+}
+
+// Config file given with --config
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use `path` instead of the default location; only the first call counts.
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
+
+// Config file names looked for, in order; the format follows the extension
+const FILE_NAMES: &[&str] = &["config.toml", "config.yaml", "config.yml", "config.json"];
+
+// The first config file in `dir`, else its config.toml
+fn find_config(dir: &Path) -> PathBuf {
+    FILE_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| dir.join(FILE_NAMES[0]))
+}
+
+/// Config location: the path given to `set_config_path`, else the default
+/// `~/.config/kb-layout-daemon/config.toml` (or `config.yaml`, `config.yml`,
+/// `config.json`, whichever exists)
+pub fn config_path() -> PathBuf {
+    if let Some(path) = CONFIG_PATH.get() {
+        return path.clone();
+    }
+    match dirs::config_dir() {
+        Some(dir) => find_config(&dir.join("kb-layout-daemon")),
+        None => PathBuf::from(FILE_NAMES[0]),
+    }
+}
+
+/// Directory of drop-in files next to the user config, each adding
+/// `[[keyboards]]` entries: `~/.config/kb-layout-daemon/config.d`
+pub fn drop_in_dir() -> PathBuf {
+    config_path().with_file_name("config.d")
+}
+
+/// The `*.toml` files in `drop_in_dir`, in name order
+pub fn drop_in_paths() -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(drop_in_dir()) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Directory of the config shipped by the administrator; the user config
+/// is merged over it
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/kb-layout-daemon";
+
+/// The system config: `config.toml` (or another format) in
+/// `SYSTEM_CONFIG_DIR`
+pub fn system_config_path() -> PathBuf {
+    find_config(Path::new(SYSTEM_CONFIG_DIR))
+}
+
+/// Formats a config file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// The format of a config file, by its extension; TOML unless it is
+    /// `.yaml`, `.yml` or `.json`
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()).map(str::to_lowercase).as_deref() {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    /// Parse a config file's content in this format.
+    pub fn parse<T: serde::de::DeserializeOwned>(self, content: &str) -> Result<T, String> {
+        match self {
+            Format::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+            Format::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            #[cfg(feature = "yaml")]
+            Format::Yaml => serde_yaml_ng::from_str(content).map_err(|e| e.to_string()),
+            #[cfg(not(feature = "yaml"))]
+            Format::Yaml => Err("this build can't read YAML (enable the \"yaml\" cargo feature)".to_string()),
+        }
+    }
+
+    /// Write a config table in this format.
+    pub fn write(self, table: &toml::Table) -> Result<String, String> {
+        match self {
+            Format::Toml => toml::to_string(table).map_err(|e| e.to_string()),
+            Format::Json => serde_json::to_string_pretty(table).map_err(|e| e.to_string()),
+            #[cfg(feature = "yaml")]
+            Format::Yaml => serde_yaml_ng::to_string(table).map_err(|e| e.to_string()),
+            #[cfg(not(feature = "yaml"))]
+            Format::Yaml => Err("this build can't write YAML (enable the \"yaml\" cargo feature)".to_string()),
+        }
+    }
+}
+
+/// Config schema this release reads and writes. A config without `version`
+/// is version 1.
+pub const CONFIG_VERSION: u32 = 2;
+
+// Upgrades from each version to the next, starting at version 1: they
+// rewrite a config table and describe each change for a warning
+const MIGRATIONS: &[fn(&mut toml::Table) -> Vec<String>] = &[migrate_layout_names];
+
+// The [[keyboards]] entries and the [default] entry of a config table
+fn entries_mut(table: &mut toml::Table) -> Vec<&mut toml::Table> {
+    let mut entries = Vec::new();
+    for (key, value) in table.iter_mut() {
+        match (key.as_str(), value) {
+            ("keyboards", toml::Value::Array(list)) => {
+                entries.extend(list.iter_mut().filter_map(toml::Value::as_table_mut))
+            }
+            ("default", toml::Value::Table(entry)) => entries.push(entry),
+            _ => {}
+        }
+    }
+    entries
+}
+
+// Entry keys that are another way to write a list: `not_name = "Mouse"`
+// adds to `exclude`
+const ENTRY_ALIASES: &[(&str, &str)] = &[("not_name", "exclude")];
+
+fn fold_aliases(table: &mut toml::Table) {
+    for entry in entries_mut(table) {
+        for &(alias, key) in ENTRY_ALIASES {
+            let values = match entry.remove(alias) {
+                Some(toml::Value::Array(values)) => values,
+                Some(value) => vec![value],
+                None => continue,
+            };
+            if let toml::Value::Array(list) = entry.entry(key).or_insert_with(|| toml::Value::Array(Vec::new())) {
+                list.extend(values);
+            }
+        }
+    }
+}
+
+// 1 -> 2: `layout_name` picks the layout by name, where it used to be a
+// label next to `layout_index`. Labels are kept but marked, and checked
+// against the desktop's layouts once they are known (see
+// layouts::resolve_indices), so an older file keeps switching by index
+// even where a label happens to name another layout
+fn migrate_layout_names(table: &mut toml::Table) -> Vec<String> {
+    let mut changes = Vec::new();
+    for entry in entries_mut(table) {
+        let label = match entry.get("layout_name").and_then(toml::Value::as_str) {
+            Some(label) if !label.is_empty() => label.to_string(),
+            _ => continue,
+        };
+        entry.insert("legacy_layout_name".to_string(), toml::Value::Boolean(true));
+        let name = entry.get("name").and_then(toml::Value::as_str).unwrap_or_default();
+        let index = entry.get("layout_index").and_then(toml::Value::as_integer).unwrap_or(0);
+        changes.push(format!(
+            "'{}': layout_name \"{}\" was a label, it picks the layout only where it names layout_index {}",
+            name, label, index
+        ));
+    }
+    changes
+}
+
+/// Upgrade a config table from the schema its `version` says to
+/// `CONFIG_VERSION`, returning the changes made, and fold aliases such as
+/// `not_name` into the keys they stand for. Fails for configs written for a
+/// newer release.
+///
+/// ```
+/// use kb_layout_daemon::config::{migrate, CONFIG_VERSION};
+///
+/// let mut table: toml::Table = r#"
+///     [[keyboards]]
+///     name = "Logitech"
+///     layout_index = 1
+///     layout_name = "German"
+/// "#
+/// .parse()
+/// .unwrap();
+/// assert_eq!(migrate(&mut table).unwrap().len(), 1);
+/// assert_eq!(table["version"].as_integer(), Some(CONFIG_VERSION as i64));
+/// assert_eq!(table["keyboards"][0]["layout_index"].as_integer(), Some(1));
+/// assert_eq!(table["keyboards"][0]["legacy_layout_name"].as_bool(), Some(true));
+///
+/// let mut newer: toml::Table = "version = 4294967298".parse().unwrap();
+/// assert!(migrate(&mut newer).is_err());
+/// ```
+pub fn migrate(table: &mut toml::Table) -> Result<Vec<String>, String> {
+    let version = match table.get("version") {
+        None => 1,
+        // Compared as read, so a huge version isn't truncated into a known one
+        Some(toml::Value::Integer(version)) if *version >= 1 => *version,
+        Some(other) => return Err(format!("version must be a positive integer, not {}", other)),
+    };
+    if version > CONFIG_VERSION as i64 {
+        return Err(format!(
+            "config version {} is newer than this release of kb-layout-daemon reads ({})",
+            version, CONFIG_VERSION
+        ));
+    }
+    fold_aliases(table);
+    let mut changes = Vec::new();
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        changes.extend(migration(table));
+    }
+    table.insert("version".to_string(), toml::Value::Integer(CONFIG_VERSION as i64));
+    Ok(changes)
+}
+
+// A config file as a TOML table, whatever its format, upgraded to the
+// current schema; None when the file doesn't exist
+fn read_table(path: &Path) -> Result<Option<toml::Table>, String> {
+    let mut table: toml::Table = match std::fs::read_to_string(path) {
+        Ok(content) => Format::of(path)
+            .parse(&content)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let changes = migrate(&mut table).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !changes.is_empty() {
+        warn!(
+            "{} uses an older config schema, read as version {}: {}. Update the file and set version = {}",
+            path.display(),
+            CONFIG_VERSION,
+            changes.join("; "),
+            CONFIG_VERSION
+        );
+    }
+    Ok(Some(table))
+}
+
+// The [[keyboards]] entries of the drop-in files, in file order. Anything
+// else in them is ignored
+fn read_drop_ins() -> Result<Vec<toml::Value>, String> {
+    let mut keyboards = Vec::new();
+    for path in drop_in_paths() {
+        let Some(mut table) = read_table(&path)? else {
+            continue;
+        };
+        table.remove("version");
+        match table.remove("keyboards") {
+            Some(toml::Value::Array(entries)) => keyboards.extend(entries),
+            Some(_) => return Err(format!("{}: keyboards must be a list of entries", path.display())),
+            None => {}
+        }
+        if !table.is_empty() {
+            let keys: Vec<&str> = table.keys().map(String::as_str).collect();
+            warn!(
+                "{}: drop-in files only add [[keyboards]] entries, ignoring {}",
+                path.display(),
+                keys.join(", ")
+            );
+        }
+    }
+    Ok(keyboards)
+}
+
+// Whether two [[keyboards]] entries are for the same keyboard
+fn same_keyboard(a: &toml::Value, b: &toml::Value) -> bool {
+    ["name", "id", "by_id", "phys", "uniq"]
+        .iter()
+        .all(|key| a.get(key) == b.get(key))
+}
+
+/// Merge the `user` config over the `system` one. Settings the user sets
+/// override the system's, section by section (`[log]`, `[osk]`, ...), with
+/// lists such as `passthrough_keys` replaced as a whole. `[[keyboards]]`
+/// entries are combined: the user's come first, so they win when both
+/// match a device, followed by the system entries the user doesn't
+/// redefine (same `name` and device rules). `inherit_keyboards = false` in the user
+/// config drops the system entries instead.
+pub fn merge(mut system: toml::Table, mut user: toml::Table) -> toml::Table {
+    let inherit = match user.remove("inherit_keyboards") {
+        Some(toml::Value::Boolean(inherit)) => inherit,
+        _ => true,
+    };
+    system.remove("inherit_keyboards");
+
+    let system_keyboards = match system.remove("keyboards") {
+        Some(toml::Value::Array(entries)) if inherit => entries,
+        _ => Vec::new(),
+    };
+    match user.get_mut("keyboards") {
+        Some(toml::Value::Array(entries)) => {
+            let extra: Vec<toml::Value> = system_keyboards
+                .into_iter()
+                .filter(|kb| !entries.iter().any(|own| same_keyboard(own, kb)))
+                .collect();
+            entries.extend(extra);
+        }
+        _ => {
+            user.insert("keyboards".to_string(), toml::Value::Array(system_keyboards));
+        }
+    }
+
+    merge_tables(&mut system, user);
+    system
+}
+
+fn merge_tables(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge_tables(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Prefix of the environment variables that override settings
+pub const ENV_PREFIX: &str = "KB_LAYOUT_DAEMON_";
+
+// Variables with the prefix that aren't settings (see main.rs)
+const ENV_NOT_SETTINGS: &[&str] = &["CONFIG"];
+
+/// Override settings in a config table with `KB_LAYOUT_DAEMON_*` variables
+/// from `vars`. The rest of the variable name, lowercased, is the setting,
+/// with `__` between a section and its field. Values are read as TOML,
+/// falling back to a plain string. Returns the variables that applied.
+///
+/// ```
+/// use kb_layout_daemon::config::apply_env;
+///
+/// let mut table: toml::Table = "mode = \"grab\"\n[log]\nlevel = \"info\"".parse().unwrap();
+/// let vars = [
+///     ("KB_LAYOUT_DAEMON_MODE", "passive"),
+///     ("KB_LAYOUT_DAEMON_RESCAN_SECS", "30"),
+///     ("KB_LAYOUT_DAEMON_LOG__LEVEL", "debug"),
+///     ("KB_LAYOUT_DAEMON_OSK__SERVICES", "[\"sm.puri.OSK0\"]"),
+///     ("HOME", "/home/me"),
+/// ];
+/// let applied = apply_env(&mut table, vars.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+/// assert_eq!(applied.len(), 4);
+/// assert_eq!(table["mode"].as_str(), Some("passive"));
+/// assert_eq!(table["rescan_secs"].as_integer(), Some(30));
+/// assert_eq!(table["log"]["level"].as_str(), Some("debug"));
+/// assert_eq!(table["osk"]["services"][0].as_str(), Some("sm.puri.OSK0"));
+/// ```
+pub fn apply_env(table: &mut toml::Table, vars: impl IntoIterator<Item = (String, String)>) -> Vec<String> {
+    let mut applied = Vec::new();
+    for (name, value) in vars {
+        let Some(setting) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        if setting.is_empty() || ENV_NOT_SETTINGS.contains(&setting) {
+            continue;
+        }
+        let value = format!("v = {}", value)
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|mut parsed| parsed.remove("v"))
+            .unwrap_or(toml::Value::String(value));
+
+        let path: Vec<String> = setting.split("__").map(str::to_lowercase).collect();
+        let (field, sections) = path.split_last().unwrap();
+        let mut target = &mut *table;
+        for section in sections {
+            let entry = target
+                .entry(section.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::Table::new());
+            }
+            target = entry.as_table_mut().unwrap();
+        }
+        target.insert(field.clone(), value);
+        applied.push(name);
+    }
+    applied.sort();
+    applied
+}
+
+/// Parse a system and a user config and merge them as `merge` does.
+///
+/// ```
+/// use kb_layout_daemon::config::layered;
+///
+/// let system = r#"
+///     version = 2
+///     mode = "passive"
+///     [[keyboards]]
+///     name = "CHERRY"
+///     layout_name = "German"
+///     [[keyboards]]
+///     name = "Lofree"
+///     layout_name = "English (US)"
+/// "#;
+/// let user = r#"
+///     version = 2
+///     [[keyboards]]
+///     name = "Lofree"
+///     layout_index = 2
+///     layout_name = "French"
+/// "#;
+/// let config = layered(system, user).unwrap();
+/// assert_eq!(config.mode, "passive");
+/// let names: Vec<_> = config.keyboards.iter().map(|kb| kb.layout_name.as_str()).collect();
+/// assert_eq!(names, ["French", "German"]);
+///
+/// let replacing = format!("inherit_keyboards = false\n{}", user);
+/// assert_eq!(layered(system, &replacing).unwrap().keyboards.len(), 1);
+/// ```
+pub fn layered(system: &str, user: &str) -> Result<Config, String> {
+    let mut system = system.parse().map_err(|e| format!("Failed to parse system config: {}", e))?;
+    let mut user = user.parse().map_err(|e| format!("Failed to parse config: {}", e))?;
+    migrate(&mut system)?;
+    migrate(&mut user)?;
+    toml::Value::Table(merge(system, user))
+        .try_into()
+        .map_err(|e| format!("Failed to parse config: {}", e))
+}
+
+// The user config merged over the system config, with the entries of the
+// drop-ins after the user's own, before environment overrides
+fn read_files() -> Result<toml::Table, String> {
+    let user_path = config_path();
+    let (system, user) = (read_table(&system_config_path())?, read_table(&user_path)?);
+    let drop_ins = read_drop_ins()?;
+    if system.is_none() && user.is_none() && drop_ins.is_empty() {
+        return Err(format!("No config file found at {:?}", user_path));
+    }
+    let mut user = user.unwrap_or_default();
+    if !drop_ins.is_empty() {
+        match user.entry("keyboards").or_insert_with(|| toml::Value::Array(Vec::new())) {
+            toml::Value::Array(entries) => entries.extend(drop_ins),
+            _ => return Err(format!("{}: keyboards must be a list of entries", user_path.display())),
+        }
+    }
+    Ok(merge(system.unwrap_or_default(), user))
+}
+
+fn parse_table(table: toml::Table) -> Result<Config, String> {
+    toml::Value::Table(table)
+        .try_into()
+        .map_err(|e| format!("Failed to parse config: {}", e))
+}
+
+/// A config with the environment overrides applied, see `parse_with_env`.
+pub struct Overridden {
+    pub config: Config,
+    /// Variables that were applied
+    pub applied: Vec<String>,
+    /// Variables the config couldn't take, each with the reason
+    pub rejected: Vec<String>,
+}
+
+/// Parse a config table with the `KB_LAYOUT_DAEMON_*` variables from `vars`
+/// applied as `apply_env` does. Each override is tried on its own, so one
+/// the config can't take is left out and reported instead of failing the
+/// whole config. Fails only if the table itself is invalid.
+///
+/// ```
+/// use kb_layout_daemon::config::{default_table, parse_with_env};
+///
+/// let vars = [("KB_LAYOUT_DAEMON_RESCAN_SECS", "soon"), ("KB_LAYOUT_DAEMON_MODE", "passive")];
+/// let env = parse_with_env(default_table(), vars.iter().map(|(k, v)| (k.to_string(), v.to_string()))).unwrap();
+/// assert_eq!(env.config.mode, "passive");
+/// assert_eq!(env.config.rescan_secs, 0);
+/// assert_eq!(env.applied, ["KB_LAYOUT_DAEMON_MODE"]);
+/// assert!(env.rejected[0].starts_with("KB_LAYOUT_DAEMON_RESCAN_SECS"));
+/// ```
+pub fn parse_with_env(
+    mut table: toml::Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Overridden, String> {
+    let mut config = parse_table(table.clone())?;
+    let mut vars: Vec<(String, String)> = vars.into_iter().collect();
+    vars.sort();
+    let (mut applied, mut rejected) = (Vec::new(), Vec::new());
+    for (name, value) in vars {
+        let mut overridden = table.clone();
+        if apply_env(&mut overridden, [(name.clone(), value)]).is_empty() {
+            continue;
+        }
+        match parse_table(overridden.clone()) {
+            Ok(parsed) => {
+                table = overridden;
+                config = parsed;
+                applied.push(name);
+            }
+            Err(e) => {
+                let reason = e.trim_start_matches("Failed to parse config: ").trim_end().replace('\n', " ");
+                rejected.push(format!("{}: {}", name, reason));
+            }
+        }
+    }
+    Ok(Overridden {
+        config,
+        applied,
+        rejected,
+    })
+}
+
+/// Read the user config (see `config_path`) merged over the system config,
+/// with environment overrides, see `parse_with_env`. The entries of the
+/// drop-in files (see `drop_in_dir`) follow the user's own. Fails if none
+/// of them exists.
+pub fn read_overridden() -> Result<Overridden, String> {
+    parse_with_env(read_files()?, std::env::vars())
+}
+
+/// Like `read_overridden`, but an invalid override fails the config.
+pub fn read_config() -> Result<Config, String> {
+    let env = read_overridden()?;
+    if !env.rejected.is_empty() {
+        return Err(env.rejected.join("\n"));
+    }
+    Ok(env.config)
+}
+
+/// Like `read_config`, but invalid overrides are left out with an error in
+/// the log rather than failing the config.
+pub fn reread_config() -> Result<Config, String> {
+    let env = read_overridden()?;
+    for rejected in &env.rejected {
+        error!("Ignoring environment override {}", rejected);
+    }
+    Ok(env.config)
+}
+
+/// Load the user config merged over the system config, falling back to
+/// the built-in defaults
+pub fn load_config() -> Config {
+    load_config_strict(false).unwrap_or_default()
+}
+
+// `strict = true` at the top level of a table, with the environment
+// overrides applied
+fn asks_strict(table: &toml::Table) -> bool {
+    let mut table = table.clone();
+    apply_env(&mut table, std::env::vars());
+    table.get("strict").and_then(toml::Value::as_bool) == Some(true)
+}
+
+// `strict = true` at the top level of a config file in any format, found
+// by a line scan when the file doesn't parse
+fn file_asks_strict(path: &Path) -> bool {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    let format = Format::of(path);
+    if let Ok(table) = format.parse::<toml::Table>(&content) {
+        return table.get("strict").and_then(toml::Value::as_bool) == Some(true);
+    }
+    let lines = content.lines().map(|line| match format {
+        Format::Json => line,
+        Format::Toml | Format::Yaml => line.split('#').next().unwrap_or(""),
+    });
+    let top_level: Vec<&str> = match format {
+        Format::Toml => lines.map(str::trim).take_while(|line| !line.starts_with('[')).collect(),
+        Format::Yaml => lines.filter(|line| !line.starts_with([' ', '\t'])).collect(),
+        Format::Json => lines.collect(),
+    };
+    top_level
+        .iter()
+        .filter_map(|line| line.split_once(if format == Format::Toml { '=' } else { ':' }))
+        .any(|(key, value)| {
+            key.trim().trim_matches('"') == "strict" && value.trim().trim_end_matches(',').trim() == "true"
+        })
+}
+
+/// Like `load_config`, but with `strict` (or `strict = true` in the config
+/// or the environment) a missing or broken config is an error instead of a
+/// reason to run with the built-in defaults.
+pub fn load_config_strict(strict: bool) -> Result<Config, String> {
+    let user_path = config_path();
+    let system_path = system_config_path();
+    let paths = [system_path.as_path(), user_path.as_path()];
+    let found: Vec<String> = paths
+        .iter()
+        .filter(|path| path.exists())
+        .map(|path| path.display().to_string())
+        .chain(drop_in_paths().iter().map(|path| path.display().to_string()))
+        .collect();
+
+    let defaults = || parse_with_env(default_table(), std::env::vars()).expect("the built-in config is valid");
+    let (env, strict) = if found.is_empty() {
+        let env = defaults();
+        if strict || env.config.strict {
+            return Err(format!("No config file found at {:?}", user_path));
+        }
+        info!("No config file found at {:?}, using defaults", user_path);
+        (env, false)
+    } else {
+        let table = read_files();
+        match table.clone().and_then(|table| parse_with_env(table, std::env::vars())) {
+            Ok(env) => {
+                info!("Loaded config from {}", found.join(" + "));
+                let strict = strict || env.config.strict;
+                (env, strict)
+            }
+            Err(e) => {
+                // Without a config, strict comes from the merged table, or
+                // from the files themselves when one doesn't parse
+                let asked = match &table {
+                    Ok(table) => asks_strict(table),
+                    Err(_) => asks_strict(&toml::Table::new()) || paths.iter().any(|path| file_asks_strict(path)),
+                };
+                if strict || asked {
+                    return Err(format!("{}\n(`kb-layout-daemon check-config` lists every problem)", e.trim_end()));
+                }
+                warn!("{}, using defaults", e);
+                (defaults(), false)
+            }
+        }
+    };
+    if !env.rejected.is_empty() && strict {
+        return Err(format!("Invalid environment override {}", env.rejected.join("; ")));
+    }
+    for rejected in &env.rejected {
+        error!("Ignoring environment override {}", rejected);
+    }
+    if !env.applied.is_empty() {
+        info!("Settings overridden by {}", env.applied.join(", "));
+    }
+    Ok(env.config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(toml: &str) -> toml::Table {
+        toml.parse().unwrap()
+    }
+
+    #[test]
+    fn migrate_marks_version_1_labels() {
+        let mut config = table(
+            r#"
+            [default]
+            layout_name = "English"
+            [[keyboards]]
+            name = "Logitech"
+            layout_index = 1
+            layout_name = "German"
+            [[keyboards]]
+            name = "Lofree"
+            layout_index = 2
+            "#,
+        );
+        let changes = migrate(&mut config).unwrap();
+        assert_eq!(changes.len(), 2);
+        let keyboards = config["keyboards"].as_array().unwrap();
+        assert_eq!(keyboards[0]["layout_name"].as_str(), Some("German"));
+        assert_eq!(keyboards[0]["legacy_layout_name"].as_bool(), Some(true));
+        assert_eq!(keyboards[0]["layout_index"].as_integer(), Some(1));
+        assert!(keyboards[1].get("legacy_layout_name").is_none());
+        assert_eq!(config["default"]["legacy_layout_name"].as_bool(), Some(true));
+        assert_eq!(config["version"].as_integer(), Some(CONFIG_VERSION as i64));
+    }
+
+    #[test]
+    fn migrate_leaves_current_version_alone() {
+        let text = "version = 2\n[[keyboards]]\nname = \"Logitech\"\nlayout_name = \"German\"\n";
+        let mut config = table(text);
+        assert!(migrate(&mut config).unwrap().is_empty());
+        assert_eq!(config, table(text));
+    }
+
+    #[test]
+    fn migrate_adds_not_name_to_exclude() {
+        let mut config = table(
+            r#"
+            version = 2
+            [[keyboards]]
+            name = "Logitech"
+            exclude = ["Mouse"]
+            not_name = "Consumer Control"
+            [[keyboards]]
+            name = "Lofree"
+            not_name = ["Receiver"]
+            "#,
+        );
+        assert!(migrate(&mut config).unwrap().is_empty());
+        let keyboards = config["keyboards"].as_array().unwrap();
+        assert_eq!(keyboards[0]["exclude"], toml::Value::from(vec!["Mouse", "Consumer Control"]));
+        assert_eq!(keyboards[1]["exclude"], toml::Value::from(vec!["Receiver"]));
+        assert!(keyboards[0].get("not_name").is_none());
+    }
+
+    #[test]
+    fn migrate_refuses_unknown_versions() {
+        for version in ["0", "-1", "3", "4294967298", "\"2\""] {
+            let mut config = table(&format!("version = {}", version));
+            assert!(migrate(&mut config).is_err(), "version = {}", version);
+        }
+    }
+}
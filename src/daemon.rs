@@ -0,0 +1,2910 @@
+use evdev::{
+    uinput::VirtualDeviceBuilder, AttributeSet, BusType, Device, EventType, InputEvent,
+    InputEventKind, InputId, Key, MiscType, RelativeAxisType,
+};
+use std::collections::HashMap;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, LazyLock, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{debug, error, info, warn};
+use zbus::fdo::{RequestNameFlags, RequestNameReply};
+use zbus::zvariant::Value;
+use zbus::{blocking::Connection, interface, SignalContext};
+
+use crate::action::{self, ActionCall};
+use crate::affinity;
+use crate::capture;
+use crate::clock::{self, Clock};
+use crate::backend::{self, Backend, LayoutSwitcher};
+use crate::config::{self, Config, Injection, KeyboardConfig, KeyboardMode};
+use crate::events::{
+    drop_corrupted, retain_passthrough, Activity, BurstDetector, Hotkey, LockState, Modifiers, PressedKeys,
+    RateLimiter, Remap, RemapRule,
+};
+use crate::filter::{DeviceInfo, EventFilter, FilterChain};
+use crate::inspect;
+#[cfg(feature = "kde")]
+use crate::kxkb;
+use crate::layouts;
+use crate::learn;
+use crate::notify;
+use crate::monitor_state::{Action, Input, MonitorState};
+#[cfg(feature = "hotplug")]
+use crate::hotplug;
+#[cfg(feature = "peer")]
+use crate::peer;
+use crate::osk;
+use crate::output::{Discard, EventSink, OutputQueue, QueueMetrics, DROPPED_EVENTS};
+use crate::preflight;
+use crate::reload;
+use crate::remap;
+use crate::session;
+use crate::state;
+use crate::sound;
+use crate::switcher::{self, SwitchQueue};
+
+// Mode: true = Grab (correct first key), false = Passive (zero latency)
+static GRAB_MODE: AtomicBool = AtomicBool::new(true);
+pub(crate) static CURRENT_LAYOUT: AtomicU32 = AtomicU32::new(0);
+// Layout changes, for the CurrentLayout D-Bus properties
+static LAYOUT_CHANGES: LazyLock<watch::Sender<u32>> = LazyLock::new(|| watch::Sender::new(0));
+// Keyboard that produced the most recent key press (undebounced)
+static LAST_ACTIVE_KEYBOARD: Mutex<String> = Mutex::new(String::new());
+// Event node of that keyboard, which unlike the name tells identical
+// keyboards apart
+static LAST_ACTIVE_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+// Active keyboard after debouncing, as reported by ContextChanged
+static ACTIVE_KEYBOARD: Mutex<String> = Mutex::new(String::new());
+// Modifiers held on each monitored keyboard; the global state is their union
+static DEVICE_MODIFIERS: Mutex<Option<HashMap<PathBuf, Modifiers>>> = Mutex::new(None);
+// An on-screen keyboard is the active input; physical keys don't switch
+static OSK_ACTIVE: AtomicBool = AtomicBool::new(false);
+// Why grabbing is suspended (e.g. an input capture session), empty when not
+static GRAB_SUSPENDED: Mutex<String> = Mutex::new(String::new());
+// Set when grabbing can't work at all in this environment, see
+// preflight::passive_only; nothing is grabbed then, locked keyboards neither
+static PASSIVE_ONLY: OnceLock<String> = OnceLock::new();
+// Keyboard entries (by config name) locked over D-Bus: grabbed whatever the
+// mode, with only their passthrough_keys reaching the system
+static LOCKED_KEYBOARDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// Lock changes, for the LockedKeyboards D-Bus property
+static LOCK_CHANGES: LazyLock<watch::Sender<()>> = LazyLock::new(|| watch::Sender::new(()));
+// Health of each monitored keyboard as (device, status), for ListKeyboards
+static KEYBOARD_STATUS: Mutex<Option<HashMap<PathBuf, (DeviceInfo, String)>>> = Mutex::new(None);
+// Health of hot-plug detection: "ok", "disabled" or what went wrong
+static HOTPLUG_STATUS: Mutex<String> = Mutex::new(String::new());
+// Config whose [[keyboards]] entries devices are matched against; replaced
+// when the config file changes
+static LIVE_CONFIG: Mutex<Option<Arc<Config>>> = Mutex::new(None);
+// Running keyboard monitors, to notice when the last keyboard is gone
+static LIVE_MONITORS: AtomicUsize = AtomicUsize::new(0);
+
+// Health of one keyboard monitor, for DebugMetrics
+struct MonitorMetrics {
+    name: String,
+    // Event node, which tells identical keyboards apart; it changes when
+    // the keyboard comes back on another node
+    path: Mutex<PathBuf>,
+    // Unix time in ms the monitor loop last went round, at least every
+    // IDLE_WAKEUP; an older value means the monitor is stuck
+    last_iteration_ms: AtomicU64,
+    output: Arc<QueueMetrics>,
+}
+
+static MONITOR_METRICS: Mutex<Vec<Arc<MonitorMetrics>>> = Mutex::new(Vec::new());
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+// Keyboard currently holding the layout (see KeyboardConfig::hold_ms)
+struct LayoutHold {
+    holder: PathBuf,
+    until: Instant,
+    // Presses from other keyboards during the hold, towards sticky_threshold
+    intruding_presses: u32,
+}
+
+static LAYOUT_HOLD: Mutex<Option<LayoutHold>> = Mutex::new(None);
+
+// When the last layout switch was submitted or made, for switch_debounce_ms
+// and GetStatus
+static LAST_SWITCH: Mutex<Option<Instant>> = Mutex::new(None);
+
+// Track active keyboard monitors for hot-plug support
+pub(crate) struct KeyboardMonitor {
+    handle: JoinHandle<()>,
+    #[cfg_attr(not(feature = "hotplug"), allow(dead_code))]
+    shutdown_tx: watch::Sender<bool>,
+    // Device name and new event node for a keyboard that reconnected
+    // within the grace period
+    name: String,
+    reattach_tx: std::sync::mpsc::Sender<PathBuf>,
+    // Changed config entry to apply without setting the monitor up again
+    update_tx: std::sync::mpsc::Sender<KeyboardConfig>,
+    // Physical device identity, see device_identity
+    identity: Option<String>,
+    // Entry the keyboard was matched with, to notice config changes
+    kb_config: KeyboardConfig,
+    // What the monitor is doing, for ListDevices
+    view: Arc<MonitorView>,
+}
+
+// State a monitor publishes for ListDevices
+struct MonitorView {
+    state: Mutex<MonitorState>,
+    layout_index: AtomicU32,
+}
+
+impl MonitorView {
+    fn publish(&self, state: MonitorState, layout_index: u32) {
+        *self.state.lock().unwrap() = state;
+        self.layout_index.store(layout_index, Ordering::Relaxed);
+    }
+}
+
+pub(crate) type ActiveMonitors = Arc<std::sync::Mutex<HashMap<PathBuf, KeyboardMonitor>>>;
+
+// Handles shared by every keyboard monitor
+#[derive(Clone)]
+pub(crate) struct MonitorHandles {
+    config: Arc<Config>,
+    context_tx: mpsc::UnboundedSender<(String, PathBuf)>,
+    switcher: Arc<SwitchQueue>,
+    filters: Arc<FilterChain>,
+    modifiers_tx: watch::Sender<Modifiers>,
+    action_tx: mpsc::UnboundedSender<ActionCall>,
+    connected_tx: mpsc::UnboundedSender<ConnectSummary>,
+}
+
+// How a keyboard was set up when it connected, for the log and the
+// KeyboardConnected signal
+struct ConnectSummary {
+    device: String,
+    path: PathBuf,
+    // Stable device ID, see inspect::device_id
+    id: String,
+    rule: String,
+    layout_index: u32,
+    layout: String,
+    mode: &'static str,
+    // Per-keyboard options that differ from the defaults, as "key=value"
+    options: Vec<String>,
+}
+
+impl ConnectSummary {
+    fn new(device: &DeviceInfo, kb: &KeyboardConfig, mode: &'static str, mirrored: bool) -> Self {
+        let mut options = Vec::new();
+        if kb.hold_ms > 0 {
+            options.push(format!("hold_ms={}", kb.hold_ms));
+        }
+        if kb.activity_on != Activity::default() {
+            let activity = kb.activity_on;
+            let values: Vec<&str> = [("press", activity.press), ("repeat", activity.repeat), ("release", activity.release)]
+                .into_iter()
+                .filter(|(_, on)| *on)
+                .map(|(name, _)| name)
+                .collect();
+            options.push(format!("activity_on={}", values.join(",")));
+        }
+        if let Some(action) = &kb.dbus_action {
+            options.push(format!("dbus_action={}.{}", action.interface, action.method));
+        }
+        if let (Some(mirror), true) = (&kb.mirror, mirrored) {
+            options.push(format!("mirror={}", mirror));
+        }
+        if !kb.passthrough_keys.is_empty() {
+            options.push(format!("passthrough_keys={}", kb.passthrough_keys.join(",")));
+        }
+        if kb.max_events_per_sec > 0 {
+            options.push(format!("max_events_per_sec={}", kb.max_events_per_sec));
+        }
+        if kb.lock_on_flood {
+            options.push("lock_on_flood".to_string());
+        }
+        if kb.assert_layout_on_start {
+            options.push("assert_layout_on_start".to_string());
+        }
+        if let Some(engine) = &kb.engine {
+            options.push(format!("engine={}", engine));
+        }
+        if kb.burst_keys > 1 {
+            options.push(format!("burst_keys={}/{}ms", kb.burst_keys, kb.burst_window_ms));
+        }
+        if kb.switch_delay_ms > 0 {
+            options.push(format!("switch_delay_ms={}", kb.switch_delay_ms));
+        }
+        if !kb.remap.is_empty() {
+            options.push(format!("remap={}", kb.remap.join(",")));
+        }
+        // The entry's device rules, after its name
+        let mut rule = kb.name.clone();
+        for (key, value) in [("id", &kb.id), ("by_id", &kb.by_id), ("phys", &kb.phys), ("uniq", &kb.uniq)] {
+            if let Some(value) = value {
+                rule.push_str(&format!(" {}={}", key, value));
+            }
+        }
+        ConnectSummary {
+            device: device.name.clone(),
+            path: device.path.clone(),
+            id: device.id.clone(),
+            rule,
+            layout_index: layouts::index_for(kb),
+            layout: layout_label(layouts::index_for(kb), &kb.layout_name),
+            mode,
+            options,
+        }
+    }
+
+    fn log(&self) {
+        info!(
+            "Connected '{}' at {:?} (id {}): rule '{}', layout {} ({}), {} mode, options: {}",
+            self.device,
+            self.path,
+            self.id,
+            self.rule,
+            self.layout_index,
+            self.layout,
+            self.mode,
+            if self.options.is_empty() { "none".to_string() } else { self.options.join(" ") }
+        );
+    }
+
+    // Signal payload: device, path, id, rule, layout_index, layout, mode, options
+    fn to_dict(&self) -> HashMap<&'static str, Value<'static>> {
+        HashMap::from([
+            ("device", Value::from(self.device.clone())),
+            ("path", Value::from(self.path.to_string_lossy().into_owned())),
+            ("id", Value::from(self.id.clone())),
+            ("rule", Value::from(self.rule.clone())),
+            ("layout_index", Value::from(self.layout_index)),
+            ("layout", Value::from(self.layout.clone())),
+            ("mode", Value::from(self.mode)),
+            ("options", Value::from(self.options.clone())),
+        ])
+    }
+}
+
+
+// Check if a device matches any configured keyboard: the matching entry
+// with the highest priority, the first in file order among equals
+pub(crate) fn match_keyboard_config<'a>(
+    device: &Device,
+    path: &Path,
+    config: &'a Config,
+) -> Option<&'a KeyboardConfig> {
+    preferred_entry(&matching_keyboard_configs(device, path, config))
+}
+
+pub(crate) fn preferred_entry<'a>(entries: &[&'a KeyboardConfig]) -> Option<&'a KeyboardConfig> {
+    entries.iter().copied().reduce(|best, kb| if kb.priority > best.priority { kb } else { best })
+}
+
+// All entries matching a device, in file order
+pub(crate) fn matching_keyboard_configs<'a>(device: &Device, path: &Path, config: &'a Config) -> Vec<&'a KeyboardConfig> {
+    let name = device.name().unwrap_or("Unknown");
+
+    if !device.supported_events().contains(EventType::KEY) || is_own_device(device) {
+        return Vec::new();
+    }
+
+    if is_ignored(device, config) {
+        return Vec::new();
+    }
+    let name = name.to_lowercase();
+    let contains_any = |patterns: &[String]| patterns.iter().any(|p| name.contains(&p.to_lowercase()));
+    let id = inspect::device_id(device);
+    let phys = device.physical_path().unwrap_or("").to_lowercase();
+    let uniq = device.unique_name().unwrap_or("");
+    let matches = |kb: &KeyboardConfig| {
+        (kb.is_catch_all() || name.contains(&kb.name.to_lowercase()))
+            && !contains_any(&kb.exclude)
+            && kb.id.as_ref().is_none_or(|want| want.eq_ignore_ascii_case(&id))
+            && kb.by_id.as_ref().is_none_or(|link| inspect::resolve_by_id(link).is_some_and(|node| node == path))
+            && kb.phys.as_ref().is_none_or(|want| phys.starts_with(&want.to_lowercase()))
+            && kb.uniq.as_ref().is_none_or(|want| want.eq_ignore_ascii_case(uniq))
+            && has_capabilities(device, kb)
+    };
+    let found = config.keyboards.iter().filter(|kb| matches(kb)).collect();
+    let default = config.default.as_ref().filter(|default| matches(default));
+    select_entries(found, default, is_physical_keyboard(device))
+}
+
+// Which of the entries matching a device apply, in order of precedence:
+// specific entries; catch-all entries, for a physical keyboard no specific
+// entry matches; [default], for a physical keyboard no entry matches at all.
+// So a catch-all that matches a keyboard leaves [default] nothing.
+fn select_entries<'a>(
+    mut found: Vec<&'a KeyboardConfig>,
+    default: Option<&'a KeyboardConfig>,
+    physical: bool,
+) -> Vec<&'a KeyboardConfig> {
+    if found.iter().any(|kb| !kb.is_catch_all()) || !physical {
+        found.retain(|kb| !kb.is_catch_all());
+    }
+    match default {
+        Some(default) if found.is_empty() && physical => vec![default],
+        _ => found,
+    }
+}
+
+// The entry's require_keys and reject_rel; unknown key names never match,
+// so a typo doesn't widen the entry
+fn has_capabilities(device: &Device, kb: &KeyboardConfig) -> bool {
+    let keys = device.supported_keys();
+    kb.require_keys
+        .iter()
+        .all(|key| key.parse::<Key>().is_ok_and(|key| keys.is_some_and(|keys| keys.contains(key))))
+        && !(kb.reject_rel && device.supported_relative_axes().is_some_and(|axes| axes.iter().next().is_some()))
+}
+
+/// Whether the top-level `ignore` list names the device, which is then
+/// never taken, listed or learned.
+pub(crate) fn is_ignored(device: &Device, config: &Config) -> bool {
+    ignores(config, device.name().unwrap_or("Unknown"))
+}
+
+/// Like `is_ignored`, before opening the event node: by the name sysfs
+/// gives for it, so an ignored device (one that hangs when opened, say) is
+/// never opened. False where sysfs doesn't know the node.
+pub(crate) fn is_ignored_node(path: &Path, config: &Config) -> bool {
+    !config.ignore.is_empty() && sysfs_name(path).is_some_and(|name| ignores(config, &name))
+}
+
+// Device name from /sys/class/input/eventN/device/name
+fn sysfs_name(path: &Path) -> Option<String> {
+    let node = Path::new("/sys/class/input").join(path.file_name()?);
+    let name = std::fs::read_to_string(node.join("device/name")).ok()?;
+    Some(name.trim_end().to_string())
+}
+
+fn ignores(config: &Config, name: &str) -> bool {
+    let name = name.to_lowercase();
+    config.ignore.iter().any(|p| name.contains(&p.to_lowercase()))
+}
+
+// One of this daemon's virtual keyboards, which are named after the
+// keyboards they forward and would otherwise match their entries
+fn is_own_device(device: &Device) -> bool {
+    let id = device.input_id();
+    id.bus_type() == BusType::BUS_VIRTUAL && id.vendor() == VIRTUAL_VENDOR
+}
+
+// A keyboard the [default] entry takes on: letter keys and a space bar, and
+// not a virtual device (ours, or another remapper's)
+pub(crate) fn is_physical_keyboard(device: &Device) -> bool {
+    let typing = [Key::KEY_A, Key::KEY_Z, Key::KEY_SPACE];
+    device.input_id().bus_type() != BusType::BUS_VIRTUAL
+        && device.supported_keys().is_some_and(|keys| typing.iter().all(|key| keys.contains(*key)))
+}
+
+// Warn about a device several entries match, naming the one it gets
+fn warn_ambiguous_match(device: &str, path: &Path, entries: &[&KeyboardConfig]) {
+    let Some(chosen) = preferred_entry(entries) else {
+        return;
+    };
+    if entries.len() < 2 {
+        return;
+    }
+    let listed: Vec<String> = entries
+        .iter()
+        .map(|kb| format!("'{}' (priority {})", kb.name, kb.priority))
+        .collect();
+    let tied = entries.iter().filter(|kb| kb.priority == chosen.priority).count() > 1;
+    warn!(
+        "'{}' at {:?} matches {} [[keyboards]] entries: {}; using '{}'{}",
+        device,
+        path,
+        entries.len(),
+        listed.join(", "),
+        chosen.name,
+        if tied { " (first in file order, set `priority` to choose)" } else { "" }
+    );
+}
+
+// Connected keyboard whose layout is set at startup: the primary keyboard,
+// else the first entry asking for it with assert_layout_on_start
+fn startup_keyboard<'a>(
+    config: &'a Config,
+    connected: &HashMap<PathBuf, (String, KeyboardConfig)>,
+) -> Option<&'a KeyboardConfig> {
+    let is_connected = |kb: &KeyboardConfig| connected.values().any(|(_, c)| c.same_keyboard(kb));
+    let primary = config.primary_keyboard.as_deref();
+    config
+        .keyboards
+        .iter()
+        .find(|kb| Some(kb.name.as_str()) == primary && is_connected(kb))
+        .or_else(|| {
+            config
+                .keyboards
+                .iter()
+                .find(|kb| kb.assert_layout_on_start && is_connected(kb))
+        })
+}
+
+// Probe event nodes in parallel: opening one can take a while (gaming gear
+// exposes dozens, some slow to answer), and a node that hangs is given up
+// on after SCAN_OPEN_TIMEOUT instead of stalling startup. `warn_ambiguous`
+// reports devices several entries match
+fn find_keyboards(config: &Arc<Config>, warn_ambiguous: bool) -> HashMap<PathBuf, (String, KeyboardConfig)> {
+    let mut keyboards = HashMap::new();
+    let mut pending: Vec<PathBuf> = std::fs::read_dir("/dev/input")
+        .unwrap()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.to_string_lossy().contains("event") && !is_ignored_node(path, config))
+        .collect();
+    let parallelism = thread::available_parallelism().map_or(4, |n| n.get()).min(SCAN_PARALLELISM);
+
+    let (result_tx, result_rx) = std::sync::mpsc::channel();
+    let mut in_flight: HashMap<PathBuf, Instant> = HashMap::new();
+    loop {
+        while in_flight.len() < parallelism {
+            let Some(path) = pending.pop() else {
+                break;
+            };
+            in_flight.insert(path.clone(), Instant::now() + SCAN_OPEN_TIMEOUT);
+            let config = Arc::clone(config);
+            let result_tx = result_tx.clone();
+            thread::spawn(move || {
+                let found = Device::open(&path).ok().and_then(|device| {
+                    let name = device.name().unwrap_or("Unknown").to_string();
+                    let entries = matching_keyboard_configs(&device, &path, &config);
+                    if warn_ambiguous {
+                        warn_ambiguous_match(&name, &path, &entries);
+                    }
+                    Some((name, preferred_entry(&entries)?.clone()))
+                });
+                let _ = result_tx.send((path, found));
+            });
+        }
+        let Some(deadline) = in_flight.values().min().copied() else {
+            break;
+        };
+
+        match result_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((path, found)) => {
+                // Results of nodes already given up on are dropped
+                if in_flight.remove(&path).is_some() {
+                    if let Some(found) = found {
+                        keyboards.insert(path, found);
+                    }
+                }
+            }
+            Err(_) => {
+                let now = Instant::now();
+                in_flight.retain(|path, deadline| {
+                    let waiting = *deadline > now;
+                    if !waiting {
+                        warn!("Opening {:?} timed out, skipping it", path);
+                    }
+                    waiting
+                });
+            }
+        }
+    }
+
+    keyboards
+}
+
+fn set_current_layout(layout_index: u32) {
+    CURRENT_LAYOUT.store(layout_index, Ordering::SeqCst);
+    LAYOUT_CHANGES.send_replace(layout_index);
+}
+
+// Display name of a layout, falling back to the configured layout_name
+fn layout_label(layout_index: u32, configured: &str) -> String {
+    layouts::display_name(layout_index).unwrap_or_else(|| configured.to_string())
+}
+
+pub(crate) fn switch_layout(conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+    backend::switch(conn, layout_index)?;
+    set_current_layout(layout_index);
+    *LAST_SWITCH.lock().unwrap() = Some(clock::now());
+    Ok(())
+}
+
+fn get_current_layout(conn: &Connection) -> Result<u32, zbus::Error> {
+    backend::current(conn)
+}
+
+// Configured layouts as (short name, variant, long name), in index order
+pub(crate) fn get_layouts_list(conn: &Connection) -> Result<Vec<(String, String, String)>, zbus::Error> {
+    backend::layouts(conn)
+}
+
+// Layout changes pushed by the backend (KDE's layoutChanged signal). Each
+// report bumps the generation, so a switch only accepts reports that arrive
+// after its own request: a late report from a superseded switch to the same
+// index, or a stale read of the old layout, can't confirm it.
+struct LayoutReports {
+    generation: u64,
+    layout: u32,
+    // The backend pushes reports; otherwise switches poll the layout
+    live: bool,
+}
+
+static LAYOUT_REPORTS: Mutex<LayoutReports> = Mutex::new(LayoutReports {
+    generation: 0,
+    layout: 0,
+    live: false,
+});
+static LAYOUT_REPORTED: Condvar = Condvar::new();
+
+// Switch confirmation timeout and poll interval (confirm_timeout_ms,
+// confirm_poll_us), set once at startup
+static CONFIRM_TIMING: OnceLock<(Duration, Duration)> = OnceLock::new();
+
+// Device probing at startup and on rescans
+const SCAN_PARALLELISM: usize = 8;
+const SCAN_OPEN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Take the switch confirmation timing from the config; only the first
+/// call counts.
+pub(crate) fn set_confirm_timing(config: &Config) {
+    let timeout = Duration::from_millis(config.confirm_timeout_ms);
+    let poll = Duration::from_micros(config.confirm_poll_us.max(1));
+    let _ = CONFIRM_TIMING.set((timeout, poll));
+}
+
+fn confirm_timing() -> (Duration, Duration) {
+    *CONFIRM_TIMING.get_or_init(|| {
+        (
+            Duration::from_millis(config::default_confirm_timeout_ms()),
+            Duration::from_micros(config::default_confirm_poll_us()),
+        )
+    })
+}
+
+/// How often a backend without change reports is asked for the layout
+/// while a switch waits for confirmation.
+pub(crate) fn confirm_poll_interval() -> Duration {
+    confirm_timing().1
+}
+
+/// Record whether the backend currently pushes layout changes; while it
+/// doesn't, switches are confirmed by polling.
+#[cfg_attr(not(feature = "kde"), allow(dead_code))]
+pub(crate) fn set_layout_reports_live(live: bool) {
+    LAYOUT_REPORTS.lock().unwrap().live = live;
+}
+
+/// Record a layout change reported by the backend, whoever caused it.
+#[cfg_attr(not(feature = "kde"), allow(dead_code))]
+pub(crate) fn report_layout(layout_index: u32) {
+    {
+        let mut reports = LAYOUT_REPORTS.lock().unwrap();
+        reports.generation += 1;
+        reports.layout = layout_index;
+    }
+    LAYOUT_REPORTED.notify_all();
+    set_current_layout(layout_index);
+}
+
+/// Switch layout and wait for the backend to confirm the change, with a
+/// timeout. Backends that push changes confirm through reports newer than
+/// the request; the others are polled.
+pub(crate) fn switch_layout_confirmed(conn: &Connection, layout_index: u32) -> Result<(), zbus::Error> {
+    let (live, generation) = {
+        let reports = LAYOUT_REPORTS.lock().unwrap();
+        (reports.live, reports.generation)
+    };
+    switch_layout(conn, layout_index)?;
+
+    let deadline = clock::now() + confirm_timing().0;
+    if live {
+        let mut reports = LAYOUT_REPORTS.lock().unwrap();
+        loop {
+            if reports.generation > generation && reports.layout == layout_index {
+                return Ok(());
+            }
+            let slice = clock::wait_slice(deadline);
+            if slice.is_zero() {
+                break;
+            }
+            reports = LAYOUT_REPORTED.wait_timeout(reports, slice).unwrap().0;
+        }
+    } else if backend::confirm(conn, layout_index, deadline) {
+        return Ok(());
+    }
+
+    // Timeout reached - proceed anyway, layout was set
+    warn!("Layout switch confirmation timeout - proceeding");
+    Ok(())
+}
+
+// Longest a monitor waits for input before looking at shutdown requests and
+// mode changes again
+const IDLE_WAKEUP: Duration = Duration::from_millis(500);
+
+// Backoff between attempts to create a virtual keyboard
+const VK_RETRY_MIN: Duration = Duration::from_secs(1);
+const VK_RETRY_MAX: Duration = Duration::from_secs(60);
+
+// Vendor id of virtual keyboards ("KL"); the product id is the source index
+pub(crate) const VIRTUAL_VENDOR: u16 = 0x4b4c;
+// Longest device name uinput takes, leaving room for the terminating NUL
+const VIRTUAL_NAME_MAX: usize = 78;
+// Highest EV_MSC code the kernel accepts (MSC_MAX)
+const MSC_MAX: u16 = 0x07;
+
+/// Parse the `source_tag` option into an EV_MSC code. MSC_SCAN is refused
+/// since forwarded scan codes already use it.
+pub(crate) fn parse_source_tag(tag: &str) -> Option<MiscType> {
+    let code = match tag.to_ascii_uppercase().as_str() {
+        "MSC_SERIAL" => MiscType::MSC_SERIAL,
+        "MSC_PULSELED" => MiscType::MSC_PULSELED,
+        "MSC_GESTURE" => MiscType::MSC_GESTURE,
+        "MSC_RAW" => MiscType::MSC_RAW,
+        "MSC_TIMESTAMP" => MiscType::MSC_TIMESTAMP,
+        other => MiscType(other.parse::<u16>().ok()?),
+    };
+    if code == MiscType::MSC_SCAN || code.0 > MSC_MAX {
+        return None;
+    }
+    Some(code)
+}
+
+/// The `virtual_name` template filled in for the keyboard `original`.
+pub(crate) fn virtual_name(template: &str, original: &str) -> String {
+    template.replace("{original}", original)
+}
+
+fn create_virtual_keyboard(
+    name: &str,
+    source_index: u16,
+    source_tag: Option<MiscType>,
+    pointer: bool,
+) -> Result<evdev::uinput::VirtualDevice, std::io::Error> {
+    let mut keys = AttributeSet::<Key>::new();
+    // Include all possible key codes (KEY_MAX is typically 767)
+    for i in 0..768u16 {
+        keys.insert(Key::new(i));
+    }
+
+    // Add MSC types (for scan codes)
+    let mut misc = AttributeSet::<MiscType>::new();
+    misc.insert(MiscType::MSC_SCAN);
+    if let Some(tag) = source_tag {
+        misc.insert(tag);
+    }
+
+    // Longer names would be refused by uinput (and panic in evdev)
+    let mut end = name.len().min(VIRTUAL_NAME_MAX);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut builder = VirtualDeviceBuilder::new()?
+        .name(&name[..end])
+        // uinput can't set uniq, so the stable per-source identifier lives
+        // in the input id: vendor 0x4b4c, product = keyboard entry index
+        .input_id(InputId::new(BusType::BUS_VIRTUAL, VIRTUAL_VENDOR, source_index, 1))
+        .with_keys(&keys)?
+        .with_msc(&misc)?;
+
+    // Add relative axes (for keyboards with trackpads/scroll). Plain
+    // keyboards skip them, otherwise desktops and games see a phantom mouse
+    if pointer {
+        let mut rel = AttributeSet::<RelativeAxisType>::new();
+        rel.insert(RelativeAxisType::REL_X);
+        rel.insert(RelativeAxisType::REL_Y);
+        rel.insert(RelativeAxisType::REL_WHEEL);
+        rel.insert(RelativeAxisType::REL_HWHEEL);
+        rel.insert(RelativeAxisType::REL_WHEEL_HI_RES);
+        rel.insert(RelativeAxisType::REL_HWHEEL_HI_RES);
+        builder = builder.with_relative_axes(&rel)?;
+    }
+
+    builder.build()
+}
+
+// Where grab mode forwards a keyboard's events: its own uinput or Wayland
+// virtual keyboard, or the RemoteDesktop portal session all keyboards share
+fn create_output(
+    config: &Config,
+    name: &str,
+    source_index: u16,
+    source_tag: Option<MiscType>,
+    pointer: bool,
+) -> Result<Box<dyn EventSink>, std::io::Error> {
+    if PASSIVE_ONLY.get().is_some() {
+        return Ok(Box::new(Discard));
+    }
+    match config.injection {
+        Injection::Uinput => Ok(Box::new(create_virtual_keyboard(
+            &virtual_name(&config.virtual_name, name),
+            source_index,
+            source_tag,
+            pointer,
+        )?)),
+        #[cfg(feature = "portal")]
+        Injection::Portal => Ok(Box::new(crate::portal::sink()?)),
+        #[cfg(feature = "wayland")]
+        Injection::Wayland => Ok(Box::new(crate::wayland::sink()?)),
+        #[allow(unreachable_patterns)]
+        injection => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("{} injection is not in this build", injection.name()),
+        )),
+    }
+}
+
+const BUS_NAME: &str = "org.kblayout.Daemon";
+
+// Own the daemon's bus name, refusing to start when another instance has
+// it unless `replace` takes it over. The name may in turn be taken from
+// this instance, which then exits the way it does on SIGTERM.
+fn claim_bus_name(replace: bool) -> Result<Connection, String> {
+    let conn = Connection::session().map_err(|e| {
+        let problem = preflight::bus_problem("session", &e);
+        format!("{}; {}", problem.what, problem.fix)
+    })?;
+    let mut flags = RequestNameFlags::AllowReplacement | RequestNameFlags::DoNotQueue;
+    if replace {
+        flags |= RequestNameFlags::ReplaceExisting;
+    }
+    match conn.request_name_with_flags(BUS_NAME, flags) {
+        Ok(RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner) => {}
+        Ok(_) if replace => {
+            return Err(format!(
+                "another instance owns {} and doesn't allow being replaced; stop it first",
+                BUS_NAME
+            ))
+        }
+        Ok(_) => {
+            return Err(format!(
+                "kb-layout-daemon is already running ({} is taken); stop it, or start with --replace to take over",
+                BUS_NAME
+            ))
+        }
+        Err(e) => return Err(format!("cannot claim {}: {}", BUS_NAME, e)),
+    }
+    if replace {
+        info!("Took over {} from the running instance", BUS_NAME);
+    }
+
+    let lost = zbus::blocking::fdo::DBusProxy::new(&conn)
+        .and_then(|proxy| proxy.receive_name_lost())
+        .map_err(|e| format!("cannot watch {}: {}", BUS_NAME, e))?;
+    thread::spawn(move || {
+        for signal in lost {
+            if signal.args().is_ok_and(|args| args.name() == BUS_NAME) {
+                info!("Replaced by a new instance, exiting");
+                unsafe { libc::raise(libc::SIGTERM) };
+            }
+        }
+    });
+    Ok(conn)
+}
+
+// Build metadata exposed over D-Bus for bug reports
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_HASH: &str = env!("KB_LAYOUT_DAEMON_GIT_HASH");
+
+// D-Bus interface for controlling the daemon
+struct DaemonControl {
+    started: std::time::Instant,
+    monitors: ActiveMonitors,
+}
+
+#[interface(name = "org.kblayout.Daemon")]
+impl DaemonControl {
+    fn get_mode(&self) -> &str {
+        if GRAB_MODE.load(Ordering::SeqCst) {
+            "grab"
+        } else {
+            "passive"
+        }
+    }
+
+    fn set_mode(&self, mode: &str) -> bool {
+        match mode.to_lowercase().as_str() {
+            "passive" => {
+                GRAB_MODE.store(false, Ordering::SeqCst);
+                info!("Mode set to: passive (zero latency, first key may be wrong)");
+                state::set_mode("passive");
+                true
+            }
+            "grab" => {
+                GRAB_MODE.store(true, Ordering::SeqCst);
+                info!("Mode set to: grab (correct first key)");
+                state::set_mode("grab");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn toggle_mode(&self) -> &str {
+        let was_grab = GRAB_MODE.fetch_xor(true, Ordering::SeqCst);
+        let mode = if was_grab { "passive" } else { "grab" };
+        info!("Mode toggled to: {}", mode);
+        state::set_mode(mode);
+        mode
+    }
+
+    /// Lock or unlock a configured keyboard (by its `name` entry). A locked
+    /// keyboard is grabbed in either mode and only its `passthrough_keys`
+    /// reach the system. Returns false for unknown keyboards, and when
+    /// nothing can be grabbed (see GrabSuspended).
+    fn set_keyboard_locked(&self, keyboard: &str, locked: bool) -> bool {
+        if PASSIVE_ONLY.get().is_some() || !current_config().keyboards.iter().any(|kb| kb.name == keyboard) {
+            return false;
+        }
+        set_locked(keyboard, locked);
+        true
+    }
+
+    /// Wait for a key press on a keyboard without a `[[keyboards]]` entry
+    /// and return a proposed entry for it as TOML, with the current layout;
+    /// `append` also writes it to a file in config.d. Fails when no such key
+    /// press comes within 20 seconds.
+    async fn start_learning(&self, append: bool) -> zbus::fdo::Result<String> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(learn::learn(append));
+        });
+        match rx.await {
+            Ok(result) => result.map_err(zbus::fdo::Error::Failed),
+            Err(_) => Err(zbus::fdo::Error::Failed("learning stopped".to_string())),
+        }
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn version(&self) -> &str {
+        VERSION
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn git_hash(&self) -> &str {
+        GIT_HASH
+    }
+
+    /// Seconds since the daemon started
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn uptime(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// Layout switching backend in use
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn backend(&self) -> &str {
+        backend::active().name()
+    }
+
+    /// Name of the keyboard currently in use (debounced)
+    #[zbus(property)]
+    fn active_keyboard(&self) -> String {
+        ACTIVE_KEYBOARD.lock().unwrap().clone()
+    }
+
+    /// Events dropped because a virtual keyboard's output queue overflowed
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn dropped_events(&self) -> u64 {
+        DROPPED_EVENTS.load(Ordering::Relaxed)
+    }
+
+    /// Index of the current layout
+    #[zbus(property)]
+    fn current_layout(&self) -> u32 {
+        CURRENT_LAYOUT.load(Ordering::SeqCst)
+    }
+
+    /// Display name of the current layout, per `layout_display`
+    #[zbus(property)]
+    fn current_layout_name(&self) -> String {
+        let index = CURRENT_LAYOUT.load(Ordering::SeqCst);
+        layouts::display_name(index).unwrap_or_default()
+    }
+
+    /// Modifiers held across all monitored keyboards
+    #[zbus(property)]
+    fn modifiers(&self) -> Vec<&'static str> {
+        held_modifiers().names()
+    }
+
+    /// True while an on-screen keyboard suppresses layout switching
+    #[zbus(property)]
+    fn on_screen_keyboard_active(&self) -> bool {
+        OSK_ACTIVE.load(Ordering::SeqCst)
+    }
+
+    /// Why keyboards are passive despite grab mode, e.g. "input capture by
+    /// deskflow"; empty while grabbing isn't suspended
+    #[zbus(property)]
+    fn grab_suspended(&self) -> String {
+        match PASSIVE_ONLY.get() {
+            Some(reason) => format!("passive only: {}", reason),
+            None => GRAB_SUSPENDED.lock().unwrap().clone(),
+        }
+    }
+
+    /// Monitored keyboards as (name, event node, status, device ID); status
+    /// is "ok" or says what is wrong, e.g. a virtual keyboard that can't be
+    /// created. The device ID stays the same across reconnects
+    fn list_keyboards(&self) -> Vec<(String, String, String, String)> {
+        let guard = KEYBOARD_STATUS.lock().unwrap();
+        let mut keyboards: Vec<(String, String, String, String)> = guard
+            .iter()
+            .flatten()
+            .map(|(path, (device, status))| {
+                (device.name.clone(), path.display().to_string(), status.clone(), device.id.clone())
+            })
+            .collect();
+        keyboards.sort_by(|a, b| a.1.cmp(&b.1));
+        keyboards
+    }
+
+    /// Daemon health at a glance for status bars and scripts: uptime_secs,
+    /// mode, layout_index, layout, backend, devices (keyboards listed by
+    /// ListDevices), last_switch_ms (unix time of the daemon's last layout
+    /// switch, 0 before the first), hotplug ("ok", "disabled", or what went
+    /// wrong while hot-plug detection is being restarted), grab_suspended
+    /// (as the GrabSuspended property) and debug (the DebugMetrics entries)
+    fn get_status(&self) -> HashMap<&'static str, Value<'static>> {
+        let layout_index = CURRENT_LAYOUT.load(Ordering::SeqCst);
+        // Wall-clock time of the last switch, from the same record the
+        // debounce uses
+        let last_switch_ms = LAST_SWITCH.lock().unwrap().map_or(0, |last| {
+            let ago = clock::now().saturating_duration_since(last).as_millis() as u64;
+            unix_millis().saturating_sub(ago)
+        });
+        HashMap::from([
+            ("uptime_secs", Value::from(self.started.elapsed().as_secs())),
+            ("mode", Value::from(self.get_mode().to_string())),
+            ("layout_index", Value::from(layout_index)),
+            ("layout", Value::from(layouts::display_name(layout_index).unwrap_or_default())),
+            ("backend", Value::from(backend::active().name())),
+            ("devices", Value::from(self.list_devices().len() as u32)),
+            ("last_switch_ms", Value::from(last_switch_ms)),
+            ("hotplug", Value::from(HOTPLUG_STATUS.lock().unwrap().clone())),
+            ("grab_suspended", Value::from(self.grab_suspended())),
+            ("debug", Value::from(self.debug_metrics())),
+        ])
+    }
+
+    /// Keyboards the daemon handles, as (event node, device name, matching
+    /// entry or "[default]", layout index, layout name, grabbed, connected).
+    /// A keyboard that went away stays listed, not connected, while the
+    /// daemon waits for it to return
+    fn list_devices(&self) -> Vec<(String, String, String, u32, String, bool, bool)> {
+        let config = current_config();
+        let monitors = self.monitors.lock().unwrap();
+        let mut devices: Vec<_> = monitors
+            .iter()
+            .filter(|(_, m)| !m.handle.is_finished())
+            .map(|(path, m)| {
+                let entry = if config.keyboards.iter().any(|kb| kb.same_keyboard(&m.kb_config)) {
+                    m.kb_config.name.clone()
+                } else {
+                    "[default]".to_string()
+                };
+                let state = *m.view.state.lock().unwrap();
+                let layout_index = m.view.layout_index.load(Ordering::Relaxed);
+                (
+                    path.display().to_string(),
+                    m.name.clone(),
+                    entry,
+                    layout_index,
+                    layout_label(layout_index, &m.kb_config.layout_name),
+                    state.is_grabbed(),
+                    state != MonitorState::Disconnected,
+                )
+            })
+            .collect();
+        devices.sort_by(|a, b| a.0.cmp(&b.0));
+        devices
+    }
+
+    /// Per-monitor internals for diagnosing slowness in the field, as
+    /// (keyboard, event node, output queue depth, unix time in ms of the
+    /// monitor loop's last iteration, failed writes to the virtual keyboard)
+    fn debug_metrics(&self) -> Vec<(String, String, u32, u64, u64)> {
+        MONITOR_METRICS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| {
+                (
+                    m.name.clone(),
+                    m.path.lock().unwrap().display().to_string(),
+                    m.output.depth.load(Ordering::Relaxed) as u32,
+                    m.last_iteration_ms.load(Ordering::Relaxed),
+                    m.output.write_errors.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    /// KDE settings found at startup that fight the daemon's switches
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn kde_conflicts(&self) -> Vec<String> {
+        #[cfg(feature = "kde")]
+        return kxkb::conflicts();
+        #[cfg(not(feature = "kde"))]
+        Vec::new()
+    }
+
+    /// Keyboards locked with SetKeyboardLocked
+    #[zbus(property)]
+    fn locked_keyboards(&self) -> Vec<String> {
+        LOCKED_KEYBOARDS.lock().unwrap().clone()
+    }
+
+    /// Emitted when the active keyboard changes, after debouncing, with the
+    /// names and event paths of the old and the new one
+    #[zbus(signal)]
+    async fn context_changed(
+        ctxt: &SignalContext<'_>,
+        old: &str,
+        new: &str,
+        old_path: &str,
+        new_path: &str,
+    ) -> zbus::Result<()>;
+
+    /// Emitted once a keyboard is connected and set up, with how it was
+    /// set up (device, path, id, rule, layout_index, layout, mode, options)
+    #[zbus(signal)]
+    async fn keyboard_connected(
+        ctxt: &SignalContext<'_>,
+        summary: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<()>;
+}
+
+fn is_locked(keyboard: &str) -> bool {
+    LOCKED_KEYBOARDS.lock().unwrap().iter().any(|name| name == keyboard)
+}
+
+fn set_locked(keyboard: &str, locked: bool) {
+    {
+        let mut locked_keyboards = LOCKED_KEYBOARDS.lock().unwrap();
+        locked_keyboards.retain(|name| name != keyboard);
+        if locked {
+            locked_keyboards.push(keyboard.to_string());
+        }
+    }
+    info!("Keyboard '{}' {}", keyboard, if locked { "locked" } else { "unlocked" });
+    LOCK_CHANGES.send_replace(());
+}
+
+/// The config keyboards are matched against: the startup config, or the
+/// config file as last reloaded.
+pub(crate) fn current_config() -> Arc<Config> {
+    Arc::clone(LIVE_CONFIG.lock().unwrap().as_ref().expect("config set at startup"))
+}
+
+// Wait up to `timeout` for input on the device; true when there is some
+// (or an error for fetch_events to report)
+fn wait_readable(device: &Device, timeout: Duration) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: device.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as i32) != 0 }
+}
+
+fn set_current_config(config: Arc<Config>) {
+    *LIVE_CONFIG.lock().unwrap() = Some(config);
+}
+
+/// Record the health of hot-plug detection, for GetStatus.
+pub(crate) fn set_hotplug_status(status: &str) {
+    *HOTPLUG_STATUS.lock().unwrap() = status.to_string();
+}
+
+/// Name of the keyboard that produced the most recent key press.
+#[cfg(feature = "command")]
+pub(crate) fn last_active_keyboard() -> String {
+    LAST_ACTIVE_KEYBOARD.lock().unwrap().clone()
+}
+
+// Record a key press from a keyboard, notifying the context watcher if it
+// isn't the keyboard that was used last; identical keyboards differ by path
+fn note_activity(path: &Path, name: &str, context_tx: &mpsc::UnboundedSender<(String, PathBuf)>) {
+    let mut last_path = LAST_ACTIVE_PATH.lock().unwrap();
+    if last_path.as_deref() != Some(path) {
+        *last_path = Some(path.to_path_buf());
+        *LAST_ACTIVE_KEYBOARD.lock().unwrap() = name.to_string();
+        let _ = context_tx.send((name.to_string(), path.to_path_buf()));
+    }
+}
+
+// Run a context hook with the old/new keyboard names and event paths in its
+// environment
+async fn run_context_hook(hook: String, old: (String, PathBuf), new: (String, PathBuf)) {
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(&hook)
+        .env("KB_OLD_KEYBOARD", &old.0)
+        .env("KB_NEW_KEYBOARD", &new.0)
+        .env("KB_OLD_KEYBOARD_PATH", &old.1)
+        .env("KB_NEW_KEYBOARD_PATH", &new.1)
+        .status()
+        .await;
+
+    match status {
+        Ok(s) if s.success() => {}
+        Ok(s) => warn!("Context hook '{}' exited with {}", hook, s),
+        Err(e) => warn!("Failed to run context hook '{}': {}", hook, e),
+    }
+}
+
+/// Modifiers currently held across all monitored keyboards.
+pub(crate) fn held_modifiers() -> Modifiers {
+    DEVICE_MODIFIERS
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|map| map.values())
+        .fold(Modifiers::NONE, |all, &mods| all | mods)
+}
+
+// Record a monitored keyboard's health; None forgets the keyboard
+fn set_keyboard_status(device: &DeviceInfo, status: Option<&str>) {
+    let mut guard = KEYBOARD_STATUS.lock().unwrap();
+    let map = guard.get_or_insert_with(HashMap::new);
+    match status {
+        Some(status) => map.insert(device.path.clone(), (device.clone(), status.to_string())),
+        None => map.remove(&device.path),
+    };
+}
+
+// Sleep for `duration` unless shutdown is signalled first; true on shutdown
+fn sleep_unless_shutdown(shutdown_rx: &watch::Receiver<bool>, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if *shutdown_rx.borrow() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100).min(deadline - Instant::now()));
+    }
+    *shutdown_rx.borrow()
+}
+
+// Record the modifiers held on one keyboard and publish the global state
+fn update_modifiers(path: &Path, mods: Modifiers, tx: &watch::Sender<Modifiers>) {
+    let all = {
+        let mut guard = DEVICE_MODIFIERS.lock().unwrap();
+        let map = guard.get_or_insert_with(HashMap::new);
+        if mods.is_empty() {
+            map.remove(path);
+        } else {
+            map.insert(path.to_path_buf(), mods);
+        }
+        map.values().fold(Modifiers::NONE, |all, &m| all | m)
+    };
+    tx.send_if_modified(|current| std::mem::replace(current, all) != all);
+}
+
+// Emit CurrentLayout property changes, wherever the switch came from
+async fn run_layout_watcher(conn: zbus::Connection) {
+    let mut layout_rx = LAYOUT_CHANGES.subscribe();
+    while layout_rx.changed().await.is_ok() {
+        if let Ok(iface) = conn
+            .object_server()
+            .interface::<_, DaemonControl>("/org/kblayout/Daemon")
+            .await
+        {
+            let ctxt = iface.signal_context();
+            let control = iface.get().await;
+            let _ = control.current_layout_changed(ctxt).await;
+            let _ = control.current_layout_name_changed(ctxt).await;
+        }
+    }
+}
+
+// Emit LockedKeyboards property changes, from D-Bus or a flooding keyboard
+async fn run_lock_watcher(conn: zbus::Connection) {
+    let mut lock_rx = LOCK_CHANGES.subscribe();
+    while lock_rx.changed().await.is_ok() {
+        if let Ok(iface) = conn
+            .object_server()
+            .interface::<_, DaemonControl>("/org/kblayout/Daemon")
+            .await
+        {
+            let _ = iface.get().await.locked_keyboards_changed(iface.signal_context()).await;
+        }
+    }
+}
+
+// Log connect summaries from the monitor threads and emit KeyboardConnected
+async fn run_connect_watcher(conn: zbus::Connection, mut connected_rx: mpsc::UnboundedReceiver<ConnectSummary>) {
+    while let Some(summary) = connected_rx.recv().await {
+        summary.log();
+        if let Ok(iface) = conn
+            .object_server()
+            .interface::<_, DaemonControl>("/org/kblayout/Daemon")
+            .await
+        {
+            let _ = DaemonControl::keyboard_connected(iface.signal_context(), summary.to_dict()).await;
+        }
+    }
+}
+
+// Emit Modifiers property changes from the monitor threads' updates
+async fn run_modifiers_watcher(conn: zbus::Connection, mut modifiers_rx: watch::Receiver<Modifiers>) {
+    while modifiers_rx.changed().await.is_ok() {
+        if let Ok(iface) = conn
+            .object_server()
+            .interface::<_, DaemonControl>("/org/kblayout/Daemon")
+            .await
+        {
+            let _ = iface.get().await.modifiers_changed(iface.signal_context()).await;
+        }
+    }
+}
+
+/// Suspend grabbing for `reason`, or resume it with None, and notify D-Bus
+/// clients. Locked keyboards stay grabbed.
+pub(crate) async fn set_grab_suspended(conn: &zbus::Connection, reason: Option<String>) {
+    let reason = reason.unwrap_or_default();
+    {
+        let mut suspended = GRAB_SUSPENDED.lock().unwrap();
+        if *suspended == reason {
+            return;
+        }
+        *suspended = reason.clone();
+    }
+    if reason.is_empty() {
+        info!("Grabbing resumed");
+    } else {
+        info!("Grabbing suspended: {}", reason);
+    }
+
+    match conn
+        .object_server()
+        .interface::<_, DaemonControl>("/org/kblayout/Daemon")
+        .await
+    {
+        Ok(iface) => {
+            let _ = iface.get().await.grab_suspended_changed(iface.signal_context()).await;
+        }
+        Err(e) => warn!("Failed to look up D-Bus interface: {}", e),
+    }
+}
+
+fn grab_suspended() -> bool {
+    PASSIVE_ONLY.get().is_some() || !GRAB_SUSPENDED.lock().unwrap().is_empty()
+}
+
+/// Update the on-screen keyboard state and notify D-Bus clients.
+pub(crate) async fn set_osk_active(conn: &zbus::Connection, active: bool) {
+    if OSK_ACTIVE.swap(active, Ordering::SeqCst) == active {
+        return;
+    }
+    if active {
+        info!("On-screen keyboard active - suppressing layout switching");
+    } else {
+        info!("On-screen keyboard inactive - layout switching resumed");
+    }
+
+    match conn
+        .object_server()
+        .interface::<_, DaemonControl>("/org/kblayout/Daemon")
+        .await
+    {
+        Ok(iface) => {
+            let _ = iface
+                .get()
+                .await
+                .on_screen_keyboard_active_changed(iface.signal_context())
+                .await;
+        }
+        Err(e) => warn!("Failed to look up D-Bus interface: {}", e),
+    }
+}
+
+// Debounce active keyboard changes and fire the ContextChanged signal and hooks
+async fn run_context_watcher(
+    config: Arc<Config>,
+    conn: zbus::Connection,
+    mut context_rx: mpsc::UnboundedReceiver<(String, PathBuf)>,
+) {
+    let debounce = Duration::from_millis(config.context.debounce_ms);
+    let mut active_path = PathBuf::new();
+
+    while let Some(mut latest) = context_rx.recv().await {
+        // Wait until no other keyboard has been used for the debounce period
+        let mut deadline = clock::now() + debounce;
+        loop {
+            let slice = clock::wait_slice(deadline);
+            if slice.is_zero() {
+                break;
+            }
+            match tokio::time::timeout(slice, context_rx.recv()).await {
+                Ok(Some(keyboard)) => {
+                    latest = keyboard;
+                    deadline = clock::now() + debounce;
+                }
+                Ok(None) => return,
+                Err(_) => {}
+            }
+        }
+
+        if active_path == latest.1 {
+            continue;
+        }
+        let old_name = std::mem::replace(&mut *ACTIVE_KEYBOARD.lock().unwrap(), latest.0.clone());
+        let old = (old_name, std::mem::replace(&mut active_path, latest.1.clone()));
+        info!(
+            "Active keyboard changed: '{}' ({}) -> '{}' ({})",
+            old.0,
+            old.1.display(),
+            latest.0,
+            latest.1.display()
+        );
+
+        match conn
+            .object_server()
+            .interface::<_, DaemonControl>("/org/kblayout/Daemon")
+            .await
+        {
+            Ok(iface) => {
+                let ctxt = iface.signal_context();
+                let (old_path, new_path) = (old.1.to_string_lossy(), latest.1.to_string_lossy());
+                if let Err(e) = DaemonControl::context_changed(ctxt, &old.0, &latest.0, &old_path, &new_path).await {
+                    warn!("Failed to emit ContextChanged: {}", e);
+                }
+                let _ = iface.get().await.active_keyboard_changed(ctxt).await;
+            }
+            Err(e) => warn!("Failed to look up D-Bus interface: {}", e),
+        }
+
+        for hook in &config.context.hooks {
+            tokio::spawn(run_context_hook(hook.clone(), old.clone(), latest.clone()));
+        }
+    }
+}
+
+// Whether another keyboard's hold keeps `path` from switching the layout.
+// Presses made during the hold count towards the sticky threshold; reaching
+// it breaks the hold.
+fn hold_blocks_switch(path: &Path, presses: u32, sticky_threshold: u32) -> bool {
+    let mut hold = LAYOUT_HOLD.lock().unwrap();
+    let Some(h) = hold.as_mut() else {
+        return false;
+    };
+
+    if h.holder == path {
+        return false;
+    }
+    if clock::now() >= h.until {
+        *hold = None;
+        return false;
+    }
+
+    h.intruding_presses += presses;
+    if sticky_threshold > 0 && h.intruding_presses >= sticky_threshold {
+        *hold = None;
+        return false;
+    }
+    true
+}
+
+// Whether a switch now would follow the previous one within `debounce_ms`;
+// a switch that may go ahead is recorded as the previous one
+fn debounce_blocks_switch(debounce_ms: u64) -> bool {
+    let mut last = LAST_SWITCH.lock().unwrap();
+    let now = clock::now();
+    if debounce_ms > 0
+        && last.is_some_and(|last| now.saturating_duration_since(last) < Duration::from_millis(debounce_ms))
+    {
+        return true;
+    }
+    *last = Some(now);
+    false
+}
+
+// Codes of a key list option such as passthrough_keys; unknown names are
+// skipped with a warning
+fn key_codes(keys: &[String], option: &str, name: &str) -> Vec<u16> {
+    keys.iter()
+        .filter_map(|key| match key.parse::<Key>() {
+            Ok(key) => Some(key.code()),
+            Err(_) => {
+                warn!("Unknown key '{}' in {} of '{}'", key, option, name);
+                None
+            }
+        })
+        .collect()
+}
+
+// Keys that still reach the system while the keyboard is locked
+fn passthrough_codes(kb_config: &KeyboardConfig, name: &str) -> Vec<u16> {
+    key_codes(&kb_config.passthrough_keys, "passthrough_keys", name)
+}
+
+// Keys whose events are no activity: ignore_keys, and the modifiers with
+// ignore_modifiers, so a shortcut's Ctrl alone doesn't take the layout
+fn ignored_codes(kb_config: &KeyboardConfig, name: &str) -> Vec<u16> {
+    let mut codes = key_codes(&kb_config.ignore_keys, "ignore_keys", name);
+    if kb_config.ignore_modifiers {
+        let modifiers = [
+            Key::KEY_LEFTSHIFT,
+            Key::KEY_RIGHTSHIFT,
+            Key::KEY_LEFTCTRL,
+            Key::KEY_RIGHTCTRL,
+            Key::KEY_LEFTALT,
+            Key::KEY_RIGHTALT,
+            Key::KEY_LEFTMETA,
+            Key::KEY_RIGHTMETA,
+        ];
+        codes.extend(modifiers.map(|key| key.code()));
+    }
+    codes
+}
+
+// The entry's layout cycling hotkey, if it has one and layouts to cycle
+fn cycle_hotkey(kb_config: &KeyboardConfig, name: &str) -> Option<Hotkey> {
+    let spec = kb_config.cycle_key.as_deref()?;
+    if kb_config.layouts.is_empty() {
+        warn!("'{}' has a cycle_key but no layouts to cycle through", name);
+        return None;
+    }
+    match remap::hotkey(spec) {
+        Ok((modifiers, key)) => Some(Hotkey::new(modifiers, key)),
+        Err(e) => {
+            warn!("Invalid cycle_key '{}' for '{}': {}", spec, name, e);
+            None
+        }
+    }
+}
+
+// The entry's remap rules; invalid lines disable remapping
+fn remap_rules(kb_config: &KeyboardConfig, name: &str) -> Vec<RemapRule> {
+    remap::parse(&kb_config.remap).unwrap_or_else(|e| {
+        warn!("Not remapping keys of '{}': {}", name, e);
+        Vec::new()
+    })
+}
+
+// The keyboard's own mode, else the global one
+fn wants_grab(kb_config: &KeyboardConfig) -> bool {
+    match kb_config.mode {
+        Some(mode) => mode == KeyboardMode::Grab,
+        None => GRAB_MODE.load(Ordering::SeqCst),
+    }
+}
+
+// A layout switch waiting out switch_delay_ms
+#[derive(Debug, Clone, Copy)]
+struct DelayedSwitch {
+    due: Instant,
+    layout_index: u32,
+}
+
+impl DelayedSwitch {
+    fn new(delay_ms: u64, layout_index: u32) -> Self {
+        DelayedSwitch {
+            due: clock::now() + Duration::from_millis(delay_ms),
+            layout_index,
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        clock::now() >= self.due
+    }
+
+    // How long the monitor may block on input before it has to look again
+    fn wait(&self) -> Duration {
+        clock::wait_slice(self.due).min(IDLE_WAKEUP)
+    }
+}
+
+// Why a delayed switch of the keyboard at `path` can't go ahead now that it
+// is due: the gates of an immediate switch, checked again as they may have
+// changed during the delay, and typing on another keyboard meanwhile, which
+// makes the press a brush
+fn delayed_switch_blocked(path: &Path, locked: bool, sticky_threshold: u32) -> Option<&'static str> {
+    if locked {
+        return Some("the keyboard is locked");
+    }
+    if OSK_ACTIVE.load(Ordering::SeqCst) {
+        return Some("the on-screen keyboard is active");
+    }
+    if hold_blocks_switch(path, 0, sticky_threshold) {
+        return Some("another keyboard holds the layout");
+    }
+    if LAST_ACTIVE_PATH.lock().unwrap().as_deref() != Some(path) {
+        return Some("another keyboard was used within switch_delay_ms");
+    }
+    None
+}
+
+fn renew_hold(path: &Path, hold_ms: u64) {
+    *LAYOUT_HOLD.lock().unwrap() = Some(LayoutHold {
+        holder: path.to_path_buf(),
+        until: clock::now() + Duration::from_millis(hold_ms),
+        intruding_presses: 0,
+    });
+}
+
+// The monitor's ends of the channels its KeyboardMonitor sends on
+struct MonitorChannels {
+    shutdown_rx: watch::Receiver<bool>,
+    reattach_rx: std::sync::mpsc::Receiver<PathBuf>,
+    update_rx: std::sync::mpsc::Receiver<KeyboardConfig>,
+}
+
+// Keyboard monitor - runs in its own thread with its own virtual keyboard
+fn monitor_keyboard(
+    mut path: PathBuf,
+    name: String,
+    mut kb_config: KeyboardConfig,
+    handles: MonitorHandles,
+    channels: MonitorChannels,
+    view: Arc<MonitorView>,
+) {
+    let MonitorChannels {
+        shutdown_rx,
+        reattach_rx,
+        update_rx,
+    } = channels;
+    info!("Starting monitor for '{}' at {:?}", name, path);
+
+    let mut device_info = DeviceInfo {
+        path: path.clone(),
+        id: Device::open(&path).map(|d| inspect::device_id(&d)).unwrap_or_default(),
+        name: name.clone(),
+        layout_index: layouts::index_for(&kb_config),
+    };
+
+    // Index of the matching [[keyboards]] entry, stable across reconnects;
+    // keyboards taken by [default] come after the last entry
+    let entry_index = handles.config.keyboards.iter().position(|kb| kb.same_keyboard(&kb_config));
+    let source_index = entry_index.unwrap_or(handles.config.keyboards.len()) as u16;
+    let tag_code = handles.config.source_tag.as_deref().and_then(parse_source_tag);
+    let source_tag = tag_code.map(|code| InputEvent::new(EventType::MISC, code.0, source_index as i32));
+
+    let pointer = kb_config.advertise_pointer.unwrap_or_else(|| {
+        Device::open(&path)
+            .ok()
+            .and_then(|d| d.supported_relative_axes().map(|axes| axes.iter().next().is_some()))
+            .unwrap_or(false)
+    });
+
+    let mut passthrough = passthrough_codes(&kb_config, &name);
+    let mut ignored = ignored_codes(&kb_config, &name);
+    let mut remap = Remap::new(remap_rules(&kb_config, &name));
+    let mut cycle = cycle_hotkey(&kb_config, &name);
+    // Layout picked with the cycle key, instead of the entry's own until
+    // the entry's layouts change; kept across restarts
+    let mut cycled = state::layout(&kb_config.name).filter(|layout| kb_config.layouts.contains(layout));
+
+    // Create dedicated virtual keyboard for this physical keyboard. Without
+    // it the keyboard would work unswitched, so keep trying (uinput may not
+    // be loaded or accessible yet) and tell the user
+    let mut backoff = VK_RETRY_MIN;
+    let mut notified = false;
+    let virtual_kb = loop {
+        match create_output(&handles.config, &name, source_index, tag_code, pointer) {
+            Ok(vk) => break vk,
+            Err(e) => {
+                error!(
+                    "Failed to create virtual keyboard for '{}': {}, retrying in {}s",
+                    name,
+                    e,
+                    backoff.as_secs()
+                );
+                let status = format!("no virtual keyboard: {} (retrying)", e);
+                set_keyboard_status(&device_info, Some(&status));
+                if !notified {
+                    notify::notify(
+                        "Keyboard layout switching unavailable",
+                        &format!("Cannot create a virtual keyboard for '{}': {}", name, e),
+                    );
+                    notified = true;
+                }
+                if sleep_unless_shutdown(&shutdown_rx, backoff) {
+                    set_keyboard_status(&device_info, None);
+                    return;
+                }
+                backoff = (backoff * 2).min(VK_RETRY_MAX);
+            }
+        }
+    };
+    if notified {
+        info!("Created virtual keyboard for '{}' after retrying", name);
+    }
+    set_keyboard_status(&device_info, Some("ok"));
+    // Written from its own thread so a stalled consumer can't block reading
+    let output = OutputQueue::spawn(
+        virtual_kb,
+        name.clone(),
+        source_tag,
+        Arc::clone(&handles.switcher),
+    );
+    // Copy of the physical stream: never held back for layout switches and
+    // fed in passive mode too, so timing stays that of the keyboard
+    let mirror = kb_config.mirror.as_deref().and_then(|mirror_name| {
+        match create_virtual_keyboard(mirror_name, source_index, tag_code, pointer) {
+            Ok(vk) => {
+                info!("Mirroring '{}' to '{}'", name, mirror_name);
+                Some(OutputQueue::spawn(
+                    Box::new(vk),
+                    format!("{} (mirror)", name),
+                    source_tag,
+                    Arc::clone(&handles.switcher),
+                ))
+            }
+            Err(e) => {
+                warn!("Failed to create mirror device for '{}': {}", name, e);
+                None
+            }
+        }
+    });
+
+    // Open/grab/drain transitions live in the state machine; this loop
+    // performs the actions it asks for
+    let mut state = MonitorState::Disconnected;
+    let mut device: Option<Device> = None;
+    // Track actually pressed keys to avoid releasing unpressed keys (especially Meta)
+    let mut pressed_keys = PressedKeys::new();
+    // Lock key state, kept across reconnects; read from the LEDs on first open
+    let mut locks: Option<LockState> = None;
+    // Set after a reconnect, until the new node's key state is reconciled
+    let mut reattached = false;
+    // Inside a block invalidated by SYN_DROPPED
+    let mut dropping = false;
+    // Event-rate ceiling against malfunctioning hardware
+    let mut limiter = RateLimiter::new(kb_config.max_events_per_sec);
+    // Typing bursts, without which this keyboard doesn't take the layout
+    let mut burst = BurstDetector::new(kb_config.burst_keys, kb_config.burst_window_ms);
+    // Switch waiting out switch_delay_ms
+    let mut delayed_switch: Option<DelayedSwitch> = None;
+    let mut flooding = false;
+    let started = clock::now();
+    // Left because the device went away (not shutdown or replacement)
+    let mut disconnected = false;
+    // Connect summary sent for the current device node
+    let mut announced = false;
+    LIVE_MONITORS.fetch_add(1, Ordering::SeqCst);
+    let metrics = Arc::new(MonitorMetrics {
+        name: name.clone(),
+        path: Mutex::new(path.clone()),
+        last_iteration_ms: AtomicU64::new(unix_millis()),
+        output: output.metrics(),
+    });
+    MONITOR_METRICS.lock().unwrap().push(Arc::clone(&metrics));
+
+    // Retry delay for opening and grabbing the device, reset once it works
+    let reopen_min = Duration::from_millis(handles.config.reopen_retry_ms.max(1));
+    let reopen_max = Duration::from_millis(handles.config.reopen_retry_max_ms).max(reopen_min);
+    let mut reopen_backoff = reopen_min;
+
+    loop {
+        metrics.last_iteration_ms.store(unix_millis(), Ordering::Relaxed);
+        // Check for shutdown signal
+        if *shutdown_rx.borrow() {
+            info!("Shutdown signal received for '{}', stopping monitor", name);
+            break;
+        }
+
+        // Entry changes that keep the device and its grab as they are
+        while let Ok(update) = update_rx.try_recv() {
+            if update.max_events_per_sec != kb_config.max_events_per_sec {
+                limiter = RateLimiter::new(update.max_events_per_sec);
+                flooding = false;
+            }
+            if (update.burst_keys, update.burst_window_ms) != (kb_config.burst_keys, kb_config.burst_window_ms) {
+                burst = BurstDetector::new(update.burst_keys, update.burst_window_ms);
+            }
+            if update.remap != kb_config.remap {
+                remap.set_rules(remap_rules(&update, &name));
+            }
+            if (&update.layouts, &update.cycle_key) != (&kb_config.layouts, &kb_config.cycle_key) {
+                cycle = cycle_hotkey(&update, &name);
+                cycled = None;
+                state::set_layout(&update.name, None);
+            }
+            kb_config = update;
+            passthrough = passthrough_codes(&kb_config, &name);
+            ignored = ignored_codes(&kb_config, &name);
+            info!("Applied changed config entry for '{}'", name);
+        }
+
+        // A locked keyboard has to be grabbed to hold its input back
+        let locked = is_locked(&kb_config.name);
+        let is_grab_mode = (wants_grab(&kb_config) && !grab_suspended()) || (locked && PASSIVE_ONLY.get().is_none());
+
+        // A finished switch lets forwarded frames through again
+        if let MonitorState::Switching { .. } = state {
+            let done = handles.switcher.completed();
+            state = state.next(Input::SwitchCompleted { ticket: done }, false).0;
+        }
+
+        // Mode changes need the device re-opened with a different grab state
+        let (next, action) = state.next(Input::Mode { grab: is_grab_mode }, !pressed_keys.is_empty());
+        state = next;
+        if action == Action::ReleaseHeld {
+            // Release only actually pressed keys before switching
+            // This avoids sending spurious Meta key releases that trigger KDE launcher
+            output.push(&pressed_keys.release_events());
+            pressed_keys.clear();
+            update_modifiers(&path, Modifiers::NONE, &handles.modifiers_tx);
+            state = state.next(Input::Drained, false).0;
+        }
+
+        if state == MonitorState::Disconnected {
+            device = None;
+
+            // Open device
+            let mut dev = match Device::open(&path) {
+                Ok(d) => d,
+                Err(e) => {
+                    warn!("Failed to open {:?}: {}, retrying in {}ms", path, e, reopen_backoff.as_millis());
+                    set_keyboard_status(&device_info, Some(&format!("cannot open: {} (retrying)", e)));
+                    sleep_unless_shutdown(&shutdown_rx, reopen_backoff);
+                    reopen_backoff = (reopen_backoff * 2).min(reopen_max);
+                    continue;
+                }
+            };
+
+            // Grab if in grab mode
+            if is_grab_mode {
+                if let Err(e) = dev.grab() {
+                    warn!("Failed to grab {:?}: {}, retrying in {}ms", path, e, reopen_backoff.as_millis());
+                    set_keyboard_status(&device_info, Some(&format!("cannot grab: {} (retrying)", e)));
+                    sleep_unless_shutdown(&shutdown_rx, reopen_backoff);
+                    reopen_backoff = (reopen_backoff * 2).min(reopen_max);
+                    continue;
+                }
+            }
+            reopen_backoff = reopen_min;
+
+            // Keys released while the keyboard was gone never produced a
+            // release event; release them now so nothing stays stuck
+            if reattached {
+                if let Ok(held) = dev.get_key_state() {
+                    let releases = remap.retain_held(|code| held.contains(Key::new(code)));
+                    pressed_keys.process(&releases);
+                    if !releases.is_empty() {
+                        output.push(&releases);
+                        if let Some(mirror) = &mirror {
+                            mirror.push(&releases);
+                        }
+                        update_modifiers(&path, pressed_keys.modifiers(), &handles.modifiers_tx);
+                    }
+                }
+                reattached = false;
+            }
+
+            // A grabbed keyboard gets no LED updates from the system, and a
+            // reconnected one comes back with its LEDs off
+            let locks = locks.get_or_insert_with(|| {
+                dev.get_led_state()
+                    .map(|leds| LockState::from_leds(&leds))
+                    .unwrap_or_default()
+            });
+            if is_grab_mode {
+                if let Err(e) = dev.send_events(&locks.led_events()) {
+                    debug!("Failed to set lock LEDs of '{}': {}", name, e);
+                }
+            }
+
+            device = Some(dev);
+            state = state.next(Input::Opened { grabbed: is_grab_mode }, false).0;
+            set_keyboard_status(&device_info, Some("ok"));
+            info!(
+                "'{}' now in {} mode",
+                name,
+                if is_grab_mode { "GRAB" } else { "PASSIVE" }
+            );
+            if !announced {
+                let mode = match (locked, is_grab_mode) {
+                    (true, _) => "locked",
+                    (false, true) => "grab",
+                    (false, false) => "passive",
+                };
+                let mut summary = ConnectSummary::new(&device_info, &kb_config, mode, mirror.is_some());
+                if entry_index.is_none() {
+                    summary.rule = "[default]".to_string();
+                }
+                let _ = handles.connected_tx.send(summary);
+                announced = true;
+            }
+        }
+
+        // An idle keyboard still goes round the loop now and then, so it
+        // can be stopped (e.g. removed from the config) without a key press
+        if let Some(DelayedSwitch { layout_index: index, .. }) = delayed_switch.filter(DelayedSwitch::is_due) {
+            delayed_switch = None;
+            // A config change may have moved the keyboard to another layout
+            let target = cycled
+                .as_deref()
+                .and_then(layouts::index_of)
+                .unwrap_or_else(|| layouts::index_for(&kb_config));
+            let locked = is_locked(&kb_config.name);
+            if let Some(reason) = delayed_switch_blocked(&path, locked, handles.config.sticky_threshold) {
+                debug!("Not switching for '{}' after switch_delay_ms: {}", name, reason);
+            } else if target == index
+                && CURRENT_LAYOUT.load(Ordering::SeqCst) != index
+                && !debounce_blocks_switch(handles.config.switch_debounce_ms)
+            {
+                info!(
+                    "Switching layout to {} (index {}) - input from '{}' {}ms ago",
+                    layout_label(index, &kb_config.layout_name),
+                    index,
+                    name,
+                    kb_config.switch_delay_ms
+                );
+                let ticket = handles.switcher.submit(index);
+                let (next, action) = state.next(Input::SwitchSubmitted { ticket }, false);
+                state = next;
+                if let Action::AwaitSwitch { ticket } = action {
+                    output.await_switch(ticket);
+                }
+            }
+        }
+        view.publish(state, device_info.layout_index);
+        let wait = delayed_switch.map_or(IDLE_WAKEUP, |delayed| delayed.wait());
+        if !wait_readable(device.as_ref().unwrap(), wait) {
+            continue;
+        }
+
+        // Read events in a block to limit borrow scope
+        let events: Option<Vec<InputEvent>> = {
+            let dev = device.as_mut().unwrap();
+            match dev.fetch_events() {
+                Ok(iter) => Some(iter.collect()),
+                Err(_) => None,
+            }
+        };
+
+        let mut events = match events {
+            Some(e) if !e.is_empty() => e,
+            Some(_) => continue,
+            None => {
+                // Keep the virtual device and key state for a short while
+                // in case the keyboard comes right back (Bluetooth blips)
+                device = None;
+                state = state.next(Input::Lost, false).0;
+                view.publish(state, device_info.layout_index);
+                if *shutdown_rx.borrow() {
+                    info!("Shutdown signal received for '{}', stopping monitor", name);
+                    break;
+                }
+                set_keyboard_status(&device_info, Some("disconnected, waiting for it to return"));
+                if let Some(new_path) = wait_for_reattach(&name, &handles.config, &reattach_rx) {
+                    set_keyboard_status(&device_info, None);
+                    path = new_path;
+                    device_info.path = path.clone();
+                    *metrics.path.lock().unwrap() = path.clone();
+                    reattached = true;
+                    announced = false;
+                    continue;
+                }
+
+                // Device disconnected - exit thread, udev will respawn if device reconnects
+                info!("Device '{}' disconnected, stopping monitor", name);
+                disconnected = true;
+                break;
+            }
+        };
+
+        // The kernel lost events: discard the broken block and resync held
+        // keys from the device instead of forwarding a corrupted stream
+        if drop_corrupted(&mut events, &mut dropping) {
+            warn!("Kernel dropped events for '{}', resynchronizing", name);
+            if let Some(Ok(held)) = device.as_ref().map(|d| d.get_key_state()) {
+                let held: Vec<u16> = held.iter().map(|key| key.code()).collect();
+                let fixes = remap.resync(&held);
+                pressed_keys.process(&fixes);
+                if state.is_grabbed() && !fixes.is_empty() {
+                    output.push(&fixes);
+                }
+                if let Some(mirror) = mirror.as_ref().filter(|_| !fixes.is_empty()) {
+                    mirror.push(&fixes);
+                }
+                update_modifiers(&path, pressed_keys.modifiers(), &handles.modifiers_tx);
+            }
+        }
+
+        // The layout may have moved in the desktop's list since the last batch
+        let mut layout_index = cycled
+            .as_deref()
+            .and_then(layouts::index_of)
+            .unwrap_or_else(|| layouts::index_for(&kb_config));
+        device_info.layout_index = layout_index;
+
+        // Run embedder filters; an emptied batch is dropped entirely
+        handles.filters.apply(&device_info, &mut events);
+        remap.apply(&mut events);
+        // A flooding keyboard only gets its held keys released; the first
+        // batch over the limit raises the alert
+        let now_ms = clock::now().saturating_duration_since(started).as_millis() as u64;
+        let was_flooding = flooding;
+        flooding = limiter.check(&events, now_ms);
+        if flooding {
+            retain_passthrough(&mut events, &pressed_keys, &[]);
+            if !was_flooding {
+                warn!(
+                    "'{}' sends more than {} key events per second, dropping its events",
+                    name, kb_config.max_events_per_sec
+                );
+                notify::notify(
+                    "Keyboard flooding",
+                    &format!("'{}' sends keys faster than a person can type and is ignored", name),
+                );
+                if kb_config.lock_on_flood && !is_locked(&kb_config.name) {
+                    set_locked(&kb_config.name, true);
+                }
+            }
+        } else if was_flooding {
+            info!("'{}' calmed down, forwarding its events again", name);
+        }
+
+        // The lock may have been set while this read was blocked
+        let locked = is_locked(&kb_config.name);
+        if locked {
+            retain_passthrough(&mut events, &pressed_keys, &passthrough);
+        }
+        // The cycle key moves this keyboard on to the next of its layouts
+        let mut cycle_pressed = false;
+        if let Some(cycle) = cycle.as_mut().filter(|_| !locked) {
+            if cycle.take(&mut events, pressed_keys.modifiers()) > 0 {
+                if let Some(next) = layouts::next_in(&kb_config.layouts, layout_index) {
+                    layout_index = layouts::index_of(next).unwrap_or(layout_index);
+                    device_info.layout_index = layout_index;
+                    info!("'{}' cycles to {}", name, layout_label(layout_index, next));
+                    state::set_layout(&kb_config.name, Some(next));
+                    cycled = Some(next.clone());
+                    cycle_pressed = true;
+                }
+            }
+        }
+        // A newer layout choice replaces a switch still waiting out its delay
+        if delayed_switch.is_some_and(|delayed| cycle_pressed || delayed.layout_index != layout_index) {
+            delayed_switch = None;
+        }
+        if events.is_empty() && !cycle_pressed {
+            continue;
+        }
+        if let Some(mirror) = &mirror {
+            mirror.push(&events);
+        }
+
+        // Check if we need to switch layout (on key press) and track pressed keys
+        let current = CURRENT_LAYOUT.load(Ordering::SeqCst);
+        pressed_keys.process(&events);
+        let locks_changed = locks.as_mut().is_some_and(|locks| locks.process(&events));
+        if locks_changed && state.is_grabbed() {
+            if let (Some(dev), Some(locks)) = (device.as_mut(), &locks) {
+                if let Err(e) = dev.send_events(&locks.led_events()) {
+                    debug!("Failed to set lock LEDs of '{}': {}", name, e);
+                }
+            }
+        }
+        // Activity as configured; presses only unless activity_on says
+        // otherwise. A locked keyboard's passthrough keys are no activity
+        let activity = if locked {
+            0
+        } else {
+            kb_config
+                .activity_on
+                .count_except(&events, |key| ignored.contains(&key.code())) as u32
+        };
+        update_modifiers(&path, pressed_keys.modifiers(), &handles.modifiers_tx);
+        let had_activity = activity > 0;
+        let switches_layout = kb_config
+            .dbus_action
+            .as_ref()
+            .is_none_or(|action| action.switch_layout);
+        let mut need_switch = had_activity && switches_layout && current != layout_index;
+
+        if let Some(action) = kb_config.dbus_action.as_ref().filter(|_| !locked) {
+            for ev in &events {
+                if let (InputEventKind::Key(key), 1) = (ev.kind(), ev.value()) {
+                    let _ = handles.action_tx.send(ActionCall {
+                        action: action.clone(),
+                        keyboard: name.clone(),
+                        code: key.code(),
+                        layout_index,
+                    });
+                }
+            }
+        }
+
+        // Another keyboard may be holding the layout
+        let held_off = need_switch
+            && hold_blocks_switch(&path, activity, handles.config.sticky_threshold);
+        if held_off {
+            debug!("Layout held by another keyboard, not switching for '{}'", name);
+            need_switch = false;
+        }
+        // Don't change the layout under someone typing on the touch screen
+        if need_switch && OSK_ACTIVE.load(Ordering::SeqCst) {
+            debug!("On-screen keyboard active, not switching for '{}'", name);
+            need_switch = false;
+        }
+        // Isolated presses (a bumped key, a lone shortcut) don't take the layout
+        let bursting = burst.check(activity, now_ms);
+        if need_switch && !bursting {
+            debug!("No typing burst yet, not switching for '{}'", name);
+            need_switch = false;
+        }
+        if had_activity && !held_off && kb_config.hold_ms > 0 {
+            renew_hold(&path, kb_config.hold_ms);
+        }
+
+        if had_activity {
+            note_activity(&path, &name, &handles.context_tx);
+        }
+        // A delayed switch only happens if the press isn't a brush, see above
+        if need_switch && kb_config.switch_delay_ms > 0 {
+            if delayed_switch.is_none() {
+                debug!("Switching for '{}' in {}ms unless another keyboard is used", name, kb_config.switch_delay_ms);
+                delayed_switch = Some(DelayedSwitch::new(kb_config.switch_delay_ms, layout_index));
+            }
+            need_switch = false;
+        }
+        // Keyboards used in quick alternation would switch on every batch
+        if need_switch && debounce_blocks_switch(handles.config.switch_debounce_ms) {
+            debug!("Switched less than switch_debounce_ms ago, not switching for '{}'", name);
+            need_switch = false;
+        }
+        // Cycling is a deliberate choice, whatever holds and bursts say
+        if cycle_pressed && current != layout_index {
+            need_switch = true;
+        }
+
+        // Switch layout before forwarding events
+        if need_switch {
+            let mode_str = if state.is_grabbed() { "Grab" } else { "Passive" };
+            info!(
+                "[{}] Switching layout to {} (index {}) - input from '{}'",
+                mode_str,
+                layout_label(layout_index, &kb_config.layout_name),
+                layout_index,
+                name
+            );
+
+            let ticket = handles.switcher.submit(layout_index);
+
+            // In grab mode the batch must reach the new layout; the writer
+            // holds it back until the switch is done while reading goes on
+            let (next, action) = state.next(Input::SwitchSubmitted { ticket }, false);
+            state = next;
+            if let Action::AwaitSwitch { ticket } = action {
+                output.await_switch(ticket);
+            }
+        }
+
+        // Forward events in grab mode, framed at SYN_REPORT boundaries
+        if state.is_grabbed() {
+            output.push(&events);
+        }
+    }
+
+    // Keys held on a vanished keyboard are no longer held
+    update_modifiers(&path, Modifiers::NONE, &handles.modifiers_tx);
+    set_keyboard_status(&device_info, None);
+    MONITOR_METRICS.lock().unwrap().retain(|m| !Arc::ptr_eq(m, &metrics));
+
+    // Monitors stopped on shutdown or replaced by a newer node don't count
+    // as the keyboard going away
+    if LIVE_MONITORS.fetch_sub(1, Ordering::SeqCst) == 1 && disconnected {
+        all_keyboards_gone(&name, &handles);
+    }
+}
+
+// Apply `[all_disconnected]` once no monitored keyboard is left
+fn all_keyboards_gone(last: &str, handles: &MonitorHandles) {
+    let policy = &handles.config.all_disconnected;
+    info!("Last keyboard '{}' disconnected", last);
+
+    if let Some(index) = policy.layout_index {
+        info!("Reverting layout to index {}", index);
+        handles.switcher.submit(index);
+    }
+
+    if let Some(hook) = &policy.hook {
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(hook)
+            .env("KB_LAST_KEYBOARD", last)
+            .status();
+        match status {
+            Ok(s) if s.success() => {}
+            Ok(s) => warn!("Disconnect hook '{}' exited with {}", hook, s),
+            Err(e) => warn!("Failed to run disconnect hook '{}': {}", hook, e),
+        }
+    }
+}
+
+// Identity of the physical device behind an event node, stable when the
+// device re-enumerates under a new node. None when the kernel reports
+// neither phys nor uniq, as such nodes can't be told apart.
+fn device_identity(device: &Device) -> Option<String> {
+    let phys = device.physical_path().unwrap_or("");
+    let uniq = device.unique_name().unwrap_or("");
+    if phys.is_empty() && uniq.is_empty() {
+        return None;
+    }
+    // Interfaces of one Bluetooth keyboard share phys and uniq
+    Some(format!("{}|{}|{}", device.name().unwrap_or(""), phys, uniq))
+}
+
+// Spawn a keyboard monitor thread with shutdown signaling
+pub(crate) fn spawn_keyboard_monitor(
+    path: PathBuf,
+    name: String,
+    kb_config: KeyboardConfig,
+    handles: MonitorHandles,
+    monitors: &ActiveMonitors,
+) {
+    let mut monitors_guard = monitors.lock().unwrap();
+
+    // Don't spawn if already monitoring this path
+    if monitors_guard.get(&path).is_some_and(|m| !m.handle.is_finished()) {
+        return;
+    }
+
+    // Some keyboards re-enumerate on resume while the old node lingers;
+    // keep only one monitor (and one grab) per physical device
+    let identity = Device::open(&path).ok().and_then(|d| device_identity(&d));
+    if let Some(id) = &identity {
+        let stale: Vec<PathBuf> = monitors_guard
+            .iter()
+            .filter(|(_, m)| m.identity.as_ref() == Some(id) && !m.handle.is_finished())
+            .map(|(p, _)| p.clone())
+            .collect();
+        for stale_path in stale {
+            info!(
+                "'{}' re-enumerated at {:?}, replacing monitor for {:?}",
+                name, path, stale_path
+            );
+            if let Some(monitor) = monitors_guard.remove(&stale_path) {
+                let _ = monitor.shutdown_tx.send(true);
+            }
+        }
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (reattach_tx, reattach_rx) = std::sync::mpsc::channel();
+    let (update_tx, update_rx) = std::sync::mpsc::channel();
+    let path_clone = path.clone();
+    let name_clone = name.clone();
+
+    let kb_config_clone = kb_config.clone();
+    let view = Arc::new(MonitorView {
+        state: Mutex::new(MonitorState::Disconnected),
+        layout_index: AtomicU32::new(layouts::index_for(&kb_config)),
+    });
+    let view_clone = Arc::clone(&view);
+
+    let handle = thread::spawn(move || {
+        affinity::pin_current_thread(&handles.config.threads.forward_cpus);
+        monitor_keyboard(
+            path_clone,
+            name_clone,
+            kb_config_clone,
+            handles,
+            MonitorChannels {
+                shutdown_rx,
+                reattach_rx,
+                update_rx,
+            },
+            view_clone,
+        );
+    });
+
+    monitors_guard.insert(
+        path,
+        KeyboardMonitor {
+            handle,
+            shutdown_tx,
+            name,
+            reattach_tx,
+            update_tx,
+            identity,
+            kb_config,
+            view,
+        },
+    );
+}
+
+// Whether keyboards connected after startup are picked up at all
+fn detects_new_keyboards(config: &Config) -> bool {
+    (cfg!(feature = "hotplug") && config.hotplug) || config.rescan_secs > 0
+}
+
+// Periodic rescan for setups without hot-plug events (containers, test rigs)
+async fn run_rescan(config: Arc<Config>, handles: MonitorHandles, monitors: ActiveMonitors) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.rescan_secs));
+    // The startup scan just ran
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        scan_for_new_keyboards(&handles, &monitors, "Rescan").await;
+    }
+}
+
+// Apply config file changes to the keyboards: monitors of keyboards that
+// are no longer configured, or whose match rules or virtual devices
+// changed, are stopped, and newly matching keyboards (including the
+// changed ones) get monitors. Other entry changes (layout, hold time, ...)
+// are handed to the running monitor, which keeps its grab and key state.
+// Other settings take effect on the next start.
+async fn run_config_reload(dbus_conn: Arc<Connection>, handles: MonitorHandles, monitors: ActiveMonitors) {
+    let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+    for path in [config::config_path(), config::system_config_path()] {
+        let changed_tx = changed_tx.clone();
+        thread::spawn(move || reload::watch(&path, changed_tx));
+    }
+    let drop_ins_tx = changed_tx.clone();
+    thread::spawn(move || reload::watch_drop_ins(&config::drop_in_dir(), drop_ins_tx));
+    drop(changed_tx);
+
+    while changed_rx.recv().await.is_some() {
+        let conn = Arc::clone(&dbus_conn);
+        let loaded = tokio::task::spawn_blocking(move || {
+            let mut config = config::reread_config()?;
+            let backend = backend::active();
+            for name in backend::assign_names(backend, &mut config.keyboards) {
+                warn!("Keyboard '{}' has no engine for the {} backend", name, backend.name());
+            }
+            if backend != Backend::Ibus {
+                layouts::resolve_indices(&conn, &mut config.keyboards);
+            }
+            Ok::<_, String>(config)
+        })
+        .await;
+        let config = match loaded {
+            Ok(Ok(config)) => Arc::new(config),
+            Ok(Err(e)) => {
+                warn!("Config changed: {} - keeping the current config", e);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        info!(
+            "Config changed: reloading {} keyboard entries (other settings apply after a restart)",
+            config.keyboards.len()
+        );
+        set_current_config(Arc::clone(&config));
+
+        let monitors_for_check = Arc::clone(&monitors);
+        let stopped = tokio::task::spawn_blocking(move || {
+            let mut guard = monitors_for_check.lock().unwrap();
+            let stale: Vec<PathBuf> = guard
+                .iter_mut()
+                .filter(|(_, m)| !m.handle.is_finished())
+                .filter_map(|(path, m)| {
+                    let entry = Device::open(path)
+                        .ok()
+                        .and_then(|device| match_keyboard_config(&device, path, &config).cloned());
+                    match entry {
+                        Some(entry) if entry == m.kb_config => None,
+                        Some(entry) if !entry.needs_restart(&m.kb_config) => {
+                            info!("Updating monitor for '{}': its config entry changed", m.name);
+                            m.kb_config = entry.clone();
+                            let _ = m.update_tx.send(entry);
+                            None
+                        }
+                        Some(_) => {
+                            info!("Restarting monitor for '{}': its match rules or devices changed", m.name);
+                            Some(path.clone())
+                        }
+                        None => {
+                            info!("Stopping monitor for '{}': no longer configured", m.name);
+                            Some(path.clone())
+                        }
+                    }
+                })
+                .collect();
+            let handles: Vec<JoinHandle<()>> = stale
+                .iter()
+                .filter_map(|path| guard.remove(path))
+                .map(|monitor| {
+                    let _ = monitor.shutdown_tx.send(true);
+                    monitor.handle
+                })
+                .collect();
+            drop(guard);
+            // Let go of the devices before new monitors grab them
+            for handle in handles {
+                let _ = handle.join();
+            }
+        })
+        .await;
+        if stopped.is_ok() {
+            scan_for_new_keyboards(&handles, &monitors, "Config reload").await;
+        }
+    }
+}
+
+/// Start monitors for connected keyboards that aren't monitored yet, e.g.
+/// ones plugged in while hot-plug detection was down. `origin` prefixes
+/// the log lines.
+pub(crate) async fn scan_for_new_keyboards(handles: &MonitorHandles, monitors: &ActiveMonitors, origin: &str) {
+    let found = tokio::task::spawn_blocking(|| find_keyboards(&current_config(), false))
+        .await
+        .unwrap_or_default();
+    for (path, (name, kb_config)) in found {
+        let monitored = monitors
+            .lock()
+            .unwrap()
+            .get(&path)
+            .is_some_and(|m| !m.handle.is_finished());
+        if monitored || reattach_keyboard_monitor(&path, &name, monitors) {
+            continue;
+        }
+        info!(
+            "{}: Found keyboard '{}' at {:?} -> {} (index {})",
+            origin, name, path, kb_config.layout_name, kb_config.layout_index
+        );
+        spawn_keyboard_monitor(path, name, kb_config, handles.clone(), monitors);
+    }
+}
+
+// Wait out the reconnect grace period; returns the keyboard's new node
+fn wait_for_reattach(
+    name: &str,
+    config: &Config,
+    reattach_rx: &std::sync::mpsc::Receiver<PathBuf>,
+) -> Option<PathBuf> {
+    // Only hot-plug or a rescan can report the keyboard coming back
+    if !detects_new_keyboards(config) || config.reconnect_grace_ms == 0 {
+        return None;
+    }
+
+    info!(
+        "'{}' disconnected, keeping its virtual keyboard for {}ms",
+        name, config.reconnect_grace_ms
+    );
+    match reattach_rx.recv_timeout(Duration::from_millis(config.reconnect_grace_ms)) {
+        Ok(new_path) => {
+            info!("'{}' reconnected at {:?}", name, new_path);
+            Some(new_path)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Hand a reconnected keyboard to its monitor if that monitor is still in
+/// its grace period. Returns false if there is none.
+pub(crate) fn reattach_keyboard_monitor(path: &Path, name: &str, monitors: &ActiveMonitors) -> bool {
+    let mut monitors_guard = monitors.lock().unwrap();
+
+    // A live monitor for this device name whose node is gone (or reused)
+    let old_path = monitors_guard
+        .iter()
+        .find(|(old, m)| {
+            m.name == name && !m.handle.is_finished() && (old.as_path() == path || !old.exists())
+        })
+        .map(|(old, _)| old.clone());
+
+    let Some(old_path) = old_path else {
+        return false;
+    };
+    let monitor = monitors_guard.remove(&old_path).unwrap();
+    if monitor.reattach_tx.send(path.to_path_buf()).is_err() {
+        return false;
+    }
+    monitors_guard.insert(path.to_path_buf(), monitor);
+    true
+}
+
+// Stop a keyboard monitor
+#[cfg_attr(not(feature = "hotplug"), allow(dead_code))]
+pub(crate) fn stop_keyboard_monitor(path: &PathBuf, monitors: &ActiveMonitors) {
+    let mut monitors_guard = monitors.lock().unwrap();
+
+    if let Some(monitor) = monitors_guard.remove(path) {
+        // Signal shutdown
+        let _ = monitor.shutdown_tx.send(true);
+        // Don't wait for thread - it will exit on its own
+    }
+}
+
+/// The daemon: monitors configured keyboards, switches layouts and serves
+/// the `org.kblayout.Daemon` D-Bus interface.
+///
+/// Embedders can add processing to the input path with [`Daemon::with_filter`]:
+///
+/// ```no_run
+/// use kb_layout_daemon::{config::load_config, Daemon};
+///
+/// Daemon::new(load_config()).run().unwrap();
+/// ```
+pub struct Daemon {
+    config: Config,
+    filters: FilterChain,
+    switcher: Option<Box<dyn LayoutSwitcher>>,
+    clock: Option<Box<dyn Clock>>,
+    replace: bool,
+    mode: Option<String>,
+}
+
+impl Daemon {
+    pub fn new(config: Config) -> Self {
+        Daemon {
+            config,
+            filters: FilterChain::default(),
+            switcher: None,
+            clock: None,
+            replace: false,
+            mode: None,
+        }
+    }
+
+    /// Append a filter to the event pipeline. Filters run in the order they
+    /// were added, on every batch read from every monitored keyboard.
+    pub fn with_filter(mut self, filter: impl EventFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Switch layouts with `switcher` instead of the configured backend.
+    pub fn with_switcher(mut self, switcher: impl LayoutSwitcher + 'static) -> Self {
+        self.switcher = Some(Box::new(switcher));
+        self
+    }
+
+    /// Read the time for holds, rate limits, bursts, cooldowns and the
+    /// switch confirmation and context debounce timeouts from `clock`
+    /// instead of the system clock, e.g. a `SimulatedClock`.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Take over from an instance that is already running, which then
+    /// exits, instead of refusing to start.
+    pub fn replace_running(mut self) -> Self {
+        self.replace = true;
+        self
+    }
+
+    /// Start in `mode` ("grab" or "passive"), whatever the config and the
+    /// saved state say.
+    pub fn with_mode(mut self, mode: impl Into<String>) -> Self {
+        self.mode = Some(mode.into());
+        self
+    }
+
+    /// Run the daemon. Only returns on startup failure.
+    pub fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        let started = std::time::Instant::now();
+        info!("kb-layout-daemon {} ({}) starting...", VERSION, GIT_HASH);
+        if let Some(clock) = self.clock {
+            clock::set(clock);
+        }
+
+        // Before any device is touched: a second instance would grab the
+        // same keyboards and type every key twice
+        let control_conn = match claim_bus_name(self.replace) {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Cannot start: {}", e);
+                return Err(e.into());
+            }
+        };
+
+        let mut config = self.config;
+        if self.switcher.is_some() {
+            config.backend = Backend::Custom;
+        }
+        if config.backend == Backend::Auto {
+            let conn = backend::connect(&config.backend_bus).ok();
+            let (detected, reason) = session::detect_backend(conn.as_ref());
+            if detected == Backend::None {
+                warn!("Not switching layouts: {}", reason);
+            } else {
+                info!("Detected the {} backend: {}", detected.name(), reason);
+            }
+            if detected == Backend::Command && config.command.switch.is_none() {
+                config.command.switch = Some(session::HYPRLAND_SWITCH.to_string());
+            }
+            config.backend = detected;
+        }
+        for name in backend::assign_names(config.backend, &mut config.keyboards) {
+            warn!(
+                "Keyboard '{}' has no engine for the {} backend; its layout_index picks one of the others",
+                name,
+                config.backend.name()
+            );
+        }
+        info!("Configuration: {:?}", config);
+
+        // Set initial mode: the one last set over D-Bus, unless overridden
+        if config.persist_state {
+            if let Some(mode) = state::load() {
+                config.mode = mode;
+            }
+        }
+        if let Some(mode) = self.mode {
+            config.mode = mode;
+        }
+        let initial_grab = config.mode.to_lowercase() != "passive";
+        GRAB_MODE.store(initial_grab, Ordering::SeqCst);
+        info!(
+            "Initial mode: {}",
+            if initial_grab { "grab" } else { "passive" }
+        );
+
+        if !config.backend.is_available() {
+            let e = format!(
+                "this build has no {} backend (enable the \"{}\" cargo feature)",
+                config.backend.name(),
+                config.backend.name()
+            );
+            error!("Cannot start: {}", e);
+            return Err(e.into());
+        }
+        if !config.injection.is_available() {
+            let e = format!(
+                "this build has no {} injection (enable the \"{}\" cargo feature)",
+                config.injection.name(),
+                config.injection.name()
+            );
+            error!("Cannot start: {}", e);
+            return Err(e.into());
+        }
+        if let Err(e) = backend::configure(&config) {
+            error!("Cannot start: {}", e);
+            return Err(e.into());
+        }
+        backend::select(config.backend, self.switcher);
+        set_confirm_timing(&config);
+        info!("Layout backend: {}", config.backend.name());
+
+        if let Some(tag) = &config.source_tag {
+            match parse_source_tag(tag) {
+                Some(code) => info!("Tagging forwarded events with EV_MSC code {}", code.0),
+                None => warn!("Invalid source_tag '{}' - events will not be tagged", tag),
+            }
+        }
+
+        // Check permissions up front and report everything that is missing at once
+        let mut problems = preflight::check(initial_grab, config.injection == Injection::Uinput);
+        let dbus_conn = backend::connect(&config.backend_bus);
+        if let Err(e) = &dbus_conn {
+            problems.push(preflight::bus_problem(&config.backend_bus, e));
+        }
+        preflight::report("Permission check", &problems);
+        if let Some(reason) = preflight::passive_only(config.injection == Injection::Uinput) {
+            warn!("Running passive only, keyboards are never grabbed: {}", reason);
+            let _ = PASSIVE_ONLY.set(reason);
+        }
+
+        // Set up D-Bus connection for layout switching
+        let dbus_conn = Arc::new(dbus_conn?);
+        if config.backend_bus != "session" {
+            info!("Backend bus: {}", config.backend_bus);
+        }
+
+        // Refuse to run a backend that can't work in this session
+        if let Err(e) = session::check_backend(&dbus_conn, backend::active().name()) {
+            error!("Cannot start: {}", e);
+            return Err(e.into());
+        }
+        let current = get_current_layout(&dbus_conn).unwrap_or(0);
+        set_current_layout(current);
+        layouts::resolve(&dbus_conn, &config.layout_display);
+        // IBus keyboards were already given indices by their engine
+        if config.backend != Backend::Ibus {
+            layouts::resolve_indices(&dbus_conn, &mut config.keyboards);
+        }
+        let config = Arc::new(config);
+        set_current_config(Arc::clone(&config));
+        info!(
+            "Current layout: {} (index {})",
+            layout_label(current, "unknown"),
+            current
+        );
+
+        // Catch broken layout mappings now rather than on the first keystroke
+        if config.validate_layouts {
+            preflight::report("Layout check", &preflight::check_layouts(&dbus_conn, &config, Some(current)));
+        }
+
+        // KDE settings that would undo the daemon's switches
+        #[cfg(feature = "kde")]
+        let kde_fixed = backend::active() == backend::Backend::Kde && {
+            let problems = kxkb::check();
+            preflight::report("KDE settings check", &problems);
+            let fixed = config.fix_kde_conflicts && !problems.is_empty() && kxkb::fix(&dbus_conn);
+            if fixed {
+                kxkb::check();
+            }
+            fixed
+        };
+
+        // Shared state for active keyboard monitors (for hot-plug support)
+        let monitors: ActiveMonitors = Arc::new(std::sync::Mutex::new(HashMap::new()));
+
+        // Active keyboard changes flow from monitor threads to the context watcher
+        let (context_tx, context_rx) = mpsc::unbounded_channel();
+        // Layouts switched to by local keyboards, for peers to follow
+        let (peer_tx, _) = broadcast::channel(16);
+
+        sound::configure(&config.sound);
+
+        // Single worker applies (coalesced) layout switches
+        let switch_queue = Arc::new(SwitchQueue::default());
+        switcher::spawn_worker(
+            Arc::clone(&switch_queue),
+            Arc::clone(&dbus_conn),
+            peer_tx.clone(),
+        );
+
+        // Global modifier state, published over D-Bus
+        let (modifiers_tx, modifiers_rx) = watch::channel(Modifiers::NONE);
+        // Per-keyboard D-Bus actions, run on the async runtime
+        let (action_tx, action_rx) = mpsc::unbounded_channel();
+        // Connect summaries, logged and signalled from the async runtime
+        let (connected_tx, connected_rx) = mpsc::unbounded_channel();
+
+        let handles = MonitorHandles {
+            config: Arc::clone(&config),
+            context_tx,
+            switcher: switch_queue,
+            filters: Arc::new(self.filters),
+            modifiers_tx,
+            action_tx,
+            connected_tx,
+        };
+
+        // Find and start monitoring initially connected keyboards
+        let keyboards = find_keyboards(&config, true);
+
+        // Don't leave a stale layout until the expected keyboard's first key
+        if let Some(kb) = startup_keyboard(&config, &keyboards) {
+            if kb.layout_index != current {
+                info!(
+                    "Setting layout {} (index {}) for '{}' at startup",
+                    layout_label(kb.layout_index, &kb.layout_name),
+                    kb.layout_index,
+                    kb.name
+                );
+                handles.switcher.submit(kb.layout_index);
+            }
+        }
+
+        if keyboards.is_empty() {
+            warn!("No configured keyboards found at startup.");
+            warn!("Available input devices:");
+            for entry in std::fs::read_dir("/dev/input")?.flatten() {
+                let path = entry.path();
+                if path.to_string_lossy().contains("event") && !is_ignored_node(&path, &config) {
+                    if let Ok(device) = Device::open(&path) {
+                        if device.supported_events().contains(EventType::KEY) && !is_ignored(&device, &config) {
+                            warn!("  {:?}: {}", path, device.name().unwrap_or("Unknown"));
+                        }
+                    }
+                }
+            }
+            if detects_new_keyboards(&config) {
+                warn!("Hot-plug detection is active - connect a configured keyboard.");
+            }
+        } else {
+            // Spawn monitors for initially connected keyboards
+            for (path, (name, kb_config)) in keyboards {
+                info!(
+                    "Found keyboard '{}' at {:?} -> {} (index {})",
+                    name, path, kb_config.layout_name, kb_config.layout_index
+                );
+                spawn_keyboard_monitor(
+                    path,
+                    name,
+                    kb_config,
+                    handles.clone(),
+                    &monitors,
+                );
+            }
+        }
+
+        // Start D-Bus service and hot-plug monitor in async runtime
+        let config_for_udev = Arc::clone(&config);
+        let monitors_for_udev = Arc::clone(&monitors);
+
+        thread::spawn(move || {
+            let runtime_cpus = config_for_udev.threads.runtime_cpus.clone();
+            affinity::pin_current_thread(&runtime_cpus);
+
+            let mut builder = match config_for_udev.threads.runtime_threads {
+                Some(workers) if workers > 0 => {
+                    let mut builder = tokio::runtime::Builder::new_multi_thread();
+                    builder
+                        .worker_threads(workers)
+                        .on_thread_start(move || affinity::pin_current_thread(&runtime_cpus));
+                    builder
+                }
+                _ => tokio::runtime::Builder::new_current_thread(),
+            };
+            let rt = builder.enable_all().build().unwrap();
+
+            rt.block_on(async {
+                // Start D-Bus service on the connection owning the name
+                let conn = control_conn.inner().clone();
+                conn.object_server()
+                    .at(
+                        "/org/kblayout/Daemon",
+                        DaemonControl {
+                            started,
+                            monitors: Arc::clone(&monitors_for_udev),
+                        },
+                    )
+                    .await
+                    .unwrap();
+
+                info!("D-Bus service started at org.kblayout.Daemon");
+
+                tokio::spawn(run_context_watcher(
+                    Arc::clone(&config_for_udev),
+                    conn.clone(),
+                    context_rx,
+                ));
+
+                tokio::spawn(run_modifiers_watcher(conn.clone(), modifiers_rx));
+                tokio::spawn(run_layout_watcher(conn.clone()));
+                tokio::spawn(run_lock_watcher(conn.clone()));
+                tokio::spawn(run_connect_watcher(conn.clone(), connected_rx));
+                tokio::spawn(backend::watch(dbus_conn.inner().clone()));
+                #[cfg(feature = "kde")]
+                if kde_fixed {
+                    tokio::spawn(kxkb::restore_on_exit());
+                }
+                tokio::spawn(action::run(conn.clone(), action_rx));
+
+                if config_for_udev.osk.enabled {
+                    tokio::spawn(osk::run(Arc::clone(&config_for_udev), conn.clone()));
+                }
+                if config_for_udev.capture.enabled {
+                    tokio::spawn(capture::run(conn.clone()));
+                }
+
+                #[cfg(feature = "peer")]
+                if let Some(peer_config) = config_for_udev.peer.clone() {
+                    tokio::spawn(peer::run(
+                        peer_config,
+                        Arc::clone(&handles.switcher),
+                        peer_tx.clone(),
+                    ));
+                }
+
+                tokio::spawn(run_config_reload(
+                    Arc::clone(&dbus_conn),
+                    handles.clone(),
+                    Arc::clone(&monitors_for_udev),
+                ));
+
+                if config_for_udev.rescan_secs > 0 {
+                    tokio::spawn(run_rescan(
+                        Arc::clone(&config_for_udev),
+                        handles.clone(),
+                        Arc::clone(&monitors_for_udev),
+                    ));
+                }
+
+                // Run hot-plug monitor (restarted whenever detection fails)
+                #[cfg(feature = "hotplug")]
+                if config_for_udev.hotplug {
+                    hotplug::run(config_for_udev, handles, monitors_for_udev).await;
+                } else {
+                    info!("Hot-plug detection disabled - using the startup scan only");
+                    set_hotplug_status("disabled");
+                }
+                #[cfg(not(feature = "hotplug"))]
+                set_hotplug_status("disabled");
+
+                // Keep the runtime alive for the D-Bus service
+                std::future::pending::<()>().await;
+            });
+        });
+
+        // Give D-Bus service time to start
+        thread::sleep(Duration::from_millis(100));
+
+        info!("Monitoring keyboards... Press Ctrl+C to stop.");
+        info!("Toggle mode: dbus-send --session --print-reply --dest=org.kblayout.Daemon /org/kblayout/Daemon org.kblayout.Daemon.ToggleMode");
+
+        // Keep main thread alive
+        loop {
+            thread::sleep(Duration::from_secs(3600));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str) -> KeyboardConfig {
+        KeyboardConfig {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn names(entries: Vec<&KeyboardConfig>) -> Vec<&str> {
+        entries.into_iter().map(|kb| kb.name.as_str()).collect()
+    }
+
+    #[test]
+    fn specific_entries_win_over_catch_alls() {
+        let (all, cherry) = (entry("*"), entry("CHERRY"));
+        assert_eq!(names(select_entries(vec![&all, &cherry], None, true)), ["CHERRY"]);
+        assert_eq!(names(select_entries(vec![&all], None, true)), ["*"]);
+    }
+
+    #[test]
+    fn catch_alls_leave_default_only_keyboards_they_miss() {
+        let (all, default) = (entry("*"), entry("fallback"));
+        assert_eq!(names(select_entries(vec![&all], Some(&default), true)), ["*"]);
+        assert_eq!(names(select_entries(Vec::new(), Some(&default), true)), ["fallback"]);
+    }
+
+    #[test]
+    fn only_specific_entries_take_non_physical_devices() {
+        let (all, mouse, default) = (entry("*"), entry("Mouse"), entry("fallback"));
+        assert!(select_entries(vec![&all], Some(&default), false).is_empty());
+        assert_eq!(names(select_entries(vec![&all, &mouse], Some(&default), false)), ["Mouse"]);
+    }
+
+    // Two identical keyboards: same name, different event nodes
+    const OWN: &str = "/dev/input/event5";
+    const OTHER: &str = "/dev/input/event6";
+
+    fn typed_on(path: &str) {
+        note_activity(Path::new(path), "Keychron K2", &mpsc::unbounded_channel().0);
+    }
+
+    fn reset_gates() {
+        *LAYOUT_HOLD.lock().unwrap() = None;
+        OSK_ACTIVE.store(false, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn delayed_switch_is_due_after_its_delay() {
+        let (clock, _guard) = clock::simulated();
+        let delayed = DelayedSwitch::new(300, 2);
+        assert!(!delayed.is_due());
+        clock.advance(Duration::from_millis(299));
+        assert!(!delayed.is_due());
+        assert!(delayed.wait() <= Duration::from_millis(1));
+        clock.advance(Duration::from_millis(1));
+        assert!(delayed.is_due());
+        assert_eq!(delayed.wait(), Duration::ZERO);
+    }
+
+    #[test]
+    fn typing_on_an_identical_keyboard_cancels_a_delayed_switch() {
+        let (_clock, _guard) = clock::simulated();
+        reset_gates();
+        typed_on(OWN);
+        assert_eq!(delayed_switch_blocked(Path::new(OWN), false, 0), None);
+        typed_on(OTHER);
+        assert!(delayed_switch_blocked(Path::new(OWN), false, 0).is_some());
+        assert_eq!(delayed_switch_blocked(Path::new(OTHER), false, 0), None);
+    }
+
+    #[test]
+    fn delayed_switch_checks_the_gates_again() {
+        let (clock, _guard) = clock::simulated();
+        reset_gates();
+        typed_on(OWN);
+        assert!(delayed_switch_blocked(Path::new(OWN), true, 0).is_some());
+
+        OSK_ACTIVE.store(true, Ordering::SeqCst);
+        assert!(delayed_switch_blocked(Path::new(OWN), false, 0).is_some());
+        OSK_ACTIVE.store(false, Ordering::SeqCst);
+
+        // A hold taken during the delay blocks the switch until it expires
+        renew_hold(Path::new(OTHER), 500);
+        assert!(delayed_switch_blocked(Path::new(OWN), false, 0).is_some());
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(delayed_switch_blocked(Path::new(OWN), false, 0), None);
+    }
+}
@@ -0,0 +1,805 @@
+// Pure event-processing stages of the input path. Everything here runs on
+// every batch read from a physical keyboard, so it must never panic on
+// malformed input - see the fuzz targets in fuzz/.
+
+use evdev::{AttributeSetRef, EventType, InputEvent, InputEventKind, Key, LedType};
+use serde::Deserialize;
+use std::collections::{HashSet, VecDeque};
+
+/// Key codes currently held down on one device.
+///
+/// Tracking actual presses lets the daemon release only keys that are down
+/// when the grab state changes, instead of spraying releases for every key
+/// (a spurious Meta release opens the KDE launcher).
+#[derive(Debug, Default, Clone)]
+pub struct PressedKeys {
+    keys: HashSet<u16>,
+}
+
+impl PressedKeys {
+    pub fn new() -> Self {
+        PressedKeys::default()
+    }
+
+    /// Update the tracker from a batch of events. Returns the number of key
+    /// presses in the batch (repeats don't count).
+    pub fn process(&mut self, events: &[InputEvent]) -> usize {
+        let mut presses = 0;
+        for ev in events {
+            if let InputEventKind::Key(key) = ev.kind() {
+                match ev.value() {
+                    1 => {
+                        self.keys.insert(key.code());
+                        presses += 1;
+                    }
+                    0 => {
+                        self.keys.remove(&key.code());
+                    }
+                    _ => {} // Key repeat (value=2) - ignore for tracking
+                }
+            }
+        }
+        presses
+    }
+
+    pub fn contains(&self, code: u16) -> bool {
+        self.keys.contains(&code)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Release events for every key currently held down.
+    pub fn release_events(&self) -> Vec<InputEvent> {
+        self.keys
+            .iter()
+            .map(|&code| InputEvent::new(EventType::KEY, code, 0))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    /// Bring the tracker in line with the keys the kernel reports as held,
+    /// e.g. after SYN_DROPPED. Returns the corrective releases and presses.
+    pub fn resync(&mut self, held: &[u16]) -> Vec<InputEvent> {
+        let mut fixes = self.retain_held(|code| held.contains(&code));
+        for &code in held {
+            if self.keys.insert(code) {
+                fixes.push(InputEvent::new(EventType::KEY, code, 1));
+            }
+        }
+        fixes
+    }
+
+    /// Forget keys for which `is_held` is false, e.g. after a reconnect, and
+    /// return release events for them.
+    pub fn retain_held(&mut self, is_held: impl Fn(u16) -> bool) -> Vec<InputEvent> {
+        let released: Vec<u16> = self.keys.iter().copied().filter(|&code| !is_held(code)).collect();
+        for code in &released {
+            self.keys.remove(code);
+        }
+        released
+            .into_iter()
+            .map(|code| InputEvent::new(EventType::KEY, code, 0))
+            .collect()
+    }
+
+    /// Modifiers among the keys currently held down.
+    pub fn modifiers(&self) -> Modifiers {
+        self.keys
+            .iter()
+            .fold(Modifiers::NONE, |mods, &code| mods | Modifiers::from_key(code))
+    }
+}
+
+/// Set of held modifiers, left and right variants merged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const SHIFT: Modifiers = Modifiers(1);
+    pub const CTRL: Modifiers = Modifiers(2);
+    pub const ALT: Modifiers = Modifiers(4);
+    pub const ALTGR: Modifiers = Modifiers(8);
+    pub const META: Modifiers = Modifiers(16);
+
+    const NAMES: [(Modifiers, &'static str); 5] = [
+        (Modifiers::SHIFT, "Shift"),
+        (Modifiers::CTRL, "Ctrl"),
+        (Modifiers::ALT, "Alt"),
+        (Modifiers::ALTGR, "AltGr"),
+        (Modifiers::META, "Meta"),
+    ];
+
+    /// The modifier a key code belongs to, if any.
+    pub fn from_key(code: u16) -> Modifiers {
+        match Key::new(code) {
+            Key::KEY_LEFTSHIFT | Key::KEY_RIGHTSHIFT => Modifiers::SHIFT,
+            Key::KEY_LEFTCTRL | Key::KEY_RIGHTCTRL => Modifiers::CTRL,
+            Key::KEY_LEFTALT => Modifiers::ALT,
+            Key::KEY_RIGHTALT => Modifiers::ALTGR,
+            Key::KEY_LEFTMETA | Key::KEY_RIGHTMETA => Modifiers::META,
+            _ => Modifiers::NONE,
+        }
+    }
+
+    pub fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Names of the held modifiers, in a fixed order (Shift, Ctrl, Alt,
+    /// AltGr, Meta).
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(m, _)| self.contains(*m))
+            .map(|&(_, name)| name)
+            .collect()
+    }
+}
+
+impl std::ops::BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, rhs: Modifiers) -> Modifiers {
+        Modifiers(self.0 | rhs.0)
+    }
+}
+
+/// Caps, Num and Scroll Lock of one logical keyboard, toggled by presses of
+/// their keys. A grabbed keyboard gets no LED updates from the system and a
+/// reconnected one comes back with its LEDs off, so the daemon sets the
+/// LEDs from this state.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LockState {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+impl LockState {
+    /// The state the device's LEDs show.
+    pub fn from_leds(leds: &AttributeSetRef<LedType>) -> Self {
+        LockState {
+            caps: leds.contains(LedType::LED_CAPSL),
+            num: leds.contains(LedType::LED_NUML),
+            scroll: leds.contains(LedType::LED_SCROLLL),
+        }
+    }
+
+    /// Toggle locks for the lock key presses in the batch. Returns whether
+    /// the state changed.
+    pub fn process(&mut self, events: &[InputEvent]) -> bool {
+        let before = *self;
+        for ev in events.iter().filter(|ev| ev.value() == 1) {
+            match ev.kind() {
+                InputEventKind::Key(Key::KEY_CAPSLOCK) => self.caps = !self.caps,
+                InputEventKind::Key(Key::KEY_NUMLOCK) => self.num = !self.num,
+                InputEventKind::Key(Key::KEY_SCROLLLOCK) => self.scroll = !self.scroll,
+                _ => {}
+            }
+        }
+        *self != before
+    }
+
+    /// Events setting a device's LEDs to this state, as one frame.
+    pub fn led_events(&self) -> Vec<InputEvent> {
+        vec![
+            InputEvent::new(EventType::LED, LedType::LED_CAPSL.0, self.caps as i32),
+            InputEvent::new(EventType::LED, LedType::LED_NUML.0, self.num as i32),
+            InputEvent::new(EventType::LED, LedType::LED_SCROLLL.0, self.scroll as i32),
+            InputEvent::new(EventType::SYNCHRONIZATION, 0, 0),
+        ]
+    }
+}
+
+/// A hotkey taken out of a keyboard's input: a key pressed while the given
+/// modifiers are held. The hotkey's press, repeats and release don't reach
+/// the system; the key still types when pressed without the modifiers.
+#[derive(Debug, Clone)]
+pub struct Hotkey {
+    key: Key,
+    modifiers: Modifiers,
+    // The press was taken, so its repeats and release are too
+    taken: bool,
+}
+
+impl Hotkey {
+    pub fn new(modifiers: Modifiers, key: Key) -> Self {
+        Hotkey {
+            key,
+            modifiers,
+            taken: false,
+        }
+    }
+
+    /// Remove the hotkey's events from the batch. `held` are the modifiers
+    /// held before the batch. Returns the number of hotkey presses.
+    pub fn take(&mut self, events: &mut Vec<InputEvent>, held: Modifiers) -> usize {
+        let mut modifiers = held;
+        let mut presses = 0;
+        events.retain(|ev| {
+            let InputEventKind::Key(key) = ev.kind() else {
+                return true;
+            };
+            if key != self.key {
+                if ev.value() == 1 {
+                    modifiers = modifiers | Modifiers::from_key(key.code());
+                }
+                return true;
+            }
+            match ev.value() {
+                1 if modifiers.contains(self.modifiers) => {
+                    self.taken = true;
+                    presses += 1;
+                    false
+                }
+                1 => {
+                    self.taken = false;
+                    true
+                }
+                0 => !std::mem::take(&mut self.taken),
+                _ => !self.taken,
+            }
+        });
+        presses
+    }
+}
+
+/// Which key event values count as activity on a keyboard, i.e. can switch
+/// the layout, renew a hold or change the active keyboard. Configured as a
+/// list of `"press"`, `"repeat"` and `"release"`; presses only by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+pub struct Activity {
+    pub press: bool,
+    pub repeat: bool,
+    pub release: bool,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity {
+            press: true,
+            repeat: false,
+            release: false,
+        }
+    }
+}
+
+impl TryFrom<Vec<String>> for Activity {
+    type Error = String;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        let mut activity = Activity {
+            press: false,
+            repeat: false,
+            release: false,
+        };
+        for name in &names {
+            match name.as_str() {
+                "press" => activity.press = true,
+                "repeat" => activity.repeat = true,
+                "release" => activity.release = true,
+                other => {
+                    return Err(format!(
+                        "unknown activity '{}' (expected press, repeat or release)",
+                        other
+                    ))
+                }
+            }
+        }
+        Ok(activity)
+    }
+}
+
+impl Activity {
+    /// Number of key events in the batch that count as activity.
+    pub fn count(&self, events: &[InputEvent]) -> usize {
+        self.count_except(events, |_| false)
+    }
+
+    /// Number of key events in the batch that count as activity, leaving
+    /// out the keys `ignored` says to.
+    pub fn count_except(&self, events: &[InputEvent], ignored: impl Fn(Key) -> bool) -> usize {
+        events
+            .iter()
+            .filter(|ev| matches!(ev.kind(), InputEventKind::Key(key) if !ignored(key)))
+            .filter(|ev| match ev.value() {
+                1 => self.press,
+                2 => self.repeat,
+                0 => self.release,
+                _ => false,
+            })
+            .count()
+    }
+}
+
+/// True for the SYN_REPORT marker that terminates a frame of events.
+pub fn is_syn_report(ev: &InputEvent) -> bool {
+    ev.event_type() == EventType::SYNCHRONIZATION && ev.code() == 0
+}
+
+/// True for SYN_DROPPED: the kernel's buffer overflowed and events were lost.
+pub fn is_syn_dropped(ev: &InputEvent) -> bool {
+    ev.event_type() == EventType::SYNCHRONIZATION && ev.code() == 3
+}
+
+/// Remove events invalidated by SYN_DROPPED: everything from the marker up
+/// to and including the next SYN_REPORT, which may arrive in a later batch
+/// (`dropping` carries that across calls). Returns true if a SYN_DROPPED was
+/// seen, in which case the caller must resync its state from the device.
+pub fn drop_corrupted(events: &mut Vec<InputEvent>, dropping: &mut bool) -> bool {
+    let mut seen = false;
+    events.retain(|ev| {
+        if is_syn_dropped(ev) {
+            seen = true;
+            *dropping = true;
+            return false;
+        }
+        if *dropping {
+            if is_syn_report(ev) {
+                *dropping = false;
+            }
+            return false;
+        }
+        true
+    });
+    seen
+}
+
+/// Split a stream of events into frames at SYN_REPORT boundaries.
+///
+/// The SYN_REPORT markers themselves are dropped, since the uinput writer
+/// terminates each frame it emits. Empty frames are skipped, and trailing
+/// events without a final SYN_REPORT form the last frame.
+pub fn split_frames(events: &[InputEvent]) -> impl Iterator<Item = &[InputEvent]> {
+    events
+        .split(is_syn_report)
+        .filter(|frame| !frame.is_empty())
+}
+
+fn is_key_value(ev: &InputEvent, value: i32) -> bool {
+    ev.event_type() == EventType::KEY && ev.value() == value
+}
+
+/// True for a frame that only carries key repeats (plus side events such as
+/// MSC_SCAN), i.e. one that can be dropped without losing state.
+pub fn is_repeat_frame(frame: &[InputEvent]) -> bool {
+    frame.iter().any(|ev| is_key_value(ev, 2))
+        && !frame.iter().any(|ev| {
+            is_key_value(ev, 0) || is_key_value(ev, 1) || ev.event_type() == EventType::RELATIVE
+        })
+}
+
+/// True if the frame releases any key.
+pub fn has_key_release(frame: &[InputEvent]) -> bool {
+    frame.iter().any(|ev| is_key_value(ev, 0))
+}
+
+/// Per-device ceiling on key events per second, against failing hardware
+/// flooding the session with phantom keys. Presses and releases count;
+/// repeats don't, they are fast by design. Time is passed in so the limiter
+/// stays pure.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    // Events per second; 0 disables the limiter
+    limit: u32,
+    window_start_ms: u64,
+    count: u32,
+    flooding: bool,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32) -> Self {
+        RateLimiter {
+            limit,
+            ..RateLimiter::default()
+        }
+    }
+
+    /// Account a batch read at `now_ms`. Returns true while the device is
+    /// flooding: from the batch that crosses the limit until a whole
+    /// one-second window stays under it.
+    pub fn check(&mut self, events: &[InputEvent], now_ms: u64) -> bool {
+        if self.limit == 0 {
+            return false;
+        }
+        let elapsed = now_ms.saturating_sub(self.window_start_ms);
+        if elapsed >= 1000 {
+            // A calm window (or a silent one in between) ends the flood
+            if self.count <= self.limit || elapsed >= 2000 {
+                self.flooding = false;
+            }
+            self.window_start_ms = now_ms;
+            self.count = 0;
+        }
+        let keys = events.iter().filter(|ev| is_key_value(ev, 0) || is_key_value(ev, 1)).count();
+        self.count = self.count.saturating_add(keys as u32);
+        if self.count > self.limit {
+            self.flooding = true;
+        }
+        self.flooding
+    }
+}
+
+/// Per-device typing-burst detector: a keyboard only takes the layout once
+/// it produced `keys` activity events within `window_ms`, so a bumped key or
+/// a lone shortcut on a secondary keyboard doesn't switch. Time is passed
+/// in, as for RateLimiter.
+#[derive(Debug, Default)]
+pub struct BurstDetector {
+    // Events needed; 0 or 1 makes every event a burst
+    keys: u32,
+    window_ms: u64,
+    // Times of the latest events, at most `keys` of them
+    times: VecDeque<u64>,
+}
+
+impl BurstDetector {
+    pub fn new(keys: u32, window_ms: u64) -> Self {
+        BurstDetector {
+            keys,
+            window_ms,
+            times: VecDeque::new(),
+        }
+    }
+
+    /// Record `count` activity events at `now_ms`. Returns whether the
+    /// device is in a burst.
+    pub fn check(&mut self, count: u32, now_ms: u64) -> bool {
+        if self.keys <= 1 {
+            return count > 0;
+        }
+        let keys = self.keys as usize;
+        for _ in 0..count.min(self.keys) {
+            self.times.push_back(now_ms);
+        }
+        while self.times.len() > keys {
+            self.times.pop_front();
+        }
+        let since = now_ms.saturating_sub(self.window_ms);
+        while self.times.front().is_some_and(|&time| time < since) {
+            self.times.pop_front();
+        }
+        self.times.len() >= keys
+    }
+}
+
+/// Reduce a locked keyboard's batch to what may still reach the system:
+/// events of the `passthrough` keys, and releases of keys `pressed` before
+/// the batch (so a key held when the lock began doesn't stay stuck). SYN
+/// events are kept for framing; a batch left without key events is cleared.
+pub fn retain_passthrough(events: &mut Vec<InputEvent>, pressed: &PressedKeys, passthrough: &[u16]) {
+    events.retain(|ev| match ev.kind() {
+        InputEventKind::Synchronization(_) => true,
+        InputEventKind::Key(key) => {
+            passthrough.contains(&key.code()) || (ev.value() == 0 && pressed.contains(key.code()))
+        }
+        _ => false,
+    });
+    if !events.iter().any(|ev| ev.event_type() == EventType::KEY) {
+        events.clear();
+    }
+}
+
+/// One key remapping: `from` is sent as `to`, only while `layer` is held
+/// when set. A layer key is held back until it is clear whether it is used
+/// as a layer, see `Remap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemapRule {
+    pub layer: Option<u16>,
+    pub from: u16,
+    pub to: u16,
+}
+
+// What a held layer key turned out to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layer {
+    // Nothing pressed with it yet
+    Pending,
+    // One of its rules fired, so the key itself is never sent
+    Used,
+    // A key without a rule in the layer was pressed with it, so it was sent
+    // as itself (AltGr typing '@') and remaps nothing until released
+    Forwarded,
+}
+
+/// Per-device key remapping. A key keeps the code it was pressed with
+/// until it is released, so releasing the layer key first can't leave the
+/// remapped key stuck. A layer key still works as itself: tapped alone, or
+/// held with a key its layer doesn't remap, it is sent after all.
+#[derive(Debug, Default)]
+pub struct Remap {
+    rules: Vec<RemapRule>,
+    held_layers: Vec<(u16, Layer)>,
+    // Pressed key -> code it was sent as (itself when not remapped)
+    active: Vec<(u16, u16)>,
+}
+
+impl Remap {
+    pub fn new(rules: Vec<RemapRule>) -> Self {
+        Remap {
+            rules,
+            ..Remap::default()
+        }
+    }
+
+    /// Switch to new rules; keys held now are still released as they were
+    /// sent.
+    pub fn set_rules(&mut self, rules: Vec<RemapRule>) {
+        self.rules = rules;
+    }
+
+    fn is_layer(&self, code: u16) -> bool {
+        self.rules.iter().any(|rule| rule.layer == Some(code))
+            || self.held_layers.iter().any(|&(layer, _)| layer == code)
+    }
+
+    // Target for a press of `code` and the layer providing it: a held
+    // layer's rule wins over a plain one
+    fn target(&self, code: u16) -> Option<(u16, Option<u16>)> {
+        let remaps = |layer: u16| {
+            self.held_layers
+                .iter()
+                .any(|&(held, state)| held == layer && state != Layer::Forwarded)
+        };
+        let layered = self
+            .rules
+            .iter()
+            .find(|rule| rule.from == code && rule.layer.is_some_and(remaps));
+        layered
+            .or_else(|| self.rules.iter().find(|rule| rule.from == code && rule.layer.is_none()))
+            .map(|rule| (rule.to, rule.layer))
+    }
+
+    // A press of a key that isn't a layer key settles the pending layers:
+    // the one remapping it is used, the others are sent. Modifiers leave
+    // them pending, so AltGr+Shift+q still types
+    fn settle_layers(&mut self, code: u16, layer: Option<u16>, out: &mut Vec<InputEvent>) {
+        if !Modifiers::from_key(code).is_empty() && layer.is_none() {
+            return;
+        }
+        for (held, state) in self.held_layers.iter_mut().filter(|(_, state)| *state == Layer::Pending) {
+            if Some(*held) == layer {
+                *state = Layer::Used;
+            } else {
+                out.push(InputEvent::new(EventType::KEY, *held, 1));
+                *state = Layer::Forwarded;
+            }
+        }
+    }
+
+    fn layer_event(&mut self, code: u16, ev: InputEvent, out: &mut Vec<InputEvent>) {
+        let state = self.held_layers.iter().find(|&&(held, _)| held == code).map(|&(_, state)| state);
+        match (ev.value(), state) {
+            (1, None) => self.held_layers.push((code, Layer::Pending)),
+            (0, Some(state)) => {
+                self.held_layers.retain(|&(held, _)| held != code);
+                match state {
+                    // Tapped on its own
+                    Layer::Pending => {
+                        out.push(InputEvent::new(EventType::KEY, code, 1));
+                        out.push(ev);
+                    }
+                    Layer::Forwarded => out.push(ev),
+                    Layer::Used => {}
+                }
+            }
+            // Repeats of a key that was sent, and a release of one pressed
+            // before it became a layer key
+            (2, Some(Layer::Forwarded)) | (0, None) => out.push(ev),
+            _ => {}
+        }
+    }
+
+    /// Forget keys for which `is_held` is false, e.g. after a reconnect, and
+    /// return releases of the codes they were sent as. A layer key that was
+    /// never sent is dropped without its tap.
+    pub fn retain_held(&mut self, is_held: impl Fn(u16) -> bool) -> Vec<InputEvent> {
+        let mut releases = Vec::new();
+        self.held_layers.retain(|&(code, state)| {
+            if !is_held(code) && state == Layer::Forwarded {
+                releases.push(InputEvent::new(EventType::KEY, code, 0));
+            }
+            is_held(code)
+        });
+        self.active.retain(|&(code, to)| {
+            if !is_held(code) {
+                releases.push(InputEvent::new(EventType::KEY, to, 0));
+            }
+            is_held(code)
+        });
+        releases
+    }
+
+    /// Bring the held keys in line with the physical keys the kernel reports
+    /// as held, e.g. after SYN_DROPPED. Returns the corrective releases and
+    /// presses as they are sent.
+    pub fn resync(&mut self, held: &[u16]) -> Vec<InputEvent> {
+        let mut fixes = self.retain_held(|code| held.contains(&code));
+        let mut presses: Vec<InputEvent> = held
+            .iter()
+            .filter(|&&code| {
+                !self.active.iter().any(|&(source, _)| source == code)
+                    && !self.held_layers.iter().any(|&(layer, _)| layer == code)
+            })
+            .map(|&code| InputEvent::new(EventType::KEY, code, 1))
+            .collect();
+        self.apply(&mut presses);
+        fixes.append(&mut presses);
+        fixes
+    }
+
+    /// Rewrite the key events of a batch in place; layer key events are
+    /// held back until the layer key is released or another key is pressed.
+    pub fn apply(&mut self, events: &mut Vec<InputEvent>) {
+        let mut out = Vec::with_capacity(events.len());
+        for ev in events.drain(..) {
+            let InputEventKind::Key(key) = ev.kind() else {
+                out.push(ev);
+                continue;
+            };
+            let code = key.code();
+            if self.is_layer(code) {
+                self.layer_event(code, ev, &mut out);
+                continue;
+            }
+            let held = self.active.iter().find(|&&(source, _)| source == code).map(|&(_, to)| to);
+            let sent = match (ev.value(), held) {
+                (1, None) => {
+                    let (to, layer) = self.target(code).unwrap_or((code, None));
+                    self.settle_layers(code, layer, &mut out);
+                    self.active.push((code, to));
+                    to
+                }
+                (0, Some(to)) => {
+                    self.active.retain(|&(source, _)| source != code);
+                    to
+                }
+                (_, held) => held.unwrap_or(code),
+            };
+            if sent == code {
+                out.push(ev);
+            } else {
+                out.push(InputEvent::new(EventType::KEY, sent, ev.value()));
+            }
+        }
+        *events = out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const H: u16 = Key::KEY_H.code();
+    const Q: u16 = Key::KEY_Q.code();
+    const ALTGR: u16 = Key::KEY_RIGHTALT.code();
+    const SHIFT: u16 = Key::KEY_LEFTSHIFT.code();
+    const LEFT: u16 = Key::KEY_LEFT.code();
+    const CAPSLOCK: u16 = Key::KEY_CAPSLOCK.code();
+    const ESC: u16 = Key::KEY_ESC.code();
+
+    fn key(code: u16, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY, code, value)
+    }
+
+    fn syn(code: u16) -> InputEvent {
+        InputEvent::new(EventType::SYNCHRONIZATION, code, 0)
+    }
+
+    fn keys(events: &[InputEvent]) -> Vec<(u16, i32)> {
+        events
+            .iter()
+            .filter(|ev| ev.event_type() == EventType::KEY)
+            .map(|ev| (ev.code(), ev.value()))
+            .collect()
+    }
+
+    #[test]
+    fn syn_dropped_block_spans_batches() {
+        let mut dropping = false;
+        let mut first = vec![key(H, 1), syn(0), syn(3), key(Q, 1)];
+        assert!(drop_corrupted(&mut first, &mut dropping));
+        assert_eq!(keys(&first), [(H, 1)]);
+        assert!(dropping);
+
+        // Still dropping up to and including the next SYN_REPORT
+        let mut second = vec![key(SHIFT, 1), syn(0), key(H, 0), syn(0)];
+        assert!(!drop_corrupted(&mut second, &mut dropping));
+        assert_eq!(keys(&second), [(H, 0)]);
+        assert_eq!(second.len(), 2);
+        assert!(!dropping);
+    }
+
+    #[test]
+    fn resync_releases_and_presses_to_match_the_device() {
+        let mut pressed = PressedKeys::new();
+        pressed.process(&[key(H, 1), key(SHIFT, 1)]);
+        let mut fixes = keys(&pressed.resync(&[SHIFT, Q]));
+        fixes.sort();
+        assert_eq!(fixes, [(Q, 1), (H, 0)]);
+        assert!(pressed.contains(SHIFT) && pressed.contains(Q) && !pressed.contains(H));
+        assert!(pressed.resync(&[SHIFT, Q]).is_empty());
+    }
+
+    // ralt+h -> left
+    fn layered() -> Remap {
+        Remap::new(vec![RemapRule {
+            layer: Some(ALTGR),
+            from: H,
+            to: LEFT,
+        }])
+    }
+
+    // Feed key events one batch each, returning what is sent as (code, value)
+    fn feed(remap: &mut Remap, keys: &[(u16, i32)]) -> Vec<(u16, i32)> {
+        let mut sent = Vec::new();
+        for &(code, value) in keys {
+            let mut events = vec![InputEvent::new(EventType::KEY, code, value)];
+            remap.apply(&mut events);
+            sent.extend(events.iter().map(|ev| (ev.code(), ev.value())));
+        }
+        sent
+    }
+
+    #[test]
+    fn layer_rule_swallows_the_layer_key() {
+        let sent = feed(&mut layered(), &[(ALTGR, 1), (H, 1), (H, 0), (ALTGR, 0)]);
+        assert_eq!(sent, [(LEFT, 1), (LEFT, 0)]);
+    }
+
+    #[test]
+    fn layer_key_types_with_keys_it_does_not_remap() {
+        let mut remap = layered();
+        let sent = feed(&mut remap, &[(ALTGR, 1), (SHIFT, 1), (Q, 1), (Q, 0), (SHIFT, 0), (ALTGR, 2), (ALTGR, 0)]);
+        assert_eq!(sent, [(SHIFT, 1), (ALTGR, 1), (Q, 1), (Q, 0), (SHIFT, 0), (ALTGR, 2), (ALTGR, 0)]);
+        // Once sent as itself it no longer remaps, until pressed again
+        let sent = feed(&mut remap, &[(ALTGR, 1), (Q, 1), (H, 1), (H, 0), (Q, 0), (ALTGR, 0)]);
+        assert_eq!(sent, [(ALTGR, 1), (Q, 1), (H, 1), (H, 0), (Q, 0), (ALTGR, 0)]);
+    }
+
+    #[test]
+    fn tapped_layer_key_is_sent_on_release() {
+        let sent = feed(&mut layered(), &[(ALTGR, 1), (ALTGR, 2), (ALTGR, 0)]);
+        assert_eq!(sent, [(ALTGR, 1), (ALTGR, 0)]);
+    }
+
+    #[test]
+    fn resync_releases_keys_as_they_were_sent() {
+        let mut remap = Remap::new(vec![RemapRule {
+            layer: None,
+            from: CAPSLOCK,
+            to: ESC,
+        }]);
+        feed(&mut remap, &[(CAPSLOCK, 1)]);
+        // The release was lost: ESC is released, CAPSLOCK never pressed
+        assert_eq!(keys(&remap.resync(&[Q])), [(ESC, 0), (Q, 1)]);
+        assert_eq!(feed(&mut remap, &[(Q, 0)]), [(Q, 0)]);
+    }
+
+    #[test]
+    fn resync_drops_layers_released_meanwhile() {
+        let mut remap = layered();
+        feed(&mut remap, &[(ALTGR, 1)]);
+        assert!(remap.resync(&[]).is_empty());
+        // ALTGR no longer held, so h is not remapped
+        assert_eq!(feed(&mut remap, &[(H, 1), (H, 0)]), [(H, 1), (H, 0)]);
+
+        feed(&mut remap, &[(ALTGR, 1), (Q, 1)]);
+        assert_eq!(keys(&remap.retain_held(|code| code == Q)), [(ALTGR, 0)]);
+    }
+}
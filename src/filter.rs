@@ -0,0 +1,60 @@
+// Event pipeline extension point for crates embedding the daemon
+
+use evdev::InputEvent;
+use std::path::PathBuf;
+
+/// The physical keyboard a batch of events came from.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    /// Stable device ID, see `inspect::device_id`
+    pub id: String,
+    pub name: String,
+    pub layout_index: u32,
+}
+
+/// Inspects, modifies or drops event batches before the daemon acts on them.
+///
+/// Filters run on each keyboard's monitor thread, after events are read and
+/// before pressed-key tracking, layout switching and (in grab mode)
+/// forwarding to the virtual keyboard. Removing a key press from the batch
+/// therefore also keeps it from triggering a layout switch; clearing the
+/// batch drops it entirely. In passive mode the original events still reach
+/// the system, so changes only affect switching decisions.
+///
+/// Filters are shared by all monitors and must be cheap: they sit directly
+/// in the input path.
+pub trait EventFilter: Send + Sync {
+    fn filter(&self, device: &DeviceInfo, events: &mut Vec<InputEvent>);
+}
+
+impl<F> EventFilter for F
+where
+    F: Fn(&DeviceInfo, &mut Vec<InputEvent>) + Send + Sync,
+{
+    fn filter(&self, device: &DeviceInfo, events: &mut Vec<InputEvent>) {
+        self(device, events)
+    }
+}
+
+/// Ordered list of filters, applied in insertion order.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn EventFilter>>,
+}
+
+impl FilterChain {
+    pub fn push(&mut self, filter: Box<dyn EventFilter>) {
+        self.filters.push(filter);
+    }
+
+    /// Run every filter over the batch, stopping early once it is empty.
+    pub fn apply(&self, device: &DeviceInfo, events: &mut Vec<InputEvent>) {
+        for filter in &self.filters {
+            if events.is_empty() {
+                return;
+            }
+            filter.filter(device, events);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+// FreeBSD hot-plug detection via devd. devd broadcasts device node creation
+// and removal on its pipe as lines such as:
+//   !system=DEVFS subsystem=CDEV type=CREATE cdev=input/event5
+
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tracing::info;
+
+use super::HotplugEvent;
+
+const DEVD_PIPE: &str = "/var/run/devd.pipe";
+
+pub async fn watch(event_tx: mpsc::UnboundedSender<HotplugEvent>) -> std::io::Result<()> {
+    let stream = UnixStream::connect(DEVD_PIPE).await?;
+    let mut lines = BufReader::new(stream).lines();
+
+    info!("devd monitor started - hot-plug detection enabled");
+
+    while let Some(line) = lines.next_line().await? {
+        if let Some(event) = parse_devd_line(&line) {
+            if event_tx.send(event).is_err() {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_devd_line(line: &str) -> Option<HotplugEvent> {
+    // Only notifications ('!') carry key=value pairs
+    let body = line.strip_prefix('!')?;
+
+    let mut system = None;
+    let mut subsystem = None;
+    let mut event_type = None;
+    let mut cdev = None;
+    for pair in body.split_whitespace() {
+        match pair.split_once('=') {
+            Some(("system", v)) => system = Some(v),
+            Some(("subsystem", v)) => subsystem = Some(v),
+            Some(("type", v)) => event_type = Some(v),
+            Some(("cdev", v)) => cdev = Some(v),
+            _ => {}
+        }
+    }
+
+    if system != Some("DEVFS") || subsystem != Some("CDEV") {
+        return None;
+    }
+
+    let devnode = PathBuf::from("/dev").join(cdev?);
+    match event_type? {
+        "CREATE" => Some(HotplugEvent::Added(devnode)),
+        "DESTROY" => Some(HotplugEvent::Removed(devnode)),
+        _ => None,
+    }
+}
@@ -0,0 +1,137 @@
+// Hot-plug detection. Each platform turns its native device notifications
+// (udev on Linux, devd on FreeBSD) into HotplugEvents; matching against the
+// config and spawning/stopping monitors is shared.
+
+use evdev::Device;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::daemon::{
+    current_config, is_ignored_node, match_keyboard_config, reattach_keyboard_monitor, scan_for_new_keyboards,
+    set_hotplug_status, spawn_keyboard_monitor, stop_keyboard_monitor, ActiveMonitors, MonitorHandles,
+};
+
+#[cfg(target_os = "freebsd")]
+mod devd;
+#[cfg(target_os = "linux")]
+mod udev;
+
+#[cfg(target_os = "freebsd")]
+use devd::watch;
+#[cfg(target_os = "linux")]
+use udev::watch;
+
+// Backoff between attempts to restart a failed watcher; a watcher that ran
+// this long before failing starts over at the minimum
+const RESTART_MIN: Duration = Duration::from_secs(1);
+const RESTART_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum HotplugEvent {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+pub async fn run(config: Arc<Config>, handles: MonitorHandles, monitors: ActiveMonitors) {
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+
+    // The udev socket is not Send, so drive the watcher alongside the handler
+    // rather than spawning it. A watcher whose socket dies or stream ends is
+    // restarted with backoff, and a rescan picks up keyboards plugged in
+    // meanwhile
+    let rescan = (handles.clone(), Arc::clone(&monitors));
+    let watcher = async move {
+        let mut backoff = RESTART_MIN;
+        let mut restarting = false;
+        loop {
+            set_hotplug_status("ok");
+            if restarting {
+                let (handles, monitors) = &rescan;
+                scan_for_new_keyboards(handles, monitors, "Hot-plug restart").await;
+            }
+            let started = Instant::now();
+            let reason = match watch(event_tx.clone()).await {
+                Ok(()) if event_tx.is_closed() => return,
+                Ok(()) => "event stream ended".to_string(),
+                Err(e) => e.to_string(),
+            };
+            if started.elapsed() >= RESTART_MAX {
+                backoff = RESTART_MIN;
+            }
+            if restarting {
+                warn!("Hot-plug detection failed again: {}, retrying in {}s", reason, backoff.as_secs());
+            } else {
+                error!("Hot-plug detection failed: {}, restarting in {}s", reason, backoff.as_secs());
+            }
+            set_hotplug_status(&format!("{} (restarting)", reason));
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(RESTART_MAX);
+            restarting = true;
+        }
+    };
+
+    let handler = async move {
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                HotplugEvent::Added(devnode) => {
+                    // Only handle /dev/input/event* devices
+                    if !devnode.to_string_lossy().contains("/dev/input/event") {
+                        continue;
+                    }
+
+                    // Small delay to let device settle
+                    tokio::time::sleep(Duration::from_millis(config.hotplug_settle_ms)).await;
+
+                    if is_ignored_node(&devnode, &current_config()) {
+                        continue;
+                    }
+
+                    // Try to open and check if it matches config
+                    if let Ok(device) = Device::open(&devnode) {
+                        let live = current_config();
+                        if let Some(kb_config) = match_keyboard_config(&device, &devnode, &live) {
+                            let name = device.name().unwrap_or("Unknown").to_string();
+                            // Reconnected within the grace period
+                            if reattach_keyboard_monitor(&devnode, &name, &monitors) {
+                                continue;
+                            }
+                            info!(
+                                "Hot-plug: Found keyboard '{}' at {:?} -> {} (index {})",
+                                name, devnode, kb_config.layout_name, kb_config.layout_index
+                            );
+                            spawn_keyboard_monitor(
+                                devnode,
+                                name,
+                                kb_config.clone(),
+                                handles.clone(),
+                                &monitors,
+                            );
+                        }
+                    }
+                }
+                HotplugEvent::Removed(devnode) => {
+                    // Check if we were monitoring this device
+                    let was_monitored = {
+                        let guard = monitors.lock().unwrap();
+                        guard.contains_key(&devnode)
+                    };
+
+                    // With a grace period the monitor notices the removal
+                    // itself and waits for the keyboard to come back
+                    if was_monitored {
+                        info!("Hot-plug: Device removed at {:?}", devnode);
+                        if config.reconnect_grace_ms == 0 {
+                            stop_keyboard_monitor(&devnode, &monitors);
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::join!(watcher, handler);
+}
@@ -0,0 +1,42 @@
+// Linux hot-plug detection via udev
+
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_udev::{AsyncMonitorSocket, EventType, MonitorBuilder};
+use tracing::{info, warn};
+
+use super::HotplugEvent;
+
+pub async fn watch(event_tx: mpsc::UnboundedSender<HotplugEvent>) -> std::io::Result<()> {
+    let socket = MonitorBuilder::new()?.match_subsystem("input")?.listen()?;
+    let mut async_monitor = AsyncMonitorSocket::new(socket)?;
+
+    info!("Udev monitor started - hot-plug detection enabled");
+
+    while let Some(event) = async_monitor.next().await {
+        let event = match event {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Udev event error: {}", e);
+                continue;
+            }
+        };
+
+        let devnode = match event.devnode() {
+            Some(p) => p.to_path_buf(),
+            None => continue,
+        };
+
+        let hotplug_event = match event.event_type() {
+            EventType::Add | EventType::Bind => HotplugEvent::Added(devnode),
+            EventType::Remove | EventType::Unbind => HotplugEvent::Removed(devnode),
+            _ => continue,
+        };
+
+        if event_tx.send(hotplug_event).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
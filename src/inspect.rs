@@ -0,0 +1,100 @@
+// Capability dump for `kb-layout-daemon inspect <device>`: everything the
+// kernel reports about an input device, as JSON. Meant for writing match
+// rules and for attaching to bug reports.
+
+use evdev::{AttributeSetRef, Device, EvdevEnum};
+use serde_json::{json, Value};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, KeyboardConfig};
+
+// Persistent per-device links maintained by udev
+const BY_ID: &str = "/dev/input/by-id";
+
+// Names of the codes in a capability set, e.g. ["KEY_A", "KEY_B"]
+fn names<T: EvdevEnum + Debug>(set: Option<&AttributeSetRef<T>>) -> Value {
+    set.map(|set| set.iter().map(|code| format!("{:?}", code)).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into()
+}
+
+/// The config entries that pick up a device, exactly as the daemon matches
+/// them: `[[keyboards]]` entries in file order, or the `[default]` entry for
+/// a keyboard none of them takes. Empty for devices the daemon leaves alone.
+pub fn matching_entries<'a>(device: &Device, path: &Path, config: &'a Config) -> Vec<&'a KeyboardConfig> {
+    crate::daemon::matching_keyboard_configs(device, path, config)
+}
+
+/// Stable identifier of a physical device: a hash of vendor, product, uniq
+/// (serial or MAC) and phys (port), so it survives reconnects and event
+/// node renumbering. 16 hex digits; FNV-1a keeps it stable across builds.
+pub fn device_id(device: &Device) -> String {
+    let id = device.input_id();
+    let key = format!(
+        "{:04x}:{:04x}:{}:{}",
+        id.vendor(),
+        id.product(),
+        device.unique_name().unwrap_or(""),
+        device.physical_path().unwrap_or("")
+    );
+    let hash = key
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    format!("{:016x}", hash)
+}
+
+/// Event node a /dev/input/by-id link (its name, or a full path) points to
+/// at the moment.
+pub fn resolve_by_id(link: &str) -> Option<PathBuf> {
+    Path::new(BY_ID).join(link).canonicalize().ok()
+}
+
+// Names of the /dev/input/by-id links pointing to `path`
+fn by_id_links(path: &Path) -> Vec<String> {
+    let Ok(node) = path.canonicalize() else {
+        return Vec::new();
+    };
+    let mut links: Vec<String> = std::fs::read_dir(BY_ID)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().canonicalize().is_ok_and(|target| target == node))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    links.sort();
+    links
+}
+
+/// Describe the device at `path` as JSON.
+pub fn device_json(path: &Path) -> std::io::Result<Value> {
+    let device = Device::open(path)?;
+    let id = device.input_id();
+    let (major, minor, patch) = device.driver_version();
+
+    Ok(json!({
+        "path": path,
+        "id": device_id(&device),
+        "by_id": by_id_links(path),
+        "name": device.name(),
+        "phys": device.physical_path(),
+        "uniq": device.unique_name(),
+        "input_id": {
+            "bus_type": format!("{:?}", id.bus_type()),
+            "vendor": format!("{:04x}", id.vendor()),
+            "product": format!("{:04x}", id.product()),
+            "version": format!("{:04x}", id.version()),
+        },
+        "driver_version": format!("{}.{}.{}", major, minor, patch),
+        "event_types": names(Some(device.supported_events())),
+        "properties": names(Some(device.properties())),
+        "keys": names(device.supported_keys()),
+        "relative_axes": names(device.supported_relative_axes()),
+        "absolute_axes": names(device.supported_absolute_axes()),
+        "switches": names(device.supported_switches()),
+        "leds": names(device.supported_leds()),
+        "misc": names(device.misc_properties()),
+        "force_feedback": names(device.supported_ff()),
+        "sounds": names(device.supported_sounds()),
+    }))
+}
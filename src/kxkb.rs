@@ -0,0 +1,211 @@
+// KDE keyboard settings that fight the daemon. Plasma can remember a layout
+// per window, application or virtual desktop and restore it on every focus
+// change, and xkb "grp:" options or the layout switcher shortcuts switch
+// layouts behind the daemon's back. These are reported at startup and
+// exposed over D-Bus; with `fix_kde_conflicts` the switching policy and
+// grp: options are turned off through kwriteconfig while the daemon runs,
+// and put back when it exits.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use tracing::{info, warn};
+use zbus::blocking::Connection;
+
+use crate::preflight::Problem;
+
+const SHORTCUT_GROUP: &str = "KDE Keyboard Layout Switcher";
+
+// Conflicts found at startup, for the KdeConflicts property
+static CONFLICTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// Original SwitchMode and Options (None when unset) while they are overridden
+static SAVED: Mutex<Option<(Option<String>, Option<String>)>> = Mutex::new(None);
+
+fn config_file(name: &str) -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(name))
+}
+
+// Keys of one [group] in a KDE config file
+fn read_group(file: &str, group: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    let Some(content) = config_file(file).and_then(|path| std::fs::read_to_string(path).ok()) else {
+        return values;
+    };
+    let header = format!("[{}]", group);
+    let mut in_group = false;
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_group = line == header;
+        } else if in_group {
+            if let Some((key, value)) = line.split_once('=') {
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+    }
+    values
+}
+
+// xkb options that switch layouts themselves, e.g. grp:caps_toggle
+fn grp_options(options: &str) -> Vec<&str> {
+    options.split(',').map(str::trim).filter(|o| o.starts_with("grp:")).collect()
+}
+
+/// Look for KDE settings that would undo or race the daemon's switches.
+pub(crate) fn check() -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let layout = read_group("kxkbrc", "Layout");
+
+    let mode = layout.get("SwitchMode").map(String::as_str).unwrap_or("Global");
+    if mode != "Global" {
+        problems.push(Problem {
+            what: format!(
+                "KDE remembers the layout per {} (SwitchMode={}) and restores it on focus changes",
+                match mode {
+                    "WinClass" => "application",
+                    "Desktop" => "virtual desktop",
+                    _ => "window",
+                },
+                mode
+            ),
+            fix: "set Switching policy to Global in System Settings > Keyboard > Layouts, \
+                  or fix_kde_conflicts = true"
+                .to_string(),
+        });
+    }
+
+    let options = layout.get("Options").map(String::as_str).unwrap_or("");
+    for option in grp_options(options) {
+        problems.push(Problem {
+            what: format!("xkb option {} switches layouts behind the daemon's back", option),
+            fix: "remove it in System Settings > Keyboard > Key Bindings, or fix_kde_conflicts = true"
+                .to_string(),
+        });
+    }
+
+    // Values are "active,default,description"; "none" means unset
+    for (action, value) in read_group("kglobalshortcutsrc", SHORTCUT_GROUP) {
+        let active = value.split(',').next().unwrap_or("");
+        if action.starts_with("Switch to") && !active.is_empty() && active != "none" {
+            problems.push(Problem {
+                what: format!("KDE shortcut {} is bound to '{}'", active, action),
+                fix: "remove it in System Settings > Shortcuts > Keyboard Layout Switcher \
+                      if it gets pressed by accident"
+                    .to_string(),
+            });
+        }
+    }
+
+    *CONFLICTS.lock().unwrap() = problems.iter().map(|p| p.what.clone()).collect();
+    problems
+}
+
+/// Conflicts found by the last `check`.
+pub(crate) fn conflicts() -> Vec<String> {
+    CONFLICTS.lock().unwrap().clone()
+}
+
+// Write (or delete, for None) a key in kxkbrc's [Layout] group
+fn write_layout_key(key: &str, value: Option<&str>) -> bool {
+    for tool in ["kwriteconfig6", "kwriteconfig5"] {
+        let mut cmd = Command::new(tool);
+        cmd.args(["--file", "kxkbrc", "--group", "Layout", "--key", key]);
+        match value {
+            Some(value) => cmd.arg(value),
+            None => cmd.arg("--delete"),
+        };
+        match cmd.status() {
+            Ok(status) if status.success() => return true,
+            Ok(status) => {
+                warn!("{} failed for {}: {}", tool, key, status);
+                return false;
+            }
+            // Try the other Plasma version's tool
+            Err(_) => continue,
+        }
+    }
+    warn!("Neither kwriteconfig6 nor kwriteconfig5 found; cannot change KDE settings");
+    false
+}
+
+// Ask the layout service to apply kxkbrc again, as System Settings does
+fn reload(conn: &Connection) {
+    let signal = conn.emit_signal(
+        None::<zbus::names::BusName<'_>>,
+        "/Layouts",
+        "org.kde.keyboard",
+        "reloadConfig",
+        &(),
+    );
+    if let Err(e) = signal {
+        warn!("Failed to ask KDE to reload keyboard settings: {}", e);
+    }
+}
+
+/// Switch to a global policy without grp: options, remembering the
+/// original values for `restore`. Returns whether anything was changed.
+pub(crate) fn fix(conn: &Connection) -> bool {
+    let layout = read_group("kxkbrc", "Layout");
+    let mode = layout.get("SwitchMode").cloned();
+    let options = layout.get("Options").cloned();
+
+    let mode_conflicts = mode.as_deref().is_some_and(|m| m != "Global");
+    let kept: Vec<String> = options
+        .as_deref()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty() && !o.starts_with("grp:"))
+        .map(str::to_string)
+        .collect();
+    let options_conflict = !grp_options(options.as_deref().unwrap_or("")).is_empty();
+    if !mode_conflicts && !options_conflict {
+        return false;
+    }
+
+    *SAVED.lock().unwrap() = Some((mode, options));
+    if mode_conflicts && write_layout_key("SwitchMode", Some("Global")) {
+        info!("Set KDE keyboard switching policy to Global while running");
+    }
+    if options_conflict && write_layout_key("Options", Some(&kept.join(","))) {
+        info!("Removed KDE layout switching options while running");
+    }
+    reload(conn);
+    true
+}
+
+/// Put back the settings changed by `fix`.
+pub(crate) fn restore(conn: &Connection) {
+    let Some((mode, options)) = SAVED.lock().unwrap().take() else {
+        return;
+    };
+    write_layout_key("SwitchMode", mode.as_deref());
+    write_layout_key("Options", options.as_deref());
+    reload(conn);
+    info!("Restored KDE keyboard settings");
+}
+
+/// Restore the KDE settings and exit on SIGTERM or SIGINT.
+pub(crate) async fn restore_on_exit() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (Ok(mut term), Ok(mut int)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        warn!("Cannot catch termination signals; KDE settings won't be restored on exit");
+        return;
+    };
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = int.recv() => {}
+    }
+
+    let _ = tokio::task::spawn_blocking(|| {
+        if let Ok(conn) = Connection::session() {
+            restore(&conn);
+        }
+    })
+    .await;
+    std::process::exit(0);
+}
@@ -0,0 +1,264 @@
+// Layout display names. The backend's own long names are already localized
+// ("Deutsch (Neo 2)"), so they are preferred; backends that only report
+// short codes fall back to the descriptions in the xkb rules registry.
+// Names are resolved at startup, and again when the backend reports a new
+// layout list, and shown wherever the daemon reports a layout to the user.
+// The same list maps each keyboard's layout_name to its current index, so
+// reordering layouts doesn't break the config.
+
+use std::sync::{Mutex, OnceLock};
+use tracing::{debug, info, warn};
+use zbus::blocking::Connection;
+
+use crate::config::KeyboardConfig;
+use crate::daemon::get_layouts_list;
+use crate::notify;
+use crate::xkb;
+
+// Display name per layout index
+static DISPLAY_NAMES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// Name per layout index as a config's layout_name, see layout_name
+static LAYOUT_NAMES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// layout_display, for refreshing the names
+static STYLE: OnceLock<String> = OnceLock::new();
+// Configured layout_names and their current index, if the backend has them
+static INDICES: Mutex<Vec<(String, Option<u32>)>> = Mutex::new(Vec::new());
+
+/// Resolve display names from the backend. `style` is `"long"` or `"short"`.
+pub(crate) fn resolve(conn: &Connection, style: &str) {
+    let _ = STYLE.set(style.to_string());
+    let layouts = match get_layouts_list(conn) {
+        Ok(layouts) => layouts,
+        Err(e) => {
+            debug!("Layout names unavailable: {}", e);
+            return;
+        }
+    };
+
+    *LAYOUT_NAMES.lock().unwrap() = layouts
+        .iter()
+        .map(|(short, variant, long)| if long.is_empty() { code(short, variant) } else { long.clone() })
+        .collect();
+    let names: Vec<String> = layouts
+        .into_iter()
+        .map(|(short, variant, long)| {
+            if style == "short" {
+                return code(&short, &variant);
+            }
+            if !long.is_empty() {
+                return long;
+            }
+            xkb::description(&short).unwrap_or(short)
+        })
+        .collect();
+
+    info!("Layouts: {}", names.join(", "));
+    *DISPLAY_NAMES.lock().unwrap() = names;
+}
+
+/// Name to show for a layout index, if the backend reported one.
+pub(crate) fn display_name(index: u32) -> Option<String> {
+    DISPLAY_NAMES.lock().unwrap().get(index as usize).cloned()
+}
+
+/// Name for a layout index as a config's `layout_name`, which resolves back
+/// to it: the backend's long name, else the code ("de(neo)").
+pub(crate) fn layout_name(index: u32) -> Option<String> {
+    LAYOUT_NAMES.lock().unwrap().get(index as usize).cloned()
+}
+
+// A layout's code, "de" or "de(neo)"
+fn code(short: &str, variant: &str) -> String {
+    if variant.is_empty() {
+        short.to_string()
+    } else {
+        format!("{}({})", short, variant)
+    }
+}
+
+// Index of the layout called `name`: its code ("de" or "de(neo)") or its
+// long name, ignoring case
+fn find_index(layouts: &[(String, String, String)], name: &str) -> Option<u32> {
+    let name = name.to_lowercase();
+    layouts
+        .iter()
+        .position(|(short, variant, _)| code(short, variant).to_lowercase() == name)
+        .or_else(|| layouts.iter().position(|(_, _, long)| long.to_lowercase() == name))
+        .map(|index| index as u32)
+}
+
+// A layout_name from a version 1 file was only a label: it is kept where
+// it names the keyboard's layout_index (or no layout at all), and dropped
+// where it names another layout, so layout_index still decides
+fn drop_legacy_names(layouts: &[(String, String, String)], keyboards: &mut [KeyboardConfig]) {
+    for kb in keyboards.iter_mut().filter(|kb| kb.legacy_layout_name) {
+        let named = find_index(layouts, &kb.layout_name).filter(|&index| index != kb.layout_index);
+        if let Some(index) = named {
+            warn!(
+                "Keyboard '{}': layout_name '{}' is a version 1 label naming layout_index {}, \
+                 switching to its layout_index {}; set version = 2 and fix the name",
+                kb.name, kb.layout_name, index, kb.layout_index
+            );
+            kb.layout_name.clear();
+        }
+    }
+}
+
+/// Point each keyboard at the index its `layout_name` has in the backend's
+/// layout list. Keyboards whose name isn't in the list keep their
+/// configured `layout_index`.
+pub(crate) fn resolve_indices(conn: &Connection, keyboards: &mut [KeyboardConfig]) {
+    let layouts = match get_layouts_list(conn) {
+        Ok(layouts) => layouts,
+        Err(e) => {
+            debug!("Not resolving layout names: {}", e);
+            return;
+        }
+    };
+
+    drop_legacy_names(&layouts, keyboards);
+    let mut indices = INDICES.lock().unwrap();
+    let mut register = |name: &str| {
+        let found = find_index(&layouts, name);
+        if !indices.iter().any(|(known, _)| known == name) {
+            indices.push((name.to_string(), found));
+        }
+        found
+    };
+    for kb in keyboards.iter() {
+        for name in kb.layouts.iter().filter(|name| name.parse::<u32>().is_err()) {
+            if register(name).is_none() {
+                warn!("Keyboard '{}': no layout named '{}' to cycle through", kb.name, name);
+            }
+        }
+    }
+    for kb in keyboards.iter_mut().filter(|kb| !kb.layout_name.is_empty()) {
+        let found = register(&kb.layout_name);
+        match found {
+            Some(index) if index != kb.layout_index => {
+                info!(
+                    "Keyboard '{}': '{}' is layout_index {} (configured {})",
+                    kb.name, kb.layout_name, index, kb.layout_index
+                );
+                kb.layout_index = index;
+            }
+            Some(_) => {}
+            None => debug!(
+                "Keyboard '{}': no layout named '{}', using layout_index {}",
+                kb.name, kb.layout_name, kb.layout_index
+            ),
+        }
+    }
+}
+
+/// Layout index a keyboard switches to: where its `layout_name` is in the
+/// backend's current list, else its `layout_index`.
+pub(crate) fn index_for(kb: &KeyboardConfig) -> u32 {
+    INDICES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(name, _)| *name == kb.layout_name)
+        .and_then(|(_, index)| *index)
+        .unwrap_or(kb.layout_index)
+}
+
+/// Current index of a layout in a keyboard's `layouts` list: a number is
+/// the index itself, a name is looked up in the backend's list.
+pub(crate) fn index_of(layout: &str) -> Option<u32> {
+    if let Ok(index) = layout.parse() {
+        return Some(index);
+    }
+    INDICES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(name, _)| name == layout)
+        .and_then(|(_, index)| *index)
+}
+
+/// The layout after `current` in `layouts`, wrapping around; the first one
+/// when `current` isn't in the list. Layouts the backend doesn't have are
+/// skipped.
+pub(crate) fn next_in(layouts: &[String], current: u32) -> Option<&String> {
+    let available: Vec<(&String, u32)> = layouts
+        .iter()
+        .filter_map(|layout| Some((layout, index_of(layout)?)))
+        .collect();
+    let next = match available.iter().position(|(_, index)| *index == current) {
+        Some(position) => (position + 1) % available.len(),
+        None => 0,
+    };
+    available.get(next).map(|(layout, _)| *layout)
+}
+
+/// Re-read the backend's layout list after it changed (a layout added,
+/// removed or moved): refresh display names and move configured layouts to
+/// their new indices, warning about configured layouts that are gone.
+#[cfg_attr(not(feature = "kde"), allow(dead_code))]
+pub(crate) fn refresh(conn: &Connection) {
+    let layouts = match get_layouts_list(conn) {
+        Ok(layouts) => layouts,
+        Err(e) => {
+            warn!("Layout list changed but can't be read: {}", e);
+            return;
+        }
+    };
+    resolve(conn, STYLE.get().map_or("long", String::as_str));
+
+    let mut gone = Vec::new();
+    for (name, index) in INDICES.lock().unwrap().iter_mut() {
+        let found = find_index(&layouts, name);
+        match (*index, found) {
+            (Some(_), None) => {
+                warn!("Layout '{}' was removed from the desktop's layouts", name);
+                gone.push(name.clone());
+            }
+            (old, Some(new)) if old != Some(new) => info!("Layout '{}' is now layout_index {}", name, new),
+            _ => {}
+        }
+        *index = found;
+    }
+    if !gone.is_empty() {
+        notify::notify(
+            "Keyboard layout removed",
+            &format!(
+                "{} no longer in the desktop's layouts; keyboards using it switch to their configured layout_index",
+                gone.join(", ")
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layouts() -> Vec<(String, String, String)> {
+        [("us", "", "English (US)"), ("de", "", "German")]
+            .iter()
+            .map(|&(short, variant, long)| (short.to_string(), variant.to_string(), long.to_string()))
+            .collect()
+    }
+
+    fn legacy(layout_name: &str, layout_index: u32) -> KeyboardConfig {
+        KeyboardConfig {
+            layout_name: layout_name.to_string(),
+            layout_index,
+            legacy_layout_name: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn legacy_label_is_kept_where_it_agrees_with_the_index() {
+        let current = KeyboardConfig {
+            layout_name: "de".to_string(),
+            ..Default::default()
+        };
+        let mut keyboards = [legacy("German", 1), legacy("de", 0), legacy("My keyboard", 1), current];
+        drop_legacy_names(&layouts(), &mut keyboards);
+        let names: Vec<&str> = keyboards.iter().map(|kb| kb.layout_name.as_str()).collect();
+        assert_eq!(names, ["German", "", "My keyboard", "de"]);
+    }
+}
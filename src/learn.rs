@@ -0,0 +1,144 @@
+// Learn mode behind the StartLearning D-Bus method: the next key press on
+// a keyboard without a [[keyboards]] entry of its own identifies it, and an
+// entry for it is proposed with the current layout, optionally written to a
+// config.d drop-in file (the reload watcher then starts monitoring it).
+// Devices are only read, not grabbed, so the press still reaches the system.
+
+use evdev::{Device, InputEventKind};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::config::{self, Config, KeyboardConfig, CONFIG_VERSION};
+use crate::daemon::{current_config, is_ignored, is_ignored_node, is_physical_keyboard, matching_keyboard_configs, CURRENT_LAYOUT};
+use crate::inspect;
+use crate::layouts;
+use crate::preflight;
+
+// How long StartLearning waits for a key press, below D-Bus's default
+// method call timeout of 25 s
+const LEARN_TIMEOUT: Duration = Duration::from_secs(20);
+
+static LEARNING: AtomicBool = AtomicBool::new(false);
+
+// Keyboards that only [default] or a catch-all, or no entry at all, would
+// take on, and that `ignore` doesn't name
+fn unconfigured_keyboards(config: &Config) -> Vec<Device> {
+    preflight::event_nodes()
+        .into_iter()
+        .filter(|path| !is_ignored_node(path, config))
+        .filter_map(|path| {
+            let device = Device::open(&path).ok()?;
+            let entries = matching_keyboard_configs(&device, &path, config);
+            let is_default = |kb: &KeyboardConfig| config.default.as_ref().is_some_and(|default| std::ptr::eq(kb, default));
+            let configured = entries.iter().any(|kb| !kb.is_catch_all() && !is_default(kb));
+            (is_physical_keyboard(&device) && !configured && !is_ignored(&device, config)).then_some(device)
+        })
+        .collect()
+}
+
+// The first of `devices` to send a key press before the deadline
+fn first_press(devices: &mut [Device], deadline: Instant) -> Option<usize> {
+    let mut pollfds: Vec<libc::pollfd> = devices
+        .iter()
+        .map(|device| libc::pollfd {
+            fd: device.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return None;
+        }
+        let timeout = remaining.as_millis() as i32;
+        let ready = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+        if ready < 0 {
+            return None;
+        }
+        for (index, pollfd) in pollfds.iter_mut().enumerate() {
+            if pollfd.revents == 0 {
+                continue;
+            }
+            pollfd.revents = 0;
+            let Ok(events) = devices[index].fetch_events() else {
+                // Unplugged meanwhile; stop polling it
+                pollfd.fd = -1;
+                continue;
+            };
+            if events.into_iter().any(|ev| matches!(ev.kind(), InputEventKind::Key(_)) && ev.value() == 1) {
+                return Some(index);
+            }
+        }
+    }
+}
+
+// Write the entry to a drop-in file of its own, named after the keyboard,
+// leaving the main config file as it is
+fn write_drop_in(name: &str, text: &str) -> Result<PathBuf, String> {
+    let dir = config::drop_in_dir();
+    std::fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let slug: String = name
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let slug = if slug.is_empty() { "keyboard".to_string() } else { slug };
+    let path = (1..)
+        .map(|n| match n {
+            1 => dir.join(format!("{}.toml", slug)),
+            n => dir.join(format!("{}-{}.toml", slug, n)),
+        })
+        .find(|path| !path.exists())
+        .unwrap();
+    let content = format!("version = {}\n\n{}", CONFIG_VERSION, text);
+    std::fs::write(&path, content).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Wait for a key press on an unconfigured keyboard and return a proposed
+/// `[[keyboards]]` entry for it as TOML, writing it to a drop-in file (see
+/// `config::drop_in_dir`) with `append`. Blocks for up to LEARN_TIMEOUT.
+pub(crate) fn learn(append: bool) -> Result<String, String> {
+    if LEARNING.swap(true, Ordering::SeqCst) {
+        return Err("already learning a keyboard".to_string());
+    }
+    let result = learn_next(append);
+    LEARNING.store(false, Ordering::SeqCst);
+    result
+}
+
+fn learn_next(append: bool) -> Result<String, String> {
+    let mut devices = unconfigured_keyboards(&current_config());
+    if devices.is_empty() {
+        return Err("no keyboard without a [[keyboards]] entry is connected".to_string());
+    }
+    info!("Learning: waiting for a key press on one of {} unconfigured keyboards", devices.len());
+    let index = first_press(&mut devices, Instant::now() + LEARN_TIMEOUT).ok_or_else(|| {
+        format!("no key press on an unconfigured keyboard within {}s", LEARN_TIMEOUT.as_secs())
+    })?;
+    let device = &devices[index];
+
+    let name = device.name().unwrap_or("Unknown").to_string();
+    let layout_index = CURRENT_LAYOUT.load(Ordering::SeqCst);
+    let mut entry = toml::Table::new();
+    entry.insert("name".into(), name.clone().into());
+    entry.insert("id".into(), inspect::device_id(device).into());
+    entry.insert("layout_index".into(), (layout_index as i64).into());
+    entry.insert("layout_name".into(), layouts::layout_name(layout_index).unwrap_or_default().into());
+    let mut wrapper = toml::Table::new();
+    wrapper.insert("keyboards".into(), toml::Value::Array(vec![toml::Value::Table(entry)]));
+    let text = toml::to_string(&wrapper).map_err(|e| e.to_string())?;
+
+    if append {
+        let path = write_drop_in(&name, &text)?;
+        info!("Learned '{}', added it to the config as {:?}", name, path);
+    } else {
+        info!("Learned '{}'", name);
+    }
+    Ok(text)
+}
@@ -0,0 +1,47 @@
+// Library half of kb-layout-daemon. The binary is a thin wrapper around
+// `Daemon`; embedders can construct one themselves and extend the event
+// pipeline with `EventFilter`s.
+
+pub mod action;
+pub mod affinity;
+pub mod backend;
+pub mod capture;
+pub mod check;
+pub mod clock;
+pub mod config;
+mod daemon;
+pub mod events;
+pub mod filter;
+#[cfg(feature = "hotplug")]
+mod hotplug;
+pub mod inspect;
+#[cfg(feature = "kde")]
+mod kxkb;
+mod layouts;
+mod learn;
+pub mod logging;
+pub mod monitor_state;
+mod notify;
+pub mod osk;
+mod output;
+#[cfg(feature = "peer")]
+mod peer;
+#[cfg(feature = "portal")]
+mod portal;
+mod preflight;
+mod reload;
+pub mod remap;
+mod session;
+pub mod setup;
+pub mod sound;
+mod state;
+mod switcher;
+#[cfg(feature = "wayland")]
+mod wayland;
+pub mod xkb;
+
+pub use config::Config;
+pub use daemon::Daemon;
+pub use filter::{DeviceInfo, EventFilter};
+#[cfg(feature = "peer")]
+pub use peer::PeerConfig;
@@ -0,0 +1,182 @@
+// Logging setup: always to stderr (journald under systemd), optionally also
+// to a size-rotated file for systems without journald.
+
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Deserialize)]
+pub struct LogConfig {
+    // Also log to a file (default path in the XDG state dir)
+    #[serde(default)]
+    pub to_file: bool,
+    pub path: Option<PathBuf>,
+    #[serde(default = "default_max_size_kb")]
+    pub max_size_kb: u64,
+    // Number of rotated files to keep besides the active one
+    #[serde(default = "default_keep")]
+    pub keep: u32,
+    // "error", "warn", "info" (default), "debug" or "trace"
+    pub level: Option<String>,
+}
+
+fn default_max_size_kb() -> u64 {
+    1024
+}
+
+fn default_keep() -> u32 {
+    3
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            to_file: false,
+            path: None,
+            max_size_kb: default_max_size_kb(),
+            keep: default_keep(),
+            level: None,
+        }
+    }
+}
+
+/// Default log file: `$XDG_STATE_HOME/kb-layout-daemon/daemon.log`
+pub fn default_log_path() -> PathBuf {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|p| p.join("kb-layout-daemon").join("daemon.log"))
+        .unwrap_or_else(|| PathBuf::from("kb-layout-daemon.log"))
+}
+
+fn env_filter(level: tracing::Level) -> EnvFilter {
+    EnvFilter::from_default_env().add_directive(level.into())
+}
+
+/// Stderr-only subscriber, for logging before the config has been read.
+pub fn stderr_subscriber() -> impl tracing::Subscriber + Send + Sync {
+    tracing_subscriber::fmt().with_env_filter(env_filter(tracing::Level::INFO)).finish()
+}
+
+/// Install the global subscriber. `log_file` (from `--log-file`) overrides
+/// the configured path and enables file logging.
+pub fn init(config: &LogConfig, log_file: Option<PathBuf>) -> io::Result<()> {
+    let level = match config.level.as_deref().map(str::parse::<tracing::Level>) {
+        None => tracing::Level::INFO,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid log level '{}'", config.level.as_deref().unwrap_or_default()),
+            ))
+        }
+    };
+    let path = match log_file {
+        Some(p) => Some(p),
+        None if config.to_file => Some(config.path.clone().unwrap_or_else(default_log_path)),
+        None => None,
+    };
+
+    let file_layer = match path {
+        Some(path) => {
+            let writer = RotatingFile::open(path, config.max_size_kb * 1024, config.keep)?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(writer),
+            )
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(env_filter(level))
+        .with(tracing_subscriber::fmt::layer())
+        .with(file_layer)
+        .init();
+
+    Ok(())
+}
+
+struct RotatingState {
+    file: File,
+    size: u64,
+}
+
+/// Log file rotated by size: `daemon.log` -> `daemon.log.1` -> ... ->
+/// `daemon.log.<keep>`, oldest dropped.
+#[derive(Clone)]
+pub struct RotatingFile {
+    path: Arc<PathBuf>,
+    max_size: u64,
+    keep: u32,
+    state: Arc<Mutex<RotatingState>>,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_size: u64, keep: u32) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: Arc::new(path),
+            max_size,
+            keep,
+            state: Arc::new(Mutex::new(RotatingState { file, size })),
+        })
+    }
+
+    fn rotated_path(path: &Path, n: u32) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self, state: &mut RotatingState) -> io::Result<()> {
+        if self.keep == 0 {
+            let _ = fs::remove_file(&*self.path);
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = Self::rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, Self::rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&*self.path, Self::rotated_path(&self.path, 1))?;
+        }
+        state.file = OpenOptions::new().create(true).append(true).open(&*self.path)?;
+        state.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if self.max_size > 0 && state.size > 0 && state.size + buf.len() as u64 > self.max_size {
+            self.rotate(&mut state)?;
+        }
+        let written = state.file.write(buf)?;
+        state.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = RotatingFile;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
@@ -0,0 +1,241 @@
+// Per-keyboard monitor state machine. The monitor loop used to juggle the
+// open device, the grab state and held keys in ad-hoc flags, and the bugs
+// lived in the transitions between them (a spurious Meta release opening
+// the KDE launcher, mode changes applied late). Here every transition is
+// explicit and pure, so it can be fuzzed (fuzz/fuzz_targets/monitor_state.rs);
+// the monitor performs the returned actions.
+
+/// Where a keyboard monitor is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonitorState {
+    /// No device open: at startup, after a disconnect, or before a reopen
+    Disconnected,
+    /// Device open without a grab; events reach the desktop directly
+    Passive,
+    /// Device grabbed; events are forwarded through the virtual keyboard
+    Grabbed,
+    /// Grabbed with a layout switch in flight; forwarded frames wait for it
+    Switching { ticket: u64 },
+    /// Releasing held keys on the virtual keyboard before dropping the grab
+    Draining,
+}
+
+/// Something that happened to the monitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// The device was opened, with or without a grab
+    Opened { grabbed: bool },
+    /// The global mode is grab (true) or passive (false)
+    Mode { grab: bool },
+    /// A layout switch was submitted with this ticket
+    SwitchSubmitted { ticket: u64 },
+    /// The switch for this ticket (or one superseding it) has completed
+    SwitchCompleted { ticket: u64 },
+    /// Reading failed: the device is gone
+    Lost,
+    /// The releases asked for by `Action::ReleaseHeld` were emitted
+    Drained,
+}
+
+/// What the monitor has to do after a transition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    None,
+    /// Close the device and open it again, grabbed or not
+    Reopen { grab: bool },
+    /// Emit releases for the held keys, then report `Input::Drained`
+    ReleaseHeld,
+    /// Hold back forwarded frames until this switch completes
+    AwaitSwitch { ticket: u64 },
+}
+
+impl MonitorState {
+    /// True while events are forwarded through the virtual keyboard.
+    pub fn is_grabbed(self) -> bool {
+        matches!(self, MonitorState::Grabbed | MonitorState::Switching { .. })
+    }
+
+    /// Apply `input`. `keys_held` says whether any forwarded key is still
+    /// down on the virtual keyboard.
+    pub fn next(self, input: Input, keys_held: bool) -> (MonitorState, Action) {
+        use MonitorState::*;
+
+        match (self, input) {
+            // A vanished device is gone whatever we were doing; held keys are
+            // kept for a reconnect and reconciled when the device reopens
+            (_, Input::Lost) => (Disconnected, Action::None),
+
+            (Disconnected, Input::Opened { grabbed: true }) => (Grabbed, Action::None),
+            (Disconnected, Input::Opened { grabbed: false }) => (Passive, Action::None),
+
+            (Passive, Input::Mode { grab: true }) => (Disconnected, Action::Reopen { grab: true }),
+            // Only release keys that are actually down: a release for an
+            // unpressed Meta opens the KDE launcher
+            (Grabbed | Switching { .. }, Input::Mode { grab: false }) => {
+                if keys_held {
+                    (Draining, Action::ReleaseHeld)
+                } else {
+                    (Disconnected, Action::Reopen { grab: false })
+                }
+            }
+            (Draining, Input::Drained) => (Disconnected, Action::Reopen { grab: false }),
+
+            // Passive mode can't hold events back, so there is nothing to wait for
+            (Grabbed | Switching { .. }, Input::SwitchSubmitted { ticket }) => {
+                (Switching { ticket }, Action::AwaitSwitch { ticket })
+            }
+            (Switching { ticket }, Input::SwitchCompleted { ticket: done }) if done >= ticket => {
+                (Grabbed, Action::None)
+            }
+
+            (state, _) => (state, Action::None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Action::{AwaitSwitch, ReleaseHeld, Reopen};
+    use super::Input::*;
+    use super::MonitorState::*;
+    use super::*;
+
+    const NONE: Action = Action::None;
+
+    // Every input a state can see, with what `next` should return for it
+    fn check(state: MonitorState, keys_held: bool, cases: &[(Input, MonitorState, Action)]) {
+        for &(input, next, action) in cases {
+            assert_eq!(
+                state.next(input, keys_held),
+                (next, action),
+                "{:?} + {:?} (keys held: {})",
+                state,
+                input,
+                keys_held
+            );
+        }
+    }
+
+    #[test]
+    fn disconnected() {
+        for keys_held in [false, true] {
+            check(
+                Disconnected,
+                keys_held,
+                &[
+                    (Opened { grabbed: true }, Grabbed, NONE),
+                    (Opened { grabbed: false }, Passive, NONE),
+                    // The mode is applied by the next open
+                    (Mode { grab: true }, Disconnected, NONE),
+                    (Mode { grab: false }, Disconnected, NONE),
+                    (SwitchSubmitted { ticket: 7 }, Disconnected, NONE),
+                    (SwitchCompleted { ticket: 7 }, Disconnected, NONE),
+                    (Lost, Disconnected, NONE),
+                    (Drained, Disconnected, NONE),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn passive() {
+        check(
+            Passive,
+            false,
+            &[
+                (Opened { grabbed: true }, Passive, NONE),
+                (Mode { grab: true }, Disconnected, Reopen { grab: true }),
+                (Mode { grab: false }, Passive, NONE),
+                // Passive events can't be held back for a switch
+                (SwitchSubmitted { ticket: 7 }, Passive, NONE),
+                (SwitchCompleted { ticket: 7 }, Passive, NONE),
+                (Lost, Disconnected, NONE),
+                (Drained, Passive, NONE),
+            ],
+        );
+    }
+
+    #[test]
+    fn grabbed() {
+        check(
+            Grabbed,
+            false,
+            &[
+                (Opened { grabbed: false }, Grabbed, NONE),
+                (Mode { grab: true }, Grabbed, NONE),
+                (Mode { grab: false }, Disconnected, Reopen { grab: false }),
+                (SwitchSubmitted { ticket: 7 }, Switching { ticket: 7 }, AwaitSwitch { ticket: 7 }),
+                (SwitchCompleted { ticket: 7 }, Grabbed, NONE),
+                (Lost, Disconnected, NONE),
+                (Drained, Grabbed, NONE),
+            ],
+        );
+    }
+
+    #[test]
+    fn switching() {
+        let state = Switching { ticket: 5 };
+        check(
+            state,
+            false,
+            &[
+                (Opened { grabbed: true }, state, NONE),
+                (Mode { grab: true }, state, NONE),
+                (Mode { grab: false }, Disconnected, Reopen { grab: false }),
+                // A newer switch supersedes the pending one
+                (SwitchSubmitted { ticket: 6 }, Switching { ticket: 6 }, AwaitSwitch { ticket: 6 }),
+                // An older completion doesn't release the held frames
+                (SwitchCompleted { ticket: 4 }, state, NONE),
+                (SwitchCompleted { ticket: 5 }, Grabbed, NONE),
+                (SwitchCompleted { ticket: 6 }, Grabbed, NONE),
+                (Lost, Disconnected, NONE),
+                (Drained, state, NONE),
+            ],
+        );
+    }
+
+    #[test]
+    fn draining() {
+        check(
+            Draining,
+            false,
+            &[
+                (Opened { grabbed: true }, Draining, NONE),
+                // Releases already asked for aren't asked for again
+                (Mode { grab: true }, Draining, NONE),
+                (Mode { grab: false }, Draining, NONE),
+                (SwitchSubmitted { ticket: 7 }, Draining, NONE),
+                (SwitchCompleted { ticket: 7 }, Draining, NONE),
+                (Lost, Disconnected, NONE),
+                (Drained, Disconnected, Reopen { grab: false }),
+            ],
+        );
+    }
+
+    #[test]
+    fn passive_mode_releases_only_held_keys() {
+        // Keys down on the virtual keyboard are released before the grab
+        // goes, from either grabbed state
+        for state in [Grabbed, Switching { ticket: 3 }] {
+            assert_eq!(state.next(Mode { grab: false }, true), (Draining, ReleaseHeld));
+        }
+        // Nothing held: no release at all, so no spurious Meta release
+        // reaches the desktop and opens the KDE launcher
+        for state in [Grabbed, Switching { ticket: 3 }] {
+            assert_eq!(state.next(Mode { grab: false }, false), (Disconnected, Reopen { grab: false }));
+        }
+    }
+
+    #[test]
+    fn mode_change_round_trip() {
+        let (state, action) = Grabbed.next(Mode { grab: false }, true);
+        assert_eq!((state, action), (Draining, ReleaseHeld));
+        let (state, action) = state.next(Drained, false);
+        assert_eq!(action, Reopen { grab: false });
+        let (state, _) = state.next(Opened { grabbed: false }, false);
+        assert_eq!(state, Passive);
+        let (state, action) = state.next(Mode { grab: true }, false);
+        assert_eq!(action, Reopen { grab: true });
+        assert_eq!(state.next(Opened { grabbed: true }, false).0, Grabbed);
+    }
+}
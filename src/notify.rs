@@ -0,0 +1,38 @@
+// Desktop notifications through org.freedesktop.Notifications, for problems
+// the user has to know about without reading the journal. Sent from a
+// short-lived thread so callers (monitor threads) never wait on the bus.
+
+use std::collections::HashMap;
+use tracing::debug;
+use zbus::blocking::Connection;
+use zbus::zvariant::Value;
+
+/// Show a notification; failures (no notification daemon) are only logged.
+pub(crate) fn notify(summary: &str, body: &str) {
+    let summary = summary.to_string();
+    let body = body.to_string();
+    std::thread::spawn(move || {
+        let result = Connection::session().and_then(|conn| {
+            let hints: HashMap<&str, Value<'_>> = HashMap::new();
+            conn.call_method(
+                Some("org.freedesktop.Notifications"),
+                "/org/freedesktop/Notifications",
+                Some("org.freedesktop.Notifications"),
+                "Notify",
+                &(
+                    "kb-layout-daemon",
+                    0u32,
+                    "input-keyboard",
+                    summary.as_str(),
+                    body.as_str(),
+                    Vec::<&str>::new(),
+                    hints,
+                    -1i32,
+                ),
+            )
+        });
+        if let Err(e) = result {
+            debug!("Failed to show notification '{}': {}", summary, e);
+        }
+    });
+}
@@ -0,0 +1,112 @@
+// On-screen keyboard detection. While an on-screen keyboard (Maliit) is the
+// active input, typing on a physical keyboard must not switch the layout
+// under the touch user. Under KWin the virtual keyboard state is read from
+// KWin itself; elsewhere the OSK counts as active while one of its known
+// D-Bus services is running.
+
+use futures::StreamExt;
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+use zbus::{fdo::DBusProxy, proxy::CacheProperties, Connection, Proxy, ProxyBuilder};
+
+use crate::config::Config;
+use crate::daemon::set_osk_active;
+
+#[derive(Debug, Deserialize)]
+pub struct OskConfig {
+    // Suppress switching while an on-screen keyboard is active
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // D-Bus names of on-screen keyboards, used when KWin isn't available
+    #[serde(default = "default_services")]
+    pub services: Vec<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_services() -> Vec<String> {
+    vec!["org.maliit.server".to_string()]
+}
+
+impl Default for OskConfig {
+    fn default() -> Self {
+        OskConfig {
+            enabled: default_enabled(),
+            services: default_services(),
+        }
+    }
+}
+
+async fn kwin_proxy(conn: &Connection) -> zbus::Result<Proxy<'static>> {
+    ProxyBuilder::new(conn)
+        .destination("org.kde.KWin")?
+        .path("/VirtualKeyboard")?
+        .interface("org.kde.kwin.VirtualKeyboard")?
+        // KWin announces changes through visibleChanged, not PropertiesChanged
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+}
+
+// Follow KWin's virtual keyboard; returns an error if KWin doesn't have one
+async fn watch_kwin(conn: &Connection) -> zbus::Result<()> {
+    let proxy = kwin_proxy(conn).await?;
+    let mut changes = proxy.receive_signal("visibleChanged").await?;
+    set_osk_active(conn, proxy.get_property::<bool>("visible").await?).await;
+    info!("Watching KWin virtual keyboard");
+
+    while changes.next().await.is_some() {
+        match proxy.get_property::<bool>("visible").await {
+            Ok(visible) => set_osk_active(conn, visible).await,
+            Err(e) => warn!("Failed to read KWin virtual keyboard state: {}", e),
+        }
+    }
+    Ok(())
+}
+
+// Fallback: the OSK is active while any of its services is on the bus
+async fn watch_services(conn: &Connection, services: &[String]) -> zbus::Result<()> {
+    let dbus = DBusProxy::new(conn).await?;
+    let mut changes = dbus.receive_name_owner_changed().await?;
+    info!("Watching on-screen keyboard services {:?}", services);
+
+    loop {
+        let mut active = false;
+        for service in services {
+            let Ok(name) = service.as_str().try_into() else {
+                continue;
+            };
+            if dbus.name_has_owner(name).await.unwrap_or(false) {
+                active = true;
+                break;
+            }
+        }
+        set_osk_active(conn, active).await;
+
+        // Wait for one of our services to appear or vanish
+        loop {
+            let Some(change) = changes.next().await else {
+                return Ok(());
+            };
+            let Ok(args) = change.args() else {
+                continue;
+            };
+            if services.iter().any(|s| s.as_str() == args.name().as_str()) {
+                break;
+            }
+        }
+    }
+}
+
+/// Track whether an on-screen keyboard is active. Runs forever.
+pub(crate) async fn run(config: Arc<Config>, conn: Connection) {
+    if let Err(e) = watch_kwin(&conn).await {
+        debug!("No KWin virtual keyboard ({}), falling back to service names", e);
+        if let Err(e) = watch_services(&conn, &config.osk.services).await {
+            warn!("On-screen keyboard detection stopped: {}", e);
+        }
+    }
+}
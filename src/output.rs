@@ -0,0 +1,221 @@
+// Per-device output queue. Each virtual keyboard is written by its own
+// thread, so neither a uinput write that blocks (e.g. a frozen compositor)
+// nor waiting for a layout switch delays the monitor reading the physical
+// device, which would overrun the kernel's event buffer (SYN_DROPPED). The
+// queue is bounded; on overflow key repeats are dropped first, and releases
+// are never dropped so no key can end up stuck down. Frames go to an
+// `EventSink`: the uinput device, or the RemoteDesktop portal.
+
+use evdev::uinput::VirtualDevice;
+use evdev::InputEvent;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::events::{has_key_release, is_repeat_frame, split_frames};
+use crate::switcher::SwitchQueue;
+
+// Frames a device may have queued before the overflow policy kicks in
+const QUEUE_FRAMES: usize = 256;
+
+// Longest the writer holds frames back waiting for their layout switch
+const SWITCH_WAIT_TIMEOUT: Duration = Duration::from_millis(200);
+
+enum Item {
+    Frame(Vec<InputEvent>),
+    // Frames after this one must reach the layout switch with this ticket
+    AwaitSwitch(u64),
+}
+
+impl Item {
+    fn is_repeat(&self) -> bool {
+        matches!(self, Item::Frame(frame) if is_repeat_frame(frame))
+    }
+}
+
+/// Where a writer thread delivers frames. `emit` is given one frame without
+/// its SYN_REPORT.
+pub(crate) trait EventSink: Send {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()>;
+}
+
+/// Sink for keyboards that are never grabbed, which have nothing to forward.
+pub(crate) struct Discard;
+
+impl EventSink for Discard {
+    fn emit(&mut self, _: &[InputEvent]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl EventSink for VirtualDevice {
+    // Terminates the frame with SYN_REPORT
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        VirtualDevice::emit(self, events)
+    }
+}
+
+// Events dropped on overflow, across all devices
+pub(crate) static DROPPED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+struct State {
+    items: VecDeque<Item>,
+    closed: bool,
+}
+
+/// Live counters of one output queue, for the DebugMetrics method.
+#[derive(Default)]
+pub(crate) struct QueueMetrics {
+    // Frames and switch waits queued but not yet written
+    pub(crate) depth: AtomicUsize,
+    // Frames the sink failed to write
+    pub(crate) write_errors: AtomicU64,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+    metrics: Arc<QueueMetrics>,
+}
+
+fn count_dropped(name: &str, events: usize) {
+    let before = DROPPED_EVENTS.fetch_add(events as u64, Ordering::Relaxed);
+    // Warn on the first drop and then sparingly
+    if before == 0 || (before + events as u64) / 1000 > before / 1000 {
+        warn!(
+            "Virtual keyboard for '{}' is not keeping up - {} event(s) dropped so far",
+            name,
+            before + events as u64
+        );
+    }
+}
+
+/// Sending half of a device's output queue. Dropping it stops the writer
+/// thread once the remaining frames are written.
+pub(crate) struct OutputQueue {
+    shared: Arc<Shared>,
+    name: String,
+}
+
+impl OutputQueue {
+    /// Start the writer thread for `vk`. With a source tag, each frame is
+    /// prefixed by the tag event.
+    pub(crate) fn spawn(
+        vk: Box<dyn EventSink>,
+        name: String,
+        source_tag: Option<InputEvent>,
+        switcher: Arc<SwitchQueue>,
+    ) -> Self {
+        let shared = Arc::new(Shared::default());
+        let writer_shared = Arc::clone(&shared);
+        let writer_name = name.clone();
+        thread::spawn(move || run_writer(vk, writer_shared, &writer_name, source_tag, &switcher));
+        OutputQueue { shared, name }
+    }
+
+    pub(crate) fn metrics(&self) -> Arc<QueueMetrics> {
+        Arc::clone(&self.shared.metrics)
+    }
+
+    /// Hold back everything queued after this until the switch for `ticket`
+    /// (or one superseding it) is done, so it reaches the new layout.
+    pub(crate) fn await_switch(&self, ticket: u64) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.items.push_back(Item::AwaitSwitch(ticket));
+        self.shared.metrics.depth.store(state.items.len(), Ordering::Relaxed);
+    }
+
+    /// Queue a batch for forwarding, one frame per SYN_REPORT.
+    pub(crate) fn push(&self, events: &[InputEvent]) {
+        let mut state = self.shared.state.lock().unwrap();
+        for frame in split_frames(events) {
+            if state.items.len() >= QUEUE_FRAMES {
+                let dropped = Self::make_room(&mut state.items);
+                if dropped > 0 {
+                    count_dropped(&self.name, dropped);
+                } else if !has_key_release(frame) {
+                    // Nothing expendable queued; lose this frame instead
+                    count_dropped(&self.name, frame.len());
+                    continue;
+                }
+                // Releases are queued even past the bound
+            }
+            state.items.push_back(Item::Frame(frame.to_vec()));
+        }
+        self.shared.metrics.depth.store(state.items.len(), Ordering::Relaxed);
+        self.shared.cond.notify_one();
+    }
+
+    // Drop the oldest queued repeat frame; returns the events dropped
+    fn make_room(items: &mut VecDeque<Item>) -> usize {
+        match items.iter().position(Item::is_repeat) {
+            Some(i) => match items.remove(i) {
+                Some(Item::Frame(frame)) => frame.len(),
+                _ => 0,
+            },
+            None => 0,
+        }
+    }
+}
+
+impl Drop for OutputQueue {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.cond.notify_one();
+    }
+}
+
+fn run_writer(
+    mut vk: Box<dyn EventSink>,
+    shared: Arc<Shared>,
+    name: &str,
+    source_tag: Option<InputEvent>,
+    switcher: &SwitchQueue,
+) {
+    let mut tagged = Vec::new();
+    loop {
+        let item = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if let Some(item) = state.items.pop_front() {
+                    shared.metrics.depth.store(state.items.len(), Ordering::Relaxed);
+                    break item;
+                }
+                if state.closed {
+                    return;
+                }
+                state = shared.cond.wait(state).unwrap();
+            }
+        };
+
+        let frame = match item {
+            Item::Frame(frame) => frame,
+            Item::AwaitSwitch(ticket) => {
+                if !switcher.wait(ticket, SWITCH_WAIT_TIMEOUT) {
+                    warn!("Layout switch still pending - forwarding anyway");
+                }
+                continue;
+            }
+        };
+
+        let result = match source_tag {
+            Some(tag) => {
+                tagged.clear();
+                tagged.push(tag);
+                tagged.extend_from_slice(&frame);
+                vk.emit(&tagged)
+            }
+            None => vk.emit(&frame),
+        };
+        if let Err(e) = result {
+            shared.metrics.write_errors.fetch_add(1, Ordering::Relaxed);
+            error!("Failed to emit events for '{}': {}", name, e);
+        }
+    }
+}
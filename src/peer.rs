@@ -0,0 +1,487 @@
+// Layout synchronization between daemon instances on machines that share
+// keyboards through a hardware KVM. When one machine switches layout because
+// of local typing, its peers switch too, so flipping the KVM never lands on a
+// stale layout.
+//
+// Protocol (newline-delimited text over TCP or a Unix socket):
+//   both sides  -> "HELLO <nonce>"
+//   both sides  -> "AUTH <hmac-sha256(secret, role ':' peer_nonce)>"
+//   then either -> "LAYOUT <index> <seq> <hmac-sha256(session, role ':' seq ':' 'LAYOUT <index>')>"
+// The role ("listen" or "connect") is mixed into every MAC so a peer cannot
+// authenticate by reflecting our own messages back at us. The session key is
+// derived from the secret and both nonces, and each side numbers its messages
+// from 0, so messages can't be replayed into another connection or reordered.
+// Nothing is encrypted; layout indices aren't secret.
+
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::io::Read;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+use crate::daemon::CURRENT_LAYOUT;
+use crate::switcher::SwitchQueue;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// Longest line accepted from a peer; every valid message is far shorter
+const MAX_LINE: usize = 256;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeerConfig {
+    // "host:port" or "unix:/path/to/socket"
+    pub listen: Option<String>,
+    pub connect: Option<String>,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Listen,
+    Connect,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::Listen => "listen",
+            Role::Connect => "connect",
+        }
+    }
+
+    fn other(self) -> Role {
+        match self {
+            Role::Listen => Role::Connect,
+            Role::Connect => Role::Listen,
+        }
+    }
+}
+
+enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    fn parse(s: &str) -> Address {
+        match s.strip_prefix("unix:") {
+            Some(path) => Address::Unix(PathBuf::from(path)),
+            None => Address::Tcp(s.to_string()),
+        }
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn random_nonce() -> std::io::Result<String> {
+    let mut bytes = [0u8; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(to_hex(&bytes))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok().filter(|p| p.len() == 2)?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+fn sign(secret: &[u8], role: Role, nonce: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(role.as_str().as_bytes());
+    mac.update(b":");
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+// Message authentication for an established connection
+struct Session {
+    key: Vec<u8>,
+    role: Role,
+    sent: u64,
+    received: u64,
+}
+
+impl Session {
+    fn new(secret: &str, role: Role, nonce: &str, peer_nonce: &str) -> Session {
+        let (listen_nonce, connect_nonce) = match role {
+            Role::Listen => (nonce, peer_nonce),
+            Role::Connect => (peer_nonce, nonce),
+        };
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(format!("session:{}:{}", listen_nonce, connect_nonce).as_bytes());
+        Session {
+            key: mac.finalize().into_bytes().to_vec(),
+            role,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    // Add sequence number and MAC to an outgoing message
+    fn seal(&mut self, body: &str) -> String {
+        let seq = self.sent;
+        self.sent += 1;
+        let mac = sign(&self.key, self.role, &format!("{}:{}", seq, body));
+        format!("{} {} {}\n", body, seq, to_hex(&mac.finalize().into_bytes()))
+    }
+
+    // Check an incoming message and return its body
+    fn open<'a>(&mut self, line: &'a str) -> Result<&'a str, String> {
+        let (rest, mac) = line.rsplit_once(' ').ok_or("missing MAC")?;
+        let (body, seq) = rest.rsplit_once(' ').ok_or("missing sequence number")?;
+        if seq.parse::<u64>().ok() != Some(self.received) {
+            return Err(format!("expected message {}, got {:?}", self.received, seq));
+        }
+        let mac = from_hex(mac).ok_or("malformed MAC")?;
+        sign(&self.key, self.role.other(), &format!("{}:{}", seq, body))
+            .verify_slice(&mac)
+            .map_err(|_| "bad MAC".to_string())?;
+        self.received += 1;
+        Ok(body)
+    }
+}
+
+// Start the listener and/or outbound connection described by the config
+pub async fn run(config: PeerConfig, switcher: Arc<SwitchQueue>, peer_tx: broadcast::Sender<u32>) {
+    if config.secret.is_empty() {
+        error!("Peer sync disabled: [peer] secret must not be empty");
+        return;
+    }
+
+    let config = Arc::new(config);
+
+    if let Some(addr) = config.listen.clone() {
+        tokio::spawn(listen(
+            Address::parse(&addr),
+            Arc::clone(&config),
+            Arc::clone(&switcher),
+            peer_tx.clone(),
+        ));
+    }
+
+    if let Some(addr) = config.connect.clone() {
+        tokio::spawn(connect(Address::parse(&addr), config, switcher, peer_tx));
+    }
+}
+
+async fn listen(
+    addr: Address,
+    config: Arc<PeerConfig>,
+    switcher: Arc<SwitchQueue>,
+    peer_tx: broadcast::Sender<u32>,
+) {
+    match addr {
+        Address::Tcp(addr) => {
+            let listener = match TcpListener::bind(&addr).await {
+                Ok(l) => l,
+                Err(e) => {
+                    error!("Failed to listen for peers on {}: {}", addr, e);
+                    return;
+                }
+            };
+            info!("Listening for peers on {}", addr);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, remote)) => {
+                        info!("Peer connected from {}", remote);
+                        tokio::spawn(handle_peer(
+                            stream,
+                            Role::Listen,
+                            Arc::clone(&config),
+                            Arc::clone(&switcher),
+                            peer_tx.subscribe(),
+                        ));
+                    }
+                    Err(e) => warn!("Failed to accept peer: {}", e),
+                }
+            }
+        }
+        Address::Unix(path) => {
+            // Remove a stale socket left behind by a previous run, but
+            // never a regular file a typo in the config points at
+            let is_socket = std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket());
+            if is_socket {
+                let _ = std::fs::remove_file(&path);
+            }
+            let listener = match UnixListener::bind(&path) {
+                Ok(l) => l,
+                Err(e) => {
+                    error!("Failed to listen for peers on {:?}: {}", path, e);
+                    return;
+                }
+            };
+            info!("Listening for peers on {:?}", path);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        info!("Peer connected on {:?}", path);
+                        tokio::spawn(handle_peer(
+                            stream,
+                            Role::Listen,
+                            Arc::clone(&config),
+                            Arc::clone(&switcher),
+                            peer_tx.subscribe(),
+                        ));
+                    }
+                    Err(e) => warn!("Failed to accept peer: {}", e),
+                }
+            }
+        }
+    }
+}
+
+// Keep an outbound connection to the peer alive, reconnecting on failure
+async fn connect(
+    addr: Address,
+    config: Arc<PeerConfig>,
+    switcher: Arc<SwitchQueue>,
+    peer_tx: broadcast::Sender<u32>,
+) {
+    loop {
+        let result = match &addr {
+            Address::Tcp(a) => match TcpStream::connect(a).await {
+                Ok(stream) => {
+                    info!("Connected to peer {}", a);
+                    handle_peer(
+                        stream,
+                        Role::Connect,
+                        Arc::clone(&config),
+                        Arc::clone(&switcher),
+                        peer_tx.subscribe(),
+                    )
+                    .await;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            Address::Unix(p) => match UnixStream::connect(p).await {
+                Ok(stream) => {
+                    info!("Connected to peer {:?}", p);
+                    handle_peer(
+                        stream,
+                        Role::Connect,
+                        Arc::clone(&config),
+                        Arc::clone(&switcher),
+                        peer_tx.subscribe(),
+                    )
+                    .await;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to connect to peer: {}, retrying...", e);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn handle_peer<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    role: Role,
+    config: Arc<PeerConfig>,
+    switcher: Arc<SwitchQueue>,
+    mut layout_rx: broadcast::Receiver<u32>,
+) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+
+    let handshake = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        authenticate(&mut reader, &mut buf, &mut writer, role, &config.secret),
+    );
+    let mut session = match handshake.await {
+        Ok(Ok(session)) => {
+            info!("Peer authenticated");
+            session
+        }
+        Ok(Err(e)) => {
+            warn!("Peer authentication failed: {}", e);
+            return;
+        }
+        Err(_) => {
+            warn!("Peer authentication timed out");
+            return;
+        }
+    };
+
+    loop {
+        tokio::select! {
+            line = read_line(&mut reader, &mut buf) => {
+                let line = match line {
+                    Ok(Some(l)) => l,
+                    Ok(None) => {
+                        info!("Peer disconnected");
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Peer read error: {}", e);
+                        return;
+                    }
+                };
+                match session.open(&line) {
+                    Ok(body) => apply_peer_message(body, &switcher),
+                    Err(e) => {
+                        warn!("Dropping peer after invalid message: {}", e);
+                        return;
+                    }
+                }
+            }
+            layout = layout_rx.recv() => {
+                let layout = match layout {
+                    Ok(l) => l,
+                    Err(broadcast::error::RecvError::Lagged(_)) => CURRENT_LAYOUT.load(Ordering::SeqCst),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let message = session.seal(&format!("LAYOUT {}", layout));
+                if let Err(e) = writer.write_all(message.as_bytes()).await {
+                    warn!("Failed to send layout to peer: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn authenticate<R, W>(
+    reader: &mut BufReader<R>,
+    buf: &mut Vec<u8>,
+    writer: &mut W,
+    role: Role,
+    secret: &str,
+) -> Result<Session, String>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let nonce = random_nonce().map_err(|e| format!("failed to generate nonce: {}", e))?;
+    writer
+        .write_all(format!("HELLO {}\n", nonce).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let peer_nonce = next_field(reader, buf, "HELLO").await?;
+    if peer_nonce == nonce {
+        return Err("peer echoed our nonce".to_string());
+    }
+
+    let response = to_hex(&sign(secret.as_bytes(), role, &peer_nonce).finalize().into_bytes());
+    writer
+        .write_all(format!("AUTH {}\n", response).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let peer_response = next_field(reader, buf, "AUTH").await?;
+    let peer_mac = from_hex(&peer_response).ok_or("malformed AUTH")?;
+    sign(secret.as_bytes(), role.other(), &nonce)
+        .verify_slice(&peer_mac)
+        .map_err(|_| "wrong secret".to_string())?;
+    Ok(Session::new(secret, role, &nonce, &peer_nonce))
+}
+
+// Read one line of at most MAX_LINE bytes; None at end of stream. A partly
+// read line stays in `buf`, so this is safe to cancel in select!.
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut BufReader<R>, buf: &mut Vec<u8>) -> Result<Option<String>, String> {
+    loop {
+        if buf.len() > MAX_LINE {
+            return Err(format!("line longer than {} bytes", MAX_LINE));
+        }
+        let limit = (MAX_LINE + 1 - buf.len()) as u64;
+        let n = (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', buf)
+            .await
+            .map_err(|e| e.to_string())?;
+        if buf.last() == Some(&b'\n') {
+            let line = String::from_utf8(std::mem::take(buf)).map_err(|_| "invalid UTF-8".to_string())?;
+            return Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()));
+        }
+        if n == 0 {
+            return match buf.is_empty() {
+                true => Ok(None),
+                false => Err("connection closed mid-line".to_string()),
+            };
+        }
+    }
+}
+
+// Read the next line and return its argument if it starts with `keyword`
+async fn next_field<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    buf: &mut Vec<u8>,
+    keyword: &str,
+) -> Result<String, String> {
+    let line = read_line(reader, buf).await?.ok_or("connection closed")?;
+    line.strip_prefix(keyword)
+        .and_then(|rest| rest.strip_prefix(' '))
+        .map(|rest| rest.trim().to_string())
+        .ok_or_else(|| format!("expected {}", keyword))
+}
+
+fn apply_peer_message(body: &str, switcher: &SwitchQueue) {
+    let layout_index = match body
+        .strip_prefix("LAYOUT ")
+        .and_then(|i| i.trim().parse::<u32>().ok())
+    {
+        Some(i) => i,
+        None => {
+            warn!("Ignoring unknown peer message: {:?}", body);
+            return;
+        }
+    };
+
+    if CURRENT_LAYOUT.load(Ordering::SeqCst) == layout_index {
+        return;
+    }
+
+    // Queued like local switches, so it coalesces with them instead of racing
+    info!("Peer switched layout to index {}, following", layout_index);
+    switcher.follow(layout_index);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_bound_to_session_and_order() {
+        let mut listener = Session::new("secret", Role::Listen, "aa", "bb");
+        let mut connector = Session::new("secret", Role::Connect, "bb", "aa");
+
+        let first = listener.seal("LAYOUT 1");
+        let second = listener.seal("LAYOUT 2");
+        assert_eq!(connector.open(first.trim_end()), Ok("LAYOUT 1"));
+        // Replaying a message or skipping one is rejected
+        assert!(connector.open(first.trim_end()).is_err());
+        assert_eq!(connector.open(second.trim_end()), Ok("LAYOUT 2"));
+
+        // Our own messages reflected back don't verify
+        let mut reflected = Session::new("secret", Role::Listen, "aa", "bb");
+        let own = reflected.seal("LAYOUT 3");
+        assert!(listener.open(own.trim_end()).is_err());
+
+        // Nor do messages from another connection
+        let mut other = Session::new("secret", Role::Connect, "cc", "aa");
+        let mut fresh = Session::new("secret", Role::Listen, "aa", "bb");
+        assert!(fresh.open(other.seal("LAYOUT 1").trim_end()).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+// Event injection through the XDG RemoteDesktop portal, for sandboxes and
+// locked-down sessions where /dev/uinput can't be used. All keyboards share
+// one portal session, so the desktop asks for permission once; key presses
+// and releases (and pointer motion and wheel steps of keyboards with
+// trackpads) are sent as Notify* calls. Key repeats are left to the
+// compositor, which repeats held keys itself.
+
+use evdev::{InputEvent, InputEventKind, RelativeAxisType};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+
+use crate::output::EventSink;
+
+const PORTAL: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const REMOTE_DESKTOP: &str = "org.freedesktop.portal.RemoteDesktop";
+// Device types for SelectDevices
+const KEYBOARD: u32 = 1;
+const POINTER: u32 = 2;
+
+struct Session {
+    conn: Connection,
+    handle: OwnedObjectPath,
+}
+
+// The shared session; a refusal is remembered so the user isn't asked again
+static SESSION: Mutex<Option<Result<Arc<Session>, String>>> = Mutex::new(None);
+static TOKENS: AtomicU32 = AtomicU32::new(0);
+
+fn failure(message: String) -> io::Error {
+    io::Error::other(format!("RemoteDesktop portal: {}", message))
+}
+
+// Call a portal method that answers through a Request object, returning
+// the results of its Response signal. Err(None) means the user refused.
+fn request(
+    conn: &Connection,
+    method: &str,
+    args: impl FnOnce(&str) -> Result<OwnedObjectPath, zbus::Error>,
+) -> Result<HashMap<String, OwnedValue>, Option<String>> {
+    let token = format!("kb_layout_daemon_{}_{}", std::process::id(), TOKENS.fetch_add(1, Ordering::Relaxed));
+    // The request path is known up front, so the response can't be missed
+    let sender = conn
+        .unique_name()
+        .map(|name| name.trim_start_matches(':').replace('.', "_"))
+        .unwrap_or_default();
+    let path = format!("{}/request/{}/{}", PORTAL_PATH, sender, token);
+    let responses = Proxy::new(conn, PORTAL, path.as_str(), "org.freedesktop.portal.Request")
+        .and_then(|request| request.receive_signal("Response"))
+        .map_err(|e| Some(e.to_string()))?;
+
+    args(&token).map_err(|e| Some(format!("{} failed: {}", method, e)))?;
+    let response = responses
+        .into_iter()
+        .next()
+        .ok_or_else(|| Some(format!("no response to {}", method)))?;
+    let (code, results): (u32, HashMap<String, OwnedValue>) =
+        response.body().deserialize().map_err(|e| Some(e.to_string()))?;
+    match code {
+        0 => Ok(results),
+        1 => Err(None),
+        _ => Err(Some(format!("{} was aborted", method))),
+    }
+}
+
+// Create a session for keyboard and pointer input and start it, which
+// shows the desktop's permission dialog
+fn start() -> Result<Session, Option<String>> {
+    let conn = Connection::session().map_err(|e| Some(e.to_string()))?;
+    let portal = Proxy::new(&conn, PORTAL, PORTAL_PATH, REMOTE_DESKTOP).map_err(|e| Some(e.to_string()))?;
+
+    let results = request(&conn, "CreateSession", |token| {
+        let options = HashMap::from([
+            ("handle_token", Value::from(token)),
+            ("session_handle_token", Value::from(token)),
+        ]);
+        portal.call("CreateSession", &(options,))
+    })?;
+    let handle = results
+        .get("session_handle")
+        .and_then(|handle| String::try_from(handle.try_clone().ok()?).ok())
+        .and_then(|handle| OwnedObjectPath::try_from(handle).ok())
+        .ok_or_else(|| Some("CreateSession returned no session".to_string()))?;
+
+    request(&conn, "SelectDevices", |token| {
+        let options = HashMap::from([
+            ("handle_token", Value::from(token)),
+            ("types", Value::from(KEYBOARD | POINTER)),
+        ]);
+        portal.call("SelectDevices", &(&handle, options))
+    })?;
+    request(&conn, "Start", |token| {
+        let options = HashMap::from([("handle_token", Value::from(token))]);
+        portal.call("Start", &(&handle, "", options))
+    })?;
+
+    info!("RemoteDesktop portal session started");
+    Ok(Session { conn, handle })
+}
+
+/// Sink sending events through the shared portal session, starting it on
+/// first use.
+pub(crate) fn sink() -> io::Result<PortalSink> {
+    let mut session = SESSION.lock().unwrap();
+    if session.is_none() {
+        match start() {
+            Ok(started) => *session = Some(Ok(Arc::new(started))),
+            Err(None) => *session = Some(Err("permission was refused".to_string())),
+            // Other failures (no portal running yet) are retried
+            Err(Some(e)) => return Err(failure(e)),
+        }
+    }
+    match session.as_ref().unwrap() {
+        Ok(session) => Ok(PortalSink {
+            session: Arc::clone(session),
+        }),
+        Err(e) => Err(failure(e.clone())),
+    }
+}
+
+pub(crate) struct PortalSink {
+    session: Arc<Session>,
+}
+
+impl PortalSink {
+    fn notify<B>(&self, method: &str, body: &B) -> io::Result<()>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        self.session
+            .conn
+            .call_method(Some(PORTAL), PORTAL_PATH, Some(REMOTE_DESKTOP), method, body)
+            .map(|_| ())
+            .map_err(|e| failure(format!("{} failed: {}", method, e)))
+    }
+}
+
+impl EventSink for PortalSink {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        let handle = &self.session.handle;
+        let options: HashMap<&str, Value<'_>> = HashMap::new();
+        let (mut dx, mut dy) = (0.0, 0.0);
+        for ev in events {
+            match ev.kind() {
+                InputEventKind::Key(key) if ev.value() == 0 || ev.value() == 1 => {
+                    let state = ev.value() as u32;
+                    self.notify("NotifyKeyboardKeycode", &(handle, &options, key.code() as i32, state))?;
+                }
+                InputEventKind::RelAxis(RelativeAxisType::REL_X) => dx += ev.value() as f64,
+                InputEventKind::RelAxis(RelativeAxisType::REL_Y) => dy += ev.value() as f64,
+                // evdev scrolls up for positive values, the portal down
+                InputEventKind::RelAxis(RelativeAxisType::REL_WHEEL) => {
+                    self.notify("NotifyPointerAxisDiscrete", &(handle, &options, 0u32, -ev.value()))?;
+                }
+                InputEventKind::RelAxis(RelativeAxisType::REL_HWHEEL) => {
+                    self.notify("NotifyPointerAxisDiscrete", &(handle, &options, 1u32, ev.value()))?;
+                }
+                _ => {}
+            }
+        }
+        if dx != 0.0 || dy != 0.0 {
+            self.notify("NotifyPointerMotion", &(handle, &options, dx, dy))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+// Startup permission checks. Missing permissions otherwise show up as "No
+// keyboards found" or an endless stream of per-device retry warnings, so
+// everything is checked once up front and reported together, naming the
+// exact group or udev rule that is missing. The configured layouts are
+// checked against the backend the same way.
+
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tracing::{debug, error, info};
+use zbus::blocking::Connection;
+
+use crate::backend::{self, Backend};
+use crate::config::Config;
+use crate::daemon::{get_layouts_list, switch_layout, switch_layout_confirmed};
+use crate::xkb;
+
+const UINPUT: &str = "/dev/uinput";
+
+pub(crate) struct Problem {
+    pub(crate) what: String,
+    pub(crate) fix: String,
+}
+
+// Supplementary groups of this process
+fn process_groups() -> Vec<u32> {
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if count <= 0 {
+        return vec![unsafe { libc::getegid() }];
+    }
+    let mut groups = vec![0 as libc::gid_t; count as usize];
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    groups.push(unsafe { libc::getegid() });
+    groups
+}
+
+// Group name and member list from /etc/group
+fn group_entry(gid: u32) -> Option<(String, Vec<String>)> {
+    let content = std::fs::read_to_string("/etc/group").ok()?;
+    content.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 4 || fields[2].parse::<u32>().ok()? != gid {
+            return None;
+        }
+        let members = fields[3]
+            .split(',')
+            .filter(|m| !m.is_empty())
+            .map(str::to_string)
+            .collect();
+        Some((fields[0].to_string(), members))
+    })
+}
+
+fn user_name() -> Option<String> {
+    std::env::var("USER").ok().filter(|u| !u.is_empty())
+}
+
+// Explain how to get access to a node owned by `gid`
+fn group_fix(gid: u32) -> String {
+    let Some((group, members)) = group_entry(gid) else {
+        return format!("grant your user access to group id {}", gid);
+    };
+
+    let in_group_file = user_name().is_some_and(|u| members.contains(&u));
+    if in_group_file && !process_groups().contains(&gid) {
+        format!(
+            "you were added to the '{}' group but this session predates it - log out and back in",
+            group
+        )
+    } else {
+        format!(
+            "add yourself to the '{}' group: sudo usermod -aG {} $USER, then log out and back in",
+            group, group
+        )
+    }
+}
+
+pub(crate) fn event_nodes() -> Vec<PathBuf> {
+    let mut nodes: Vec<PathBuf> = std::fs::read_dir("/dev/input")
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.to_string_lossy().contains("event"))
+                .collect()
+        })
+        .unwrap_or_default();
+    nodes.sort();
+    nodes
+}
+
+fn check_input_nodes(problems: &mut Vec<Problem>) {
+    let nodes = event_nodes();
+    let denied: Vec<&PathBuf> = nodes
+        .iter()
+        .filter(|p| {
+            matches!(
+                OpenOptions::new().read(true).open(p),
+                Err(e) if e.kind() == ErrorKind::PermissionDenied
+            )
+        })
+        .collect();
+
+    if denied.is_empty() {
+        return;
+    }
+
+    let gid = denied[0].metadata().map(|m| m.gid()).unwrap_or(0);
+    problems.push(Problem {
+        what: format!(
+            "cannot read {} of {} input devices (e.g. {:?}); keyboards cannot be monitored",
+            denied.len(),
+            nodes.len(),
+            denied[0]
+        ),
+        fix: group_fix(gid),
+    });
+}
+
+fn check_uinput(problems: &mut Vec<Problem>, grab_mode: bool) {
+    let consequence = if grab_mode {
+        "grab mode cannot forward keys"
+    } else {
+        "switching to grab mode will not work"
+    };
+
+    match OpenOptions::new().write(true).open(UINPUT) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => problems.push(Problem {
+            what: format!("{} does not exist; {}", UINPUT, consequence),
+            fix: "load the uinput module: sudo modprobe uinput (and add it to /etc/modules-load.d/)"
+                .to_string(),
+        }),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let meta = Path::new(UINPUT).metadata().ok();
+            let group_writable = meta.as_ref().is_some_and(|m| m.mode() & 0o020 != 0);
+            let fix = match meta {
+                Some(m) if group_writable && m.gid() != 0 => group_fix(m.gid()),
+                _ => "add a udev rule giving the input group access, e.g. in /etc/udev/rules.d/99-uinput.rules:\n      \
+                      KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\", OPTIONS+=\"static_node=uinput\"\n    \
+                      then run: sudo udevadm control --reload && sudo udevadm trigger"
+                    .to_string(),
+            };
+            problems.push(Problem {
+                what: format!("cannot write {}; {}", UINPUT, consequence),
+                fix,
+            });
+        }
+        Err(e) => problems.push(Problem {
+            what: format!("cannot open {}: {}; {}", UINPUT, e, consequence),
+            fix: "check that the uinput module is loaded".to_string(),
+        }),
+    }
+}
+
+pub(crate) fn bus_problem(bus: &str, e: &zbus::Error) -> Problem {
+    if bus != "session" {
+        return Problem {
+            what: format!("cannot connect to the backend's D-Bus bus '{}': {}", bus, e),
+            fix: "check backend_bus, and that the bus is running and this user may connect to it"
+                .to_string(),
+        };
+    }
+    let fix = if std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_none() {
+        "DBUS_SESSION_BUS_ADDRESS is not set - run the daemon inside your graphical session \
+         (as a systemd user service, or after `systemctl --user import-environment`)"
+    } else {
+        "make sure the session bus from DBUS_SESSION_BUS_ADDRESS is running and belongs to this user"
+    };
+    Problem {
+        what: format!("cannot connect to the D-Bus session bus: {}", e),
+        fix: fix.to_string(),
+    }
+}
+
+/// Check device permissions; the session bus is checked by the caller.
+pub(crate) fn check(grab_mode: bool, uinput: bool) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    check_input_nodes(&mut problems);
+    if uinput {
+        check_uinput(&mut problems, grab_mode);
+    }
+    problems
+}
+
+/// Why only passive mode can work, if so: keyboards can be read but not
+/// opened for writing (read-only bind mounts in containers, kiosk
+/// policies) and there is no uinput to forward grabbed keys through.
+/// `uinput` says whether grab mode forwards through uinput at all.
+pub(crate) fn passive_only(uinput: bool) -> Option<String> {
+    if !uinput || OpenOptions::new().write(true).open(UINPUT).is_ok() {
+        return None;
+    }
+    let nodes = event_nodes();
+    let readable: Vec<&PathBuf> = nodes
+        .iter()
+        .filter(|p| OpenOptions::new().read(true).open(p).is_ok())
+        .collect();
+    // Unreadable devices are a different problem, reported by check()
+    let writable = |p: &&PathBuf| OpenOptions::new().read(true).write(true).open(p).is_ok();
+    if readable.is_empty() || readable.iter().any(writable) {
+        return None;
+    }
+    Some(format!("input devices are read-only and {} is unavailable", UINPUT))
+}
+
+/// Verify every configured layout exists in the backend. With `restore`,
+/// also switch to each one, restoring that layout index afterwards.
+pub(crate) fn check_layouts(conn: &Connection, config: &Config, restore: Option<u32>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    // Backends whose layout_names are xkb layouts can still be checked
+    // against the xkb registry when they can't list their layouts
+    let xkb_names = matches!(
+        backend::active(),
+        Backend::Kde | Backend::Gnome | Backend::Sway | Backend::Command | Backend::X11
+    );
+    let layouts = match get_layouts_list(conn) {
+        Ok(layouts) => layouts,
+        Err(zbus::Error::Unsupported) if xkb_names => return check_offline(config),
+        Err(zbus::Error::Unsupported) => {
+            debug!("No layout backend - skipping layout check");
+            return problems;
+        }
+        Err(e) => {
+            problems.push(Problem {
+                what: format!("cannot read the backend's layout list: {}", e),
+                fix: "make sure the desktop's keyboard layout service is running".to_string(),
+            });
+            if xkb_names {
+                problems.extend(check_offline(config));
+            }
+            return problems;
+        }
+    };
+    let available = layouts
+        .iter()
+        .enumerate()
+        .map(|(i, (_, _, long))| format!("{}={}", i, long))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut tried = Vec::new();
+    for kb in &config.keyboards {
+        // Keyboards driving a D-Bus action instead don't use their layout
+        if kb.dbus_action.as_ref().is_some_and(|a| !a.switch_layout) {
+            continue;
+        }
+        let Some((short, _, long)) = layouts.get(kb.layout_index as usize) else {
+            problems.push(Problem {
+                what: format!(
+                    "keyboard '{}' uses layout_index {} but only {} layout(s) are configured ({})",
+                    kb.name,
+                    kb.layout_index,
+                    layouts.len(),
+                    available
+                ),
+                fix: "fix layout_index or add the layout in the desktop's keyboard settings".to_string(),
+            });
+            continue;
+        };
+
+        let wanted = kb.layout_name.to_lowercase();
+        if !wanted.is_empty() && wanted != short.to_lowercase() && !long.to_lowercase().contains(&wanted) {
+            problems.push(Problem {
+                what: format!(
+                    "keyboard '{}' expects '{}' but layout_index {} is '{}'",
+                    kb.name, kb.layout_name, kb.layout_index, long
+                ),
+                fix: format!("pick the matching layout_index ({})", available),
+            });
+        }
+
+        // Round-trip each layout once
+        if restore.is_none() || tried.contains(&kb.layout_index) {
+            continue;
+        }
+        tried.push(kb.layout_index);
+        if let Err(e) = switch_layout_confirmed(conn, kb.layout_index) {
+            problems.push(Problem {
+                what: format!(
+                    "switching to layout_index {} ('{}') failed: {}",
+                    kb.layout_index, long, e
+                ),
+                fix: "check the layout in the desktop's keyboard settings".to_string(),
+            });
+        }
+    }
+
+    if let (Some(current), false) = (restore, tried.is_empty()) {
+        if let Err(e) = switch_layout(conn, current) {
+            error!("Failed to restore layout index {}: {}", current, e);
+        }
+    }
+
+    problems
+}
+
+// Check layout_names against the xkb registry and, where libxkbcommon is
+// available, that a keymap compiles for them
+fn check_offline(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if xkb::registry().is_empty() {
+        debug!("No xkb rules registry - skipping offline layout check");
+        return problems;
+    }
+
+    let mut checked: Vec<&str> = Vec::new();
+    for kb in &config.keyboards {
+        if kb.dbus_action.as_ref().is_some_and(|a| !a.switch_layout)
+            || kb.layout_name.is_empty()
+            || checked.contains(&kb.layout_name.as_str())
+        {
+            continue;
+        }
+        checked.push(&kb.layout_name);
+
+        let Some((layout, variant)) = xkb::find(&kb.layout_name) else {
+            problems.push(Problem {
+                what: format!("keyboard '{}' uses '{}', which xkb doesn't know", kb.name, kb.layout_name),
+                fix: "use an xkb layout code such as \"de\" or \"de(neo)\", or its description \
+                      (see `kb-layout-daemon layouts`)"
+                    .to_string(),
+            });
+            continue;
+        };
+        #[cfg(feature = "xkb")]
+        if xkb::compiles(&layout, &variant) == Some(false) {
+            problems.push(Problem {
+                what: format!(
+                    "keyboard '{}' uses '{}', but no keymap compiles for layout {} variant '{}'",
+                    kb.name, kb.layout_name, layout, variant
+                ),
+                fix: "check the xkb data installed on this system (xkeyboard-config)".to_string(),
+            });
+        }
+        #[cfg(not(feature = "xkb"))]
+        let _ = (layout, variant);
+    }
+    problems
+}
+
+/// Log all problems as a single report.
+pub(crate) fn report(check: &str, problems: &[Problem]) {
+    if problems.is_empty() {
+        info!("{} passed", check);
+        return;
+    }
+
+    let mut report = format!("{} found {} problem(s):", check, problems.len());
+    for (i, p) in problems.iter().enumerate() {
+        report.push_str(&format!("\n  {}. {}\n     fix: {}", i + 1, p.what, p.fix));
+    }
+    error!("{}", report);
+}
@@ -0,0 +1,102 @@
+// Config file watching for live reload. The file's directory is watched
+// with inotify rather than the file itself, since editors usually replace
+// the file (write a copy, rename it over) instead of writing in place. A
+// change is reported once writes have been quiet for SETTLE, so an editor
+// saving in several steps causes one reload.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+// Quiet period after the last change before reloading
+const SETTLE: Duration = Duration::from_millis(300);
+
+const EVENTS: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_DELETE;
+
+// Names of the directory entries in a buffer of inotify events
+fn event_names(buf: &[u8]) -> Vec<&[u8]> {
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut names = Vec::new();
+    let mut offset = 0;
+    while offset + header <= buf.len() {
+        let event: libc::inotify_event =
+            unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr().cast()) };
+        let start = offset + header;
+        let end = (start + event.len as usize).min(buf.len());
+        let name = &buf[start..end];
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        names.push(&name[..len]);
+        offset = end;
+    }
+    names
+}
+
+// Wait up to `timeout` for the inotify fd to become readable
+fn readable(fd: i32, timeout: Option<Duration>) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = timeout.map_or(-1, |t| t.as_millis() as i32);
+    unsafe { libc::poll(&mut pollfd, 1, timeout) > 0 }
+}
+
+/// Watch `path` from the calling thread, sending on `tx` after each
+/// change. Returns when the receiver is gone or watching fails.
+pub(crate) fn watch(path: &Path, tx: mpsc::UnboundedSender<()>) {
+    let (Some(dir), Some(file)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    watch_entries(dir, |name| name == file.as_bytes(), tx);
+}
+
+/// Watch the `*.toml` files in `dir` like `watch`, for the drop-in
+/// directory. A directory created later isn't watched.
+pub(crate) fn watch_drop_ins(dir: &Path, tx: mpsc::UnboundedSender<()>) {
+    watch_entries(dir, |name| name.ends_with(b".toml"), tx);
+}
+
+fn watch_entries(dir: &Path, wanted: impl Fn(&[u8]) -> bool, tx: mpsc::UnboundedSender<()>) {
+    let Ok(dir_c) = CString::new(dir.as_os_str().as_bytes()) else {
+        return;
+    };
+
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        warn!("Cannot watch the config for changes: {}", std::io::Error::last_os_error());
+        return;
+    }
+    if unsafe { libc::inotify_add_watch(fd, dir_c.as_ptr(), EVENTS) } < 0 {
+        debug!("Not watching {:?} for changes: {}", dir, std::io::Error::last_os_error());
+        unsafe { libc::close(fd) };
+        return;
+    }
+    debug!("Watching {:?} for changes", dir);
+
+    let mut buf = [0u8; 4096];
+    let mut changed = false;
+    loop {
+        // Block until something happens, then until things settle
+        let timeout = changed.then_some(SETTLE);
+        if !readable(fd, timeout) {
+            if changed && tx.send(()).is_err() {
+                break;
+            }
+            changed = false;
+            continue;
+        }
+        let read = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        if read <= 0 {
+            warn!("Stopped watching the config for changes: {}", std::io::Error::last_os_error());
+            break;
+        }
+        if event_names(&buf[..read as usize]).into_iter().any(&wanted) {
+            changed = true;
+        }
+    }
+    unsafe { libc::close(fd) };
+}
@@ -0,0 +1,195 @@
+// Remap DSL: the `remap` lines of a [[keyboards]] entry, such as
+// "capslock -> esc" or "ralt+hjkl -> arrows", expanded into the rules the
+// Remap stage in events.rs applies. Both sides of a line are key lists of
+// the same length: a key name, several names separated by commas, a group
+// such as "arrows", or a run of single-character keys ("hjkl").
+
+use evdev::Key;
+
+use crate::events::{Modifiers, RemapRule};
+
+// Short names for keys whose evdev names are long or unusual
+const ALIASES: &[(&str, Key)] = &[
+    ("caps", Key::KEY_CAPSLOCK),
+    ("escape", Key::KEY_ESC),
+    ("return", Key::KEY_ENTER),
+    ("del", Key::KEY_DELETE),
+    ("pgup", Key::KEY_PAGEUP),
+    ("pgdn", Key::KEY_PAGEDOWN),
+    ("ctrl", Key::KEY_LEFTCTRL),
+    ("lctrl", Key::KEY_LEFTCTRL),
+    ("rctrl", Key::KEY_RIGHTCTRL),
+    ("shift", Key::KEY_LEFTSHIFT),
+    ("lshift", Key::KEY_LEFTSHIFT),
+    ("rshift", Key::KEY_RIGHTSHIFT),
+    ("alt", Key::KEY_LEFTALT),
+    ("lalt", Key::KEY_LEFTALT),
+    ("ralt", Key::KEY_RIGHTALT),
+    ("altgr", Key::KEY_RIGHTALT),
+    ("meta", Key::KEY_LEFTMETA),
+    ("super", Key::KEY_LEFTMETA),
+    ("lmeta", Key::KEY_LEFTMETA),
+    ("rmeta", Key::KEY_RIGHTMETA),
+];
+
+// Named key lists, in the order of the keys they usually pair with
+// ("hjkl -> arrows")
+const GROUPS: &[(&str, &[Key])] = &[
+    ("arrows", &[Key::KEY_LEFT, Key::KEY_DOWN, Key::KEY_UP, Key::KEY_RIGHT]),
+    (
+        "digits",
+        &[
+            Key::KEY_1,
+            Key::KEY_2,
+            Key::KEY_3,
+            Key::KEY_4,
+            Key::KEY_5,
+            Key::KEY_6,
+            Key::KEY_7,
+            Key::KEY_8,
+            Key::KEY_9,
+            Key::KEY_0,
+        ],
+    ),
+];
+
+// A single key: alias, evdev name with or without KEY_ prefix
+fn key(name: &str) -> Option<Key> {
+    let name = name.trim().to_lowercase();
+    if let Some((_, key)) = ALIASES.iter().find(|(alias, _)| *alias == name) {
+        return Some(*key);
+    }
+    let name = name.strip_prefix("key_").unwrap_or(&name);
+    format!("KEY_{}", name.to_uppercase()).parse().ok()
+}
+
+fn keys(spec: &str) -> Result<Vec<Key>, String> {
+    let spec = spec.trim();
+    if let Some((_, keys)) = GROUPS.iter().find(|(group, _)| spec.eq_ignore_ascii_case(group)) {
+        return Ok(keys.to_vec());
+    }
+    if spec.contains(',') {
+        return spec
+            .split(',')
+            .map(|name| key(name).ok_or_else(|| format!("unknown key '{}'", name.trim())))
+            .collect();
+    }
+    if let Some(key) = key(spec) {
+        return Ok(vec![key]);
+    }
+    if spec.len() > 1 && spec.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return spec
+            .chars()
+            .map(|c| key(&c.to_string()).ok_or_else(|| format!("unknown key '{}'", c)))
+            .collect();
+    }
+    Err(format!("unknown key '{}'", spec))
+}
+
+fn parse_line(line: &str) -> Result<Vec<RemapRule>, String> {
+    let (from, to) = line.split_once("->").ok_or("expected '<keys> -> <keys>'")?;
+    let (layer, from) = match from.split_once('+') {
+        Some((layer, from)) => {
+            let layer = key(layer).ok_or_else(|| format!("unknown layer key '{}'", layer.trim()))?;
+            (Some(layer.code()), from)
+        }
+        None => (None, from),
+    };
+    let (from, to) = (keys(from)?, keys(to)?);
+    if from.len() != to.len() {
+        return Err(format!("{} keys mapped to {} keys", from.len(), to.len()));
+    }
+    Ok(from
+        .iter()
+        .zip(&to)
+        .map(|(from, to)| RemapRule {
+            layer,
+            from: from.code(),
+            to: to.code(),
+        })
+        .collect())
+}
+
+/// Parse a hotkey such as `"ctrl+space"` or `"KEY_F13"`: modifiers joined
+/// with `+`, then the key, named as in remap lines.
+///
+/// ```
+/// use evdev::Key;
+/// use kb_layout_daemon::events::Modifiers;
+/// use kb_layout_daemon::remap::hotkey;
+///
+/// assert_eq!(hotkey("ctrl+alt+space"), Ok((Modifiers::CTRL | Modifiers::ALT, Key::KEY_SPACE)));
+/// assert_eq!(hotkey("f13"), Ok((Modifiers::NONE, Key::KEY_F13)));
+/// assert!(hotkey("space+ctrl").is_err());
+/// ```
+pub fn hotkey(spec: &str) -> Result<(Modifiers, Key), String> {
+    let mut names: Vec<&str> = spec.split('+').collect();
+    let last = names.pop().unwrap_or_default();
+    let target = key(last).ok_or_else(|| format!("unknown key '{}'", last.trim()))?;
+    let mut modifiers = Modifiers::NONE;
+    for name in names {
+        let modifier = key(name).map_or(Modifiers::NONE, |key| Modifiers::from_key(key.code()));
+        if modifier.is_empty() {
+            return Err(format!("'{}' is not a modifier", name.trim()));
+        }
+        modifiers = modifiers | modifier;
+    }
+    if !Modifiers::from_key(target.code()).is_empty() {
+        return Err(format!("'{}' is a modifier, a hotkey needs another key", last.trim()));
+    }
+    Ok((modifiers, target))
+}
+
+/// Expand `remap` lines into rules. Fails on unparsable lines and on
+/// conflicts: a key mapped to two different keys (in the same layer), or a
+/// layer key that is also remapped.
+pub fn parse(lines: &[String]) -> Result<Vec<RemapRule>, String> {
+    // Each rule with the line it came from, for conflict messages
+    let mut rules: Vec<(RemapRule, &str)> = Vec::new();
+    for line in lines {
+        for rule in parse_line(line).map_err(|e| format!("'{}': {}", line, e))? {
+            if let Some((other, other_line)) = rules
+                .iter()
+                .find(|(other, _)| other.layer == rule.layer && other.from == rule.from)
+            {
+                if other.to != rule.to {
+                    return Err(format!(
+                        "'{}' and '{}' both remap {}",
+                        other_line,
+                        line,
+                        describe_from(&rule)
+                    ));
+                }
+                continue;
+            }
+            rules.push((rule, line));
+        }
+    }
+
+    for (rule, line) in &rules {
+        let Some(layer) = rule.layer else {
+            continue;
+        };
+        if let Some((_, other_line)) = rules.iter().find(|(other, _)| other.from == layer) {
+            return Err(format!(
+                "'{}' uses {:?} as a layer key, which '{}' remaps",
+                line,
+                Key::new(layer),
+                other_line
+            ));
+        }
+    }
+    Ok(rules.into_iter().map(|(rule, _)| rule).collect())
+}
+
+fn describe_from(rule: &RemapRule) -> String {
+    match rule.layer {
+        Some(layer) => format!("{:?}+{:?}", Key::new(layer), Key::new(rule.from)),
+        None => format!("{:?}", Key::new(rule.from)),
+    }
+}
+
+/// One expanded rule, e.g. "KEY_RIGHTALT+KEY_H -> KEY_LEFT".
+pub fn describe(rule: &RemapRule) -> String {
+    format!("{} -> {:?}", describe_from(rule), Key::new(rule.to))
+}
@@ -0,0 +1,181 @@
+// Session detection. A backend that can't work in the running session
+// (e.g. the KDE backend under sway) would otherwise start fine and simply
+// never switch, so the daemon refuses to start and says which backend the
+// session needs instead.
+
+use tracing::info;
+use zbus::blocking::Connection;
+
+use crate::backend::Backend;
+
+/// Switch command for Hyprland, which has no native backend yet.
+pub(crate) const HYPRLAND_SWITCH: &str = "hyprctl switchxkblayout all {index}";
+
+pub(crate) struct Session {
+    // "x11", "wayland", "tty" or empty when unknown
+    pub(crate) kind: String,
+    // Lowercased XDG_CURRENT_DESKTOP entries, e.g. ["kde"]
+    pub(crate) desktops: Vec<String>,
+}
+
+impl Session {
+    pub(crate) fn detect() -> Self {
+        let kind = std::env::var("XDG_SESSION_TYPE")
+            .unwrap_or_default()
+            .to_lowercase();
+        let mut desktops: Vec<String> = std::env::var("XDG_CURRENT_DESKTOP")
+            .unwrap_or_default()
+            .split(':')
+            .filter(|d| !d.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        // sway doesn't always set XDG_CURRENT_DESKTOP
+        if desktops.is_empty() && std::env::var_os("SWAYSOCK").is_some() {
+            desktops.push("sway".to_string());
+        }
+        Session { kind, desktops }
+    }
+
+    fn is(&self, desktop: &str) -> bool {
+        self.desktops.iter().any(|d| d == desktop)
+    }
+
+    fn describe(&self) -> String {
+        let desktop = if self.desktops.is_empty() {
+            "unknown desktop".to_string()
+        } else {
+            self.desktops.join(":")
+        };
+        let kind = if self.kind.is_empty() {
+            "unknown"
+        } else {
+            &self.kind
+        };
+        format!("{} ({} session)", desktop, kind)
+    }
+
+    // Backend this session needs, and whether this build has it
+    fn suggestion(&self) -> String {
+        if self.is("kde") && cfg!(feature = "kde") {
+            "set backend = \"kde\" in the config".to_string()
+        } else if self.is("gnome") && cfg!(feature = "gnome") {
+            "set backend = \"gnome\" in the config".to_string()
+        } else if self.is("sway") && cfg!(feature = "sway") {
+            "set backend = \"sway\" in the config".to_string()
+        } else if self.is("kde") || self.is("gnome") || self.is("sway") {
+            "this build doesn't have the backend it needs".to_string()
+        } else if self.is("hyprland") && cfg!(feature = "command") {
+            format!(
+                "set backend = \"command\" with switch = \"{}\" in the [command] section",
+                HYPRLAND_SWITCH
+            )
+        } else if self.is("hyprland") {
+            "Hyprland needs the command backend, which this build doesn't have".to_string()
+        } else if self.kind == "x11" && cfg!(feature = "x11") {
+            "set backend = \"x11\" in the config".to_string()
+        } else if self.kind == "tty" && cfg!(feature = "console") {
+            "set backend = \"console\" with keymaps in the [console] section".to_string()
+        } else {
+            "no layout backend in this build supports it".to_string()
+        }
+    }
+}
+
+fn bus_has_name(conn: &Connection, name: &str) -> bool {
+    zbus::blocking::fdo::DBusProxy::new(conn)
+        .ok()
+        .and_then(|dbus| {
+            name.try_into()
+                .ok()
+                .and_then(|n| dbus.name_has_owner(n).ok())
+        })
+        .unwrap_or(false)
+}
+
+/// Pick the backend for `backend = "auto"`: the first one this build has
+/// whose desktop is running, with what gave it away. None is picked when
+/// nothing matches, so keyboards are still monitored.
+pub(crate) fn detect_backend(conn: Option<&Connection>) -> (Backend, String) {
+    let session = Session::detect();
+    let on_bus = |name: &str| conn.is_some_and(|conn| bus_has_name(conn, name));
+    let hyprland = std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() || session.is("hyprland");
+
+    let candidates = [
+        (Backend::Kde, on_bus("org.kde.keyboard"), "org.kde.keyboard is on the session bus"),
+        (Backend::Sway, std::env::var_os("SWAYSOCK").is_some(), "$SWAYSOCK is set"),
+        (Backend::Command, hyprland, "Hyprland is running, switching with hyprctl"),
+        (
+            Backend::Gnome,
+            session.is("gnome") || on_bus("org.gnome.Shell"),
+            "this is a GNOME session",
+        ),
+        (
+            Backend::X11,
+            session.kind == "x11" && std::env::var_os("DISPLAY").is_some(),
+            "this is an X11 session",
+        ),
+        (Backend::Console, session.kind == "tty", "this is a console session"),
+    ];
+    for (backend, detected, reason) in candidates {
+        if detected && backend.is_available() {
+            return (backend, reason.to_string());
+        }
+    }
+    (
+        Backend::None,
+        format!("no layout backend found for {}; {}", session.describe(), session.suggestion()),
+    )
+}
+
+/// Check that `backend` can work in this session. Unknown sessions pass;
+/// so does a session where the backend's service is on the bus anyway.
+pub(crate) fn check_backend(conn: &Connection, backend: &str) -> Result<(), String> {
+    let session = Session::detect();
+    info!("Session: {}", session.describe());
+
+    if session.desktops.is_empty() {
+        return Ok(());
+    }
+
+    match backend {
+        "kde" if !session.is("kde") && !bus_has_name(conn, "org.kde.keyboard") => Err(format!(
+            "the KDE backend needs a Plasma session, but this is {} and org.kde.keyboard is not \
+             on the session bus; {}",
+            session.describe(),
+            session.suggestion()
+        )),
+        "gnome" if !session.is("gnome") && !bus_has_name(conn, "org.gnome.Shell") => Err(format!(
+            "the GNOME backend needs a GNOME Shell session, but this is {} and org.gnome.Shell \
+             is not on the session bus; {}",
+            session.describe(),
+            session.suggestion()
+        )),
+        "fcitx5" if !bus_has_name(conn, "org.fcitx.Fcitx5") => Err(format!(
+            "the fcitx5 backend needs fcitx5 running, but org.fcitx.Fcitx5 is not on the \
+             session bus in {}; start fcitx5 or pick another backend",
+            session.describe()
+        )),
+        #[cfg(feature = "ibus")]
+        "ibus" if !crate::backend::ibus_running() => Err(format!(
+            "the ibus backend needs ibus-daemon running, but it was not found in {}; \
+             start it with `ibus-daemon -drx` or pick another backend",
+            session.describe()
+        )),
+        "x11" if std::env::var_os("DISPLAY").is_none() => Err(format!(
+            "the x11 backend needs $DISPLAY, but it is not set in {}; {}",
+            session.describe(),
+            session.suggestion()
+        )),
+        "console" if session.kind == "x11" || session.kind == "wayland" => Err(format!(
+            "the console backend only changes the keymap of virtual terminals, but this is {}; {}",
+            session.describe(),
+            session.suggestion()
+        )),
+        "sway" if std::env::var_os("SWAYSOCK").is_none() => Err(format!(
+            "the sway backend needs $SWAYSOCK, but it is not set in {}; {}",
+            session.describe(),
+            session.suggestion()
+        )),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,266 @@
+// `kb-layout-daemon setup`: an interactive first configuration. Lists the
+// connected keyboards, has the user press Enter on each keyboard to set up
+// (the Enter press tells which device it is, and ends the terminal line at
+// the same time), offers the desktop's layouts and writes the entries into
+// the config file, keeping its other settings.
+
+use evdev::{Device, InputEventKind, Key};
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::backend::{self, Backend};
+use crate::config::{self, Config, Format, CONFIG_VERSION};
+use crate::daemon::{get_layouts_list, is_ignored, is_ignored_node, is_physical_keyboard};
+use crate::inspect;
+use crate::preflight;
+use crate::session;
+use crate::xkb;
+
+struct Keyboard {
+    path: PathBuf,
+    name: String,
+    id: String,
+}
+
+// Keyboards the daemon could take on: letter keys and a space bar, not a
+// virtual device, not in `ignore`
+fn keyboards(config: &Config) -> Vec<Keyboard> {
+    preflight::event_nodes()
+        .into_iter()
+        .filter(|path| !is_ignored_node(path, config))
+        .filter_map(|path| {
+            let device = Device::open(&path).ok()?;
+            if !is_physical_keyboard(&device) || is_ignored(&device, config) {
+                return None;
+            }
+            Some(Keyboard {
+                name: device.name().unwrap_or("Unknown").to_string(),
+                id: inspect::device_id(&device),
+                path,
+            })
+        })
+        .collect()
+}
+
+// Record which keyboard Enter was last pressed on, from a thread per
+// keyboard reading alongside the terminal
+fn watch_enter(keyboards: &[Keyboard]) -> Arc<Mutex<Option<usize>>> {
+    let last = Arc::new(Mutex::new(None));
+    for (index, keyboard) in keyboards.iter().enumerate() {
+        let Ok(mut device) = Device::open(&keyboard.path) else {
+            continue;
+        };
+        let last = Arc::clone(&last);
+        std::thread::spawn(move || {
+            while let Ok(events) = device.fetch_events() {
+                let enter = events.into_iter().any(|ev| {
+                    matches!(ev.kind(), InputEventKind::Key(Key::KEY_ENTER | Key::KEY_KPENTER)) && ev.value() == 1
+                });
+                if enter {
+                    *last.lock().unwrap() = Some(index);
+                }
+            }
+        });
+    }
+    last
+}
+
+// The desktop's layouts as (name for layout_name, label), in index order
+fn desktop_layouts() -> Result<Vec<(String, String)>, String> {
+    let mut config: Config = config::load_config();
+    if config.backend == Backend::Auto {
+        let conn = backend::connect(&config.backend_bus).ok();
+        let (detected, reason) = session::detect_backend(conn.as_ref());
+        if detected == Backend::None {
+            return Err(format!("no layout backend detected: {}", reason));
+        }
+        if detected == Backend::Command && config.command.switch.is_none() {
+            config.command.switch = Some(session::HYPRLAND_SWITCH.to_string());
+        }
+        config.backend = detected;
+    }
+    if !config.backend.is_available() {
+        return Err(format!("this build has no {} backend", config.backend.name()));
+    }
+    backend::configure(&config)?;
+    backend::select(config.backend, None);
+    let conn = backend::connect(&config.backend_bus).map_err(|e| e.to_string())?;
+    let layouts = get_layouts_list(&conn).map_err(|e| format!("cannot read the desktop's layouts: {}", e))?;
+    Ok(layouts
+        .into_iter()
+        .map(|(short, variant, long)| {
+            let code = if variant.is_empty() {
+                short.clone()
+            } else {
+                format!("{}({})", short, variant)
+            };
+            if !long.is_empty() {
+                return (long, code);
+            }
+            let label = xkb::description(&short).unwrap_or_else(|| code.clone());
+            (code, label)
+        })
+        .collect())
+}
+
+fn read_line() -> Result<Option<String>, String> {
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(line.trim().to_string())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Ask for a layout until the answer is one of the listed indices
+fn ask_layout(keyboard: &str, count: usize) -> Result<Option<usize>, String> {
+    loop {
+        print!("Layout for '{}' (0-{}): ", keyboard, count - 1);
+        let Some(answer) = read_line()? else {
+            return Ok(None);
+        };
+        match answer.parse::<usize>() {
+            Ok(index) if index < count => return Ok(Some(index)),
+            _ => println!("Enter a number from the list."),
+        }
+    }
+}
+
+/// Run the interactive setup on the terminal and write the chosen
+/// keyboards into the config file.
+pub fn run() -> Result<(), String> {
+    let keyboards = keyboards(&config::load_config());
+    if keyboards.is_empty() {
+        return Err("no keyboards found; the user needs read access to /dev/input/event* (the input group)".into());
+    }
+    let layouts = desktop_layouts()?;
+    if layouts.is_empty() {
+        return Err("the desktop reports no layouts; configure them in its keyboard settings first".into());
+    }
+
+    println!("Keyboards:");
+    for keyboard in &keyboards {
+        println!("  {} ({})", keyboard.name, keyboard.path.display());
+    }
+    println!("Layouts:");
+    for (index, (_, label)) in layouts.iter().enumerate() {
+        println!("  {:<3}{}", index, label);
+    }
+    println!();
+
+    let last_enter = watch_enter(&keyboards);
+    let mut entries: Vec<toml::Value> = Vec::new();
+    loop {
+        print!("Press Enter on a keyboard to set it up, or type 'done': ");
+        *last_enter.lock().unwrap() = None;
+        let Some(answer) = read_line()? else {
+            break;
+        };
+        if answer.eq_ignore_ascii_case("done") {
+            break;
+        }
+        // The device's Enter event may trail the terminal's line a little
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let Some(index) = *last_enter.lock().unwrap() else {
+            println!("That Enter didn't come from a listed keyboard.");
+            continue;
+        };
+        let keyboard = &keyboards[index];
+        println!("That is '{}'.", keyboard.name);
+        let Some(layout) = ask_layout(&keyboard.name, layouts.len())? else {
+            break;
+        };
+
+        let mut entry = toml::Table::new();
+        entry.insert("name".into(), keyboard.name.clone().into());
+        // Several keyboards of the same name need the id to tell them apart
+        if keyboards.iter().filter(|other| other.name == keyboard.name).count() > 1 {
+            entry.insert("id".into(), keyboard.id.clone().into());
+        }
+        entry.insert("layout_index".into(), (layout as i64).into());
+        entry.insert("layout_name".into(), layouts[layout].0.clone().into());
+        entries.retain(|other| other.get("name") != entry.get("name") || other.get("id") != entry.get("id"));
+        entries.push(entry.into());
+    }
+    if entries.is_empty() {
+        println!("No keyboards set up, config left as it was.");
+        return Ok(());
+    }
+
+    let path = config::config_path();
+    let format = Format::of(&path);
+    let (mut table, content) = match std::fs::read_to_string(&path) {
+        Ok(content) => {
+            print!("Replace the keyboard entries in {}? [y/N] ", path.display());
+            if !read_line()?.is_some_and(|answer| answer.eq_ignore_ascii_case("y")) {
+                return Err("config left as it was".into());
+            }
+            let mut table: toml::Table = format.parse(&content)?;
+            let upgraded = !config::migrate(&mut table)?.is_empty();
+            if upgraded && format == Format::Toml {
+                println!("{} uses an older schema and is written anew, without its comments.", path.display());
+            }
+            (table, Some(content).filter(|_| !upgraded && format == Format::Toml))
+        }
+        Err(_) => (toml::Table::new(), None),
+    };
+    let content = match content {
+        // A current TOML file keeps its comments and other settings
+        Some(content) => replace_entries(&content, &entries)?,
+        None => {
+            table.insert("version".into(), (CONFIG_VERSION as i64).into());
+            table.insert("keyboards".into(), toml::Value::Array(entries));
+            format.write(&table)?
+        }
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    }
+    std::fs::write(&path, content).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    println!("Wrote {}. Run `kb-layout-daemon check-config` to verify it.", path.display());
+    Ok(())
+}
+
+// Replace the [[keyboards]] entries of a TOML config in place, keeping its
+// comments and other settings as they are
+fn replace_entries(content: &str, entries: &[toml::Value]) -> Result<String, String> {
+    let mut document: toml_edit::DocumentMut = content.parse().map_err(|e: toml_edit::TomlError| e.to_string())?;
+    if document.get("version").and_then(|item| item.as_integer()) != Some(CONFIG_VERSION as i64) {
+        document.insert("version", toml_edit::value(CONFIG_VERSION as i64));
+    }
+    let mut keyboards = toml_edit::ArrayOfTables::new();
+    for entry in entries.iter().filter_map(toml::Value::as_table) {
+        let mut table = toml_edit::Table::new();
+        for (key, value) in entry {
+            let mut value: toml_edit::Value = value.to_string().parse().map_err(|e: toml_edit::TomlError| e.to_string())?;
+            value.decor_mut().clear();
+            table.insert(key, toml_edit::Item::Value(value));
+        }
+        keyboards.push(table);
+    }
+    // Re-inserted, so the new entries follow the other tables
+    document.remove("keyboards");
+    document.insert("keyboards", toml_edit::Item::ArrayOfTables(keyboards));
+    Ok(document.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replacing_entries_keeps_comments() {
+        let content = "# my settings\nmode = \"passive\" # not grab\n\n[[keyboards]]\nname = \"Old\"\n\n[log]\nlevel = \"debug\"\n";
+        let mut entry = toml::Table::new();
+        entry.insert("name".into(), "Lofree".into());
+        entry.insert("layout_index".into(), 1.into());
+        let written = replace_entries(content, &[entry.into()]).unwrap();
+        assert_eq!(
+            written,
+            "# my settings\nmode = \"passive\" # not grab\nversion = 2\n\n[log]\nlevel = \"debug\"\n\n\
+             [[keyboards]]\nlayout_index = 1\nname = \"Lofree\"\n"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+// Audible feedback on layout switches, for people who type without looking
+// at the screen. Off by default. Plays a sound theme event through
+// libcanberra's `canberra-gtk-play`, or runs a user command instead, from a
+// short-lived thread so the switch worker never waits on it. Sounds closer
+// together than `min_interval_ms` are skipped, so alternating keyboards
+// don't produce a barrage.
+
+use serde::Deserialize;
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::clock;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SoundConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Sound theme event played with canberra-gtk-play
+    #[serde(default = "default_event")]
+    pub event: String,
+    // Shell command run instead, with {index} and {layout} substituted
+    pub command: Option<String>,
+    // Switches within this long of the last sound stay silent
+    #[serde(default = "default_min_interval_ms")]
+    pub min_interval_ms: u64,
+}
+
+fn default_event() -> String {
+    "audio-volume-change".to_string()
+}
+
+fn default_min_interval_ms() -> u64 {
+    500
+}
+
+impl Default for SoundConfig {
+    fn default() -> Self {
+        SoundConfig {
+            enabled: false,
+            event: default_event(),
+            command: None,
+            min_interval_ms: default_min_interval_ms(),
+        }
+    }
+}
+
+static CONFIG: OnceLock<SoundConfig> = OnceLock::new();
+static LAST_PLAYED: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Take the `[sound]` section; without this nothing is played.
+pub(crate) fn configure(config: &SoundConfig) {
+    if config.enabled {
+        let _ = CONFIG.set(config.clone());
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Play the switch sound for `layout`, unless one played too recently.
+pub(crate) fn play(layout_index: u32, layout: &str) {
+    let Some(config) = CONFIG.get() else {
+        return;
+    };
+    {
+        let mut last = LAST_PLAYED.lock().unwrap();
+        let min_interval = Duration::from_millis(config.min_interval_ms);
+        let now = clock::now();
+        if last.is_some_and(|last| now.saturating_duration_since(last) < min_interval) {
+            return;
+        }
+        *last = Some(now);
+    }
+
+    let mut command = match &config.command {
+        Some(template) => {
+            let command = template
+                .replace("{index}", &layout_index.to_string())
+                .replace("{layout}", &quote(layout));
+            let mut sh = process::Command::new("sh");
+            sh.args(["-c", &command]);
+            sh
+        }
+        None => {
+            let mut play = process::Command::new("canberra-gtk-play");
+            play.args(["-i", &config.event, "-d", "kb-layout-daemon"]);
+            play
+        }
+    };
+    std::thread::spawn(move || match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => debug!("Switch sound exited with {}", status),
+        Err(e) => debug!("Failed to play switch sound: {}", e),
+    });
+}
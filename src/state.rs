@@ -0,0 +1,89 @@
+// Runtime state kept across restarts in
+// `$XDG_STATE_HOME/kb-layout-daemon/state.json`: the mode last set over
+// D-Bus and the layout each keyboard last cycled to with its cycle_key.
+// Read at startup and written on every change; `persist_state = false`
+// turns both off.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    mode: Option<String>,
+    // Keyboard entry name -> layout from its `layouts` list
+    #[serde(default)]
+    layouts: BTreeMap<String, String>,
+}
+
+// None while state isn't kept
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn path() -> Option<PathBuf> {
+    dirs::state_dir().map(|dir| dir.join("kb-layout-daemon").join("state.json"))
+}
+
+/// Read the saved state and keep it up to date from now on. Returns the
+/// saved mode, if any.
+pub(crate) fn load() -> Option<String> {
+    let Some(path) = path() else {
+        debug!("No state directory, runtime state isn't kept");
+        return None;
+    };
+    let state = match std::fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable state file {:?}: {}", path, e);
+            State::default()
+        }),
+        Err(_) => State::default(),
+    };
+    if state.mode.is_some() || !state.layouts.is_empty() {
+        info!("Restoring state from {:?}", path);
+    }
+    let mode = state.mode.clone();
+    *STATE.lock().unwrap() = Some(state);
+    mode
+}
+
+fn save(state: &State) {
+    let Some(path) = path() else {
+        return;
+    };
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(&path, serde_json::to_string_pretty(state).unwrap_or_default()));
+    if let Err(e) = written {
+        warn!("Failed to save state to {:?}: {}", path, e);
+    }
+}
+
+// Change the state if it is kept, saving it when it changed
+fn update(change: impl FnOnce(&mut State) -> bool) {
+    let mut guard = STATE.lock().unwrap();
+    if let Some(state) = guard.as_mut() {
+        if change(state) {
+            save(state);
+        }
+    }
+}
+
+/// Remember the mode set over D-Bus.
+pub(crate) fn set_mode(mode: &str) {
+    update(|state| state.mode.replace(mode.to_string()).as_deref() != Some(mode));
+}
+
+/// The layout the keyboard entry `keyboard` last cycled to.
+pub(crate) fn layout(keyboard: &str) -> Option<String> {
+    STATE.lock().unwrap().as_ref()?.layouts.get(keyboard).cloned()
+}
+
+/// Remember the layout a keyboard entry cycled to; None forgets it.
+pub(crate) fn set_layout(keyboard: &str, layout: Option<&str>) {
+    update(|state| match layout {
+        Some(layout) => state.layouts.insert(keyboard.to_string(), layout.to_string()).as_deref() != Some(layout),
+        None => state.layouts.remove(keyboard).is_some(),
+    });
+}
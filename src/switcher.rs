@@ -0,0 +1,162 @@
+// Layout switch queue. Monitors submit target layouts and a single worker
+// thread applies them. Requests arriving while a switch is in flight are
+// coalesced, so when two keyboards alternate rapidly only the most recent
+// target is applied next instead of a burst of setLayout calls and
+// confirmation waits.
+
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, error};
+use zbus::blocking::Connection;
+
+use crate::clock;
+use crate::daemon::{switch_layout_confirmed, CURRENT_LAYOUT};
+use crate::layouts;
+use crate::sound;
+
+#[derive(Default)]
+struct State {
+    // Target layout, and whether peers should follow it
+    pending: Option<(u32, bool)>,
+    // Tickets handed out / resolved so far; a ticket is resolved once a
+    // switch that started after it was submitted has finished
+    submitted: u64,
+    completed: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct SwitchQueue {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl SwitchQueue {
+    /// Request a switch to `layout_index`, replacing any request that hasn't
+    /// started yet. Returns a ticket for `wait`.
+    pub(crate) fn submit(&self, layout_index: u32) -> u64 {
+        self.push(layout_index, true)
+    }
+
+    /// Like `submit`, for a switch made by a peer: it isn't sent back to
+    /// peers, so two machines can't bounce stale layouts at each other.
+    #[cfg(feature = "peer")]
+    pub(crate) fn follow(&self, layout_index: u32) -> u64 {
+        self.push(layout_index, false)
+    }
+
+    fn push(&self, layout_index: u32, notify_peers: bool) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        if let Some((old, _)) = state.pending.replace((layout_index, notify_peers)) {
+            if old != layout_index {
+                debug!("Coalescing switch to index {} into index {}", old, layout_index);
+            }
+        }
+        state.submitted += 1;
+        self.cond.notify_all();
+        state.submitted
+    }
+
+    /// Wait until the switch for `ticket` (or one superseding it) is done.
+    /// Returns false on timeout.
+    pub(crate) fn wait(&self, ticket: u64, timeout: Duration) -> bool {
+        let deadline = clock::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while state.completed < ticket {
+            let slice = clock::wait_slice(deadline);
+            if slice.is_zero() {
+                return false;
+            }
+            state = self.cond.wait_timeout(state, slice).unwrap().0;
+        }
+        true
+    }
+
+    /// The latest ticket resolved so far, without waiting.
+    pub(crate) fn completed(&self) -> u64 {
+        self.state.lock().unwrap().completed
+    }
+
+    // Block until there is work; returns the latest target, whether peers
+    // should follow it, and the ticket it resolves
+    fn next(&self) -> (u32, bool, u64) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some((layout_index, notify_peers)) = state.pending.take() {
+                return (layout_index, notify_peers, state.submitted);
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    fn complete(&self, ticket: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.completed = state.completed.max(ticket);
+        self.cond.notify_all();
+    }
+}
+
+/// Start the worker thread that applies queued switches.
+pub(crate) fn spawn_worker(
+    queue: Arc<SwitchQueue>,
+    dbus_conn: Arc<Connection>,
+    peer_tx: broadcast::Sender<u32>,
+) {
+    thread::spawn(move || loop {
+        let (layout_index, notify_peers, ticket) = queue.next();
+
+        // Alternation may have coalesced back to the layout already active
+        if CURRENT_LAYOUT.load(Ordering::SeqCst) != layout_index {
+            // Use confirmed switch to wait for KDE to apply the layout
+            match switch_layout_confirmed(&dbus_conn, layout_index) {
+                Ok(()) => {
+                    // No receivers just means peer sync is disabled
+                    if notify_peers {
+                        let _ = peer_tx.send(layout_index);
+                    }
+                    sound::play(layout_index, &layouts::display_name(layout_index).unwrap_or_default());
+                }
+                Err(e) => error!("Failed to switch layout: {}", e),
+            }
+        }
+
+        queue.complete(ticket);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_times_out_on_the_configured_clock() {
+        let (clock, _guard) = clock::simulated();
+        let queue = Arc::new(SwitchQueue::default());
+        let ticket = queue.submit(1);
+
+        let waiter = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.wait(ticket, Duration::from_millis(50)))
+        };
+        // Real time passing doesn't reach the timeout
+        thread::sleep(Duration::from_millis(200));
+        assert!(!waiter.is_finished());
+        clock.advance(Duration::from_millis(50));
+        assert!(!waiter.join().unwrap());
+    }
+
+    #[test]
+    fn wait_returns_once_the_switch_is_done() {
+        let (_clock, _guard) = clock::simulated();
+        let queue = Arc::new(SwitchQueue::default());
+        queue.submit(1);
+        let ticket = queue.submit(2);
+        // Both requests coalesce into one switch to the latest target
+        assert_eq!(queue.next(), (2, true, ticket));
+        queue.complete(ticket);
+        assert!(queue.wait(ticket, Duration::ZERO));
+        assert_eq!(queue.completed(), ticket);
+    }
+}
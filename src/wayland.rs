@@ -0,0 +1,195 @@
+// Event injection as an unprivileged Wayland client, through the
+// zwp_virtual_keyboard_v1 protocol of wlroots-based compositors (sway,
+// Hyprland, river, ...), so grab mode needs no /dev/uinput. Each keyboard
+// gets its own virtual keyboard, given the seat's keymap; the layout is
+// selected through the xkb group, which follows the current layout index.
+// A virtual keyboard has no pointer, so trackpad motion and wheel events
+// are not forwarded.
+
+use evdev::{InputEvent, InputEventKind};
+use std::fmt::Display;
+use std::io;
+use std::os::fd::{AsFd, OwnedFd};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+use wayland_client::protocol::{wl_keyboard, wl_registry, wl_seat};
+use wayland_client::{delegate_noop, Connection, Dispatch, EventQueue, QueueHandle, WEnum};
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1;
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1;
+
+use crate::daemon::CURRENT_LAYOUT;
+use crate::events::{LockState, Modifiers, PressedKeys};
+use crate::output::EventSink;
+
+// wl_keyboard.keymap_format.xkb_v1
+const XKB_V1: u32 = 1;
+
+// Real modifier bits of the xkb state, as in the standard pc keymaps
+const SHIFT_MASK: u32 = 1 << 0;
+const LOCK_MASK: u32 = 1 << 1;
+const CONTROL_MASK: u32 = 1 << 2;
+const MOD1_MASK: u32 = 1 << 3;
+const MOD2_MASK: u32 = 1 << 4;
+const MOD4_MASK: u32 = 1 << 6;
+const MOD5_MASK: u32 = 1 << 7;
+
+// The seat keymap, as last announced to our wl_keyboard
+#[derive(Default)]
+struct State {
+    keymap: Option<(OwnedFd, u32)>,
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(
+        _: &mut Self,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<wl_keyboard::WlKeyboard, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &wl_keyboard::WlKeyboard,
+        event: wl_keyboard::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_keyboard::Event::Keymap {
+            format: WEnum::Value(wl_keyboard::KeymapFormat::XkbV1),
+            fd,
+            size,
+        } = event
+        {
+            state.keymap = Some((fd, size));
+        }
+    }
+}
+
+delegate_noop!(State: ignore wl_seat::WlSeat);
+delegate_noop!(State: ZwpVirtualKeyboardManagerV1);
+delegate_noop!(State: ZwpVirtualKeyboardV1);
+
+fn failure(e: impl Display) -> io::Error {
+    io::Error::other(format!("Wayland virtual keyboard: {}", e))
+}
+
+/// Sink forwarding key events to a new virtual keyboard on the
+/// compositor of `$WAYLAND_DISPLAY`.
+pub(crate) fn sink() -> io::Result<WaylandSink> {
+    let conn = Connection::connect_to_env().map_err(failure)?;
+    let (globals, mut queue) = registry_queue_init::<State>(&conn).map_err(failure)?;
+    let qh = queue.handle();
+    let seat: wl_seat::WlSeat = globals.bind(&qh, 1..=1, ()).map_err(failure)?;
+    let manager: ZwpVirtualKeyboardManagerV1 = globals
+        .bind(&qh, 1..=1, ())
+        .map_err(|_| failure("the compositor does not offer zwp_virtual_keyboard_manager_v1"))?;
+
+    // The seat's keymap, which the virtual keyboard has to be given
+    let _seat_keyboard = seat.get_keyboard(&qh, ());
+    let mut state = State::default();
+    queue.roundtrip(&mut state).map_err(failure)?;
+    let keyboard = manager.create_virtual_keyboard(&seat, &qh, ());
+
+    let mut sink = WaylandSink {
+        conn,
+        queue,
+        state,
+        keyboard,
+        started: Instant::now(),
+        pressed: PressedKeys::new(),
+        locks: LockState::default(),
+        group: None,
+    };
+    if !sink.update_keymap() {
+        return Err(failure("the seat has no xkb keymap"));
+    }
+    sink.conn.flush().map_err(failure)?;
+    Ok(sink)
+}
+
+pub(crate) struct WaylandSink {
+    conn: Connection,
+    queue: EventQueue<State>,
+    state: State,
+    keyboard: ZwpVirtualKeyboardV1,
+    started: Instant,
+    // Modifier and lock keys as sent, for the modifier state that goes
+    // with a group change
+    pressed: PressedKeys,
+    locks: LockState,
+    // xkb group last sent
+    group: Option<u32>,
+}
+
+impl WaylandSink {
+    // Hand a newly announced seat keymap (e.g. after a layout was added) to
+    // the virtual keyboard. Returns whether there was one.
+    fn update_keymap(&mut self) -> bool {
+        let Some((fd, size)) = self.state.keymap.take() else {
+            return false;
+        };
+        self.keyboard.keymap(XKB_V1, fd.as_fd(), size);
+        // A new keymap starts out in group 0
+        self.group = None;
+        true
+    }
+
+    // Process whatever the compositor sent since the last frame
+    fn dispatch(&mut self) -> io::Result<()> {
+        if let Some(guard) = self.queue.prepare_read() {
+            // Nothing to read is not an error here
+            let _ = guard.read();
+        }
+        self.queue.dispatch_pending(&mut self.state).map_err(failure)?;
+        Ok(())
+    }
+
+    fn send_modifiers(&self, group: u32) {
+        let mods = self.pressed.modifiers();
+        let depressed = [
+            (Modifiers::SHIFT, SHIFT_MASK),
+            (Modifiers::CTRL, CONTROL_MASK),
+            (Modifiers::ALT, MOD1_MASK),
+            (Modifiers::ALTGR, MOD5_MASK),
+            (Modifiers::META, MOD4_MASK),
+        ]
+        .into_iter()
+        .filter(|(modifier, _)| mods.contains(*modifier))
+        .fold(0, |mask, (_, bit)| mask | bit);
+        let locked = if self.locks.caps { LOCK_MASK } else { 0 } | if self.locks.num { MOD2_MASK } else { 0 };
+        self.keyboard.modifiers(depressed, 0, locked, group);
+    }
+}
+
+impl EventSink for WaylandSink {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        self.dispatch()?;
+        self.update_keymap();
+
+        let group = CURRENT_LAYOUT.load(Ordering::SeqCst);
+        if self.group != Some(group) {
+            self.send_modifiers(group);
+            self.group = Some(group);
+        }
+
+        let time = self.started.elapsed().as_millis() as u32;
+        for ev in events {
+            // The compositor repeats held keys itself
+            if let InputEventKind::Key(key) = ev.kind() {
+                if ev.value() == 0 || ev.value() == 1 {
+                    self.keyboard.key(time, key.code() as u32, ev.value() as u32);
+                }
+            }
+        }
+        self.pressed.process(events);
+        self.locks.process(events);
+        self.conn.flush().map_err(failure)
+    }
+}
@@ -0,0 +1,109 @@
+// Offline xkb layout data, for when no desktop backend can be asked: the
+// rules registry lists every layout and variant, and with the `xkb` feature
+// libxkbcommon (loaded at runtime) compiles a keymap to prove a configured
+// layout actually works.
+
+use std::path::PathBuf;
+
+fn rules_file() -> PathBuf {
+    let root = std::env::var_os("XKB_CONFIG_ROOT").unwrap_or_else(|| "/usr/share/X11/xkb".into());
+    PathBuf::from(root).join("rules/evdev.lst")
+}
+
+/// Layouts and variants known to xkb as (layout, variant, description);
+/// the variant is empty for the base layout. Empty when the registry is
+/// missing.
+pub fn registry() -> Vec<(String, String, String)> {
+    let Ok(content) = std::fs::read_to_string(rules_file()) else {
+        return Vec::new();
+    };
+    let mut entries = Vec::new();
+    let mut section = "";
+    for line in content.lines() {
+        if let Some(name) = line.strip_prefix("! ") {
+            section = name.trim();
+            continue;
+        }
+        let Some((name, rest)) = line.trim().split_once(char::is_whitespace) else {
+            continue;
+        };
+        let rest = rest.trim();
+        match section {
+            // "  de              German"
+            "layout" => entries.push((name.to_string(), String::new(), rest.to_string())),
+            // "  neo             de: German (Neo 2)"
+            "variant" => {
+                if let Some((layout, description)) = rest.split_once(": ") {
+                    entries.push((layout.to_string(), name.to_string(), description.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    entries
+}
+
+/// Description of a layout code, e.g. "German" for "de".
+pub(crate) fn description(layout: &str) -> Option<String> {
+    registry()
+        .into_iter()
+        .find(|(code, variant, _)| code == layout && variant.is_empty())
+        .map(|(_, _, description)| description)
+}
+
+/// Find a configured layout name in the registry: a code ("de"), a code
+/// with variant ("de(neo)") or a description ("German (Neo 2)"), ignoring
+/// case. Returns (layout, variant).
+pub(crate) fn find(name: &str) -> Option<(String, String)> {
+    let wanted = name.to_lowercase();
+    registry()
+        .into_iter()
+        .find(|(layout, variant, description)| {
+            let code = if variant.is_empty() {
+                layout.clone()
+            } else {
+                format!("{}({})", layout, variant)
+            };
+            code == wanted || description.to_lowercase() == wanted
+        })
+        .map(|(layout, variant, _)| (layout, variant))
+}
+
+/// Whether libxkbcommon compiles a keymap for the layout; None when the
+/// library can't be loaded.
+#[cfg(feature = "xkb")]
+pub(crate) fn compiles(layout: &str, variant: &str) -> Option<bool> {
+    use std::ffi::CString;
+    use xkbcommon_dl::{xkb_context_flags, xkb_keymap_compile_flags, xkb_rule_names, xkbcommon_option};
+
+    let xkb = xkbcommon_option()?;
+    let (Ok(layout), Ok(variant)) = (CString::new(layout), CString::new(variant)) else {
+        return Some(false);
+    };
+    let names = xkb_rule_names {
+        rules: std::ptr::null(),
+        model: std::ptr::null(),
+        layout: layout.as_ptr(),
+        variant: variant.as_ptr(),
+        options: std::ptr::null(),
+    };
+    // SAFETY: the names outlive the calls, and the context and keymap are
+    // released before returning
+    unsafe {
+        let context = (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_ENVIRONMENT_NAMES);
+        if context.is_null() {
+            return None;
+        }
+        let keymap = (xkb.xkb_keymap_new_from_names)(
+            context,
+            &names,
+            xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+        );
+        let compiled = !keymap.is_null();
+        if compiled {
+            (xkb.xkb_keymap_unref)(keymap);
+        }
+        (xkb.xkb_context_unref)(context);
+        Some(compiled)
+    }
+}